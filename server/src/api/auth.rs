@@ -0,0 +1,116 @@
+/**
+ * Authorization of GraphQL requests.
+ *
+ * Requests carry either a session jwt or a personal API token in the
+ * `Authorization: Bearer <token>` header. Sessions may do everything the user
+ * may do, API tokens only what their scopes allow.
+ */
+use crate::db::{
+  api_token::{ApiScope, ApiToken, API_TOKEN_PREFIX},
+  user::{Claims, User_},
+  *,
+};
+use dgraph_tonic::Client;
+use failure::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Authorization {
+  pub user_id: i64,
+  /// None for a session, which isn't limited by scopes
+  pub scopes: Option<Vec<ApiScope>>,
+}
+
+impl Authorization {
+  /// Authorize the token from the Authorization header
+  pub async fn from_bearer(conn: &Client, token: &str) -> Result<Self, Error> {
+    if token.starts_with(API_TOKEN_PREFIX) {
+      let api_token = ApiToken::use_token(conn, token).await?;
+      if read_node::<User_>(conn, api_token.user_id).await?.banned {
+        bail!("site_ban");
+      }
+      Ok(Authorization {
+        user_id: api_token.user_id,
+        scopes: Some(api_token.api_scopes()),
+      })
+    } else {
+      let user = User_::find_by_jwt(conn, token).await?;
+      Ok(Authorization {
+        user_id: user.id,
+        scopes: None,
+      })
+    }
+  }
+
+  pub fn has_scope(&self, scope: ApiScope) -> bool {
+    match &self.scopes {
+      Some(scopes) => scopes.contains(&scope),
+      None => true,
+    }
+  }
+
+  /// Tokens can't be used to manage tokens or account security
+  pub fn is_session(&self) -> bool {
+    self.scopes.is_none()
+  }
+}
+
+/**
+ * Claims of the user for the `auth` field of an API op, which takes a session
+ * jwt or an API token with scope. Ops that manage the account or its security
+ * use Claims::decode instead, which only takes sessions.
+ */
+pub async fn claims_for_scope(conn: &Client, auth: &str, scope: ApiScope) -> Result<Claims, Error> {
+  if !auth.starts_with(API_TOKEN_PREFIX) {
+    return Ok(Claims::decode(conn, auth).await?.claims);
+  }
+
+  let api_token = ApiToken::use_token(conn, auth).await?;
+  if !api_token.api_scopes().contains(&scope) {
+    bail!("missing_scope");
+  }
+  let user = read_node::<User_>(conn, api_token.user_id).await?;
+  if user.banned {
+    bail!("site_ban");
+  }
+  Ok(user.claims())
+}
+
+/// Token from an `Authorization: Bearer <token>` header value
+pub fn bearer_token(header: &str) -> Option<&str> {
+  let mut parts = header.splitn(2, ' ');
+  match (parts.next(), parts.next()) {
+    (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_scopes() {
+    let session = Authorization {
+      user_id: 1,
+      scopes: None,
+    };
+    assert!(session.has_scope(ApiScope::Admin));
+    assert!(session.is_session());
+
+    let bot = Authorization {
+      user_id: 1,
+      scopes: Some(vec![ApiScope::Read, ApiScope::Post]),
+    };
+    assert!(bot.has_scope(ApiScope::Post));
+    assert!(!bot.has_scope(ApiScope::Vote));
+    assert!(!bot.is_session());
+  }
+
+  #[test]
+  fn test_bearer_token() {
+    assert_eq!(bearer_token("Bearer lemmy_abc"), Some("lemmy_abc"));
+    assert_eq!(bearer_token("bearer  abc "), Some("abc"));
+    assert_eq!(bearer_token("Basic abc"), None);
+    assert_eq!(bearer_token("Bearer"), None);
+  }
+}
@@ -10,12 +10,14 @@
 
 use std::sync::Arc;
 
-use actix_web::{web, Error, HttpResponse};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web::http::header::AUTHORIZATION;
 use juniper::http::GraphQLRequest;
 use juniper_actix::{
     graphiql_handler as gqli_handler, graphql_handler, playground_handler as play_handler,
 };
 
+use crate::api::auth::{bearer_token, Authorization};
 use crate::api::schema::{Schema, create_schema, Context};
 
 /**
@@ -51,12 +53,30 @@ pub async fn playground_handler() -> Result<HttpResponse, Error> {
  * Handle POST and GET requests at GraphQL endpoint.
  */
 pub async fn graphql(
+    req: HttpRequest,
     conn: web::Data<dgraph_tonic::Client>,
     schema: web::Data<Arc<Schema>>,
     data: web::Json<GraphQLRequest>,
 ) -> Result<HttpResponse, Error> {
+    // A bad token is refused outright, instead of silently continuing anonymously
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(bearer_token);
+    let auth = match token {
+        Some(token) => match Authorization::from_bearer(&conn, token).await {
+            Ok(auth) => Some(auth),
+            Err(_e) => return Ok(HttpResponse::Unauthorized().json(
+                serde_json::json!({ "error": "not_logged_in" }),
+            )),
+        },
+        None => None,
+    };
+
     let ctx = Context {
-        dbpool: conn.to_owned(),
+        conn: conn.get_ref().to_owned(),
+        auth,
     };
     let res = web::block(move || {
         let res = data.execute(&schema, &ctx);
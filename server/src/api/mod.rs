@@ -1,9 +1,14 @@
 use crate::{
-  extract_usernames, fetch_iframely_and_pictshare_data, generate_random_string, naive_from_unix,
-  naive_now, remove_slurs, send_email, slur_check, slurs_vec_to_str,
+  extract_usernames, fetch_iframely_and_pictshare_data, generate_random_string, mention_changes,
+  naive_from_unix, naive_now, slurs_vec_to_str,
 };
 
-use crate::settings::Settings;
+use crate::content_filter;
+use crate::email::{self, EmailTemplate};
+use crate::db::content_filter::{FilterAction, FilterTarget};
+use crate::db::block::UserBlock;
+use crate::db::user_mention::{PostMention, PostMentionForm, UserMention, UserMentionForm};
+use crate::db::{site_view::SiteView, user::User_};
 
 use failure::Error;
 use log::{error, info};
@@ -11,6 +16,7 @@ use serde::{Deserialize, Serialize};
 
 // Re-export request types & ops
 
+pub mod auth;
 pub mod schema;
 pub mod handlers;
 pub mod types;
@@ -52,6 +58,176 @@ impl APIError {
   }
 }
 
+/// Admins and mods have to set up two-factor authentication before using their
+/// powers, if the site requires it. Fails if the site can't be read.
+pub fn check_totp_enforced(conn: &PgConnection, user_id: i64) -> Result<(), Error> {
+  let site = SiteView::read(conn)?;
+  if site.require_totp_for_mods && !User_::read(conn, user_id)?.totp_enabled {
+    return Err(APIError::err("totp_setup_required").into());
+  }
+  Ok(())
+}
+
+/// Users need a verified email address to post, if the site requires it. Fails if
+/// the site can't be read.
+pub fn check_email_verified(conn: &PgConnection, user_id: i64) -> Result<(), Error> {
+  let site = SiteView::read(conn)?;
+  if site.require_email_verification && !User_::read(conn, user_id)?.email_verified {
+    return Err(APIError::err("email_not_verified").into());
+  }
+  Ok(())
+}
+
+/// Runs posted text through the content filters. Rejected text is an error, otherwise
+/// returns the text with replacements applied and whether it has to wait for a moderator.
+pub fn filter_content(
+  text: &str,
+  target: FilterTarget,
+  community_id: Option<i64>,
+) -> Result<(String, bool), Error> {
+  let verdict = content_filter::check(text, target, community_id);
+  match verdict.action {
+    Some(FilterAction::Reject) => Err(APIError::err(&slurs_vec_to_str(&verdict.matches)).into()),
+    Some(FilterAction::Hold) => Ok((verdict.text, true)),
+    _ => Ok((verdict.text, false)),
+  }
+}
+
+/// Like filter_content, for text nobody moderates (descriptions, private messages):
+/// held text is rejected.
+pub fn filter_unmoderated(
+  text: &str,
+  target: FilterTarget,
+  community_id: Option<i64>,
+) -> Result<String, Error> {
+  let verdict = content_filter::check(text, target, community_id);
+  match verdict.action {
+    Some(FilterAction::Reject) | Some(FilterAction::Hold) => {
+      Err(APIError::err(&slurs_vec_to_str(&verdict.matches)).into())
+    }
+    _ => Ok(verdict.text),
+  }
+}
+
+/// Names can't be rewritten, so any matching filter rejects them.
+pub fn check_name(name: &str, target: FilterTarget) -> Result<(), Error> {
+  let verdict = content_filter::check(name, target, None);
+  if verdict.action.is_some() {
+    return Err(APIError::err(&slurs_vec_to_str(&verdict.matches)).into());
+  }
+  Ok(())
+}
+
+/// The post or comment a mention is made in
+#[derive(Clone, Copy, Debug)]
+pub enum MentionSource {
+  Post(i64),
+  Comment(i64),
+}
+
+/**
+ * Brings the mentions of a post or comment in line with its text after it was
+ * created (old_text is None) or edited. Newly mentioned users get a mention and
+ * an email if they want one, unread mentions of users the text no longer
+ * mentions are removed. Returns the ids of the newly mentioned users.
+ */
+pub fn reconcile_mentions(
+  conn: &PgConnection,
+  source: MentionSource,
+  author_id: i64,
+  author_name: &str,
+  old_text: Option<&str>,
+  new_text: &str,
+) -> Result<Vec<i64>, Error> {
+  let (added, removed) = mention_changes(old_text.unwrap_or(""), new_text);
+
+  let mut recipient_ids = Vec::new();
+  for username in &added {
+    let mention_user = match User_::read_from_name(conn, username.to_owned()) {
+      Ok(user) => user,
+      Err(_e) => continue,
+    };
+
+    // You can't mention yourself, and users who blocked the author aren't notified
+    if mention_user.id == author_id || UserBlock::is_blocked(conn, mention_user.id, author_id)? {
+      continue;
+    }
+
+    let created = match source {
+      MentionSource::Post(post_id) => PostMention::create(
+        conn,
+        &PostMentionForm {
+          recipient_id: mention_user.id,
+          post_id,
+          read: None,
+        },
+      )
+      .map(|_| ()),
+      MentionSource::Comment(comment_id) => UserMention::create(
+        conn,
+        &UserMentionForm {
+          recipient_id: mention_user.id,
+          comment_id,
+          read: None,
+        },
+      )
+      .map(|_| ()),
+    };
+    if let Err(e) = created {
+      error!("{}", e);
+      continue;
+    }
+    recipient_ids.push(mention_user.id);
+
+    // Send an email to those users that have notifications on
+    if mention_user.send_notifications_to_email {
+      if let Some(mention_email) = &mention_user.email {
+        let template = EmailTemplate::Mention {
+          author: author_name,
+          content: new_text,
+        };
+        let lang = &mention_user.lang;
+        if let Err(e) = email::queue(conn, mention_email, &mention_user.name, lang, &template) {
+          error!("{}", e);
+        }
+      }
+    }
+  }
+
+  if removed.is_empty() {
+    return Ok(recipient_ids);
+  }
+
+  // Read mentions stay, the recipient already saw them
+  let unread_recipient_ids: Vec<i64> = match source {
+    MentionSource::Post(post_id) => PostMention::list_for_post(conn, post_id)?
+      .into_iter()
+      .filter(|m| !m.read)
+      .map(|m| m.recipient_id)
+      .collect(),
+    MentionSource::Comment(comment_id) => UserMention::list_for_comment(conn, comment_id)?
+      .into_iter()
+      .filter(|m| !m.read)
+      .map(|m| m.recipient_id)
+      .collect(),
+  };
+  for username in &removed {
+    let recipient_id = match User_::read_from_name(conn, username.to_owned()) {
+      Ok(user) => user.id,
+      Err(_e) => continue,
+    };
+    if !unread_recipient_ids.contains(&recipient_id) {
+      continue;
+    }
+    match source {
+      MentionSource::Post(post_id) => PostMention::delete(conn, post_id, recipient_id)?,
+      MentionSource::Comment(comment_id) => UserMention::delete(conn, comment_id, recipient_id)?,
+    };
+  }
+
+  Ok(recipient_ids)
+}
+
 pub struct Oper<T> {
   data: T,
 }
@@ -1,349 +1,376 @@
 use crate::api::*;
+use crate::api::auth::claims_for_scope;
 use crate::api::types::comment::*;
+use crate::db::api_token::ApiScope;
+use crate::db::comment::{Comment, CommentForm, CommentLike, CommentLikeForm};
+use crate::db::comment_view::CommentView;
+use crate::db::content_filter::FilterTarget;
+use crate::db::revision::CommentRevision;
+use crate::email::{self, EmailTemplate};
+use log::error;
 use crate::db::{
   SortType, ListingType,
   user::Claims
 };
 
-// impl Perform for Oper<CreateComment> {
-//   type Response = CommentResponse;
-
-//   fn perform(
-//     &self,
-//     pool: Pool<ConnectionManager<PgConnection>>,
-//     websocket_info: Option<WebsocketInfo>,
-//   ) -> Result<CommentResponse, Error> {
-//     let data: &CreateComment = &self.data;
-
-//     let claims = match Claims::decode(&data.auth) {
-//       Ok(claims) => claims.claims,
-//       Err(_e) => return Err(APIError::err("not_logged_in").into()),
-//     };
-
-//     let user_id = claims.id;
-
-//     let hostname = &format!("https://{}", Settings::get().hostname);
-
-//     let conn = pool.get()?;
-
-//     // Check for a community ban
-//     let post = Post::read(&conn, data.post_id)?;
-//     if CommunityUserBanView::get(&conn, user_id, post.community_id).is_ok() {
-//       return Err(APIError::err("community_ban").into());
-//     }
-
-//     // Check for a site ban
-//     if UserView::read(&conn, user_id)?.banned {
-//       return Err(APIError::err("site_ban").into());
-//     }
-
-//     let content_slurs_removed = remove_slurs(&data.content.to_owned());
-
-//     let comment_form = CommentForm {
-//       content: content_slurs_removed,
-//       parent_id: data.parent_id.to_owned(),
-//       post_id: data.post_id,
-//       creator_id: user_id,
-//       removed: None,
-//       deleted: None,
-//       read: None,
-//       updated: None,
-//     };
-
-//     let inserted_comment = match Comment::create(&conn, &comment_form) {
-//       Ok(comment) => comment,
-//       Err(_e) => return Err(APIError::err("couldnt_create_comment").into()),
-//     };
-
-//     let mut recipient_ids = Vec::new();
-
-//     // Scan the comment for user mentions, add those rows
-//     let extracted_usernames = extract_usernames(&comment_form.content);
-
-//     for username_mention in &extracted_usernames {
-//       if let Ok(mention_user) = User_::read_from_name(&conn, (*username_mention).to_string()) {
-//         // You can't mention yourself
-//         // At some point, make it so you can't tag the parent creator either
-//         // This can cause two notifications, one for reply and the other for mention
-//         if mention_user.id != user_id {
-//           recipient_ids.push(mention_user.id);
-
-//           let user_mention_form = UserMentionForm {
-//             recipient_id: mention_user.id,
-//             comment_id: inserted_comment.id,
-//             read: None,
-//           };
-
-//           // Allow this to fail softly, since comment edits might re-update or replace it
-//           // Let the uniqueness handle this fail
-//           match UserMention::create(&conn, &user_mention_form) {
-//             Ok(_mention) => (),
-//             Err(_e) => error!("{}", &_e),
-//           };
-
-//           // Send an email to those users that have notifications on
-//           if mention_user.send_notifications_to_email {
-//             if let Some(mention_email) = mention_user.email {
-//               let subject = &format!(
-//                 "{} - Mentioned by {}",
-//                 Settings::get().hostname,
-//                 claims.username
-//               );
-//               let html = &format!(
-//                 "<h1>User Mention</h1><br><div>{} - {}</div><br><a href={}/inbox>inbox</a>",
-//                 claims.username, comment_form.content, hostname
-//               );
-//               match send_email(subject, &mention_email, &mention_user.name, html) {
-//                 Ok(_o) => _o,
-//                 Err(e) => error!("{}", e),
-//               };
-//             }
-//           }
-//         }
-//       }
-//     }
-
-//     // Send notifs to the parent commenter / poster
-//     match data.parent_id {
-//       Some(parent_id) => {
-//         let parent_comment = Comment::read(&conn, parent_id)?;
-//         if parent_comment.creator_id != user_id {
-//           let parent_user = User_::read(&conn, parent_comment.creator_id)?;
-//           recipient_ids.push(parent_user.id);
-
-//           if parent_user.send_notifications_to_email {
-//             if let Some(comment_reply_email) = parent_user.email {
-//               let subject = &format!(
-//                 "{} - Reply from {}",
-//                 Settings::get().hostname,
-//                 claims.username
-//               );
-//               let html = &format!(
-//                 "<h1>Comment Reply</h1><br><div>{} - {}</div><br><a href={}/inbox>inbox</a>",
-//                 claims.username, comment_form.content, hostname
-//               );
-//               match send_email(subject, &comment_reply_email, &parent_user.name, html) {
-//                 Ok(_o) => _o,
-//                 Err(e) => error!("{}", e),
-//               };
-//             }
-//           }
-//         }
-//       }
-//       // Its a post
-//       None => {
-//         if post.creator_id != user_id {
-//           let parent_user = User_::read(&conn, post.creator_id)?;
-//           recipient_ids.push(parent_user.id);
-
-//           if parent_user.send_notifications_to_email {
-//             if let Some(post_reply_email) = parent_user.email {
-//               let subject = &format!(
-//                 "{} - Reply from {}",
-//                 Settings::get().hostname,
-//                 claims.username
-//               );
-//               let html = &format!(
-//                 "<h1>Post Reply</h1><br><div>{} - {}</div><br><a href={}/inbox>inbox</a>",
-//                 claims.username, comment_form.content, hostname
-//               );
-//               match send_email(subject, &post_reply_email, &parent_user.name, html) {
-//                 Ok(_o) => _o,
-//                 Err(e) => error!("{}", e),
-//               };
-//             }
-//           }
-//         }
-//       }
-//     };
+/**
+ * Notify the author of the parent comment, or of the post for a top level
+ * comment, of a reply by author. Returns the id of the notified user.
+ */
+fn notify_reply(
+  conn: &PgConnection,
+  post: &Post,
+  parent_id: Option<i32>,
+  author_id: i64,
+  author_name: &str,
+  content: &str,
+) -> Result<Option<i64>, Error> {
+  let parent_user_id = match parent_id {
+    Some(parent_id) => Comment::read(conn, parent_id.into())?.creator_id,
+    // Its a post
+    None => post.creator_id,
+  };
+  if parent_user_id == author_id {
+    return Ok(None);
+  }
 
-//     // You like your own comment by default
-//     let like_form = CommentLikeForm {
-//       comment_id: inserted_comment.id,
-//       post_id: data.post_id,
-//       user_id,
-//       score: 1,
-//     };
+  let parent_user = User_::read(conn, parent_user_id)?;
+  if parent_user.send_notifications_to_email {
+    if let Some(reply_email) = &parent_user.email {
+      let template = if parent_id.is_some() {
+        EmailTemplate::CommentReply {
+          author: author_name,
+          content,
+        }
+      } else {
+        EmailTemplate::PostReply {
+          author: author_name,
+          content,
+        }
+      };
+      let lang = &parent_user.lang;
+      if let Err(e) = email::queue(conn, reply_email, &parent_user.name, lang, &template) {
+        error!("{}", e);
+      }
+    }
+  }
+  Ok(Some(parent_user.id))
+}
 
-//     let _inserted_like = match CommentLike::like(&conn, &like_form) {
-//       Ok(like) => like,
-//       Err(_e) => return Err(APIError::err("couldnt_like_comment").into()),
-//     };
+impl Perform for Oper<CreateComment> {
+  type Response = CommentResponse;
 
-//     let comment_view = CommentView::read(&conn, inserted_comment.id, Some(user_id))?;
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    websocket_info: Option<WebsocketInfo>,
+  ) -> Result<CommentResponse, Error> {
+    let data: &CreateComment = &self.data;
 
-//     let mut res = CommentResponse {
-//       comment: comment_view,
-//       recipient_ids,
-//     };
+    let conn = pool.get()?;
 
-//     if let Some(ws) = websocket_info {
-//       ws.chatserver.do_send(SendComment {
-//         op: UserOperation::CreateComment,
-//         comment: res.clone(),
-//         my_id: ws.id,
-//       });
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Post) {
+      Ok(claims) => claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
 
-//       // strip out the recipient_ids, so that
-//       // users don't get double notifs
-//       res.recipient_ids = Vec::new();
-//     }
+    let user_id = claims.id;
 
-//     Ok(res)
-//   }
-// }
-
-// impl Perform for Oper<EditComment> {
-//   type Response = CommentResponse;
-
-//   fn perform(
-//     &self,
-//     pool: Pool<ConnectionManager<PgConnection>>,
-//     websocket_info: Option<WebsocketInfo>,
-//   ) -> Result<CommentResponse, Error> {
-//     let data: &EditComment = &self.data;
-
-//     let claims = match Claims::decode(&data.auth) {
-//       Ok(claims) => claims.claims,
-//       Err(_e) => return Err(APIError::err("not_logged_in").into()),
-//     };
-
-//     let user_id = claims.id;
+    // Check for a community ban
+    let post = Post::read(&conn, data.post_id.into())?;
+    if CommunityUserBanView::get(&conn, user_id, post.community_id).is_ok() {
+      return Err(APIError::err("community_ban").into());
+    }
 
-//     let conn = pool.get()?;
+    // Check for a site ban
+    if UserView::read(&conn, user_id)?.banned {
+      return Err(APIError::err("site_ban").into());
+    }
 
-//     let orig_comment = CommentView::read(&conn, data.edit_id, None)?;
-
-//     // You are allowed to mark the comment as read even if you're banned.
-//     if data.read.is_none() {
-//       // Verify its the creator or a mod, or an admin
-//       let mut editors: Vec<i32> = vec![data.creator_id];
-//       editors.append(
-//         &mut CommunityModeratorView::for_community(&conn, orig_comment.community_id)?
-//           .into_iter()
-//           .map(|m| m.user_id)
-//           .collect(),
-//       );
-//       editors.append(&mut UserView::admins(&conn)?.into_iter().map(|a| a.id).collect());
-
-//       if !editors.contains(&user_id) {
-//         return Err(APIError::err("no_comment_edit_allowed").into());
-//       }
+    check_email_verified(&conn, user_id)?;
+
+    // Held comments wait in the mod queue until a mod approves or removes them
+    let (content, held) = filter_content(&data.content, FilterTarget::Body, Some(post.community_id))?;
+
+    let comment_form = CommentForm {
+      content,
+      parent_id: data.parent_id.to_owned(),
+      post_id: data.post_id.into(),
+      creator_id: user_id,
+      removed: None,
+      held: Some(held),
+      deleted: None,
+      read: None,
+      updated: None,
+    };
+
+    let inserted_comment = match Comment::create(&conn, &comment_form) {
+      Ok(comment) => comment,
+      Err(_e) => return Err(APIError::err("couldnt_create_comment").into()),
+    };
+
+    // Nobody is notified of a held comment until a mod approves it
+    let mut recipient_ids: Vec<i32> = Vec::new();
+    if !held {
+      // Add mentions of users, and notify them
+      recipient_ids.extend(
+        reconcile_mentions(
+          &conn,
+          MentionSource::Comment(inserted_comment.id),
+          user_id,
+          &claims.username,
+          None,
+          &comment_form.content,
+        )?
+        .into_iter()
+        .map(|id| id as i32),
+      );
+
+      // Send notifs to the parent commenter / poster
+      let parent_user_id = notify_reply(
+        &conn,
+        &post,
+        data.parent_id,
+        user_id,
+        &claims.username,
+        &comment_form.content,
+      )?;
+      recipient_ids.extend(parent_user_id.map(|id| id as i32));
+    }
 
-//       // Check for a community ban
-//       if CommunityUserBanView::get(&conn, user_id, orig_comment.community_id).is_ok() {
-//         return Err(APIError::err("community_ban").into());
-//       }
+    // You like your own comment by default
+    let like_form = CommentLikeForm {
+      comment_id: inserted_comment.id,
+      user_id,
+      score: 1,
+    };
+
+    let _inserted_like = match CommentLike::like(&conn, &like_form) {
+      Ok(like) => like,
+      Err(_e) => return Err(APIError::err("couldnt_like_comment").into()),
+    };
+
+    let comment_view = CommentView::read(&conn, inserted_comment.id as i32, Some(user_id))?;
+
+    let mut res = CommentResponse {
+      comment: comment_view,
+      recipient_ids,
+    };
+
+    if let Some(ws) = websocket_info {
+      ws.chatserver.do_send(SendComment {
+        op: UserOperation::CreateComment,
+        comment: res.clone(),
+        my_id: ws.id,
+      });
+
+      // strip out the recipient_ids, so that
+      // users don't get double notifs
+      res.recipient_ids = Vec::new();
+    }
 
-//       // Check for a site ban
-//       if UserView::read(&conn, user_id)?.banned {
-//         return Err(APIError::err("site_ban").into());
-//       }
-//     }
+    Ok(res)
+  }
+}
 
-//     let content_slurs_removed = remove_slurs(&data.content.to_owned());
+impl Perform for Oper<EditComment> {
+  type Response = CommentResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    websocket_info: Option<WebsocketInfo>,
+  ) -> Result<CommentResponse, Error> {
+    let data: &EditComment = &self.data;
+
+    let conn = pool.get()?;
+
+    // Removing is a mod action
+    let mod_action = data.removed.is_some();
+    let scope = if mod_action {
+      ApiScope::Moderate
+    } else {
+      ApiScope::Post
+    };
+    let claims = match claims_for_scope(&conn, &data.auth, scope) {
+      Ok(claims) => claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    let orig_comment = CommentView::read(&conn, data.edit_id, None)?;
+
+    // You are allowed to mark the comment as read even if you're banned.
+    if data.read.is_none() {
+      // Verify its the creator or a mod, or an admin
+      let mut editors: Vec<i32> = vec![data.creator_id];
+      editors.append(
+        &mut CommunityModeratorView::for_community(&conn, orig_comment.community_id)?
+          .into_iter()
+          .map(|m| m.user_id)
+          .collect(),
+      );
+      editors.append(&mut UserView::admins(&conn)?.into_iter().map(|a| a.id).collect());
+
+      if !editors.contains(&user_id) {
+        return Err(APIError::err("no_comment_edit_allowed").into());
+      }
 
-//     let comment_form = CommentForm {
-//       content: content_slurs_removed,
-//       parent_id: data.parent_id,
-//       post_id: data.post_id,
-//       creator_id: data.creator_id,
-//       removed: data.removed.to_owned(),
-//       deleted: data.deleted.to_owned(),
-//       read: data.read.to_owned(),
-//       updated: if data.read.is_some() {
-//         orig_comment.updated
-//       } else {
-//         Some(naive_now())
-//       },
-//     };
+      // Check for a community ban
+      if CommunityUserBanView::get(&conn, user_id, orig_comment.community_id).is_ok() {
+        return Err(APIError::err("community_ban").into());
+      }
 
-//     let _updated_comment = match Comment::update(&conn, data.edit_id, &comment_form) {
-//       Ok(comment) => comment,
-//       Err(_e) => return Err(APIError::err("couldnt_update_comment").into()),
-//     };
+      // Check for a site ban
+      if UserView::read(&conn, user_id)?.banned {
+        return Err(APIError::err("site_ban").into());
+      }
+    }
 
-//     let mut recipient_ids = Vec::new();
+    if mod_action {
+      check_totp_enforced(&conn, user_id)?;
+    }
 
-//     // Scan the comment for user mentions, add those rows
-//     let extracted_usernames = extract_usernames(&comment_form.content);
-
-//     for username_mention in &extracted_usernames {
-//       let mention_user = User_::read_from_name(&conn, (*username_mention).to_string());
-
-//       if mention_user.is_ok() {
-//         let mention_user_id = mention_user?.id;
-
-//         // You can't mention yourself
-//         // At some point, make it so you can't tag the parent creator either
-//         // This can cause two notifications, one for reply and the other for mention
-//         if mention_user_id != user_id {
-//           recipient_ids.push(mention_user_id);
-
-//           let user_mention_form = UserMentionForm {
-//             recipient_id: mention_user_id,
-//             comment_id: data.edit_id,
-//             read: None,
-//           };
-
-//           // Allow this to fail softly, since comment edits might re-update or replace it
-//           // Let the uniqueness handle this fail
-//           match UserMention::create(&conn, &user_mention_form) {
-//             Ok(_mention) => (),
-//             Err(_e) => error!("{}", &_e),
-//           }
-//         }
-//       }
-//     }
+    let (content, matches_hold) = filter_content(
+      &data.content,
+      FilterTarget::Body,
+      Some(i64::from(orig_comment.community_id)),
+    )?;
+
+    let orig_content = Comment::read(&conn, data.edit_id.into())?;
+    let content_changed = orig_content.content != content;
+
+    // Only the author's own changes to the text are held. A mod approves a held
+    // comment by restoring it, or removes it, either way it leaves the queue.
+    let held = if matches_hold && content_changed && !mod_action && user_id == orig_content.creator_id {
+      Some(true)
+    } else if data.removed.is_some() {
+      Some(false)
+    } else {
+      None
+    };
+
+    let comment_form = CommentForm {
+      content,
+      parent_id: data.parent_id,
+      post_id: data.post_id.into(),
+      creator_id: data.creator_id.into(),
+      removed: data.removed.to_owned(),
+      held,
+      deleted: data.deleted.to_owned(),
+      read: data.read.to_owned(),
+      updated: if data.read.is_some() {
+        orig_comment.updated
+      } else {
+        Some(naive_now())
+      },
+    };
+
+    // Keep the version this edit replaces, for the edit history
+    if content_changed {
+      CommentRevision::record(&conn, &orig_content, user_id)?;
+    }
 
-//     // Add to recipient ids
-//     match data.parent_id {
-//       Some(parent_id) => {
-//         let parent_comment = Comment::read(&conn, parent_id)?;
-//         if parent_comment.creator_id != user_id {
-//           let parent_user = User_::read(&conn, parent_comment.creator_id)?;
-//           recipient_ids.push(parent_user.id);
-//         }
-//       }
-//       None => {
-//         let post = Post::read(&conn, data.post_id)?;
-//         recipient_ids.push(post.creator_id);
-//       }
-//     }
+    let _updated_comment = match Comment::update(&conn, data.edit_id.into(), &comment_form) {
+      Ok(comment) => comment,
+      Err(_e) => return Err(APIError::err("couldnt_update_comment").into()),
+    };
+
+    // Mentions and replies of a held comment were never sent, a mod restoring
+    // it sends them in the name of its author. While it is held, nobody is
+    // notified of its edits.
+    let released = orig_content.held && held == Some(false) && data.removed == Some(false);
+    let still_held = held.unwrap_or(orig_content.held);
+    let mut recipient_ids: Vec<i32> = Vec::new();
+    if released {
+      let author = User_::read(&conn, orig_content.creator_id)?;
+      recipient_ids.extend(
+        reconcile_mentions(
+          &conn,
+          MentionSource::Comment(data.edit_id.into()),
+          author.id,
+          &author.name,
+          None,
+          &comment_form.content,
+        )?
+        .into_iter()
+        .map(|id| id as i32),
+      );
+
+      let post = Post::read(&conn, orig_content.post_id)?;
+      let parent_user_id = notify_reply(
+        &conn,
+        &post,
+        orig_content.parent_id,
+        author.id,
+        &author.name,
+        &comment_form.content,
+      )?;
+      recipient_ids.extend(parent_user_id.map(|id| id as i32));
+    } else if !still_held {
+      // Add mentions of newly mentioned users, drop unread ones of removed names
+      recipient_ids.extend(
+        reconcile_mentions(
+          &conn,
+          MentionSource::Comment(data.edit_id.into()),
+          user_id,
+          &claims.username,
+          Some(&orig_content.content),
+          &comment_form.content,
+        )?
+        .into_iter()
+        .map(|id| id as i32),
+      );
+
+      // Add to recipient ids
+      match data.parent_id {
+        Some(parent_id) => {
+          let parent_comment = Comment::read(&conn, parent_id.into())?;
+          if parent_comment.creator_id != user_id {
+            recipient_ids.push(parent_comment.creator_id as i32);
+          }
+        }
+        None => {
+          let post = Post::read(&conn, data.post_id.into())?;
+          recipient_ids.push(post.creator_id as i32);
+        }
+      }
+    }
 
-//     // Mod tables
-//     if let Some(removed) = data.removed.to_owned() {
-//       let form = ModRemoveCommentForm {
-//         mod_user_id: user_id,
-//         comment_id: data.edit_id,
-//         removed: Some(removed),
-//         reason: data.reason.to_owned(),
-//       };
-//       ModRemoveComment::create(&conn, &form)?;
-//     }
+    // Mod tables
+    if let Some(removed) = data.removed.to_owned() {
+      let form = ModRemoveCommentForm {
+        mod_user_id: user_id,
+        comment_id: data.edit_id.into(),
+        removed: Some(removed),
+        reason: data.reason.to_owned(),
+      };
+      ModRemoveComment::create(&conn, &form)?;
+    }
 
-//     let comment_view = CommentView::read(&conn, data.edit_id, Some(user_id))?;
+    let comment_view = CommentView::read(&conn, data.edit_id, Some(user_id))?;
 
-//     let mut res = CommentResponse {
-//       comment: comment_view,
-//       recipient_ids,
-//     };
+    let mut res = CommentResponse {
+      comment: comment_view,
+      recipient_ids,
+    };
 
-//     if let Some(ws) = websocket_info {
-//       ws.chatserver.do_send(SendComment {
-//         op: UserOperation::EditComment,
-//         comment: res.clone(),
-//         my_id: ws.id,
-//       });
+    if let Some(ws) = websocket_info {
+      ws.chatserver.do_send(SendComment {
+        op: UserOperation::EditComment,
+        comment: res.clone(),
+        my_id: ws.id,
+      });
 
-//       // strip out the recipient_ids, so that
-//       // users don't get double notifs
-//       res.recipient_ids = Vec::new();
-//     }
+      // strip out the recipient_ids, so that
+      // users don't get double notifs
+      res.recipient_ids = Vec::new();
+    }
 
-//     Ok(res)
-//   }
-// }
+    Ok(res)
+  }
+}
 
 // impl Perform for Oper<SaveComment> {
 //   type Response = CommentResponse;
@@ -495,8 +522,8 @@ fn perform_get_comments(
   ) -> Result<GetCommentsResponse, Error> {
 
   let user_claims: Option<Claims> = match &data.auth {
-    Some(auth) => match Claims::decode(&auth) {
-      Ok(claims) => Some(claims.claims),
+    Some(auth) => match claims_for_scope(conn, &auth, ApiScope::Read) {
+      Ok(claims) => Some(claims),
       Err(_e) => None,
     },
     None => None,
@@ -1,4 +1,7 @@
-use crate::api::{Perform, Oper, types::community::*};
+use crate::api::{auth::claims_for_scope, check_name, check_totp_enforced, filter_unmoderated, Perform, Oper, types::community::*};
+use crate::db::api_token::ApiScope;
+use crate::db::content_filter::FilterTarget;
+use crate::db::block::{CommunityBlock, CommunityBlockForm};
 
 impl Perform for Oper<GetCommunity> {
   type Response = GetCommunityResponse;
@@ -10,10 +13,12 @@ impl Perform for Oper<GetCommunity> {
   ) -> Result<GetCommunityResponse, Error> {
     let data: &GetCommunity = &self.data;
 
+    let conn = pool.get()?;
+
     let user_id: Option<i32> = match &data.auth {
-      Some(auth) => match Claims::decode(&auth) {
+      Some(auth) => match claims_for_scope(&conn, &auth, ApiScope::Read) {
         Ok(claims) => {
-          let user_id = claims.claims.id;
+          let user_id = claims.id;
           Some(user_id)
         }
         Err(_e) => None,
@@ -21,8 +26,6 @@ impl Perform for Oper<GetCommunity> {
       None => None,
     };
 
-    let conn = pool.get()?;
-
     let community_id = match data.id {
       Some(id) => id,
       None => {
@@ -90,29 +93,22 @@ impl Perform for Oper<CreateCommunity> {
   ) -> Result<CommunityResponse, Error> {
     let data: &CreateCommunity = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
       Ok(claims) => claims.claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
-    if let Err(slurs) = slur_check(&data.name) {
-      return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-    }
-
-    if let Err(slurs) = slur_check(&data.title) {
-      return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-    }
-
-    if let Some(description) = &data.description {
-      if let Err(slurs) = slur_check(description) {
-        return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-      }
-    }
+    check_name(&data.name, FilterTarget::CommunityName)?;
+    let title = filter_unmoderated(&data.title, FilterTarget::Title, None)?;
+    let description = match &data.description {
+      Some(description) => Some(filter_unmoderated(description, FilterTarget::Body, None)?),
+      None => None,
+    };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Check for a site ban
     if UserView::read(&conn, user_id)?.banned {
       return Err(APIError::err("site_ban").into());
@@ -121,8 +117,8 @@ impl Perform for Oper<CreateCommunity> {
     // When you create a community, make sure the user becomes a moderator and a follower
     let community_form = CommunityForm {
       name: data.name.to_owned(),
-      title: data.title.to_owned(),
-      description: data.description.to_owned(),
+      title,
+      description,
       category_id: data.category_id,
       creator_id: user_id,
       removed: None,
@@ -176,29 +172,22 @@ impl Perform for Oper<EditCommunity> {
   ) -> Result<CommunityResponse, Error> {
     let data: &EditCommunity = &self.data;
 
-    if let Err(slurs) = slur_check(&data.name) {
-      return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-    }
-
-    if let Err(slurs) = slur_check(&data.title) {
-      return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-    }
+    check_name(&data.name, FilterTarget::CommunityName)?;
+    let title = filter_unmoderated(&data.title, FilterTarget::Title, Some(i64::from(data.edit_id)))?;
+    let description = match &data.description {
+      Some(description) => Some(filter_unmoderated(description, FilterTarget::Body, Some(i64::from(data.edit_id)))?),
+      None => None,
+    };
 
-    if let Some(description) = &data.description {
-      if let Err(slurs) = slur_check(description) {
-        return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-      }
-    }
+    let conn = pool.get()?;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Moderate) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Check for a site ban
     if UserView::read(&conn, user_id)?.banned {
       return Err(APIError::err("site_ban").into());
@@ -216,11 +205,12 @@ impl Perform for Oper<EditCommunity> {
     if !editors.contains(&user_id) {
       return Err(APIError::err("no_community_edit_allowed").into());
     }
+    check_totp_enforced(&conn, user_id)?;
 
     let community_form = CommunityForm {
       name: data.name.to_owned(),
-      title: data.title.to_owned(),
-      description: data.description.to_owned(),
+      title,
+      description,
       category_id: data.category_id.to_owned(),
       creator_id: user_id,
       removed: data.removed.to_owned(),
@@ -284,9 +274,11 @@ impl Perform for Oper<ListCommunities> {
   ) -> Result<ListCommunitiesResponse, Error> {
     let data: &ListCommunities = &self.data;
 
+    let conn = pool.get()?;
+
     let user_claims: Option<Claims> = match &data.auth {
-      Some(auth) => match Claims::decode(&auth) {
-        Ok(claims) => Some(claims.claims),
+      Some(auth) => match claims_for_scope(&conn, &auth, ApiScope::Read) {
+        Ok(claims) => Some(claims),
         Err(_e) => None,
       },
       None => None,
@@ -304,8 +296,6 @@ impl Perform for Oper<ListCommunities> {
 
     let sort = SortType::from_str(&data.sort)?;
 
-    let conn = pool.get()?;
-
     let communities = CommunityQueryBuilder::create(&conn)
       .sort(&sort)
       .for_user(user_id)
@@ -329,7 +319,9 @@ impl Perform for Oper<FollowCommunity> {
   ) -> Result<CommunityResponse, Error> {
     let data: &FollowCommunity = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
       Ok(claims) => claims.claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
@@ -341,8 +333,6 @@ impl Perform for Oper<FollowCommunity> {
       user_id,
     };
 
-    let conn = pool.get()?;
-
     if data.follow {
       match CommunityFollower::follow(&conn, &community_follower_form) {
         Ok(user) => user,
@@ -363,6 +353,54 @@ impl Perform for Oper<FollowCommunity> {
   }
 }
 
+impl Perform for Oper<BlockCommunity> {
+  type Response = BlockCommunityResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<BlockCommunityResponse, Error> {
+    let data: &BlockCommunity = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    let community_block_form = CommunityBlockForm {
+      community_id: data.community_id.into(),
+      user_id,
+    };
+
+    if data.block {
+      if let Err(_e) = CommunityBlock::block(&conn, &community_block_form) {
+        return Err(APIError::err("couldnt_block_community").into());
+      }
+
+      // A blocked community doesn't show up in the subscribed listing either
+      let community_follower_form = CommunityFollowerForm {
+        community_id: data.community_id,
+        user_id,
+      };
+      CommunityFollower::ignore(&conn, &community_follower_form)?;
+    } else if let Err(_e) = CommunityBlock::unblock(&conn, &community_block_form) {
+      return Err(APIError::err("couldnt_unblock_community").into());
+    }
+
+    let community_view = CommunityView::read(&conn, data.community_id, Some(user_id))?;
+
+    Ok(BlockCommunityResponse {
+      community: community_view,
+      blocked: data.block,
+    })
+  }
+}
+
 impl Perform for Oper<GetFollowedCommunities> {
   type Response = GetFollowedCommunitiesResponse;
 
@@ -373,15 +411,15 @@ impl Perform for Oper<GetFollowedCommunities> {
   ) -> Result<GetFollowedCommunitiesResponse, Error> {
     let data: &GetFollowedCommunities = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Read) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     let communities: Vec<CommunityFollowerView> =
       match CommunityFollowerView::for_user(&conn, user_id) {
         Ok(communities) => communities,
@@ -403,8 +441,10 @@ impl Perform for Oper<BanFromCommunity> {
   ) -> Result<BanFromCommunityResponse, Error> {
     let data: &BanFromCommunity = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Moderate) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
@@ -415,7 +455,7 @@ impl Perform for Oper<BanFromCommunity> {
       user_id: data.user_id,
     };
 
-    let conn = pool.get()?;
+    check_totp_enforced(&conn, user_id)?;
 
     if data.ban {
       match CommunityUserBan::ban(&conn, &community_user_ban_form) {
@@ -475,8 +515,10 @@ impl Perform for Oper<AddModToCommunity> {
   ) -> Result<AddModToCommunityResponse, Error> {
     let data: &AddModToCommunity = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Moderate) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
@@ -487,7 +529,7 @@ impl Perform for Oper<AddModToCommunity> {
       user_id: data.user_id,
     };
 
-    let conn = pool.get()?;
+    check_totp_enforced(&conn, user_id)?;
 
     if data.added {
       match CommunityModerator::join(&conn, &community_moderator_form) {
@@ -537,15 +579,15 @@ impl Perform for Oper<TransferCommunity> {
   ) -> Result<GetCommunityResponse, Error> {
     let data: &TransferCommunity = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
       Ok(claims) => claims.claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     let read_community = Community::read(&conn, data.community_id)?;
 
     let site_creator_id = Site::read(&conn, 1)?.creator_id;
@@ -559,6 +601,8 @@ impl Perform for Oper<TransferCommunity> {
       return Err(APIError::err("not_an_admin").into());
     }
 
+    check_totp_enforced(&conn, user_id)?;
+
     let community_form = CommunityForm {
       name: read_community.name,
       title: read_community.title,
@@ -1,4 +1,13 @@
-use crate::api::{Perform, Oper, types::post::*};
+use crate::api::{
+  auth::claims_for_scope,
+  check_email_verified, check_totp_enforced, filter_content, reconcile_mentions, MentionSource,
+  Perform, Oper,
+  types::post::*,
+};
+use crate::db::api_token::ApiScope;
+use crate::db::content_filter::FilterTarget;
+use crate::db::revision::PostRevision;
+use crate::db::comment_tree::{CommentTree, TreeLimits};
 use crate::db::post_view::*;
 
 impl Perform for Oper<CreatePost> {
@@ -11,25 +20,27 @@ impl Perform for Oper<CreatePost> {
   ) -> Result<PostResponse, Error> {
     let data: &CreatePost = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Post) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
-    if let Err(slurs) = slur_check(&data.name) {
-      return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-    }
-
-    if let Some(body) = &data.body {
-      if let Err(slurs) = slur_check(body) {
-        return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
+    let community_id = Some(i64::from(data.community_id));
+    let (name, name_held) = filter_content(&data.name, FilterTarget::Title, community_id)?;
+    let (body, body_held) = match &data.body {
+      Some(body) => {
+        let (body, held) = filter_content(body, FilterTarget::Body, community_id)?;
+        (Some(body), held)
       }
-    }
+      None => (None, false),
+    };
+    // Held posts wait in the mod queue until a mod approves or removes them
+    let held = name_held || body_held;
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Check for a community ban
     if CommunityUserBanView::get(&conn, user_id, data.community_id).is_ok() {
       return Err(APIError::err("community_ban").into());
@@ -40,17 +51,20 @@ impl Perform for Oper<CreatePost> {
       return Err(APIError::err("site_ban").into());
     }
 
+    check_email_verified(&conn, user_id)?;
+
     // Fetch Iframely and Pictshare cached image
     let (iframely_title, iframely_description, iframely_html, pictshare_thumbnail) =
       fetch_iframely_and_pictshare_data(data.url.to_owned());
 
     let post_form = PostForm {
-      name: data.name.to_owned(),
+      name,
       url: data.url.to_owned(),
-      body: data.body.to_owned(),
+      body,
       community_id: data.community_id,
       creator_id: user_id,
       removed: None,
+      held: Some(held),
       deleted: None,
       nsfw: data.nsfw,
       locked: None,
@@ -75,6 +89,18 @@ impl Perform for Oper<CreatePost> {
       }
     };
 
+    // Nobody is notified of a held post until a mod approves it
+    if let (Some(body), false) = (&post_form.body, held) {
+      reconcile_mentions(
+        &conn,
+        MentionSource::Post(inserted_post.id.into()),
+        user_id,
+        &claims.username,
+        None,
+        body,
+      )?;
+    }
+
     // They like their own post by default
     let like_form = PostLikeForm {
       post_id: inserted_post.id,
@@ -118,10 +144,12 @@ impl Perform for Oper<GetPost> {
   ) -> Result<GetPostResponse, Error> {
     let data: &GetPost = &self.data;
 
+    let conn = pool.get()?;
+
     let user_id: Option<i32> = match &data.auth {
-      Some(auth) => match Claims::decode(&auth) {
+      Some(auth) => match claims_for_scope(&conn, &auth, ApiScope::Read) {
         Ok(claims) => {
-          let user_id = claims.claims.id;
+          let user_id = claims.id;
           Some(user_id)
         }
         Err(_e) => None,
@@ -129,8 +157,6 @@ impl Perform for Oper<GetPost> {
       None => None,
     };
 
-    let conn = pool.get()?;
-
     let post_view = match PostView::read(&conn, data.id, user_id) {
       Ok(post) => post,
       Err(_e) => return Err(APIError::err("couldnt_find_post").into()),
@@ -141,6 +167,8 @@ impl Perform for Oper<GetPost> {
       .my_user_id(user_id)
       .limit(9999)
       .list()?;
+    let limits = TreeLimits::new(data.max_depth, data.max_children);
+    let (comments, more_comments) = CommentTree::new(comments).top_level(0, &limits);
 
     let community = CommunityView::read(&conn, post_view.community_id, user_id)?;
 
@@ -174,6 +202,7 @@ impl Perform for Oper<GetPost> {
     Ok(GetPostResponse {
       post: post_view,
       comments,
+      more_comments,
       community,
       moderators,
       admins,
@@ -182,6 +211,64 @@ impl Perform for Oper<GetPost> {
   }
 }
 
+impl Perform for Oper<GetCommentTree> {
+  type Response = GetCommentTreeResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<GetCommentTreeResponse, Error> {
+    let data: &GetCommentTree = &self.data;
+
+    let conn = pool.get()?;
+
+    let user_id: Option<i32> = match &data.auth {
+      Some(auth) => match claims_for_scope(&conn, &auth, ApiScope::Read) {
+        Ok(claims) => Some(claims.id),
+        Err(_e) => None,
+      },
+      None => None,
+    };
+
+    let limits = TreeLimits::new(data.max_depth, data.max_children);
+    let offset = data.offset.unwrap_or(0).max(0) as usize;
+
+    // Loading more replies only needs their subtree, one level deeper than
+    // shown to count what is left out
+    let query = CommentQueryBuilder::create(&conn).my_user_id(user_id);
+    let comments = match data.parent_id {
+      Some(parent_id) => query.subtree_of(parent_id, limits.max_depth + 1),
+      None => query.for_post_id(data.post_id),
+    }
+    .limit(9999)
+    .list()?;
+    let tree = CommentTree::new(comments);
+
+    match data.parent_id {
+      Some(parent_id) => {
+        let subtree = match tree.subtree(parent_id, offset, &limits) {
+          Some(subtree) => subtree,
+          None => return Err(APIError::err("couldnt_find_comment").into()),
+        };
+        Ok(GetCommentTreeResponse {
+          parent: Some(subtree.comment),
+          comments: subtree.children,
+          more_comments: subtree.more,
+        })
+      }
+      None => {
+        let (comments, more_comments) = tree.top_level(offset, &limits);
+        Ok(GetCommentTreeResponse {
+          parent: None,
+          comments,
+          more_comments,
+        })
+      }
+    }
+  }
+}
+
 impl Perform for Oper<GetPosts> {
   type Response = GetPostsResponse;
 
@@ -192,9 +279,11 @@ impl Perform for Oper<GetPosts> {
   ) -> Result<GetPostsResponse, Error> {
     let data: &GetPosts = &self.data;
 
+    let conn = pool.get()?;
+
     let user_claims: Option<Claims> = match &data.auth {
-      Some(auth) => match Claims::decode(&auth) {
-        Ok(claims) => Some(claims.claims),
+      Some(auth) => match claims_for_scope(&conn, &auth, ApiScope::Read) {
+        Ok(claims) => Some(claims),
         Err(_e) => None,
       },
       None => None,
@@ -213,8 +302,6 @@ impl Perform for Oper<GetPosts> {
     let type_ = ListingType::from_str(&data.type_)?;
     let sort = SortType::from_str(&data.sort)?;
 
-    let conn = pool.get()?;
-
     let posts = match PostQueryBuilder::create(&conn)
       .listing_type(type_)
       .sort(&sort)
@@ -247,6 +334,52 @@ impl Perform for Oper<GetPosts> {
   }
 }
 
+impl Perform for Oper<GetHeldPosts> {
+  type Response = GetHeldPostsResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<GetHeldPostsResponse, Error> {
+    let data: &GetHeldPosts = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Moderate) {
+      Ok(claims) => claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    // Verify its a mod or admin
+    let mut mods: Vec<i32> = CommunityModeratorView::for_community(&conn, data.community_id)?
+      .into_iter()
+      .map(|m| m.user_id)
+      .collect();
+    mods.append(&mut UserView::admins(&conn)?.into_iter().map(|a| a.id).collect());
+    if !mods.contains(&user_id) {
+      return Err(APIError::err("not_a_moderator").into());
+    }
+
+    let posts = match PostQueryBuilder::create(&conn)
+      .sort(&SortType::New)
+      .for_community_id(data.community_id)
+      .held_only(true)
+      .my_user_id(user_id)
+      .page(data.page)
+      .limit(data.limit)
+      .list()
+    {
+      Ok(posts) => posts,
+      Err(_e) => return Err(APIError::err("couldnt_get_posts").into()),
+    };
+
+    Ok(GetHeldPostsResponse { posts })
+  }
+}
+
 impl Perform for Oper<CreatePostLike> {
   type Response = PostResponse;
 
@@ -257,15 +390,15 @@ impl Perform for Oper<CreatePostLike> {
   ) -> Result<PostResponse, Error> {
     let data: &CreatePostLike = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Vote) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Don't do a downvote if site has downvotes disabled
     if data.score == -1 {
       let site = SiteView::read(&conn)?;
@@ -332,25 +465,33 @@ impl Perform for Oper<EditPost> {
   ) -> Result<PostResponse, Error> {
     let data: &EditPost = &self.data;
 
-    if let Err(slurs) = slur_check(&data.name) {
-      return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-    }
-
-    if let Some(body) = &data.body {
-      if let Err(slurs) = slur_check(body) {
-        return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
+    let community_id = Some(i64::from(data.community_id));
+    let (name, name_held) = filter_content(&data.name, FilterTarget::Title, community_id)?;
+    let (body, body_held) = match &data.body {
+      Some(body) => {
+        let (body, held) = filter_content(body, FilterTarget::Body, community_id)?;
+        (Some(body), held)
       }
-    }
+      None => (None, false),
+    };
+    let matches_hold = name_held || body_held;
+
+    let conn = pool.get()?;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    // Removing, locking and stickying are mod actions
+    let mod_action = data.removed.is_some() || data.locked.is_some() || data.stickied.is_some();
+    let scope = if mod_action {
+      ApiScope::Moderate
+    } else {
+      ApiScope::Post
+    };
+    let claims = match claims_for_scope(&conn, &data.auth, scope) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Verify its the creator or a mod or admin
     let mut editors: Vec<i32> = vec![data.creator_id];
     editors.append(
@@ -364,6 +505,10 @@ impl Perform for Oper<EditPost> {
       return Err(APIError::err("no_post_edit_allowed").into());
     }
 
+    if mod_action {
+      check_totp_enforced(&conn, user_id)?;
+    }
+
     // Check for a community ban
     if CommunityUserBanView::get(&conn, user_id, data.community_id).is_ok() {
       return Err(APIError::err("community_ban").into());
@@ -374,17 +519,31 @@ impl Perform for Oper<EditPost> {
       return Err(APIError::err("site_ban").into());
     }
 
+    let orig_post = Post::read(&conn, data.edit_id)?;
+    let content_changed = PostRevision::is_changed_by(&orig_post, &name, &data.url, &body);
+
+    // Only the author's own changes to the text are held. A mod approves a held
+    // post by restoring it, or removes it, either way it leaves the queue.
+    let held = if matches_hold && content_changed && !mod_action && user_id == orig_post.creator_id {
+      Some(true)
+    } else if data.removed.is_some() {
+      Some(false)
+    } else {
+      None
+    };
+
     // Fetch Iframely and Pictshare cached image
     let (iframely_title, iframely_description, iframely_html, pictshare_thumbnail) =
       fetch_iframely_and_pictshare_data(data.url.to_owned());
 
     let post_form = PostForm {
-      name: data.name.to_owned(),
+      name,
       url: data.url.to_owned(),
-      body: data.body.to_owned(),
+      body,
       creator_id: data.creator_id.to_owned(),
       community_id: data.community_id,
       removed: data.removed.to_owned(),
+      held,
       deleted: data.deleted.to_owned(),
       nsfw: data.nsfw,
       locked: data.locked.to_owned(),
@@ -396,6 +555,11 @@ impl Perform for Oper<EditPost> {
       thumbnail_url: pictshare_thumbnail,
     };
 
+    // Keep the version this edit replaces, for the edit history
+    if content_changed {
+      PostRevision::record(&conn, &orig_post, user_id)?;
+    }
+
     let _updated_post = match Post::update(&conn, data.edit_id, &post_form) {
       Ok(post) => post,
       Err(e) => {
@@ -409,6 +573,30 @@ impl Perform for Oper<EditPost> {
       }
     };
 
+    // Mentions in a held post were never sent, a mod restoring it sends them in
+    // the name of its author. While it is held, nobody is notified of its edits.
+    let released = orig_post.held && held == Some(false) && data.removed == Some(false);
+    if released {
+      let author = User_::read(&conn, orig_post.creator_id)?;
+      reconcile_mentions(
+        &conn,
+        MentionSource::Post(data.edit_id.into()),
+        author.id,
+        &author.name,
+        None,
+        post_form.body.as_deref().unwrap_or(""),
+      )?;
+    } else if !held.unwrap_or(orig_post.held) {
+      reconcile_mentions(
+        &conn,
+        MentionSource::Post(data.edit_id.into()),
+        user_id,
+        &claims.username,
+        orig_post.body.as_deref(),
+        post_form.body.as_deref().unwrap_or(""),
+      )?;
+    }
+
     // Mod tables
     if let Some(removed) = data.removed.to_owned() {
       let form = ModRemovePostForm {
@@ -464,8 +652,10 @@ impl Perform for Oper<SavePost> {
   ) -> Result<PostResponse, Error> {
     let data: &SavePost = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Post) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
@@ -476,8 +666,6 @@ impl Perform for Oper<SavePost> {
       user_id,
     };
 
-    let conn = pool.get()?;
-
     if data.save {
       match PostSaved::save(&conn, &post_saved_form) {
         Ok(post) => post,
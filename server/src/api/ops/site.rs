@@ -1,5 +1,11 @@
-use crate::api::{Perform, Oper, types::site::*};
-use super::user::Register;
+use crate::api::{auth::claims_for_scope, check_totp_enforced, filter_unmoderated, Perform, Oper, types::site::*};
+use crate::captcha_challenge::CAPTCHA_DIFFICULTIES;
+use crate::db::api_token::ApiScope;
+use crate::db::content_filter::FilterTarget;
+use crate::email::{self, EmailTemplate};
+use crate::db::registration_application::RegistrationApplication;
+use crate::oidc;
+use crate::settings::ConfigLoadError;
 
 impl Perform for Oper<ListCategories> {
   type Response = ListCategoriesResponse;
@@ -111,37 +117,45 @@ impl Perform for Oper<CreateSite> {
   ) -> Result<SiteResponse, Error> {
     let data: &CreateSite = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Admin) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
-    if let Err(slurs) = slur_check(&data.name) {
-      return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
+    if !CAPTCHA_DIFFICULTIES.contains(&data.captcha_difficulty.as_str()) {
+      return Err(APIError::err("invalid_captcha_difficulty").into());
     }
 
-    if let Some(description) = &data.description {
-      if let Err(slurs) = slur_check(description) {
-        return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-      }
-    }
+    let name = filter_unmoderated(&data.name, FilterTarget::Title, None)?;
+    let description = match &data.description {
+      Some(description) => Some(filter_unmoderated(description, FilterTarget::Body, None)?),
+      None => None,
+    };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Make sure user is an admin
     if !UserView::read(&conn, user_id)?.admin {
       return Err(APIError::err("not_an_admin").into());
     }
+    check_totp_enforced(&conn, user_id)?;
 
     let site_form = SiteForm {
-      name: data.name.to_owned(),
-      description: data.description.to_owned(),
+      name,
+      description,
       creator_id: user_id,
       enable_downvotes: data.enable_downvotes,
       open_registration: data.open_registration,
       enable_nsfw: data.enable_nsfw,
+      require_totp_for_mods: data.require_totp_for_mods,
+      require_email_verification: data.require_email_verification,
+      require_application: data.require_application,
+      application_question: data.application_question.to_owned(),
+      captcha_enabled: data.captcha_enabled,
+      captcha_difficulty: data.captcha_difficulty.to_owned(),
+      public_edit_history: data.public_edit_history,
       updated: None,
     };
 
@@ -165,40 +179,48 @@ impl Perform for Oper<EditSite> {
   ) -> Result<SiteResponse, Error> {
     let data: &EditSite = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Admin) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
-    if let Err(slurs) = slur_check(&data.name) {
-      return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
+    if !CAPTCHA_DIFFICULTIES.contains(&data.captcha_difficulty.as_str()) {
+      return Err(APIError::err("invalid_captcha_difficulty").into());
     }
 
-    if let Some(description) = &data.description {
-      if let Err(slurs) = slur_check(description) {
-        return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-      }
-    }
+    let name = filter_unmoderated(&data.name, FilterTarget::Title, None)?;
+    let description = match &data.description {
+      Some(description) => Some(filter_unmoderated(description, FilterTarget::Body, None)?),
+      None => None,
+    };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Make sure user is an admin
     if !UserView::read(&conn, user_id)?.admin {
       return Err(APIError::err("not_an_admin").into());
     }
+    check_totp_enforced(&conn, user_id)?;
 
     let found_site = Site::read(&conn, 1)?;
 
     let site_form = SiteForm {
-      name: data.name.to_owned(),
-      description: data.description.to_owned(),
+      name,
+      description,
       creator_id: found_site.creator_id,
       updated: Some(naive_now()),
       enable_downvotes: data.enable_downvotes,
       open_registration: data.open_registration,
       enable_nsfw: data.enable_nsfw,
+      require_totp_for_mods: data.require_totp_for_mods,
+      require_email_verification: data.require_email_verification,
+      require_application: data.require_application,
+      application_question: data.application_question.to_owned(),
+      captcha_enabled: data.captcha_enabled,
+      captcha_difficulty: data.captcha_difficulty.to_owned(),
+      public_edit_history: data.public_edit_history,
     };
 
     match Site::update(&conn, 1, &site_form) {
@@ -234,33 +256,10 @@ impl Perform for Oper<GetSite> {
 
     let conn = pool.get()?;
 
-    // TODO refactor this a little
+    // The site from the config setup block is created at startup, see bootstrap.rs
     let site = Site::read(&conn, 1);
     let site_view = if site.is_ok() {
       Some(SiteView::read(&conn)?)
-    } else if let Some(setup) = Settings::get().setup.as_ref() {
-      let register = Register {
-        username: setup.admin_username.to_owned(),
-        email: setup.admin_email.to_owned(),
-        password: setup.admin_password.to_owned(),
-        password_verify: setup.admin_password.to_owned(),
-        admin: true,
-        show_nsfw: true,
-      };
-      let login_response = Oper::new(register).perform(pool.clone(), websocket_info.clone())?;
-      info!("Admin {} created", setup.admin_username);
-
-      let create_site = CreateSite {
-        name: setup.site_name.to_owned(),
-        description: None,
-        enable_downvotes: false,
-        open_registration: false,
-        enable_nsfw: false,
-        auth: login_response.jwt,
-      };
-      Oper::new(create_site).perform(pool, websocket_info.clone())?;
-      info!("Site {} created", setup.site_name);
-      Some(SiteView::read(&conn)?)
     } else {
       None
     };
@@ -291,6 +290,7 @@ impl Perform for Oper<GetSite> {
       admins,
       banned,
       online,
+      oidc_providers: oidc::provider_infos(),
     })
   }
 }
@@ -305,10 +305,12 @@ impl Perform for Oper<Search> {
   ) -> Result<SearchResponse, Error> {
     let data: &Search = &self.data;
 
+    let conn = pool.get()?;
+
     let user_id: Option<i32> = match &data.auth {
-      Some(auth) => match Claims::decode(&auth) {
+      Some(auth) => match claims_for_scope(&conn, &auth, ApiScope::Read) {
         Ok(claims) => {
-          let user_id = claims.claims.id;
+          let user_id = claims.id;
           Some(user_id)
         }
         Err(_e) => None,
@@ -326,8 +328,6 @@ impl Perform for Oper<Search> {
 
     // TODO no clean / non-nsfw searching rn
 
-    let conn = pool.get()?;
-
     match type_ {
       SearchType::Posts => {
         posts = PostQueryBuilder::create(&conn)
@@ -431,15 +431,15 @@ impl Perform for Oper<TransferSite> {
   ) -> Result<GetSiteResponse, Error> {
     let data: &TransferSite = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
       Ok(claims) => claims.claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     let read_site = Site::read(&conn, 1)?;
 
     // Make sure user is the creator
@@ -447,6 +447,8 @@ impl Perform for Oper<TransferSite> {
       return Err(APIError::err("not_an_admin").into());
     }
 
+    check_totp_enforced(&conn, user_id)?;
+
     let site_form = SiteForm {
       name: read_site.name,
       description: read_site.description,
@@ -455,6 +457,13 @@ impl Perform for Oper<TransferSite> {
       enable_downvotes: read_site.enable_downvotes,
       open_registration: read_site.open_registration,
       enable_nsfw: read_site.enable_nsfw,
+      require_totp_for_mods: read_site.require_totp_for_mods,
+      require_email_verification: read_site.require_email_verification,
+      require_application: read_site.require_application,
+      application_question: read_site.application_question,
+      captcha_enabled: read_site.captcha_enabled,
+      captcha_difficulty: read_site.captcha_difficulty,
+      public_edit_history: read_site.public_edit_history,
     };
 
     match Site::update(&conn, 1, &site_form) {
@@ -488,6 +497,7 @@ impl Perform for Oper<TransferSite> {
       admins,
       banned,
       online: 0,
+      oidc_providers: oidc::provider_infos(),
     })
   }
 }
@@ -502,15 +512,15 @@ impl Perform for Oper<GetSiteConfig> {
   ) -> Result<GetSiteConfigResponse, Error> {
     let data: &GetSiteConfig = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Admin) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Only let admins read this
     let admins = UserView::admins(&conn)?;
     let admin_ids: Vec<i32> = admins.into_iter().map(|m| m.id).collect();
@@ -518,6 +528,7 @@ impl Perform for Oper<GetSiteConfig> {
     if !admin_ids.contains(&user_id) {
       return Err(APIError::err("not_an_admin").into());
     }
+    check_totp_enforced(&conn, user_id)?;
 
     let config_hjson = Settings::read_config_file()?;
 
@@ -535,15 +546,15 @@ impl Perform for Oper<SaveSiteConfig> {
   ) -> Result<GetSiteConfigResponse, Error> {
     let data: &SaveSiteConfig = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Admin) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Only let admins read this
     let admins = UserView::admins(&conn)?;
     let admin_ids: Vec<i32> = admins.into_iter().map(|m| m.id).collect();
@@ -551,13 +562,126 @@ impl Perform for Oper<SaveSiteConfig> {
     if !admin_ids.contains(&user_id) {
       return Err(APIError::err("not_an_admin").into());
     }
+    check_totp_enforced(&conn, user_id)?;
 
+    // The new config is validated first, invalid configs are returned with their field errors
     // Make sure docker doesn't have :ro at the end of the volume, so its not a read-only filesystem
     let config_hjson = match Settings::save_config_file(&data.config_hjson) {
       Ok(config_hjson) => config_hjson,
-      Err(_e) => return Err(APIError::err("couldnt_update_site").into()),
+      Err(e) => match e.downcast::<ConfigLoadError>() {
+        Ok(config_err) => return Err(config_err.into()),
+        Err(_e) => return Err(APIError::err("couldnt_update_site").into()),
+      },
     };
 
     Ok(GetSiteConfigResponse { config_hjson })
   }
 }
+
+impl Perform for Oper<ListRegistrationApplications> {
+  type Response = ListRegistrationApplicationsResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<ListRegistrationApplicationsResponse, Error> {
+    let data: &ListRegistrationApplications = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Admin) {
+      Ok(claims) => claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    // Only let admins read this
+    if !UserView::read(&conn, user_id)?.admin {
+      return Err(APIError::err("not_an_admin").into());
+    }
+    check_totp_enforced(&conn, user_id)?;
+
+    let mut applications = Vec::new();
+    for application in RegistrationApplication::list(&conn, data.unresolved_only)? {
+      let username = User_::read(&conn, application.user_id)?.name;
+      applications.push(RegistrationApplicationView::new(application, username));
+    }
+
+    Ok(ListRegistrationApplicationsResponse { applications })
+  }
+}
+
+impl Perform for Oper<ResolveRegistrationApplication> {
+  type Response = RegistrationApplicationResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<RegistrationApplicationResponse, Error> {
+    let data: &ResolveRegistrationApplication = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Admin) {
+      Ok(claims) => claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    if !UserView::read(&conn, user_id)?.admin {
+      return Err(APIError::err("not_an_admin").into());
+    }
+    check_totp_enforced(&conn, user_id)?;
+
+    let deny_reason = if data.approve {
+      None
+    } else {
+      data.deny_reason.as_deref()
+    };
+    let application = match RegistrationApplication::resolve(
+      &conn,
+      data.id,
+      user_id,
+      data.approve,
+      deny_reason,
+    ) {
+      Ok(application) => application,
+      Err(_e) => return Err(APIError::err("couldnt_find_registration_application").into()),
+    };
+
+    // A denied user stays pending, so they see why at login
+    let applicant = if data.approve {
+      User_::update_registration_pending(&conn, application.user_id, false)?
+    } else {
+      User_::read(&conn, application.user_id)?
+    };
+
+    if let Some(applicant_email) = &applicant.email {
+      let site_name = &Site::read(&conn, 1)?.name;
+      let template = if data.approve {
+        EmailTemplate::ApplicationApproved {
+          username: &applicant.name,
+          site_name,
+        }
+      } else {
+        EmailTemplate::ApplicationDenied {
+          username: &applicant.name,
+          site_name,
+          reason: deny_reason,
+        }
+      };
+      let lang = &applicant.lang;
+      if let Err(e) = email::queue(&conn, applicant_email, &applicant.name, lang, &template) {
+        error!("{}", e);
+      }
+    }
+
+    Ok(RegistrationApplicationResponse {
+      application: RegistrationApplicationView::new(application, applicant.name),
+    })
+  }
+}
@@ -1,7 +1,36 @@
-use crate::api::{Perform, Oper, types::user::*};
+use crate::api::{auth::claims_for_scope, check_name, check_totp_enforced, filter_unmoderated, Perform, Oper, types::user::*};
+use crate::captcha_challenge::verify_answer;
+use crate::data_export;
+use crate::email::{self, EmailTemplate};
+use crate::db::content_filter::FilterTarget;
+use crate::db::data_export::DataExport;
+use crate::db::api_token::{ApiScope, ApiToken};
+use crate::db::block::{BlockList, UserBlock, UserBlockForm};
+use crate::db::email_verification::EmailVerification;
+use crate::db::external_identity::ExternalIdentity;
+use crate::db::invite_code::InviteCode;
+use crate::db::limit_and_offset;
+use crate::db::password_reset_request::PasswordResetLimitReached;
+use crate::db::registration_application::RegistrationApplication;
+use crate::db::refresh_token::RefreshToken;
+use crate::db::user_mention::{PostMention, PostMentionForm};
 use crate::is_valid_username;
-use bcrypt::verify;
+use crate::password::{needs_rehash, verify_password};
+use crate::totp;
 
+/// Upper limit for uses of a single invite code
+const MAX_INVITE_CODE_USES: i32 = 100;
+
+
+/// Check a one-time password of user and mark its time step as used, so the
+/// same code isn't accepted twice
+fn check_totp_code(conn: &PgConnection, user: &User_, code: &str) -> Result<bool, Error> {
+  let secret = user.totp_secret.as_deref().unwrap_or_default();
+  match totp::verify_code(secret, code, user.totp_last_step) {
+    Some(step) => User_::use_totp_step(conn, user.id, step),
+    None => Ok(false),
+  }
+}
 
 impl Perform for Oper<Login> {
   type Response = LoginResponse;
@@ -22,13 +51,248 @@ impl Perform for Oper<Login> {
     };
 
     // Verify the password
-    let valid: bool = verify(&data.password, &user.password_encrypted).unwrap_or(false);
+    let valid: bool = verify_password(&data.password, &user.password_encrypted);
     if !valid {
       return Err(APIError::err("password_incorrect").into());
     }
 
+    if user.registration_pending {
+      let err = match RegistrationApplication::find_for_user(&conn, user.id) {
+        Ok(application) if application.is_resolved() => "registration_denied",
+        _ => "registration_application_pending",
+      };
+      return Err(APIError::err(err).into());
+    }
+
+    // Second factor, either a one-time password or an unused recovery code
+    if user.totp_enabled {
+      let totp_code = match &data.totp_code {
+        Some(totp_code) => totp_code,
+        None => return Err(APIError::err("missing_totp_code").into()),
+      };
+      if !check_totp_code(&conn, &user, totp_code)? {
+        match totp::find_recovery_code(totp_code, &user.totp_recovery_codes) {
+          Some(index) => {
+            let secret = user.totp_secret.as_deref().unwrap_or_default();
+            let mut recovery_codes = user.totp_recovery_codes.to_owned();
+            recovery_codes.remove(index);
+            User_::update_totp(&conn, user.id, Some(secret), true, &recovery_codes)?;
+          }
+          None => return Err(APIError::err("incorrect_totp_code").into()),
+        }
+      }
+    }
+
+    // Admins and mods still get a session, so they can enroll
+    let totp_setup_required = if user.totp_enabled {
+      false
+    } else {
+      let require_totp = SiteView::read(&conn)?.require_totp_for_mods;
+      require_totp
+        && (user.admin || !CommunityModeratorView::for_user(&conn, user.id)?.is_empty())
+    };
+
+    // Old bcrypt hashes, or hashes with outdated parameters, are upgraded while
+    // the plaintext password is at hand, once the login passed every check
+    if needs_rehash(&user.password_encrypted) {
+      User_::update_password(&conn, user.id, &data.password)?;
+    }
+
     // Return the jwt
-    Ok(LoginResponse { jwt: user.jwt() })
+    let (jwt, refresh_token) = user.create_session(&conn)?;
+    Ok(LoginResponse {
+      jwt,
+      refresh_token: Some(refresh_token),
+      totp_setup_required,
+      registration_pending: false,
+    })
+  }
+}
+
+impl Perform for Oper<EnrollTotp> {
+  type Response = EnrollTotpResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<EnrollTotpResponse, Error> {
+    let data: &EnrollTotp = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user = User_::read(&conn, claims.id)?;
+    if user.totp_enabled {
+      return Err(APIError::err("totp_already_enabled").into());
+    }
+
+    // Not enabled until the user confirms a code from their app
+    let secret = totp::generate_secret();
+    User_::update_totp(&conn, user.id, Some(&secret), false, &[])?;
+
+    let issuer = match SiteView::read(&conn) {
+      Ok(site) => site.name,
+      Err(_e) => Settings::get().hostname.to_owned(),
+    };
+    let provisioning_uri = totp::provisioning_uri(&secret, &user.name, &issuer);
+
+    Ok(EnrollTotpResponse {
+      secret,
+      provisioning_uri,
+    })
+  }
+}
+
+impl Perform for Oper<ConfirmTotp> {
+  type Response = TotpRecoveryCodesResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<TotpRecoveryCodesResponse, Error> {
+    let data: &ConfirmTotp = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user = User_::read(&conn, claims.id)?;
+    let secret = match &user.totp_secret {
+      Some(secret) => secret,
+      None => return Err(APIError::err("totp_not_enrolled").into()),
+    };
+    if !check_totp_code(&conn, &user, &data.totp_code)? {
+      return Err(APIError::err("incorrect_totp_code").into());
+    }
+
+    // Only the hashes are stored, the codes are shown this one time
+    let (recovery_codes, hashes) = totp::generate_recovery_codes();
+    User_::update_totp(&conn, user.id, Some(secret), true, &hashes)?;
+
+    Ok(TotpRecoveryCodesResponse { recovery_codes })
+  }
+}
+
+impl Perform for Oper<DisableTotp> {
+  type Response = LogoutResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<LogoutResponse, Error> {
+    let data: &DisableTotp = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user = User_::read(&conn, claims.id)?;
+    if !user.totp_enabled {
+      return Err(APIError::err("totp_not_enabled").into());
+    }
+
+    // A stolen session alone isn't enough to turn off the second factor
+    if !verify_password(&data.password, &user.password_encrypted) {
+      return Err(APIError::err("password_incorrect").into());
+    }
+    if !check_totp_code(&conn, &user, &data.totp_code)? {
+      return Err(APIError::err("incorrect_totp_code").into());
+    }
+
+    User_::update_totp(&conn, user.id, None, false, &[])?;
+
+    Ok(LogoutResponse {})
+  }
+}
+
+impl Perform for Oper<RefreshAccessToken> {
+  type Response = LoginResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<LoginResponse, Error> {
+    let data: &RefreshAccessToken = &self.data;
+
+    let conn = pool.get()?;
+
+    // The old refresh token is used up, the client gets a new one
+    let (_user, jwt, refresh_token) = match User_::refresh_session(&conn, &data.refresh_token) {
+      Ok(session) => session,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    Ok(LoginResponse {
+      jwt,
+      refresh_token: Some(refresh_token),
+      totp_setup_required: false,
+      registration_pending: false,
+    })
+  }
+}
+
+impl Perform for Oper<Logout> {
+  type Response = LogoutResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<LogoutResponse, Error> {
+    let data: &Logout = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    // Only delete the token if it belongs to this user
+    if let Ok(token) = RefreshToken::read_from_token(&conn, &data.refresh_token) {
+      if token.user_id == claims.id {
+        delete_node(&conn, token.id)?;
+      }
+    }
+
+    Ok(LogoutResponse {})
+  }
+}
+
+impl Perform for Oper<LogoutAllSessions> {
+  type Response = LogoutResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<LogoutResponse, Error> {
+    let data: &LogoutAllSessions = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    User_::bump_token_version(&conn, claims.id)?;
+
+    Ok(LogoutResponse {})
   }
 }
 
@@ -44,10 +308,28 @@ impl Perform for Oper<Register> {
 
     let conn = pool.get()?;
 
-    // Make sure site has open registration
+    // Closed sites still let users in with an invite code, or through an application
+    let mut use_invite_code = false;
+    let mut registration_pending = false;
     if let Ok(site) = SiteView::read(&conn) {
+      if site.captcha_enabled {
+        match (&data.captcha_token, &data.captcha_answer) {
+          (Some(token), Some(answer)) if verify_answer(&conn, token, answer)? => (),
+          _ => return Err(APIError::err("captcha_incorrect").into()),
+        }
+      }
+
       if !site.open_registration {
-        return Err(APIError::err("registration_closed").into());
+        if data.invite_code.is_some() {
+          use_invite_code = true;
+        } else if site.require_application {
+          match &data.application_answer {
+            Some(answer) if !answer.trim().is_empty() => registration_pending = true,
+            _ => return Err(APIError::err("application_answer_required").into()),
+          }
+        } else {
+          return Err(APIError::err("registration_closed").into());
+        }
       }
     }
 
@@ -56,9 +338,7 @@ impl Perform for Oper<Register> {
       return Err(APIError::err("passwords_dont_match").into());
     }
 
-    if let Err(slurs) = slur_check(&data.username) {
-      return Err(APIError::err(&slurs_vec_to_str(slurs)).into());
-    }
+    check_name(&data.username, FilterTarget::Username)?;
 
     // Make sure there are no admins
     if data.admin && !UserView::admins(&conn)?.is_empty() {
@@ -72,7 +352,7 @@ impl Perform for Oper<Register> {
     // Register the new user
     let user_form = UserForm {
       name: data.username.to_owned(),
-      fedi_name: Settings::get().hostname,
+      fedi_name: Settings::get().hostname.to_owned(),
       email: data.email.to_owned(),
       matrix_user_id: None,
       avatar: None,
@@ -90,9 +370,16 @@ impl Perform for Oper<Register> {
       send_notifications_to_email: false,
     };
 
-    // Create the user
-    let inserted_user = match User_::register(&conn, &user_form) {
-      Ok(user) => user,
+    // Create the user, counting the use of the invite code in the same transaction
+    let registered = if use_invite_code {
+      let invite_code = data.invite_code.as_deref().unwrap_or_default();
+      User_::register_with_invite(&conn, &user_form, invite_code)
+    } else {
+      User_::register(&conn, &user_form).map(Some)
+    };
+    let inserted_user = match registered {
+      Ok(Some(user)) => user,
+      Ok(None) => return Err(APIError::err("invalid_invite_code").into()),
       Err(e) => {
         let err_type = if e.to_string()
           == "duplicate key value violates unique constraint \"user__email_key\""
@@ -106,6 +393,20 @@ impl Perform for Oper<Register> {
       }
     };
 
+    // The user exists now, they can ask for another link if this one fails
+    if let Some(email) = &inserted_user.email {
+      if let Err(e) = send_verification_email(&conn, &inserted_user, email) {
+        error!("Couldn't send verification email to {}: {}", inserted_user.name, e);
+      }
+    }
+
+    if registration_pending {
+      User_::update_registration_pending(&conn, inserted_user.id, true)?;
+      let answer = data.application_answer.as_deref().unwrap_or_default();
+      RegistrationApplication::create(&conn, inserted_user.id, answer.trim())?;
+      notify_admins_of_application(&conn, &inserted_user.name)?;
+    }
+
     // Create the main community if it doesn't exist
     let main_community: Community = match Community::read(&conn, 2) {
       Ok(c) => c,
@@ -151,9 +452,23 @@ impl Perform for Oper<Register> {
         };
     }
 
+    // No session until an admin approves the application
+    if registration_pending {
+      return Ok(LoginResponse {
+        jwt: String::new(),
+        refresh_token: None,
+        totp_setup_required: false,
+        registration_pending: true,
+      });
+    }
+
     // Return the jwt
+    let (jwt, refresh_token) = inserted_user.create_session(&conn)?;
     Ok(LoginResponse {
-      jwt: inserted_user.jwt(),
+      jwt,
+      refresh_token: Some(refresh_token),
+      totp_setup_required: false,
+      registration_pending: false,
     })
   }
 }
@@ -168,17 +483,18 @@ impl Perform for Oper<SaveUserSettings> {
   ) -> Result<LoginResponse, Error> {
     let data: &SaveUserSettings = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
       Ok(claims) => claims.claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     let read_user = User_::read(&conn, user_id)?;
 
+    let email_changed = data.email.is_some() && data.email != read_user.email;
     let email = match &data.email {
       Some(email) => Some(email.to_owned()),
       None => read_user.email,
@@ -197,11 +513,15 @@ impl Perform for Oper<SaveUserSettings> {
             match &data.old_password {
               Some(old_password) => {
                 let valid: bool =
-                  verify(old_password, &read_user.password_encrypted).unwrap_or(false);
+                  verify_password(old_password, &read_user.password_encrypted);
                 if !valid {
                   return Err(APIError::err("password_incorrect").into());
                 }
-                User_::update_password(&conn, user_id, &new_password)?.password_encrypted
+                let password_encrypted =
+                  User_::update_password(&conn, user_id, &new_password)?.password_encrypted;
+                // Log out everywhere else
+                User_::bump_token_version(&conn, user_id)?;
+                password_encrypted
               }
               None => return Err(APIError::err("password_incorrect").into()),
             }
@@ -247,9 +567,32 @@ impl Perform for Oper<SaveUserSettings> {
       }
     };
 
+    let updated_user = match data.bot_account {
+      Some(bot_account) if bot_account != updated_user.bot_account => {
+        User_::update_bot_account(&conn, user_id, bot_account)?
+      }
+      _ => updated_user,
+    };
+
+    // A new address is unverified until the link mailed to it is used
+    let updated_user = match &updated_user.email {
+      Some(email) if email_changed => {
+        let updated_user = User_::update_email(&conn, user_id, email, false)?;
+        if let Err(e) = send_verification_email(&conn, &updated_user, email) {
+          error!("Couldn't send verification email to {}: {}", updated_user.name, e);
+        }
+        updated_user
+      }
+      _ => updated_user,
+    };
+
     // Return the jwt
+    let (jwt, refresh_token) = updated_user.create_session(&conn)?;
     Ok(LoginResponse {
-      jwt: updated_user.jwt(),
+      jwt,
+      refresh_token: Some(refresh_token),
+      totp_setup_required: false,
+      registration_pending: false,
     })
   }
 }
@@ -267,8 +610,8 @@ impl Perform for Oper<GetUserDetails> {
     let conn = pool.get()?;
 
     let user_claims: Option<Claims> = match &data.auth {
-      Some(auth) => match Claims::decode(&auth) {
-        Ok(claims) => Some(claims.claims),
+      Some(auth) => match claims_for_scope(&conn, &auth, ApiScope::Read) {
+        Ok(claims) => Some(claims),
         Err(_e) => None,
       },
       None => None,
@@ -369,19 +712,20 @@ impl Perform for Oper<AddAdmin> {
   ) -> Result<AddAdminResponse, Error> {
     let data: &AddAdmin = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Admin) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Make sure user is an admin
     if !UserView::read(&conn, user_id)?.admin {
       return Err(APIError::err("not_an_admin").into());
     }
+    check_totp_enforced(&conn, user_id)?;
 
     let read_user = User_::read(&conn, data.user_id)?;
 
@@ -450,19 +794,20 @@ impl Perform for Oper<BanUser> {
   ) -> Result<BanUserResponse, Error> {
     let data: &BanUser = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Admin) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     // Make sure user is an admin
     if !UserView::read(&conn, user_id)?.admin {
       return Err(APIError::err("not_an_admin").into());
     }
+    check_totp_enforced(&conn, user_id)?;
 
     let read_user = User_::read(&conn, data.user_id)?;
 
@@ -492,6 +837,11 @@ impl Perform for Oper<BanUser> {
       Err(_e) => return Err(APIError::err("couldnt_update_user").into()),
     };
 
+    // Banned users are logged out of all sessions
+    if data.ban {
+      User_::bump_token_version(&conn, data.user_id)?;
+    }
+
     // Mod tables
     let expires = match data.expires {
       Some(time) => Some(naive_from_unix(time)),
@@ -527,6 +877,89 @@ impl Perform for Oper<BanUser> {
   }
 }
 
+impl Perform for Oper<BlockUser> {
+  type Response = BlockUserResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<BlockUserResponse, Error> {
+    let data: &BlockUser = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    if i64::from(data.user_id) == user_id {
+      return Err(APIError::err("cant_block_yourself").into());
+    }
+
+    let user_block_form = UserBlockForm {
+      user_id,
+      target_id: data.user_id.into(),
+    };
+
+    if data.block {
+      if let Err(_e) = UserBlock::block(&conn, &user_block_form) {
+        return Err(APIError::err("couldnt_block_user").into());
+      }
+    } else if let Err(_e) = UserBlock::unblock(&conn, &user_block_form) {
+      return Err(APIError::err("couldnt_unblock_user").into());
+    }
+
+    let mut user_view = UserView::read(&conn, data.user_id)?;
+    user_view.email = None;
+
+    Ok(BlockUserResponse {
+      user: user_view,
+      blocked: data.block,
+    })
+  }
+}
+
+impl Perform for Oper<GetBlockList> {
+  type Response = GetBlockListResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<GetBlockListResponse, Error> {
+    let data: &GetBlockList = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Read) {
+      Ok(claims) => claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    let blocks = BlockList::for_user(&conn, user_id)?;
+
+    let mut users = Vec::new();
+    for blocked_id in blocks.view_user_ids() {
+      let mut user_view = UserView::read(&conn, blocked_id)?;
+      user_view.email = None;
+      users.push(user_view);
+    }
+
+    let mut communities = Vec::new();
+    for blocked_id in blocks.view_community_ids() {
+      communities.push(CommunityView::read(&conn, blocked_id, Some(user_id))?);
+    }
+
+    Ok(GetBlockListResponse { users, communities })
+  }
+}
+
 impl Perform for Oper<GetReplies> {
   type Response = GetRepliesResponse;
 
@@ -537,8 +970,10 @@ impl Perform for Oper<GetReplies> {
   ) -> Result<GetRepliesResponse, Error> {
     let data: &GetReplies = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Read) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
@@ -546,8 +981,6 @@ impl Perform for Oper<GetReplies> {
 
     let sort = SortType::from_str(&data.sort)?;
 
-    let conn = pool.get()?;
-
     let replies = ReplyQueryBuilder::create(&conn, user_id)
       .sort(&sort)
       .unread_only(data.unread_only)
@@ -569,8 +1002,10 @@ impl Perform for Oper<GetUserMentions> {
   ) -> Result<GetUserMentionsResponse, Error> {
     let data: &GetUserMentions = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Read) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
@@ -578,8 +1013,6 @@ impl Perform for Oper<GetUserMentions> {
 
     let sort = SortType::from_str(&data.sort)?;
 
-    let conn = pool.get()?;
-
     let mentions = UserMentionQueryBuilder::create(&conn, user_id)
       .sort(&sort)
       .unread_only(data.unread_only)
@@ -587,7 +1020,34 @@ impl Perform for Oper<GetUserMentions> {
       .limit(data.limit)
       .list()?;
 
-    Ok(GetUserMentionsResponse { mentions })
+    let blocks = BlockList::for_user(&conn, user_id.into())?;
+    let (limit, offset) = limit_and_offset(data.page, data.limit);
+    // Mentions by blocked users are skipped before paging, so pages stay full
+    let mut post_mentions = Vec::new();
+    let mut skipped = 0;
+    for mention in PostMention::list_for_recipient(&conn, user_id.into(), data.unread_only)? {
+      if post_mentions.len() as i64 == limit {
+        break;
+      }
+      let post = PostView::read(&conn, mention.post_id, Some(user_id))?;
+      if blocks.hides(post.creator_id.into(), post.community_id.into()) {
+        continue;
+      }
+      if skipped < offset {
+        skipped += 1;
+        continue;
+      }
+      post_mentions.push(PostMentionView {
+        post,
+        read: mention.read,
+        published: mention.published,
+      });
+    }
+
+    Ok(GetUserMentionsResponse {
+      mentions,
+      post_mentions,
+    })
   }
 }
 
@@ -601,15 +1061,15 @@ impl Perform for Oper<EditUserMention> {
   ) -> Result<UserMentionResponse, Error> {
     let data: &EditUserMention = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Read) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     let user_mention = UserMention::read(&conn, data.user_mention_id)?;
 
     let user_mention_form = UserMentionForm {
@@ -642,15 +1102,15 @@ impl Perform for Oper<MarkAllAsRead> {
   ) -> Result<GetRepliesResponse, Error> {
     let data: &MarkAllAsRead = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Read) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     let replies = ReplyQueryBuilder::create(&conn, user_id)
       .unread_only(true)
       .page(1)
@@ -664,6 +1124,7 @@ impl Perform for Oper<MarkAllAsRead> {
         post_id: reply.to_owned().post_id,
         creator_id: reply.to_owned().creator_id,
         removed: None,
+        held: None,
         deleted: None,
         read: Some(true),
         updated: reply.to_owned().updated,
@@ -696,6 +1157,12 @@ impl Perform for Oper<MarkAllAsRead> {
         };
     }
 
+    for mention in PostMention::list_for_recipient(&conn, user_id.into(), true)? {
+      if let Err(_e) = PostMention::mark_read(&conn, mention.post_id, mention.recipient_id, true) {
+        return Err(APIError::err("couldnt_update_post").into());
+      }
+    }
+
     // messages
     let messages = PrivateMessageQueryBuilder::create(&conn, user_id)
       .page(1)
@@ -734,19 +1201,19 @@ impl Perform for Oper<DeleteAccount> {
   ) -> Result<LoginResponse, Error> {
     let data: &DeleteAccount = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
       Ok(claims) => claims.claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     let user: User_ = User_::read(&conn, user_id)?;
 
     // Verify the password
-    let valid: bool = verify(&data.password, &user.password_encrypted).unwrap_or(false);
+    let valid: bool = verify_password(&data.password, &user.password_encrypted);
     if !valid {
       return Err(APIError::err("password_incorrect").into());
     }
@@ -764,6 +1231,7 @@ impl Perform for Oper<DeleteAccount> {
         post_id: comment.to_owned().post_id,
         creator_id: comment.to_owned().creator_id,
         removed: None,
+        held: None,
         deleted: Some(true),
         read: None,
         updated: Some(naive_now()),
@@ -790,6 +1258,7 @@ impl Perform for Oper<DeleteAccount> {
         creator_id: post.to_owned().creator_id,
         community_id: post.to_owned().community_id,
         removed: None,
+        held: None,
         deleted: Some(true),
         nsfw: post.to_owned().nsfw,
         locked: None,
@@ -807,12 +1276,91 @@ impl Perform for Oper<DeleteAccount> {
       };
     }
 
+    User_::bump_token_version(&conn, user_id)?;
+
     Ok(LoginResponse {
       jwt: data.auth.to_owned(),
+      refresh_token: None,
+      totp_setup_required: false,
+      registration_pending: false,
     })
   }
 }
 
+impl Perform for Oper<ExportUserData> {
+  type Response = ExportUserDataResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<ExportUserDataResponse, Error> {
+    let data: &ExportUserData = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    // The build_data_exports job writes the archive
+    let file_name = data_export::file_name(user_id, data.zip);
+    let export = match DataExport::create_for_user(&conn, user_id, &file_name) {
+      Ok(export) => export,
+      Err(_e) => return Err(APIError::err("data_export_limit_reached").into()),
+    };
+
+    Ok(ExportUserDataResponse {
+      export: data_export_view(&conn, &export)?,
+    })
+  }
+}
+
+impl Perform for Oper<GetDataExport> {
+  type Response = GetDataExportResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<GetDataExportResponse, Error> {
+    let data: &GetDataExport = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let export = match DataExport::latest_for_user(&conn, claims.id)? {
+      Some(export) => Some(data_export_view(&conn, &export)?),
+      None => None,
+    };
+
+    Ok(GetDataExportResponse { export })
+  }
+}
+
+/// The export as shown to its user, with a new download link if it is ready
+fn data_export_view(conn: &PgConnection, export: &DataExport) -> Result<DataExportView, Error> {
+  let download_url = if export.is_ready_at(naive_now()) {
+    let token = DataExport::issue_download_token(conn, export.id)?;
+    Some(format!("https://{}/export/{}", Settings::get().hostname, token))
+  } else {
+    None
+  };
+
+  Ok(DataExportView {
+    status: export.status,
+    download_url,
+    expires: export.expires(),
+  })
+}
+
 impl Perform for Oper<PasswordReset> {
   type Response = PasswordResetResponse;
 
@@ -831,22 +1379,31 @@ impl Perform for Oper<PasswordReset> {
       Err(_e) => return Err(APIError::err("couldnt_find_that_username_or_email").into()),
     };
 
+    // Don't mail reset links to addresses nobody has confirmed to own
+    if !user.email_verified {
+      return Err(APIError::err("email_not_verified").into());
+    }
+
     // Generate a random token
     let token = generate_random_string();
 
-    // Insert the row
-    PasswordResetRequest::create_token(&conn, user.id, &token)?;
+    // Insert the row, replacing an earlier request
+    if let Err(e) = PasswordResetRequest::create_token(&conn, user.id, &token) {
+      if e.downcast_ref::<PasswordResetLimitReached>().is_some() {
+        return Err(APIError::err("password_reset_limit_reached").into());
+      }
+      return Err(e);
+    }
 
     // Email the pure token to the user.
-    // TODO no i18n support here.
     let user_email = &user.email.expect("email");
-    let subject = &format!("Password reset for {}", user.name);
-    let hostname = &format!("https://{}", Settings::get().hostname); //TODO add https for now.
-    let html = &format!("<h1>Password Reset Request for {}</h1><br><a href={}/password_change/{}>Click here to reset your password</a>", user.name, hostname, &token);
-    match send_email(subject, user_email, &user.name, html) {
-      Ok(_o) => _o,
-      Err(_e) => return Err(APIError::err(&_e).into()),
+    let template = EmailTemplate::PasswordReset {
+      username: &user.name,
+      token: &token,
     };
+    if let Err(e) = email::queue(&conn, user_email, &user.name, &user.lang, &template) {
+      return Err(APIError::err(&e.to_string()).into());
+    }
 
     Ok(PasswordResetResponse {})
   }
@@ -864,27 +1421,385 @@ impl Perform for Oper<PasswordChange> {
 
     let conn = pool.get()?;
 
-    // Fetch the user_id from the token
-    let user_id = PasswordResetRequest::read_from_token(&conn, &data.token)?.user_id;
-
     // Make sure passwords match
     if data.password != data.password_verify {
       return Err(APIError::err("passwords_dont_match").into());
     }
 
+    // Fetch the user_id from the token, which can't be used again after this
+    let user_id = match PasswordResetRequest::consume_token(&conn, &data.token) {
+      Ok(request) => request.user_id,
+      Err(_e) => return Err(APIError::err("invalid_password_reset_token").into()),
+    };
+
     // Update the user with the new password
-    let updated_user = match User_::update_password(&conn, user_id, &data.password) {
+    match User_::update_password(&conn, user_id, &data.password) {
       Ok(user) => user,
       Err(_e) => return Err(APIError::err("couldnt_update_user").into()),
     };
 
+    // Sessions with the old password are logged out
+    let updated_user = User_::bump_token_version(&conn, user_id)?;
+
     // Return the jwt
+    let (jwt, refresh_token) = updated_user.create_session(&conn)?;
     Ok(LoginResponse {
-      jwt: updated_user.jwt(),
+      jwt,
+      refresh_token: Some(refresh_token),
+      totp_setup_required: false,
+      registration_pending: false,
     })
   }
 }
 
+impl Perform for Oper<VerifyEmail> {
+  type Response = VerifyEmailResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<VerifyEmailResponse, Error> {
+    let data: &VerifyEmail = &self.data;
+
+    let conn = pool.get()?;
+
+    let verification = match EmailVerification::read_from_token(&conn, &data.token) {
+      Ok(verification) => verification,
+      Err(_e) => return Err(APIError::err("invalid_verification_token").into()),
+    };
+
+    // The link is for an address the user has since changed
+    let user = User_::read(&conn, verification.user_id)?;
+    if user.email.as_deref() != Some(verification.email.as_str()) {
+      EmailVerification::delete_for_user(&conn, user.id)?;
+      return Err(APIError::err("invalid_verification_token").into());
+    }
+
+    User_::update_email(&conn, user.id, &verification.email, true)?;
+    EmailVerification::delete_for_user(&conn, user.id)?;
+
+    Ok(VerifyEmailResponse {})
+  }
+}
+
+impl Perform for Oper<ResendVerificationEmail> {
+  type Response = VerifyEmailResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<VerifyEmailResponse, Error> {
+    let data: &ResendVerificationEmail = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user = User_::read(&conn, claims.id)?;
+    if user.email_verified {
+      return Err(APIError::err("email_already_verified").into());
+    }
+    let email = match &user.email {
+      Some(email) => email,
+      None => return Err(APIError::err("no_email_setup").into()),
+    };
+
+    send_verification_email(&conn, &user, email)?;
+
+    Ok(VerifyEmailResponse {})
+  }
+}
+
+impl Perform for Oper<GetOidcIdentities> {
+  type Response = OidcIdentitiesResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<OidcIdentitiesResponse, Error> {
+    let data: &GetOidcIdentities = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let linked = ExternalIdentity::list_for_user(&conn, claims.id)?
+      .into_iter()
+      .map(|identity| identity.provider)
+      .collect();
+
+    Ok(OidcIdentitiesResponse { linked })
+  }
+}
+
+impl Perform for Oper<UnlinkOidcProvider> {
+  type Response = OidcIdentitiesResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<OidcIdentitiesResponse, Error> {
+    let data: &UnlinkOidcProvider = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    ExternalIdentity::delete_for_user(&conn, claims.id, &data.provider)?;
+
+    let linked = ExternalIdentity::list_for_user(&conn, claims.id)?
+      .into_iter()
+      .map(|identity| identity.provider)
+      .collect();
+
+    Ok(OidcIdentitiesResponse { linked })
+  }
+}
+
+impl Perform for Oper<CreateApiToken> {
+  type Response = CreateApiTokenResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<CreateApiTokenResponse, Error> {
+    let data: &CreateApiToken = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    if data.name.trim().is_empty() || data.name.len() > 100 {
+      return Err(APIError::err("invalid_api_token_name").into());
+    }
+    if data.scopes.is_empty() {
+      return Err(APIError::err("no_api_token_scopes").into());
+    }
+
+    if data.scopes.contains(&ApiScope::Admin) && !UserView::read(&conn, user_id)?.admin {
+      return Err(APIError::err("not_an_admin").into());
+    }
+
+    let expires = match data.expires_days {
+      Some(days) if days <= 0 => return Err(APIError::err("invalid_api_token_expiry").into()),
+      Some(days) => Some(naive_now() + chrono::Duration::days(days)),
+      None => None,
+    };
+
+    let (api_token, token) =
+      ApiToken::create_for_user(&conn, user_id, data.name.trim(), &data.scopes, expires)?;
+
+    Ok(CreateApiTokenResponse {
+      token,
+      api_token: api_token.into(),
+    })
+  }
+}
+
+impl Perform for Oper<ListApiTokens> {
+  type Response = ListApiTokensResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<ListApiTokensResponse, Error> {
+    let data: &ListApiTokens = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let api_tokens = ApiToken::list_for_user(&conn, claims.id)?
+      .into_iter()
+      .map(ApiTokenView::from)
+      .collect();
+
+    Ok(ListApiTokensResponse { api_tokens })
+  }
+}
+
+impl Perform for Oper<RevokeApiToken> {
+  type Response = ListApiTokensResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<ListApiTokensResponse, Error> {
+    let data: &RevokeApiToken = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    if ApiToken::revoke(&conn, claims.id, data.id).is_err() {
+      return Err(APIError::err("couldnt_find_api_token").into());
+    }
+
+    let api_tokens = ApiToken::list_for_user(&conn, claims.id)?
+      .into_iter()
+      .map(ApiTokenView::from)
+      .collect();
+
+    Ok(ListApiTokensResponse { api_tokens })
+  }
+}
+
+impl Perform for Oper<CreateInviteCode> {
+  type Response = CreateInviteCodeResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<CreateInviteCodeResponse, Error> {
+    let data: &CreateInviteCode = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let user_id = claims.id;
+
+    // Admins and moderators are trusted to invite people
+    if !UserView::read(&conn, user_id)?.admin
+      && CommunityModeratorView::for_user(&conn, user_id)?.is_empty()
+    {
+      return Err(APIError::err("not_allowed_to_invite").into());
+    }
+
+    let max_uses = data.max_uses.unwrap_or(1);
+    if max_uses < 1 || max_uses > MAX_INVITE_CODE_USES {
+      return Err(APIError::err("invalid_invite_code_uses").into());
+    }
+    let expires = match data.expires_days {
+      Some(days) if days <= 0 => return Err(APIError::err("invalid_invite_code_expiry").into()),
+      Some(days) => Some(naive_now() + chrono::Duration::days(days)),
+      None => None,
+    };
+
+    let (invite_code, code) = InviteCode::create_for_user(&conn, user_id, max_uses, expires)?;
+
+    Ok(CreateInviteCodeResponse {
+      code,
+      invite_code: invite_code.into(),
+    })
+  }
+}
+
+impl Perform for Oper<ListInviteCodes> {
+  type Response = ListInviteCodesResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<ListInviteCodesResponse, Error> {
+    let data: &ListInviteCodes = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    let invite_codes = InviteCode::list_for_user(&conn, claims.id)?
+      .into_iter()
+      .map(InviteCodeView::from)
+      .collect();
+
+    Ok(ListInviteCodesResponse { invite_codes })
+  }
+}
+
+impl Perform for Oper<DeleteInviteCode> {
+  type Response = ListInviteCodesResponse;
+
+  fn perform(
+    &self,
+    pool: Pool<ConnectionManager<PgConnection>>,
+    _websocket_info: Option<WebsocketInfo>,
+  ) -> Result<ListInviteCodesResponse, Error> {
+    let data: &DeleteInviteCode = &self.data;
+
+    let conn = pool.get()?;
+
+    let claims = match Claims::decode(&conn, &data.auth) {
+      Ok(claims) => claims.claims,
+      Err(_e) => return Err(APIError::err("not_logged_in").into()),
+    };
+
+    if InviteCode::delete_for_user(&conn, claims.id, data.id).is_err() {
+      return Err(APIError::err("couldnt_find_invite_code").into());
+    }
+
+    let invite_codes = InviteCode::list_for_user(&conn, claims.id)?
+      .into_iter()
+      .map(InviteCodeView::from)
+      .collect();
+
+    Ok(ListInviteCodesResponse { invite_codes })
+  }
+}
+
+/// Let admins who want email notifications know about a new application
+fn notify_admins_of_application(conn: &PgConnection, username: &str) -> Result<(), Error> {
+  let template = EmailTemplate::NewApplication { username };
+  for admin in UserView::admins(conn)? {
+    if let (Some(admin_email), true) = (&admin.email, admin.send_notifications_to_email) {
+      let lang = User_::read(conn, admin.id)?.lang;
+      if let Err(e) = email::queue(conn, admin_email, &admin.name, &lang, &template) {
+        error!("{}", e);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Mail a link that confirms the user owns the email address
+fn send_verification_email(conn: &PgConnection, user: &User_, email: &str) -> Result<(), Error> {
+  let (_verification, token) = EmailVerification::create_for_user(conn, user.id, email)?;
+
+  let template = EmailTemplate::VerifyEmail {
+    username: &user.name,
+    token: &token,
+  };
+  match email::queue(conn, email, &user.name, &user.lang, &template) {
+    Ok(_email) => Ok(()),
+    Err(e) => Err(APIError::err(&e.to_string()).into()),
+  }
+}
+
 impl Perform for Oper<CreatePrivateMessage> {
   type Response = PrivateMessageResponse;
 
@@ -895,23 +1810,25 @@ impl Perform for Oper<CreatePrivateMessage> {
   ) -> Result<PrivateMessageResponse, Error> {
     let data: &CreatePrivateMessage = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Post) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let hostname = &format!("https://{}", Settings::get().hostname);
-
-    let conn = pool.get()?;
-
     // Check for a site ban
     if UserView::read(&conn, user_id)?.banned {
       return Err(APIError::err("site_ban").into());
     }
 
-    let content_slurs_removed = remove_slurs(&data.content.to_owned());
+    if UserBlock::is_blocked(&conn, data.recipient_id, user_id)? {
+      return Err(APIError::err("recipient_blocked_you").into());
+    }
+
+    let content_slurs_removed = filter_unmoderated(&data.content, FilterTarget::Body, None)?;
 
     let private_message_form = PrivateMessageForm {
       content: Some(content_slurs_removed.to_owned()),
@@ -933,19 +1850,14 @@ impl Perform for Oper<CreatePrivateMessage> {
     let recipient_user = User_::read(&conn, data.recipient_id)?;
     if recipient_user.send_notifications_to_email {
       if let Some(email) = recipient_user.email {
-        let subject = &format!(
-          "{} - Private Message from {}",
-          Settings::get().hostname,
-          claims.username
-        );
-        let html = &format!(
-          "<h1>Private Message</h1><br><div>{} - {}</div><br><a href={}/inbox>inbox</a>",
-          claims.username, &content_slurs_removed, hostname
-        );
-        match send_email(subject, &email, &recipient_user.name, html) {
-          Ok(_o) => _o,
-          Err(e) => error!("{}", e),
+        let template = EmailTemplate::PrivateMessage {
+          author: &claims.username,
+          content: &content_slurs_removed,
         };
+        let lang = &recipient_user.lang;
+        if let Err(e) = email::queue(&conn, &email, &recipient_user.name, lang, &template) {
+          error!("{}", e);
+        }
       }
     }
 
@@ -976,15 +1888,15 @@ impl Perform for Oper<EditPrivateMessage> {
   ) -> Result<PrivateMessageResponse, Error> {
     let data: &EditPrivateMessage = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Post) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     let orig_private_message = PrivateMessage::read(&conn, data.edit_id)?;
 
     // Check for a site ban
@@ -1000,7 +1912,7 @@ impl Perform for Oper<EditPrivateMessage> {
     }
 
     let content_slurs_removed = match &data.content {
-      Some(content) => Some(remove_slurs(content)),
+      Some(content) => Some(filter_unmoderated(content, FilterTarget::Body, None)?),
       None => None,
     };
 
@@ -1039,15 +1951,15 @@ impl Perform for Oper<GetPrivateMessages> {
   ) -> Result<PrivateMessagesResponse, Error> {
     let data: &GetPrivateMessages = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Read) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
     let user_id = claims.id;
 
-    let conn = pool.get()?;
-
     let messages = PrivateMessageQueryBuilder::create(&conn, user_id)
       .page(data.page)
       .limit(data.limit)
@@ -1063,13 +1975,15 @@ impl Perform for Oper<UserJoin> {
 
   fn perform(
     &self,
-    _pool: Pool<ConnectionManager<PgConnection>>,
+    pool: Pool<ConnectionManager<PgConnection>>,
     websocket_info: Option<WebsocketInfo>,
   ) -> Result<UserJoinResponse, Error> {
     let data: &UserJoin = &self.data;
 
-    let claims = match Claims::decode(&data.auth) {
-      Ok(claims) => claims.claims,
+    let conn = pool.get()?;
+
+    let claims = match claims_for_scope(&conn, &data.auth, ApiScope::Read) {
+      Ok(claims) => claims,
       Err(_e) => return Err(APIError::err("not_logged_in").into()),
     };
 
@@ -10,10 +10,23 @@
  */
 
 use juniper::{
-    RootNode, graphql_object, FieldResult,
+    RootNode, graphql_object, FieldResult, FieldError,
     EmptyMutation, EmptySubscription,
 };
 use dgraph_tonic::{Client};
+use crate::api::auth::Authorization;
+use crate::db::api_token::ApiScope;
+use crate::db::block::{BlockList, CommunityBlock, CommunityBlockForm, UserBlock, UserBlockForm};
+use crate::db::community::{CommunityFollower, CommunityFollowerForm};
+use crate::db::site_view::SiteView;
+use crate::db::{comment::Comment, post::Post, read_node};
+use crate::db::revision::{CommentRevision, PostRevision};
+use crate::db::content_filter::{ContentFilter, ContentFilterForm, FilterAction, FilterTarget};
+use crate::db::job_lease::{JobLease, JobStatus};
+use crate::db::user::User_;
+use crate::captcha_challenge::{generate_challenge, CaptchaChallenge};
+use crate::content_filter::{self, compile_pattern};
+use crate::edit_history::{can_view_full_history, comment_history, post_history, EditRevision};
 
 
 // TODO: dispatch queries to impl Oper in api/ops/...
@@ -26,10 +39,190 @@ use dgraph_tonic::{Client};
  */
 pub struct Context {
     pub conn: Client,
+    /// None for anonymous requests
+    pub auth: Option<Authorization>,
 }
 
 impl juniper::Context for Context {}
 
+impl Context {
+    /**
+     * Id of the logged in user, if the session or API token allows `scope`.
+     * Call this first in every resolver that acts as the user.
+     */
+    pub fn require_scope(&self, scope: ApiScope) -> FieldResult<i64> {
+        match &self.auth {
+            Some(auth) if auth.has_scope(scope) => Ok(auth.user_id),
+            Some(_) => Err(FieldError::new("missing_scope", graphql_value!({ "scope": (scope.to_string()) }))),
+            None => Err(FieldError::new("not_logged_in", juniper::Value::null())),
+        }
+    }
+
+    /**
+     * Id of the logged in user, for resolvers that API tokens can't call, like
+     * the session-only API ops.
+     */
+    pub fn require_session(&self) -> FieldResult<i64> {
+        match &self.auth {
+            Some(auth) if auth.is_session() => Ok(auth.user_id),
+            Some(_) => Err(FieldError::new("session_required", juniper::Value::null())),
+            None => Err(FieldError::new("not_logged_in", juniper::Value::null())),
+        }
+    }
+
+    /**
+     * Id of the logged in user, if they're an admin and the session or API
+     * token allows the admin scope. Admins without TOTP are refused when the
+     * site requires it for mods.
+     */
+    pub async fn require_admin(&self) -> FieldResult<i64> {
+        let user_id = self.require_scope(ApiScope::Admin)?;
+        let user = read_node::<User_>(&self.conn, user_id).await?;
+        if !user.admin {
+            return Err(FieldError::new("not_an_admin", juniper::Value::null()));
+        }
+        if SiteView::read(&self.conn)?.require_totp_for_mods && !user.totp_enabled {
+            return Err(FieldError::new("totp_setup_required", juniper::Value::null()));
+        }
+        Ok(user_id)
+    }
+
+    /**
+     * Like require_scope, for resolvers anonymous users may call too.
+     */
+    pub fn check_scope(&self, scope: ApiScope) -> FieldResult<Option<i64>> {
+        match &self.auth {
+            Some(_) => self.require_scope(scope).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/**
+ * Users and communities blocked by the logged in user.
+ */
+#[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
+pub struct Blocks {
+    pub user_ids: Vec<juniper::ID>,
+    pub community_ids: Vec<juniper::ID>,
+}
+
+impl From<BlockList> for Blocks {
+    fn from(blocks: BlockList) -> Self {
+        Blocks {
+            user_ids: blocks.user_ids.iter().map(|id| id.to_string().into()).collect(),
+            community_ids: blocks.community_ids.iter().map(|id| id.to_string().into()).collect(),
+        }
+    }
+}
+
+/**
+ * Content filter rule, see crate::content_filter.
+ */
+#[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
+pub struct ContentFilterRule {
+    pub id: juniper::ID,
+    pub pattern: String,
+    pub is_regex: bool,
+    /// Null for rules that apply to the whole site
+    pub community_id: Option<juniper::ID>,
+    pub targets: Vec<FilterTarget>,
+    pub action: FilterAction,
+    pub replacement: Option<String>,
+    /// Null for the default rules
+    pub creator_id: Option<juniper::ID>,
+    pub published: chrono::NaiveDateTime,
+    pub updated: Option<chrono::NaiveDateTime>,
+}
+
+impl From<ContentFilter> for ContentFilterRule {
+    fn from(filter: ContentFilter) -> Self {
+        ContentFilterRule {
+            id: filter.id.to_string().into(),
+            pattern: filter.pattern,
+            is_regex: filter.is_regex,
+            community_id: filter.community_id.map(|id| id.to_string().into()),
+            targets: filter.targets,
+            action: filter.action,
+            replacement: filter.replacement,
+            creator_id: filter.creator_id.map(|id| id.to_string().into()),
+            published: filter.published,
+            updated: filter.updated,
+        }
+    }
+}
+
+/**
+ * Pattern, scope and action of a new or edited content filter rule.
+ */
+#[derive(Debug, Clone, juniper::GraphQLInputObject)]
+pub struct ContentFilterInput {
+    /// A word matched on word boundaries, or a regex if isRegex is set
+    pub pattern: String,
+    pub is_regex: bool,
+    /// Only filter content of this community, the whole site if null
+    pub community_id: Option<juniper::ID>,
+    pub targets: Vec<FilterTarget>,
+    pub action: FilterAction,
+    pub replacement: Option<String>,
+}
+
+impl ContentFilterInput {
+    /// Refuses rules that would never match or can't be compiled
+    fn into_form(self) -> FieldResult<ContentFilterForm> {
+        if self.pattern.trim().is_empty() || compile_pattern(&self.pattern, self.is_regex).is_err() {
+            return Err(FieldError::new("invalid_content_filter_pattern", juniper::Value::null()));
+        }
+        if self.targets.is_empty() {
+            return Err(FieldError::new("no_content_filter_targets", juniper::Value::null()));
+        }
+        let community_id = match self.community_id {
+            Some(id) => Some(id.parse::<i64>()?),
+            None => None,
+        };
+        Ok(ContentFilterForm {
+            pattern: self.pattern,
+            is_regex: self.is_regex,
+            community_id,
+            targets: self.targets,
+            action: self.action,
+            replacement: self.replacement,
+        })
+    }
+}
+
+/**
+ * Schedule state and last run of a background job, see crate::scheduler.
+ */
+#[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
+pub struct Job {
+    pub name: String,
+    /// Null if the job never ran
+    pub status: Option<JobStatus>,
+    pub message: Option<String>,
+    pub attempts: i32,
+    pub last_started: Option<chrono::NaiveDateTime>,
+    pub last_finished: Option<chrono::NaiveDateTime>,
+    pub next_run: Option<chrono::NaiveDateTime>,
+    /// Server that ran the job last
+    pub holder: Option<String>,
+}
+
+impl From<JobLease> for Job {
+    fn from(lease: JobLease) -> Self {
+        Job {
+            name: lease.job_name,
+            status: lease.status,
+            message: lease.message,
+            attempts: lease.attempts,
+            last_started: lease.last_started,
+            last_finished: lease.last_finished,
+            next_run: lease.next_run,
+            holder: lease.holder,
+        }
+    }
+}
+
 pub struct QueryRoot;
 
 // TODO: dispatch root query ops
@@ -38,11 +231,71 @@ impl QueryRoot {
 
     #[graphql(description = "List of all users")]
     async fn getComments(context: &Context, ) -> Vec<Comment> {
+        context.check_scope(ApiScope::Read)?;
         let conn: dgraph_tonic::Client = context.conn;
 
         
         Ok(comments)
     }
+
+    #[graphql(description = "New registration captcha, null if the site doesn't require one")]
+    async fn getCaptcha(context: &Context) -> FieldResult<Option<CaptchaChallenge>> {
+        let site = SiteView::read(&context.conn)?;
+        if !site.captcha_enabled {
+            return Ok(None);
+        }
+        Ok(Some(generate_challenge(&site.captcha_difficulty)?))
+    }
+
+    #[graphql(description = "Edits of a post, oldest first. Authors, mods and admins get the \
+        earlier versions, everyone else only the changed lines if the site has a public edit \
+        history and the post isn't removed, deleted or held")]
+    async fn getPostHistory(context: &Context, post_id: juniper::ID) -> FieldResult<Vec<EditRevision>> {
+        let viewer_id = context.check_scope(ApiScope::Read)?;
+        let post = read_node::<Post>(&context.conn, post_id.parse::<i64>()?).await?;
+        let full = can_view_full_history(&context.conn, viewer_id, post.creator_id, post.community_id).await?;
+        let hidden = post.removed || post.deleted || post.held;
+        if !full && (hidden || !SiteView::read(&context.conn)?.public_edit_history) {
+            return Err(FieldError::new("not_allowed_to_view_history", juniper::Value::null()));
+        }
+        let revisions = PostRevision::list_for_post(&context.conn, post.id).await?;
+        Ok(post_history(&post, &revisions, full))
+    }
+
+    #[graphql(description = "Edits of a comment, oldest first, visible like getPostHistory")]
+    async fn getCommentHistory(context: &Context, comment_id: juniper::ID) -> FieldResult<Vec<EditRevision>> {
+        let viewer_id = context.check_scope(ApiScope::Read)?;
+        let comment = read_node::<Comment>(&context.conn, comment_id.parse::<i64>()?).await?;
+        let post = read_node::<Post>(&context.conn, comment.post_id).await?;
+        let full = can_view_full_history(&context.conn, viewer_id, comment.creator_id, post.community_id).await?;
+        let hidden = comment.removed || comment.deleted || comment.held || post.removed || post.deleted;
+        if !full && (hidden || !SiteView::read(&context.conn)?.public_edit_history) {
+            return Err(FieldError::new("not_allowed_to_view_history", juniper::Value::null()));
+        }
+        let revisions = CommentRevision::list_for_comment(&context.conn, comment.id).await?;
+        Ok(comment_history(&comment, &revisions, full))
+    }
+
+    #[graphql(description = "Users and communities the logged in user blocked")]
+    async fn getBlockList(context: &Context) -> FieldResult<Blocks> {
+        let user_id = context.require_scope(ApiScope::Read)?;
+        Ok(BlockList::for_user(&context.conn, user_id).await?.into())
+    }
+
+    #[graphql(description = "All content filter rules, oldest first. Admins only")]
+    async fn getContentFilters(context: &Context) -> FieldResult<Vec<ContentFilterRule>> {
+        context.require_admin().await?;
+        let filters = ContentFilter::list_all(&context.conn).await?;
+        Ok(filters.into_iter().map(ContentFilterRule::from).collect())
+    }
+
+    #[graphql(description = "Background jobs by name, with the outcome of their last run and \
+        their next run. Admins only")]
+    async fn getJobs(context: &Context) -> FieldResult<Vec<Job>> {
+        context.require_admin().await?;
+        let leases = JobLease::list_all(&context.conn).await?;
+        Ok(leases.into_iter().map(Job::from).collect())
+    }
 }
 
 pub struct MutationRoot;
@@ -52,6 +305,87 @@ pub struct MutationRoot;
 #[juniper::graphql_object(Context = Context)]
 impl MutationRoot {
     // TODO: dispatch root mutation ops
+
+    #[graphql(description = "Block or unblock a user, returns the new block list")]
+    async fn blockUser(context: &Context, user_id: juniper::ID, block: bool) -> FieldResult<Blocks> {
+        let my_id = context.require_session()?;
+        let target_id = user_id.parse::<i64>()?;
+        if target_id == my_id {
+            return Err(FieldError::new("cant_block_yourself", juniper::Value::null()));
+        }
+
+        let form = UserBlockForm { user_id: my_id, target_id };
+        if block {
+            if UserBlock::block(&context.conn, &form).await.is_err() {
+                return Err(FieldError::new("couldnt_block_user", juniper::Value::null()));
+            }
+        } else if UserBlock::unblock(&context.conn, &form).await.is_err() {
+            return Err(FieldError::new("couldnt_unblock_user", juniper::Value::null()));
+        }
+        Ok(BlockList::for_user(&context.conn, my_id).await?.into())
+    }
+
+    #[graphql(description = "Block or unblock a community, returns the new block list. \
+        Blocking also unsubscribes from the community")]
+    async fn blockCommunity(context: &Context, community_id: juniper::ID, block: bool) -> FieldResult<Blocks> {
+        let my_id = context.require_session()?;
+        let community_id = community_id.parse::<i64>()?;
+
+        let form = CommunityBlockForm { user_id: my_id, community_id };
+        if block {
+            if CommunityBlock::block(&context.conn, &form).await.is_err() {
+                return Err(FieldError::new("couldnt_block_community", juniper::Value::null()));
+            }
+            let follower_form = CommunityFollowerForm { community_id, user_id: my_id };
+            CommunityFollower::ignore(&context.conn, &follower_form).await?;
+        } else if CommunityBlock::unblock(&context.conn, &form).await.is_err() {
+            return Err(FieldError::new("couldnt_unblock_community", juniper::Value::null()));
+        }
+        Ok(BlockList::for_user(&context.conn, my_id).await?.into())
+    }
+
+    #[graphql(description = "Create a content filter rule, it applies to new content right \
+        away. Admins only")]
+    async fn createContentFilter(context: &Context, filter: ContentFilterInput) -> FieldResult<ContentFilterRule> {
+        let admin_id = context.require_admin().await?;
+        let form = filter.into_form()?;
+
+        let created = match ContentFilter::create(&context.conn, admin_id, &form).await {
+            Ok(created) => created,
+            Err(_e) => return Err(FieldError::new("couldnt_create_content_filter", juniper::Value::null())),
+        };
+        content_filter::reload(&context.conn).await?;
+        Ok(created.into())
+    }
+
+    #[graphql(description = "Replace pattern, scope and action of a content filter rule. \
+        Admins only")]
+    async fn editContentFilter(
+        context: &Context,
+        id: juniper::ID,
+        filter: ContentFilterInput,
+    ) -> FieldResult<ContentFilterRule> {
+        context.require_admin().await?;
+        let form = filter.into_form()?;
+
+        let updated = match ContentFilter::update(&context.conn, id.parse::<i64>()?, &form).await {
+            Ok(updated) => updated,
+            Err(_e) => return Err(FieldError::new("couldnt_update_content_filter", juniper::Value::null())),
+        };
+        content_filter::reload(&context.conn).await?;
+        Ok(updated.into())
+    }
+
+    #[graphql(description = "Delete a content filter rule. Admins only")]
+    async fn deleteContentFilter(context: &Context, id: juniper::ID) -> FieldResult<bool> {
+        context.require_admin().await?;
+
+        if ContentFilter::delete(&context.conn, id.parse::<i64>()?).await.is_err() {
+            return Err(FieldError::new("couldnt_find_content_filter", juniper::Value::null()));
+        }
+        content_filter::reload(&context.conn).await?;
+        Ok(true)
+    }
 }
 
 // Export the schema
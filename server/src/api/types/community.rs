@@ -96,6 +96,19 @@ pub struct FollowCommunity {
   auth: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct BlockCommunity {
+  community_id: i32,
+  block: bool,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockCommunityResponse {
+  community: CommunityView,
+  blocked: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GetFollowedCommunities {
   auth: String,
@@ -1,5 +1,5 @@
 use serde::{Serialize, Deserialize};
-use crate::db::{community_view::*, comment_view::*, user_view::*, post_view::*};
+use crate::db::{community_view::*, comment_tree::*, comment_view::*, user_view::*, post_view::*};
 
 
 #[derive(Serialize, Deserialize)]
@@ -20,19 +20,46 @@ pub struct PostResponse {
 #[derive(Serialize, Deserialize)]
 pub struct GetPost {
   pub id: i32,
+  /// Levels of replies to include, see comment_tree::TreeLimits
+  max_depth: Option<i64>,
+  /// Replies to include per comment
+  max_children: Option<i64>,
   auth: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct GetPostResponse {
   post: PostView,
-  comments: Vec<CommentView>,
+  /// Top level comments with their replies
+  comments: Vec<CommentNode>,
+  /// Set when top level comments were left out
+  more_comments: Option<MoreComments>,
   community: CommunityView,
   moderators: Vec<CommunityModeratorView>,
   admins: Vec<UserView>,
   pub online: usize,
 }
 
+/// Comments left out of a tree, usually fetched with the fields of a MoreComments
+#[derive(Serialize, Deserialize)]
+pub struct GetCommentTree {
+  pub post_id: i32,
+  /// Comment to get replies of, top level comments of the post if unset
+  parent_id: Option<i32>,
+  /// Replies already shown
+  offset: Option<i64>,
+  max_depth: Option<i64>,
+  max_children: Option<i64>,
+  auth: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetCommentTreeResponse {
+  parent: Option<CommentView>,
+  comments: Vec<CommentNode>,
+  more_comments: Option<MoreComments>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GetPosts {
   type_: String,
@@ -48,6 +75,20 @@ pub struct GetPostsResponse {
   posts: Vec<PostView>,
 }
 
+/// Posts of a community held by a content filter, for its mods
+#[derive(Serialize, Deserialize)]
+pub struct GetHeldPosts {
+  community_id: i32,
+  page: Option<i64>,
+  limit: Option<i64>,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetHeldPostsResponse {
+  posts: Vec<PostView>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CreatePostLike {
   post_id: i32,
@@ -1,5 +1,7 @@
 use serde::{Serialize, Deserialize};
 use crate::db::category::{Category};
+use crate::db::registration_application::RegistrationApplication;
+use crate::oidc::OidcProviderInfo;
 use crate::db::{
   comment_view::*, site_view::*, post_view::*,
   user_view::*, community_view::*,
@@ -62,6 +64,13 @@ pub struct CreateSite {
   pub enable_downvotes: bool,
   pub open_registration: bool,
   pub enable_nsfw: bool,
+  pub require_totp_for_mods: bool,
+  pub require_email_verification: bool,
+  pub require_application: bool,
+  pub application_question: Option<String>,
+  pub captcha_enabled: bool,
+  pub captcha_difficulty: String,
+  pub public_edit_history: bool,
   pub auth: String,
 }
 
@@ -72,6 +81,13 @@ pub struct EditSite {
   enable_downvotes: bool,
   open_registration: bool,
   enable_nsfw: bool,
+  require_totp_for_mods: bool,
+  require_email_verification: bool,
+  require_application: bool,
+  application_question: Option<String>,
+  captcha_enabled: bool,
+  captcha_difficulty: String,
+  public_edit_history: bool,
   auth: String,
 }
 
@@ -89,6 +105,8 @@ pub struct GetSiteResponse {
   admins: Vec<UserView>,
   banned: Vec<UserView>,
   pub online: usize,
+  /// Providers users can log in with, at /oidc/<name>/login
+  oidc_providers: Vec<OidcProviderInfo>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -112,3 +130,55 @@ pub struct SaveSiteConfig {
   config_hjson: String,
   auth: String,
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct ListRegistrationApplications {
+  /// Only applications no admin approved or denied yet
+  unresolved_only: bool,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListRegistrationApplicationsResponse {
+  applications: Vec<RegistrationApplicationView>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResolveRegistrationApplication {
+  id: i64,
+  approve: bool,
+  deny_reason: Option<String>,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RegistrationApplicationResponse {
+  application: RegistrationApplicationView,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RegistrationApplicationView {
+  pub id: i64,
+  pub user_id: i64,
+  pub username: String,
+  pub answer: String,
+  pub admin_id: Option<i64>,
+  pub approved: bool,
+  pub deny_reason: Option<String>,
+  pub published: chrono::NaiveDateTime,
+}
+
+impl RegistrationApplicationView {
+  pub fn new(application: RegistrationApplication, username: String) -> Self {
+    RegistrationApplicationView {
+      id: application.id,
+      user_id: application.user_id,
+      username,
+      answer: application.answer,
+      admin_id: application.admin_id,
+      approved: application.approved,
+      deny_reason: application.deny_reason,
+      published: application.published,
+    }
+  }
+}
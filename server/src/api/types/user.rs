@@ -2,12 +2,19 @@ use serde::{Serialize, Deserialize};
 use crate::db::{
   user_view::*, community_view::*,
   post_view::*, comment_view::*,
+  private_message_view::*, user_mention_view::*,
+  aggregates::UserVote,
+  api_token::{ApiScope, ApiToken},
+  data_export::DataExportStatus,
+  invite_code::InviteCode,
 };
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Login {
   username_or_email: String,
   password: String,
+  /// One-time password or recovery code, if two-factor authentication is enabled
+  totp_code: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -18,6 +25,13 @@ pub struct Register {
   pub password_verify: String,
   pub admin: bool,
   pub show_nsfw: bool,
+  /// Lets the user register while registration is closed
+  pub invite_code: Option<String>,
+  /// Answer to the application question, if the site requires an application
+  pub application_answer: Option<String>,
+  /// Token from getCaptcha, if the site requires a captcha
+  pub captcha_token: Option<String>,
+  pub captcha_answer: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -35,12 +49,66 @@ pub struct SaveUserSettings {
   old_password: Option<String>,
   show_avatars: bool,
   send_notifications_to_email: bool,
+  bot_account: Option<bool>,
   auth: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct LoginResponse {
   pub jwt: String,
+  pub refresh_token: Option<String>,
+  /// The site requires two-factor authentication for admins and mods, and it isn't set up yet
+  pub totp_setup_required: bool,
+  /// The user registered with an application, and can log in once an admin approves it
+  pub registration_pending: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RefreshAccessToken {
+  refresh_token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Logout {
+  refresh_token: String,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LogoutAllSessions {
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogoutResponse {}
+
+#[derive(Serialize, Deserialize)]
+pub struct EnrollTotp {
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EnrollTotpResponse {
+  secret: String,
+  provisioning_uri: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ConfirmTotp {
+  totp_code: String,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpRecoveryCodesResponse {
+  recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisableTotp {
+  password: String,
+  totp_code: String,
+  auth: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -74,6 +142,15 @@ pub struct GetRepliesResponse {
 #[derive(Serialize, Deserialize)]
 pub struct GetUserMentionsResponse {
   mentions: Vec<UserMentionView>,
+  post_mentions: Vec<PostMentionView>,
+}
+
+/// Mention of the user in the body of a post
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PostMentionView {
+  pub post: PostView,
+  pub read: bool,
+  pub published: chrono::NaiveDateTime,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -108,6 +185,30 @@ pub struct BanUserResponse {
   banned: bool,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct BlockUser {
+  user_id: i32,
+  block: bool,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockUserResponse {
+  user: UserView,
+  blocked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetBlockList {
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetBlockListResponse {
+  users: Vec<UserView>,
+  communities: Vec<CommunityView>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GetReplies {
   sort: String,
@@ -144,6 +245,69 @@ pub struct DeleteAccount {
   auth: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct ExportUserData {
+  /// Zip the json archive
+  zip: bool,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ExportUserDataResponse {
+  export: DataExportView,
+}
+
+/// Status of the latest export of the user
+#[derive(Serialize, Deserialize)]
+pub struct GetDataExport {
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GetDataExportResponse {
+  export: Option<DataExportView>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DataExportView {
+  status: DataExportStatus,
+  /// Once the export is ready. Each link works once, ask again for a new one.
+  download_url: Option<String>,
+  expires: chrono::NaiveDateTime,
+}
+
+/// Contents of a data export archive
+#[derive(Serialize, Deserialize)]
+pub struct UserDataExport {
+  pub format_version: u32,
+  pub exported: chrono::NaiveDateTime,
+  pub user: UserView,
+  pub settings: UserDataExportSettings,
+  pub posts: Vec<PostView>,
+  pub comments: Vec<CommentView>,
+  pub post_votes: Vec<UserVote>,
+  pub comment_votes: Vec<UserVote>,
+  pub saved_posts: Vec<PostView>,
+  pub saved_comments: Vec<CommentView>,
+  pub follows: Vec<CommunityFollowerView>,
+  pub private_messages: Vec<PrivateMessageView>,
+  pub mentions: Vec<UserMentionView>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UserDataExportSettings {
+  pub show_nsfw: bool,
+  pub theme: String,
+  pub default_sort_type: i16,
+  pub default_listing_type: i16,
+  pub lang: String,
+  pub show_avatars: bool,
+  pub send_notifications_to_email: bool,
+  pub matrix_user_id: Option<String>,
+  pub bot_account: bool,
+  pub totp_enabled: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct PasswordReset {
   email: String,
@@ -159,6 +323,144 @@ pub struct PasswordChange {
   password_verify: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct VerifyEmail {
+  token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResendVerificationEmail {
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct VerifyEmailResponse {}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetOidcIdentities {
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnlinkOidcProvider {
+  provider: String,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OidcIdentitiesResponse {
+  /// Names of the providers linked to the account
+  linked: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateApiToken {
+  name: String,
+  scopes: Vec<ApiScope>,
+  /// Never expires if not set
+  expires_days: Option<i64>,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CreateApiTokenResponse {
+  /// Only returned here, it can't be read again
+  token: String,
+  api_token: ApiTokenView,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiTokenView {
+  id: i64,
+  name: String,
+  scopes: Vec<String>,
+  published: chrono::NaiveDateTime,
+  expires: Option<chrono::NaiveDateTime>,
+  last_used: Option<chrono::NaiveDateTime>,
+}
+
+impl From<ApiToken> for ApiTokenView {
+  fn from(token: ApiToken) -> Self {
+    ApiTokenView {
+      id: token.id,
+      name: token.name,
+      scopes: token.scopes,
+      published: token.published,
+      expires: token.expires,
+      last_used: token.last_used,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListApiTokens {
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ListApiTokensResponse {
+  api_tokens: Vec<ApiTokenView>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RevokeApiToken {
+  id: i64,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateInviteCode {
+  /// Defaults to a single use
+  max_uses: Option<i32>,
+  /// Never expires if not set
+  expires_days: Option<i64>,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CreateInviteCodeResponse {
+  /// Only returned here, it can't be read again
+  code: String,
+  invite_code: InviteCodeView,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct InviteCodeView {
+  id: i64,
+  max_uses: i32,
+  uses: i32,
+  published: chrono::NaiveDateTime,
+  expires: Option<chrono::NaiveDateTime>,
+}
+
+impl From<InviteCode> for InviteCodeView {
+  fn from(invite: InviteCode) -> Self {
+    InviteCodeView {
+      id: invite.id,
+      max_uses: invite.max_uses,
+      uses: invite.uses,
+      published: invite.published,
+      expires: invite.expires,
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ListInviteCodes {
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteInviteCode {
+  id: i64,
+  auth: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ListInviteCodesResponse {
+  invite_codes: Vec<InviteCodeView>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CreatePrivateMessage {
   content: String,
@@ -78,6 +78,7 @@ mod tests {
       community_id: 42,
       published: naive_now(),
       removed: false,
+      held: false,
       locked: false,
       stickied: false,
       nsfw: false,
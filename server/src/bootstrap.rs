@@ -0,0 +1,255 @@
+/**
+ * First-start bootstrap of a new instance from the `setup` block in the config.
+ *
+ * Every step checks whether its nodes already exist, so running this on every
+ * start is safe, and a start that crashed halfway is completed by the next one.
+ * The admin is only created while there is no admin at all, so renaming the
+ * admin doesn't bring it back with the password from the config.
+ *
+ * `upgrade` brings data of instances created by older versions up to date and
+ * seeds the default content filters, on every start.
+ */
+use crate::db::{
+  category::{Category, CategoryForm},
+  content_filter::ContentFilter,
+  community::{
+    Community, CommunityFollower, CommunityFollowerForm, CommunityForm, CommunityModerator,
+    CommunityModeratorForm,
+  },
+  site::{Site, SiteForm},
+  user::{UserForm, User_},
+  *,
+};
+use crate::content_filter::default_filters;
+use crate::settings::{Settings, Setup};
+use dgraph_tonic as dgraph;
+use dgraph_tonic::Query;
+use failure::Error;
+use log::info;
+use serde::Deserialize;
+
+/// Name of the community every new user is subscribed to
+pub const MAIN_COMMUNITY_NAME: &str = "main";
+
+const DEFAULT_CATEGORIES: &[&str] = &[
+  "Discussion",
+  "Humor/Memes",
+  "Gaming",
+  "Movies",
+  "TV",
+  "Music",
+  "Literature",
+  "Comics",
+  "Photography",
+  "Art",
+  "Learning",
+  "DIY",
+  "Lifestyle",
+  "News",
+  "Politics",
+  "Society",
+  "Gender/Identity/Sexuality",
+  "Race/Colonisation",
+  "Religion",
+  "Science/Technology",
+  "Programming/Software",
+  "Health/Sports/Fitness",
+  "Porn",
+  "Places",
+  "Meta",
+  "Other",
+];
+
+/**
+ * Create the admin user, site, default categories and main community, if they
+ * don't exist yet. Does nothing without a `setup` block.
+ */
+pub async fn run(conn: &dgraph::Client, settings: &Settings) -> Result<(), Error> {
+  let setup = match &settings.setup {
+    Some(setup) => setup,
+    None => {
+      info!("No setup block in config, skipping bootstrap");
+      return Ok(());
+    }
+  };
+  let admin = bootstrap_admin(conn, settings, setup).await?;
+  bootstrap_site(conn, setup, &admin).await?;
+  let category_id = bootstrap_categories(conn).await?;
+  bootstrap_main_community(conn, &admin, category_id).await?;
+
+  Ok(())
+}
+
+/**
+ * Data migrations for instances created by older versions. Every step only
+ * touches data that still needs it, so this runs on every start.
+ */
+pub async fn upgrade(conn: &dgraph::Client) -> Result<(), Error> {
+  let verified = User_::backfill_email_verified(conn).await?;
+  if verified > 0 {
+    info!("Marked the email addresses of {} existing users verified", verified);
+  }
+
+  // Also without a setup block, content is never unfiltered
+  let seeded = ContentFilter::seed_defaults(conn, &default_filters()).await?;
+  if seeded > 0 {
+    info!("Created {} default content filters", seeded);
+  }
+
+  Ok(())
+}
+
+/// Any admin, to create the other nodes with
+async fn first_admin(conn: &dgraph::Client) -> Result<Option<User_>, Error> {
+  #[derive(Deserialize)]
+  struct Admins {
+    admins: Vec<User_>,
+  }
+
+  let q = format!(
+    r#"query {{
+      admins(func: eq({admin_pred}, true), first: 1) @filter(type({user})) {{
+        uid
+        expand(_all_)
+      }}
+    }}"#,
+    admin_pred = format!("{}.{}", User_::db_type_name(), "admin"),
+    user = User_::db_type_name(),
+  );
+  let resp = conn.new_read_only_txn().query(q).await?;
+  let found: Admins = resp.try_into()?;
+  Ok(found.admins.into_iter().next())
+}
+
+async fn bootstrap_admin(
+  conn: &dgraph::Client,
+  settings: &Settings,
+  setup: &Setup,
+) -> Result<User_, Error> {
+  if let Some(admin) = first_admin(conn).await? {
+    info!("Admin {} already exists, skipping", admin.name);
+    return Ok(admin);
+  }
+
+  let user_form = UserForm {
+    name: setup.admin_username.to_owned(),
+    fedi_name: settings.hostname.to_owned(),
+    email: setup.admin_email.to_owned(),
+    matrix_user_id: None,
+    avatar: None,
+    password_encrypted: setup.admin_password.to_owned(),
+    preferred_username: None,
+    updated: None,
+    admin: true,
+    banned: false,
+    show_nsfw: true,
+    theme: "darkly".into(),
+    default_sort_type: SortType::Hot as i16,
+    default_listing_type: ListingType::Subscribed as i16,
+    lang: "browser".into(),
+    show_avatars: true,
+    send_notifications_to_email: false,
+  };
+
+  let mut user = User_::register(conn, &user_form).await?;
+  // The address comes from the operator's config, there is nobody to mail a link to
+  if let Some(email) = &setup.admin_email {
+    user = User_::update_email(conn, user.id, email, true).await?;
+  }
+  info!("Admin {} created", user.name);
+  Ok(user)
+}
+
+async fn bootstrap_site(conn: &dgraph::Client, setup: &Setup, admin: &User_) -> Result<(), Error> {
+  if let Some(site) = list_nodes::<Site>(conn).await?.first() {
+    info!("Site {} already exists, skipping", site.name);
+    return Ok(());
+  }
+
+  let site_form = SiteForm {
+    name: setup.site_name.to_owned(),
+    description: None,
+    creator_id: admin.id,
+    updated: None,
+    enable_downvotes: false,
+    open_registration: false,
+    enable_nsfw: false,
+    require_totp_for_mods: false,
+    require_email_verification: false,
+    require_application: false,
+    application_question: None,
+    captcha_enabled: false,
+    captcha_difficulty: "medium".into(),
+    public_edit_history: false,
+  };
+  let mut site: Site = site_form.into();
+  create_node::<Site>(conn, &mut site).await?;
+  info!("Site {} created", site.name);
+  Ok(())
+}
+
+/// Returns the id of the category used for the main community.
+async fn bootstrap_categories(conn: &dgraph::Client) -> Result<i64, Error> {
+  let existing = Category::list_all(conn).await?;
+  if let Some(category) = existing.first() {
+    info!("{} categories already exist, skipping", existing.len());
+    return Ok(category.id);
+  }
+
+  let mut first_id = 0;
+  for name in DEFAULT_CATEGORIES {
+    let mut category: Category = CategoryForm {
+      name: name.to_string(),
+    }
+    .into();
+    let id = create_node::<Category>(conn, &mut category).await?;
+    if first_id == 0 {
+      first_id = id;
+    }
+  }
+  info!("{} default categories created", DEFAULT_CATEGORIES.len());
+  Ok(first_id)
+}
+
+async fn bootstrap_main_community(
+  conn: &dgraph::Client,
+  admin: &User_,
+  category_id: i64,
+) -> Result<(), Error> {
+  if let Ok(community) = Community::read_from_name(conn, MAIN_COMMUNITY_NAME.to_string()).await {
+    info!("Community {} already exists, skipping", community.name);
+    return Ok(());
+  }
+
+  let community_form = CommunityForm {
+    name: MAIN_COMMUNITY_NAME.to_string(),
+    title: "The Default Community".to_string(),
+    description: Some("The Default Community".to_string()),
+    category_id,
+    nsfw: false,
+    creator_id: admin.id,
+    removed: None,
+    deleted: None,
+    updated: None,
+  };
+  let mut community: Community = community_form.into();
+  create_node::<Community>(conn, &mut community).await?;
+
+  // The admin moderates and follows main, like an admin registering through the API
+  let moderator: CommunityModerator = CommunityModeratorForm {
+    community_id: community.id,
+    user_id: admin.id,
+  }
+  .into();
+  create_edge::<CommunityModerator, CommunityModeratorForm>(conn, &moderator).await?;
+
+  let follower: CommunityFollower = CommunityFollowerForm {
+    community_id: community.id,
+    user_id: admin.id,
+  }
+  .into();
+  create_edge::<CommunityFollower, CommunityFollowerForm>(conn, &follower).await?;
+
+  info!("Community {} created", community.name);
+  Ok(())
+}
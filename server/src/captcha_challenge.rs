@@ -0,0 +1,181 @@
+/**
+ * Self-hosted captcha for registration.
+ *
+ * The challenge is stateless: the server hands out a signed token with the
+ * expected answer, keyed with the jwt secret so it can't be read from the
+ * token. The only state are the nonces of solved challenges, stored in the
+ * graph until they expire, so that a solved challenge can't be replayed.
+ */
+use crate::db::used_captcha::UsedCaptcha;
+use crate::settings::Settings;
+use crate::sha256_hex;
+use captcha::{gen, Captcha, Difficulty};
+use chrono::NaiveDateTime;
+use dgraph_tonic as dgraph;
+use failure::Error;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use std::io::Cursor;
+
+/// How long the user has to solve a challenge
+const CHALLENGE_SECONDS: i64 = 10 * 60;
+/// Allowed values of the captcha_difficulty site setting
+pub const CAPTCHA_DIFFICULTIES: [&str; 3] = ["easy", "medium", "hard"];
+/// Tells captcha tokens apart from other tokens signed with the jwt secret
+const CAPTCHA_PURPOSE: &str = "captcha";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CaptchaClaims {
+  purpose: String,
+  nonce: String,
+  /// Hash of secret, nonce and answer
+  answer_hash: String,
+  exp: i64,
+}
+
+/// A challenge as sent to the client
+#[derive(Debug, Serialize, Deserialize, Clone, juniper::GraphQLObject)]
+pub struct CaptchaChallenge {
+  /// Base64 encoded png image
+  pub png: String,
+  /// Base64 encoded wav audio of the same characters, for screen reader users
+  pub wav: String,
+  /// Send this back with the answer
+  pub token: String,
+}
+
+fn difficulty(name: &str) -> Difficulty {
+  match name {
+    "easy" => Difficulty::Easy,
+    "hard" => Difficulty::Hard,
+    _ => Difficulty::Medium,
+  }
+}
+
+fn answer_hash(secret: &str, nonce: &str, answer: &str) -> String {
+  sha256_hex(&format!("{}:{}:{}", secret, nonce, answer.trim().to_lowercase()))
+}
+
+/// Generate a new challenge with the difficulty "easy", "medium" or "hard"
+pub fn generate_challenge(difficulty_name: &str) -> Result<CaptchaChallenge, Error> {
+  let captcha = gen(difficulty(difficulty_name));
+  let answer = captcha.chars_as_string();
+
+  let png = captcha
+    .as_base64()
+    .ok_or_else(|| format_err!("Couldn't render captcha image"))?;
+  let wav = as_wav_base64(&captcha)?;
+  let token = sign_challenge(
+    &Settings::get().jwt_secret,
+    &answer,
+    chrono::Utc::now().timestamp(),
+  )?;
+
+  Ok(CaptchaChallenge { png, wav, token })
+}
+
+/// The captcha crate produces one wav per character, join them into one
+fn as_wav_base64(captcha: &Captcha) -> Result<String, Error> {
+  let mut header = None;
+  let mut samples: Vec<i16> = Vec::new();
+  for letter in captcha.as_wav() {
+    let mut cursor = Cursor::new(letter.unwrap_or_default());
+    let (letter_header, letter_samples) = wav::read(&mut cursor)?;
+    if let Some(letter_samples) = letter_samples.as_sixteen() {
+      samples.extend(letter_samples);
+    }
+    header = Some(letter_header);
+  }
+
+  let header = header.ok_or_else(|| format_err!("Couldn't render captcha audio"))?;
+  let mut output = Cursor::new(Vec::new());
+  wav::write(header, &wav::BitDepth::Sixteen(samples), &mut output)?;
+  Ok(base64::encode(output.into_inner()))
+}
+
+fn sign_challenge(secret: &str, answer: &str, now: i64) -> Result<String, Error> {
+  let nonce = crate::generate_random_string();
+  let claims = CaptchaClaims {
+    purpose: CAPTCHA_PURPOSE.to_string(),
+    answer_hash: answer_hash(secret, &nonce, answer),
+    nonce,
+    exp: now + CHALLENGE_SECONDS,
+  };
+  Ok(encode(
+    &Header::default(),
+    &claims,
+    &EncodingKey::from_secret(secret.as_ref()),
+  )?)
+}
+
+/// Check the answer to a challenge. A challenge can only be solved once.
+pub async fn verify_answer(conn: &dgraph::Client, token: &str, answer: &str) -> Result<bool, Error> {
+  let claims = match check_answer_at(
+    &Settings::get().jwt_secret,
+    token,
+    answer,
+    chrono::Utc::now().timestamp(),
+  ) {
+    Some(claims) => claims,
+    None => return Ok(false),
+  };
+
+  let expires = NaiveDateTime::from_timestamp(claims.exp, 0);
+  UsedCaptcha::mark_used(conn, &claims.nonce, expires).await
+}
+
+/// Claims of the challenge, if answer solves it and it didn't expire
+fn check_answer_at(secret: &str, token: &str, answer: &str, now: i64) -> Option<CaptchaClaims> {
+  // Expiry is checked below against `now`, which the tests control
+  let validation = Validation {
+    validate_exp: false,
+    ..Validation::default()
+  };
+  let claims = match decode::<CaptchaClaims>(token, &DecodingKey::from_secret(secret.as_ref()), &validation) {
+    Ok(data) => data.claims,
+    Err(_e) => return None,
+  };
+  if claims.purpose != CAPTCHA_PURPOSE || claims.exp < now {
+    return None;
+  }
+  if claims.answer_hash != answer_hash(secret, &claims.nonce, answer) {
+    return None;
+  }
+  Some(claims)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECRET: &str = "a_secret_for_the_tests";
+
+  #[test]
+  fn test_check_answer() {
+    let token = sign_challenge(SECRET, "AbC12", 1000).unwrap();
+    assert!(check_answer_at(SECRET, &token, "wrong", 1000).is_none());
+    assert!(check_answer_at("another_secret_here", &token, "abc12", 1000).is_none());
+    assert!(check_answer_at(SECRET, &token, " abc12 ", 1000).is_some());
+  }
+
+  #[test]
+  fn test_nonce_per_challenge() {
+    // Replays are refused by the nonce, which has to differ for every challenge
+    let first = check_answer_at(SECRET, &sign_challenge(SECRET, "xyz", 1000).unwrap(), "xyz", 1000);
+    let second = check_answer_at(SECRET, &sign_challenge(SECRET, "xyz", 1000).unwrap(), "xyz", 1000);
+    assert_ne!(first.unwrap().nonce, second.unwrap().nonce);
+  }
+
+  #[test]
+  fn test_expiry() {
+    let token = sign_challenge(SECRET, "xyz", 1000).unwrap();
+    assert!(check_answer_at(SECRET, &token, "xyz", 1000 + CHALLENGE_SECONDS + 1).is_none());
+  }
+
+  #[test]
+  fn test_answer_not_in_token() {
+    let token = sign_challenge(SECRET, "SeCrT", 1000).unwrap();
+    let payload = token.split('.').nth(1).unwrap();
+    let json = String::from_utf8(base64::decode_config(payload, base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+    assert!(!json.to_lowercase().contains("secrt"));
+  }
+}
@@ -0,0 +1,298 @@
+/**
+ * Admin subcommands for the lemmy_server binary.
+ *
+ * These run a single operation against the database and exit, instead of starting
+ * the http server. They use the same db entity functions and settings as the API.
+ */
+use crate::api::schema::create_schema;
+use crate::db::{
+  aggregates,
+  community::Community,
+  establish_connection,
+  moderator::{ModBan, ModBanForm, ModRemoveCommunity, ModRemoveCommunityForm},
+  user::{UserForm, User_},
+  ListingType, SortType,
+};
+use crate::settings::Settings;
+use crate::{generate_random_string, is_email_regex, is_valid_username};
+use dgraph_tonic as dgraph;
+use failure::Error;
+use std::fs;
+
+const DGRAPH_SCHEMA_FILE: &str = "migrations_dgraph/schema.graphql";
+
+const USAGE: &str = "Usage: lemmy_server [COMMAND]
+
+Without a command, the http server is started.
+
+Commands:
+  check-config                               Validate the config files and environment
+  create-admin <username> [email]            Create a new admin user, reading the
+                                             password from stdin
+  reset-password <username>                  Set a new random password for a user
+  ban <username> <admin> [reason]            Ban a user from the site, logged as
+                                             done by admin
+  remove-community <name> <admin> [reason]   Remove a community, logged as done
+                                             by admin
+  recount-aggregates                         Rebuild stored counts and scores
+  migrate                                    Apply the Dgraph schema
+  export-schema                              Print the GraphQL API schema
+  help                                       Print this message";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+  CheckConfig,
+  /// The password is read from stdin when run, so it doesn't show up in the process
+  /// list or shell history
+  CreateAdmin {
+    username: String,
+    email: Option<String>,
+  },
+  ResetPassword {
+    username: String,
+  },
+  Ban {
+    username: String,
+    admin: String,
+    reason: Option<String>,
+  },
+  RemoveCommunity {
+    name: String,
+    admin: String,
+    reason: Option<String>,
+  },
+  RecountAggregates,
+  Migrate,
+  ExportSchema,
+  Help,
+}
+
+impl Command {
+  /// Parse the command line arguments (without the binary name). Returns None if no
+  /// command was given, in which case the server should be started.
+  pub fn parse(args: &[String]) -> Result<Option<Self>, Error> {
+    let (name, rest) = match args.split_first() {
+      Some((name, rest)) => (name.as_str(), rest),
+      None => return Ok(None),
+    };
+
+    let arg = |i: usize, what: &str| -> Result<String, Error> {
+      rest
+        .get(i)
+        .cloned()
+        .ok_or_else(|| format_err!("{} requires <{}>\n\n{}", name, what, USAGE))
+    };
+
+    let command = match name {
+      "check-config" => Command::CheckConfig,
+      "create-admin" => Command::CreateAdmin {
+        username: arg(0, "username")?,
+        email: rest.get(1).cloned(),
+      },
+      "reset-password" => Command::ResetPassword {
+        username: arg(0, "username")?,
+      },
+      "ban" => Command::Ban {
+        username: arg(0, "username")?,
+        admin: arg(1, "admin")?,
+        reason: rest.get(2).cloned(),
+      },
+      "remove-community" => Command::RemoveCommunity {
+        name: arg(0, "name")?,
+        admin: arg(1, "admin")?,
+        reason: rest.get(2).cloned(),
+      },
+      "recount-aggregates" => Command::RecountAggregates,
+      "migrate" => Command::Migrate,
+      "export-schema" => Command::ExportSchema,
+      "help" | "--help" | "-h" => Command::Help,
+      other => bail!("Unknown command '{}'\n\n{}", other, USAGE),
+    };
+
+    Ok(Some(command))
+  }
+
+  /// Run the command, printing its result to stdout.
+  pub async fn run(self) -> Result<(), Error> {
+    match self {
+      Command::Help => println!("{}", USAGE),
+      Command::CheckConfig => match Settings::check_config_file() {
+        Ok(_) => println!("Config is valid"),
+        Err(e) => bail!("Config is invalid: {}", e),
+      },
+      Command::ExportSchema => println!("{}", create_schema().as_schema_language()),
+      Command::Migrate => {
+        let settings = Settings::get();
+        let schema = fs::read_to_string(DGRAPH_SCHEMA_FILE)?;
+        let url = format!(
+          "http://{}:{}/admin/schema",
+          settings.database.host, settings.database.admin_port
+        );
+        let response = isahc::post(&url, schema)?;
+        if !response.status().is_success() {
+          bail!("Couldn't apply schema: {}", response.status());
+        }
+        println!("Applied {} to {}", DGRAPH_SCHEMA_FILE, url);
+      }
+      Command::RecountAggregates => {
+        let conn = establish_connection(&Settings::get())?;
+        let recounted = aggregates::recount(&conn).await?;
+        println!(
+          "Recounted {} posts, {} comments, {} users and {} communities",
+          recounted.posts, recounted.comments, recounted.users, recounted.communities
+        );
+      }
+      Command::CreateAdmin { username, email } => {
+        if !is_valid_username(&username) {
+          bail!("invalid_username");
+        }
+        if let Some(email) = &email {
+          if !is_email_regex(email) {
+            bail!("invalid_email");
+          }
+        }
+
+        // Hidden when typed into a terminal, also works with a pipe or redirect
+        let password = rpassword::prompt_password_stderr("Password: ")?;
+        if password.is_empty() {
+          bail!("password_required");
+        }
+
+        let conn = establish_connection(&Settings::get())?;
+        if User_::find_by_username(&conn, &username).await.is_ok() {
+          bail!("user_already_exists");
+        }
+
+        let user_form = UserForm {
+          name: username,
+          fedi_name: Settings::get().hostname.to_owned(),
+          email,
+          matrix_user_id: None,
+          avatar: None,
+          password_encrypted: password,
+          preferred_username: None,
+          updated: None,
+          admin: true,
+          banned: false,
+          show_nsfw: false,
+          theme: "darkly".into(),
+          default_sort_type: SortType::Hot as i16,
+          default_listing_type: ListingType::Subscribed as i16,
+          lang: "browser".into(),
+          show_avatars: true,
+          send_notifications_to_email: false,
+        };
+        let user = User_::register(&conn, &user_form).await?;
+        if let Some(email) = &user.email {
+          User_::update_email(&conn, user.id, email, true).await?;
+        }
+        println!("Created admin {}", user.name);
+      }
+      Command::ResetPassword { username } => {
+        let conn = establish_connection(&Settings::get())?;
+        let user = User_::find_by_username(&conn, &username).await?;
+        let password = generate_random_string();
+        User_::update_password(&conn, user.id, &password).await?;
+        // Log out every session, on all servers
+        User_::bump_token_version(&conn, user.id).await?;
+        println!("New password for {}: {}", user.name, password);
+      }
+      Command::Ban {
+        username,
+        admin,
+        reason,
+      } => {
+        let conn = establish_connection(&Settings::get())?;
+        let admin = find_admin(&conn, &admin).await?;
+        let user = User_::find_by_username(&conn, &username).await?;
+        User_::update_banned(&conn, user.id, true).await?;
+        User_::bump_token_version(&conn, user.id).await?;
+
+        let form = ModBanForm {
+          mod_user_id: admin.id,
+          other_user_id: user.id,
+          reason,
+          banned: Some(true),
+          expires: None,
+        };
+        ModBan::create(&conn, &form).await?;
+        println!("Banned {}", user.name);
+      }
+      Command::RemoveCommunity {
+        name,
+        admin,
+        reason,
+      } => {
+        let conn = establish_connection(&Settings::get())?;
+        let admin = find_admin(&conn, &admin).await?;
+        let community = Community::read_from_name(&conn, name).await?;
+        Community::update_removed(&conn, community.id, true).await?;
+
+        let form = ModRemoveCommunityForm {
+          mod_user_id: admin.id,
+          community_id: community.id,
+          reason,
+          removed: Some(true),
+          expires: None,
+        };
+        ModRemoveCommunity::create(&conn, &form).await?;
+        println!("Removed community {}", community.name);
+      }
+    };
+
+    Ok(())
+  }
+}
+
+/// The admin a command is logged as done by in the mod log
+async fn find_admin(conn: &dgraph::Client, username: &str) -> Result<User_, Error> {
+  let admin = User_::find_by_username(conn, username).await?;
+  if !admin.admin {
+    bail!("{} is not an admin", admin.name);
+  }
+  Ok(admin)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::Command;
+
+  fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+  }
+
+  #[test]
+  fn test_parse_commands() {
+    assert_eq!(Command::parse(&args(&[])).unwrap(), None);
+    assert_eq!(
+      Command::parse(&args(&["check-config"])).unwrap(),
+      Some(Command::CheckConfig)
+    );
+    assert_eq!(
+      Command::parse(&args(&["create-admin", "jim", "jim@example.com"])).unwrap(),
+      Some(Command::CreateAdmin {
+        username: "jim".into(),
+        email: Some("jim@example.com".into()),
+      })
+    );
+    assert_eq!(
+      Command::parse(&args(&["ban", "jim", "root"])).unwrap(),
+      Some(Command::Ban {
+        username: "jim".into(),
+        admin: "root".into(),
+        reason: None,
+      })
+    );
+    assert_eq!(
+      Command::parse(&args(&["remove-community", "spam", "root", "Spam only"])).unwrap(),
+      Some(Command::RemoveCommunity {
+        name: "spam".into(),
+        admin: "root".into(),
+        reason: Some("Spam only".into()),
+      })
+    );
+    assert!(Command::parse(&args(&["ban"])).is_err());
+    assert!(Command::parse(&args(&["ban", "jim"])).is_err());
+    assert!(Command::parse(&args(&["explode"])).is_err());
+  }
+}
@@ -0,0 +1,381 @@
+/**
+ * Matcher for the admin-managed content filters.
+ *
+ * The `ContentFilter` rules in the database are compiled once into a
+ * `FilterSet`, which is swapped out whenever an admin changes the rules.
+ * Checking text never touches the database. Each server also polls the rules
+ * for changes made through the others, see watch.
+ */
+use crate::db::content_filter::{ContentFilter, ContentFilterForm, FilterAction, FilterTarget};
+use arc_swap::ArcSwap;
+use dgraph_tonic as dgraph;
+use failure::Error;
+use chrono::NaiveDateTime;
+use log::{error, info};
+use regex::{Regex, RegexBuilder};
+
+/// How often each server checks whether the rules were changed on another one
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Slurs refused by a new instance, until the admins change the default rules
+const DEFAULT_SLUR_PATTERN: &str = r"(fag(g|got|tard)?|maricos?|cock\s?sucker(s|ing)?|nig(\b|g?(a|er)?(s|z)?)\b|dindu(s?)|mudslime?s?|kikes?|mongoloids?|towel\s*heads?|\bspi(c|k)s?\b|\bchinks?|niglets?|beaners?|\bnips?\b|\bcoons?\b|jungle\s*bunn(y|ies?)|jigg?aboo?s?|\bpakis?\b|rag\s*heads?|gooks?|cunts?|bitch(es|ing|y)?|puss(y|ies?)|twats?|feminazis?|whor(es?|ing)|\bslut(s|t?y)?|\btrann?(y|ies?)|ladyboy(s?)|\b(b|re|r)tard(ed)?s?)";
+
+lazy_static! {
+  static ref FILTERS: ArcSwap<FilterSet> = ArcSwap::from_pointee(FilterSet::default());
+}
+
+/// Outcome of running text through the filters
+#[derive(Debug, PartialEq)]
+pub struct Verdict {
+  /// Strictest action of the matching rules, none if nothing matched
+  pub action: Option<FilterAction>,
+  /// The text with the replacements of all matching `Replace` rules applied
+  pub text: String,
+  /// Distinct matched snippets, sorted
+  pub matches: Vec<String>,
+}
+
+struct CompiledFilter {
+  regex: Regex,
+  rule: ContentFilter,
+}
+
+/**
+ * State of the rules in the database. Creating a rule raises the latest
+ * change, editing sets its `updated`, and deleting lowers the count.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RulesVersion {
+  count: usize,
+  latest_change: Option<NaiveDateTime>,
+}
+
+impl RulesVersion {
+  pub fn of(rules: &[ContentFilter]) -> Self {
+    RulesVersion {
+      count: rules.len(),
+      latest_change: rules
+        .iter()
+        .map(|rule| rule.updated.unwrap_or(rule.published))
+        .max(),
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct FilterSet {
+  filters: Vec<CompiledFilter>,
+  /// None until the rules are loaded from the database
+  version: Option<RulesVersion>,
+}
+
+impl FilterSet {
+  /// Compiles the rules. Rules with a broken pattern are logged and left out,
+  /// so one bad rule doesn't switch off all the others.
+  pub fn compile(rules: Vec<ContentFilter>) -> Self {
+    let version = Some(RulesVersion::of(&rules));
+    let filters = rules
+      .into_iter()
+      .filter_map(|rule| match compile_pattern(&rule.pattern, rule.is_regex) {
+        Ok(regex) => Some(CompiledFilter { regex, rule }),
+        Err(e) => {
+          error!("Skipping content filter {}: {}", rule.id, e);
+          None
+        }
+      })
+      .collect();
+    FilterSet { filters, version }
+  }
+
+  pub fn len(&self) -> usize {
+    self.filters.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.filters.is_empty()
+  }
+
+  pub fn check(&self, text: &str, target: FilterTarget, community_id: Option<i64>) -> Verdict {
+    let mut action = None;
+    let mut matches: Vec<String> = Vec::new();
+    let mut filtered = text.to_owned();
+
+    for filter in self
+      .filters
+      .iter()
+      .filter(|f| f.rule.applies_to(target, community_id))
+    {
+      let found: Vec<String> = filter
+        .regex
+        .find_iter(text)
+        .map(|mat| mat.as_str().to_owned())
+        .collect();
+      if found.is_empty() {
+        continue;
+      }
+
+      if filter.rule.action == FilterAction::Replace {
+        let replacement = filter
+          .rule
+          .replacement
+          .as_deref()
+          .unwrap_or(ContentFilter::DEFAULT_REPLACEMENT);
+        filtered = filter
+          .regex
+          .replace_all(&filtered, regex::NoExpand(replacement))
+          .to_string();
+      }
+      action = action.max(Some(filter.rule.action));
+      matches.extend(found);
+    }
+
+    // Unique
+    matches.sort_unstable();
+    matches.dedup();
+
+    Verdict {
+      action,
+      text: filtered,
+      matches,
+    }
+  }
+}
+
+/// A word is matched on word boundaries, a regex as it is. Both ignore case.
+pub fn compile_pattern(pattern: &str, is_regex: bool) -> Result<Regex, regex::Error> {
+  let pattern = if is_regex {
+    pattern.to_owned()
+  } else {
+    format!(r"\b{}\b", regex::escape(pattern.trim()))
+  };
+  RegexBuilder::new(&pattern).case_insensitive(true).build()
+}
+
+/// Runs text through the currently loaded filters
+pub fn check(text: &str, target: FilterTarget, community_id: Option<i64>) -> Verdict {
+  FILTERS.load().check(text, target, community_id)
+}
+
+/**
+ * Compiles the rules from the database and swaps them in. Called on start
+ * and after every change to the rules. Returns the number of active rules.
+ */
+pub async fn reload(conn: &dgraph::Client) -> Result<usize, Error> {
+  let filters = FilterSet::compile(ContentFilter::list_all(conn).await?);
+  let count = filters.len();
+  FILTERS.store(std::sync::Arc::new(filters));
+  Ok(count)
+}
+
+/**
+ * Like reload, if the rules in the database differ from the loaded ones.
+ * Returns the number of active rules, None if nothing changed.
+ */
+pub async fn reload_if_changed(conn: &dgraph::Client) -> Result<Option<usize>, Error> {
+  let rules = ContentFilter::list_all(conn).await?;
+  if FILTERS.load().version == Some(RulesVersion::of(&rules)) {
+    return Ok(None);
+  }
+  let filters = FilterSet::compile(rules);
+  let count = filters.len();
+  FILTERS.store(std::sync::Arc::new(filters));
+  Ok(Some(count))
+}
+
+/**
+ * Polls the rules every WATCH_INTERVAL in the background, so changes an admin
+ * made through another server take effect here too. Each server runs this,
+ * unlike the leased jobs. On errors the loaded rules stay active.
+ */
+pub fn watch(conn: dgraph::Client) {
+  actix_rt::spawn(async move {
+    let mut interval = actix_rt::time::interval(WATCH_INTERVAL);
+    loop {
+      interval.tick().await;
+      match reload_if_changed(&conn).await {
+        Ok(Some(count)) => info!("Content filters changed, loaded {} rules", count),
+        Ok(None) => {}
+        Err(e) => error!("Keeping the loaded content filters, couldn't check for changes: {}", e),
+      }
+    }
+  });
+}
+
+/// Rules a new instance starts with: slurs are refused in names and titles,
+/// and replaced in bodies.
+pub fn default_filters() -> Vec<ContentFilterForm> {
+  vec![
+    ContentFilterForm {
+      pattern: DEFAULT_SLUR_PATTERN.into(),
+      is_regex: true,
+      community_id: None,
+      targets: vec![
+        FilterTarget::Username,
+        FilterTarget::Title,
+        FilterTarget::CommunityName,
+      ],
+      action: FilterAction::Reject,
+      replacement: None,
+    },
+    ContentFilterForm {
+      pattern: DEFAULT_SLUR_PATTERN.into(),
+      is_regex: true,
+      community_id: None,
+      targets: vec![FilterTarget::Body],
+      action: FilterAction::Replace,
+      replacement: None,
+    },
+  ]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::slurs_vec_to_str;
+
+  fn rule(id: i64, form: ContentFilterForm) -> ContentFilter {
+    ContentFilter {
+      id,
+      pattern: form.pattern,
+      is_regex: form.is_regex,
+      community_id: form.community_id,
+      targets: form.targets,
+      action: form.action,
+      replacement: form.replacement,
+      creator_id: Some(1),
+      published: chrono::NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, id as u32),
+      updated: None,
+    }
+  }
+
+  fn word(pattern: &str, action: FilterAction, community_id: Option<i64>) -> ContentFilterForm {
+    ContentFilterForm {
+      pattern: pattern.into(),
+      is_regex: false,
+      community_id,
+      targets: vec![FilterTarget::Title, FilterTarget::Body],
+      action,
+      replacement: None,
+    }
+  }
+
+  fn default_set() -> FilterSet {
+    FilterSet::compile(
+      default_filters()
+        .into_iter()
+        .enumerate()
+        .map(|(i, form)| rule(i as i64, form))
+        .collect(),
+    )
+  }
+
+  #[test]
+  fn test_slur_filter() {
+    let filters = default_set();
+    let test =
+      "coons test dindu ladyboy tranny retardeds. Capitalized Niggerz. This is a bunch of other safe text.";
+    let slur_free = "No slurs here";
+
+    let replaced = filters.check(test, FilterTarget::Body, None);
+    assert_eq!(replaced.action, Some(FilterAction::Replace));
+    assert_eq!(
+      replaced.text,
+      "*removed* test *removed* *removed* *removed* *removed*. Capitalized *removed*. This is a bunch of other safe text."
+    );
+
+    let has_slurs_vec = vec![
+      "Niggerz",
+      "coons",
+      "dindu",
+      "ladyboy",
+      "retardeds",
+      "tranny",
+    ];
+    let has_slurs_err_str = "No slurs - Niggerz, coons, dindu, ladyboy, retardeds, tranny";
+
+    let rejected = filters.check(test, FilterTarget::Title, None);
+    assert_eq!(rejected.action, Some(FilterAction::Reject));
+    assert_eq!(rejected.matches, has_slurs_vec);
+    assert_eq!(&slurs_vec_to_str(&rejected.matches), has_slurs_err_str);
+
+    let clean = filters.check(slur_free, FilterTarget::Title, None);
+    assert_eq!(clean.action, None);
+    assert_eq!(clean.text, slur_free);
+    assert!(clean.matches.is_empty());
+  }
+
+  #[test]
+  fn test_word_boundaries_and_case() {
+    let filters = FilterSet::compile(vec![rule(1, word("spam", FilterAction::Replace, None))]);
+    let verdict = filters.check("Spam, spammer and SPAM.", FilterTarget::Body, None);
+    assert_eq!(verdict.text, "*removed*, spammer and *removed*.");
+    assert_eq!(verdict.matches, vec!["SPAM", "Spam"]);
+
+    // Words are literal text, not patterns
+    let filters = FilterSet::compile(vec![rule(1, word("a.c", FilterAction::Reject, None))]);
+    assert_eq!(filters.check("abc", FilterTarget::Title, None).action, None);
+    assert_eq!(
+      filters.check("a.c", FilterTarget::Title, None).action,
+      Some(FilterAction::Reject)
+    );
+  }
+
+  #[test]
+  fn test_scope_and_targets() {
+    let filters = FilterSet::compile(vec![rule(1, word("offtopic", FilterAction::Hold, Some(7)))]);
+    let text = "this is offtopic";
+    assert_eq!(
+      filters.check(text, FilterTarget::Body, Some(7)).action,
+      Some(FilterAction::Hold)
+    );
+    assert_eq!(filters.check(text, FilterTarget::Body, Some(8)).action, None);
+    assert_eq!(filters.check(text, FilterTarget::Body, None).action, None);
+    assert_eq!(filters.check(text, FilterTarget::Username, Some(7)).action, None);
+  }
+
+  #[test]
+  fn test_strictest_action_wins() {
+    let mut replace = word("foo", FilterAction::Replace, None);
+    replace.replacement = Some("$1 bar".into());
+    let filters = FilterSet::compile(vec![
+      rule(1, replace),
+      rule(2, word("baz", FilterAction::Hold, None)),
+    ]);
+
+    let verdict = filters.check("foo baz", FilterTarget::Body, None);
+    assert_eq!(verdict.action, Some(FilterAction::Hold));
+    // Replacements are inserted literally
+    assert_eq!(verdict.text, "$1 bar baz");
+    assert_eq!(verdict.matches, vec!["baz", "foo"]);
+  }
+
+  #[test]
+  fn test_rules_version() {
+    let rules = vec![
+      rule(1, word("foo", FilterAction::Reject, None)),
+      rule(2, word("bar", FilterAction::Reject, None)),
+    ];
+    let version = RulesVersion::of(&rules);
+    assert_eq!(version, RulesVersion::of(&rules.clone()));
+
+    let mut edited = rules.clone();
+    edited[0].updated = Some(chrono::NaiveDate::from_ymd(2020, 2, 1).and_hms(0, 0, 0));
+    assert_ne!(RulesVersion::of(&edited), version);
+
+    assert_ne!(RulesVersion::of(&rules[1..]), version);
+    assert_ne!(RulesVersion::of(&[]), version);
+  }
+
+  #[test]
+  fn test_broken_regex_is_skipped() {
+    let mut broken = word("(unclosed", FilterAction::Reject, None);
+    broken.is_regex = true;
+    assert!(compile_pattern(&broken.pattern, true).is_err());
+
+    let filters = FilterSet::compile(vec![
+      rule(1, broken),
+      rule(2, word("foo", FilterAction::Reject, None)),
+    ]);
+    assert_eq!(filters.len(), 1);
+  }
+}
@@ -0,0 +1,283 @@
+/**
+ * Archives of a user's personal data.
+ *
+ * An archive is a single json document, optionally zipped. The
+ * build_data_exports job writes it to `data_export.directory`, where it is
+ * served through single-use download links until the `DataExport` node
+ * expires.
+ */
+use crate::api::types::user::{UserDataExport, UserDataExportSettings};
+use crate::db::{
+  aggregates::votes_of_user,
+  comment::CommentLike,
+  comment_view::CommentQueryBuilder,
+  community_view::CommunityFollowerView,
+  data_export::{DataExport, DataExportStatus},
+  post::PostLike,
+  post_view::PostQueryBuilder,
+  private_message_view::PrivateMessageQueryBuilder,
+  user::User_,
+  user_mention_view::UserMentionQueryBuilder,
+  user_view::UserView,
+  read_node, Edge, SortType,
+};
+use crate::settings::Settings;
+use crate::{generate_random_string, naive_now};
+use dgraph_tonic as dgraph;
+use failure::Error;
+use log::error;
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Bumped on incompatible changes to the archive contents
+pub const EXPORT_FORMAT_VERSION: u32 = 2;
+/// Name of the json document, inside zipped archives too
+const JSON_FILE_NAME: &str = "lemmy_data.json";
+/// Posts, comments, messages and mentions are read in pages of this size
+const PAGE_SIZE: i64 = 500;
+
+/// New unguessable archive name for user
+pub fn file_name(user_id: i64, zip: bool) -> String {
+  let extension = if zip { "zip" } else { "json" };
+  format!("{}_{}.{}", user_id, generate_random_string(), extension)
+}
+
+pub fn archive_path(file_name: &str) -> PathBuf {
+  PathBuf::from(&Settings::get().data_export.directory).join(file_name)
+}
+
+pub fn content_type(file_name: &str) -> &'static str {
+  if file_name.ends_with(".zip") {
+    "application/zip"
+  } else {
+    "application/json"
+  }
+}
+
+/// Serialize contents to the archive file_name, zipped if it ends with .zip
+pub fn write_archive<T: Serialize>(file_name: &str, contents: &T) -> Result<(), Error> {
+  let path = archive_path(file_name);
+  if let Some(directory) = path.parent() {
+    fs::create_dir_all(directory)?;
+  }
+  let bytes = archive_bytes(file_name.ends_with(".zip"), contents)?;
+  fs::write(path, bytes)?;
+  Ok(())
+}
+
+fn archive_bytes<T: Serialize>(zip: bool, contents: &T) -> Result<Vec<u8>, Error> {
+  let json = serde_json::to_vec_pretty(contents)?;
+  if !zip {
+    return Ok(json);
+  }
+
+  let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+  let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+  writer.start_file(JSON_FILE_NAME, options)?;
+  writer.write_all(&json)?;
+  Ok(writer.finish()?.into_inner())
+}
+
+/**
+ * Write the archives of all pending exports.
+ *
+ * @return number of exports built
+ */
+pub async fn build_pending(conn: &dgraph::Client) -> Result<usize, Error> {
+  let pending = DataExport::list_pending(conn).await?;
+  for export in &pending {
+    let written = match gather(conn, export.user_id).await {
+      Ok(contents) => write_archive(&export.file_name, &contents),
+      Err(e) => Err(e),
+    };
+    let status = match written {
+      Ok(()) => DataExportStatus::Ready,
+      Err(e) => {
+        error!("Couldn't build data export {}: {}", export.file_name, e);
+        DataExportStatus::Failed
+      }
+    };
+    DataExport::update_status(conn, export.id, status).await?;
+  }
+  Ok(pending.len())
+}
+
+/// Everything stored about a user
+async fn gather(conn: &dgraph::Client, user_id: i64) -> Result<UserDataExport, Error> {
+  let user = read_node::<User_>(conn, user_id).await?;
+  let user_view = UserView::read(conn, user_id)?;
+
+  let posts = all_pages(|page| {
+    PostQueryBuilder::create(conn)
+      .sort(&SortType::New)
+      .for_creator_id(user_id)
+      .my_user_id(user_id)
+      .page(page)
+      .limit(PAGE_SIZE)
+      .list()
+  })?;
+
+  let comments = all_pages(|page| {
+    CommentQueryBuilder::create(conn)
+      .sort(&SortType::New)
+      .for_creator_id(user_id)
+      .my_user_id(user_id)
+      .page(page)
+      .limit(PAGE_SIZE)
+      .list()
+  })?;
+
+  let post_votes = votes_of_user(conn, PostLike::db_type_name(), user_id).await?;
+  let comment_votes = votes_of_user(conn, CommentLike::db_type_name(), user_id).await?;
+
+  let saved_posts = all_pages(|page| {
+    PostQueryBuilder::create(conn)
+      .sort(&SortType::New)
+      .show_nsfw(true)
+      .saved_only(true)
+      .my_user_id(user_id)
+      .page(page)
+      .limit(PAGE_SIZE)
+      .list()
+  })?;
+
+  let saved_comments = all_pages(|page| {
+    CommentQueryBuilder::create(conn)
+      .sort(&SortType::New)
+      .saved_only(true)
+      .my_user_id(user_id)
+      .page(page)
+      .limit(PAGE_SIZE)
+      .list()
+  })?;
+
+  let follows = CommunityFollowerView::for_user(conn, user_id)?;
+
+  let private_messages = all_pages(|page| {
+    PrivateMessageQueryBuilder::create(conn, user_id)
+      .show_blocked(true)
+      .page(page)
+      .limit(PAGE_SIZE)
+      .list()
+  })?;
+
+  let mentions = all_pages(|page| {
+    UserMentionQueryBuilder::create(conn, user_id)
+      .sort(&SortType::New)
+      .page(page)
+      .limit(PAGE_SIZE)
+      .list()
+  })?;
+
+  Ok(UserDataExport {
+    format_version: EXPORT_FORMAT_VERSION,
+    exported: naive_now(),
+    user: user_view,
+    settings: UserDataExportSettings {
+      show_nsfw: user.show_nsfw,
+      theme: user.theme,
+      default_sort_type: user.default_sort_type,
+      default_listing_type: user.default_listing_type,
+      lang: user.lang,
+      show_avatars: user.show_avatars,
+      send_notifications_to_email: user.send_notifications_to_email,
+      matrix_user_id: user.matrix_user_id,
+      bot_account: user.bot_account,
+      totp_enabled: user.totp_enabled,
+    },
+    posts,
+    comments,
+    post_votes,
+    comment_votes,
+    saved_posts,
+    saved_comments,
+    follows,
+    private_messages,
+    mentions,
+  })
+}
+
+/// Read pages of PAGE_SIZE, starting at 1, until one comes back short
+fn all_pages<T, F>(read_page: F) -> Result<Vec<T>, Error>
+where
+  F: Fn(i64) -> Result<Vec<T>, Error>,
+{
+  let mut all = Vec::new();
+  for page in 1.. {
+    let items = read_page(page)?;
+    let last = (items.len() as i64) < PAGE_SIZE;
+    all.extend(items);
+    if last {
+      break;
+    }
+  }
+  Ok(all)
+}
+
+/**
+ * Delete expired exports and their archives.
+ *
+ * @return number of deleted exports
+ */
+pub async fn delete_expired(conn: &dgraph::Client) -> Result<usize, Error> {
+  let expired = DataExport::list_expired(conn).await?;
+  for export in &expired {
+    match fs::remove_file(archive_path(&export.file_name)) {
+      Ok(()) => {}
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+      Err(e) => return Err(e.into()),
+    }
+    DataExport::delete(conn, export.id).await?;
+  }
+  Ok(expired.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use std::io::Read;
+
+  #[test]
+  fn test_file_name() {
+    let name = file_name(42, true);
+    assert!(name.starts_with("42_"));
+    assert!(name.ends_with(".zip"));
+    assert_ne!(name, file_name(42, true));
+    assert_eq!(content_type(&file_name(42, false)), "application/json");
+  }
+
+  #[test]
+  fn test_all_pages() {
+    let items: Vec<i64> = (0..PAGE_SIZE * 2 + 3).collect();
+    let read = all_pages(|page| {
+      let start = ((page - 1) * PAGE_SIZE) as usize;
+      Ok(items.iter().skip(start).take(PAGE_SIZE as usize).cloned().collect())
+    })
+    .unwrap();
+    assert_eq!(read, items);
+
+    let empty: Vec<i64> = all_pages(|_page| Ok(Vec::new())).unwrap();
+    assert!(empty.is_empty());
+  }
+
+  #[test]
+  fn test_archive_bytes() {
+    let contents = json!({ "user": { "name": "nutella" } });
+
+    let plain = archive_bytes(false, &contents).unwrap();
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&plain).unwrap(), contents);
+
+    let zipped = archive_bytes(true, &contents).unwrap();
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(zipped)).unwrap();
+    let mut json = String::new();
+    archive
+      .by_name(JSON_FILE_NAME)
+      .unwrap()
+      .read_to_string(&mut json)
+      .unwrap();
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&json).unwrap(), contents);
+  }
+}
@@ -1 +1,2 @@
-pub mod traits;
\ No newline at end of file
+pub mod published;
+pub mod traits;
@@ -0,0 +1,52 @@
+/**
+ * Node that is only valid for some time after it was published, like a
+ * pending password reset. The lifetime and interval come from the settings of
+ * each type.
+ */
+pub trait Published {
+  fn published(&self) -> chrono::NaiveDateTime;
+
+  fn is_expired_at(&self, now: chrono::NaiveDateTime, lifetime: chrono::Duration) -> bool {
+    self.published() + lifetime <= now
+  }
+
+  /// Whether this node is too recent for the user to ask for another one
+  fn is_rate_limited_at(&self, now: chrono::NaiveDateTime, interval: chrono::Duration) -> bool {
+    self.published() + interval > now
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Duration;
+
+  struct Node(chrono::NaiveDateTime);
+
+  impl Published for Node {
+    fn published(&self) -> chrono::NaiveDateTime {
+      self.0
+    }
+  }
+
+  #[test]
+  fn test_expiry() {
+    let now = chrono::Utc::now().naive_utc();
+    let lifetime = Duration::minutes(60);
+
+    assert!(!Node(now).is_expired_at(now, lifetime));
+    assert!(!Node(now - Duration::minutes(59)).is_expired_at(now, lifetime));
+    assert!(Node(now - Duration::minutes(60)).is_expired_at(now, lifetime));
+    assert!(Node(now - Duration::days(30)).is_expired_at(now, lifetime));
+  }
+
+  #[test]
+  fn test_rate_limit() {
+    let now = chrono::Utc::now().naive_utc();
+    let interval = Duration::seconds(300);
+
+    assert!(Node(now - Duration::seconds(10)).is_rate_limited_at(now, interval));
+    assert!(!Node(now - Duration::seconds(300)).is_rate_limited_at(now, interval));
+    assert!(!Node(now).is_rate_limited_at(now, Duration::seconds(0)));
+  }
+}
@@ -0,0 +1,1324 @@
+/**
+ * Scores and counts stored on the nodes they describe.
+ *
+ * Every change that moves a count (a vote, a new post or comment, a follow,
+ * deleting or removing content) sends its CountChanges in the same upsert as
+ * the change itself, see mutate_with_counts. Changes that depend on what is
+ * in the graph read it in the transaction they are written in, see
+ * transact_with_counts. `recount` rebuilds all of them from the votes, posts,
+ * comments and follows in the database.
+ */
+use super::comment::Comment;
+use super::post::Post;
+use crate::db::*;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Times a transaction runs again after Dgraph aborted it for a conflict
+const CONFLICT_RETRIES: usize = 5;
+
+/// Node whose counter changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CountTarget {
+  Node(i64),
+  /// The site node, for the instance wide counts
+  Site,
+}
+
+/// Add delta to predicate of target
+#[derive(Debug, Clone, PartialEq)]
+pub struct CountChange {
+  pub target: CountTarget,
+  pub predicate: &'static str,
+  pub delta: i64,
+}
+
+/// Whether content shows up in counts
+pub fn counted(removed: bool, deleted: bool) -> bool {
+  !removed && !deleted
+}
+
+impl CountChange {
+  fn node(id: i64, predicate: &'static str, delta: i64) -> Self {
+    CountChange {
+      target: CountTarget::Node(id),
+      predicate,
+      delta,
+    }
+  }
+
+  fn site(predicate: &'static str, delta: i64) -> Self {
+    CountChange {
+      target: CountTarget::Site,
+      predicate,
+      delta,
+    }
+  }
+
+  /// Up and down votes for a vote score of 1, 0 or -1
+  fn votes(score: i16) -> (i64, i64) {
+    match score {
+      s if s > 0 => (1, 0),
+      s if s < 0 => (0, 1),
+      _ => (0, 0),
+    }
+  }
+
+  /**
+   * A vote on post changing from old_score to new_score, 0 meaning no vote
+   */
+  pub fn post_vote(post: &Post, old_score: i16, new_score: i16) -> Vec<Self> {
+    let (old_up, old_down) = Self::votes(old_score);
+    let (new_up, new_down) = Self::votes(new_score);
+    let delta = i64::from(new_score) - i64::from(old_score);
+    vec![
+      Self::node(post.id, PostAggregates::SCORE, delta),
+      Self::node(post.id, PostAggregates::UPVOTES, new_up - old_up),
+      Self::node(post.id, PostAggregates::DOWNVOTES, new_down - old_down),
+      Self::node(post.creator_id, UserAggregates::POST_SCORE, delta),
+    ]
+  }
+
+  /**
+   * A vote on comment changing from old_score to new_score, 0 meaning no vote
+   */
+  pub fn comment_vote(comment: &Comment, old_score: i16, new_score: i16) -> Vec<Self> {
+    let (old_up, old_down) = Self::votes(old_score);
+    let (new_up, new_down) = Self::votes(new_score);
+    let delta = i64::from(new_score) - i64::from(old_score);
+    vec![
+      Self::node(comment.id, CommentAggregates::SCORE, delta),
+      Self::node(comment.id, CommentAggregates::UPVOTES, new_up - old_up),
+      Self::node(comment.id, CommentAggregates::DOWNVOTES, new_down - old_down),
+      Self::node(comment.creator_id, UserAggregates::COMMENT_SCORE, delta),
+    ]
+  }
+
+  /**
+   * Post being added to (delta 1) or taken out of (delta -1) the counts, by
+   * creating, deleting, removing or restoring it
+   */
+  pub fn post_count(post: &Post, delta: i64) -> Vec<Self> {
+    vec![
+      Self::node(post.community_id, CommunityAggregates::NUMBER_OF_POSTS, delta),
+      Self::node(post.creator_id, UserAggregates::NUMBER_OF_POSTS, delta),
+      Self::site(SiteAggregates::NUMBER_OF_POSTS, delta),
+    ]
+  }
+
+  /**
+   * Comment on a post in community_id being added to or taken out of the
+   * counts. See post_count.
+   */
+  pub fn comment_count(comment: &Comment, community_id: i64, delta: i64) -> Vec<Self> {
+    vec![
+      Self::node(comment.post_id, PostAggregates::NUMBER_OF_COMMENTS, delta),
+      Self::node(community_id, CommunityAggregates::NUMBER_OF_COMMENTS, delta),
+      Self::node(comment.creator_id, UserAggregates::NUMBER_OF_COMMENTS, delta),
+      Self::site(SiteAggregates::NUMBER_OF_COMMENTS, delta),
+    ]
+  }
+
+  /**
+   * Changes for content going from one removed and deleted state to another,
+   * using make_changes(1) or make_changes(-1). None if the counts stay.
+   */
+  pub fn visibility(
+    (was_removed, was_deleted): (bool, bool),
+    (removed, deleted): (bool, bool),
+    make_changes: impl FnOnce(i64) -> Vec<Self>,
+  ) -> Vec<Self> {
+    match (counted(was_removed, was_deleted), counted(removed, deleted)) {
+      (false, true) => make_changes(1),
+      (true, false) => make_changes(-1),
+      _ => Vec::new(),
+    }
+  }
+
+  pub fn subscriber_count(community_id: i64, delta: i64) -> Vec<Self> {
+    vec![Self::node(
+      community_id,
+      CommunityAggregates::NUMBER_OF_SUBSCRIBERS,
+      delta,
+    )]
+  }
+
+  pub fn user_count(delta: i64) -> Vec<Self> {
+    vec![Self::site(SiteAggregates::NUMBER_OF_USERS, delta)]
+  }
+
+  pub fn community_count(delta: i64) -> Vec<Self> {
+    vec![Self::site(SiteAggregates::NUMBER_OF_COMMUNITIES, delta)]
+  }
+}
+
+/**
+ * Upsert query and nquads adding up changes. Changes to the same counter
+ * are merged, and the ones that add up to 0 left out.
+ *
+ * A counter the node doesn't have yet is set to the delta. Dgraph skips the
+ * nquads of variables that matched nothing, so exactly one of the two nquads
+ * of each counter is applied.
+ */
+fn count_upsert(changes: &[CountChange]) -> Option<(String, String)> {
+  let mut merged: Vec<CountChange> = Vec::new();
+  for change in changes {
+    match merged
+      .iter_mut()
+      .find(|m| m.target == change.target && m.predicate == change.predicate)
+    {
+      Some(m) => m.delta += change.delta,
+      None => merged.push(change.to_owned()),
+    }
+  }
+  merged.retain(|m| m.delta != 0);
+  if merged.is_empty() {
+    return None;
+  }
+
+  let mut query = String::from("query {\n");
+  let mut nquads = String::new();
+  for (i, change) in merged.iter().enumerate() {
+    let func = match change.target {
+      CountTarget::Node(id) => format!("uid({})", id),
+      CountTarget::Site => "type(Site)".to_owned(),
+    };
+    query.push_str(&format!(
+      "  c{i} as var(func: {func}) {{ v{i} as {pred} n{i} as math(v{i} + {delta}) }}\n",
+      i = i,
+      func = func,
+      pred = change.predicate,
+      delta = change.delta,
+    ));
+    query.push_str(&format!(
+      "  z{i} as var(func: {func}) @filter(NOT has({pred}))\n",
+      i = i,
+      func = func,
+      pred = change.predicate,
+    ));
+    nquads.push_str(&format!(
+      "uid(c{i}) <{pred}> val(n{i}) .\nuid(z{i}) <{pred}> \"{delta}\" .\n",
+      i = i,
+      pred = change.predicate,
+      delta = change.delta,
+    ));
+  }
+  query.push('}');
+
+  Some((query, nquads))
+}
+
+/**
+ * Run mu and apply changes in one transaction, so counts never disagree with
+ * the votes, follows and content they count. Returns the uids of the nodes
+ * created by mu.
+ */
+pub async fn mutate_with_counts(
+  conn: &dgraph::Client,
+  mu: Mutation,
+  changes: &[CountChange],
+) -> Result<HashMap<String, String>, Error> {
+  commit_with_counts(conn.new_mutated_txn(), mu, changes).await
+}
+
+/// Run mu and apply changes in txn, and commit it
+async fn commit_with_counts(
+  mut txn: dgraph::MutatedTxn,
+  mu: Mutation,
+  changes: &[CountChange],
+) -> Result<HashMap<String, String>, Error> {
+  let resp = match count_upsert(changes) {
+    Some((query, nquads)) => {
+      let mut counts = Mutation::new();
+      counts.set_set_nquads(nquads);
+      txn.upsert(query, vec![mu, counts]).await?
+    }
+    None => txn.mutate(mu).await?,
+  };
+  txn.commit().await?;
+
+  Ok(resp.uids)
+}
+
+/// What a transaction of transact_with_counts writes, and what it returns
+pub struct TxnWrites<T> {
+  pub value: T,
+  /// None if there is nothing to write
+  pub mutation: Option<Mutation>,
+  pub changes: Vec<CountChange>,
+}
+
+impl<T> TxnWrites<T> {
+  pub fn new(value: T, mu: Mutation, changes: Vec<CountChange>) -> Self {
+    TxnWrites {
+      value,
+      mutation: Some(mu),
+      changes,
+    }
+  }
+
+  /// Return value, writing nothing
+  pub fn none(value: T) -> Self {
+    TxnWrites {
+      value,
+      mutation: None,
+      changes: Vec::new(),
+    }
+  }
+}
+
+pub type TxnFuture<'t, T> = Pin<Box<dyn Future<Output = Result<TxnWrites<T>, Error>> + 't>>;
+
+/**
+ * Like mutate_with_counts, for changes that depend on the current state of
+ * the graph. `make` reads that state with the transaction its writes are then
+ * committed in. Two transactions writing the same predicates of a node
+ * conflict, and Dgraph aborts the one committing last: then `make` runs again,
+ * in a new transaction that sees the other one's writes. So the writes must
+ * include the predicates the reads decided on (the vote edge, the removed and
+ * deleted flags, the counts).
+ */
+pub async fn transact_with_counts<T, F>(conn: &dgraph::Client, make: F) -> Result<T, Error>
+where
+  F: for<'t> Fn(&'t mut dgraph::MutatedTxn) -> TxnFuture<'t, T>,
+{
+  let mut attempt = 0;
+  loop {
+    let mut txn = conn.new_mutated_txn();
+    let writes = make(&mut txn).await?;
+    let mu = match writes.mutation {
+      Some(mu) => mu,
+      None => return Ok(writes.value),
+    };
+    match commit_with_counts(txn, mu, &writes.changes).await {
+      Err(e) if is_conflict(&e) && attempt < CONFLICT_RETRIES => attempt += 1,
+      result => {
+        result?;
+        return Ok(writes.value);
+      }
+    }
+  }
+}
+
+/// Whether Dgraph aborted a transaction because it conflicted with another one
+fn is_conflict(e: &Error) -> bool {
+  format!("{:?}", e).contains("Aborted")
+}
+
+/// Like read_node, with txn
+pub async fn read_node_in_txn<T>(txn: &mut dgraph::MutatedTxn, id: i64) -> Result<T, Error>
+where
+  T: Node + DeserializeOwned,
+{
+  let q = format!(
+    r#"query {{
+    {t_name}(func: uid({id})) @filter(type({t_name})) {{
+    expand(type_name)
+    }}}}"#,
+    id = id,
+    t_name = T::db_type_name()
+  );
+
+  let resp = txn.query(q).await?;
+  let res: T = resp.try_into_owned()?;
+  Ok(res)
+}
+
+/**
+ * Like mutate_with_counts, for changes that depend on the current state of the
+ * graph. The blocks of query run in the same transaction, their variables can
+ * be used in mutations, and cond applies to mutations and counts alike. Query
+ * variables must not start with c, z, v or n followed by a digit, those are
+ * used for the counts.
+ *
+ * Returns the response, the named blocks of query tell whether cond held.
+ */
+pub async fn upsert_with_counts(
+  conn: &dgraph::Client,
+  query: &str,
+  cond: &str,
+  mutations: Vec<Mutation>,
+  changes: &[CountChange],
+) -> Result<dgraph::Response, Error> {
+  let mut mutations: Vec<Mutation> = mutations;
+  let query = match count_upsert(changes) {
+    Some((count_query, nquads)) => {
+      let mut counts = Mutation::new();
+      counts.set_set_nquads(nquads);
+      mutations.push(counts);
+      format!("query {{\n{}\n{}\n}}", query_blocks(query), query_blocks(&count_query))
+    }
+    None => query.to_owned(),
+  };
+  for mu in mutations.iter_mut() {
+    mu.set_cond(cond);
+  }
+
+  let mut txn = conn.new_mutated_txn();
+  let resp = txn.upsert(query, mutations).await?;
+  txn.commit().await?;
+  Ok(resp)
+}
+
+/// The blocks of a `query { ... }`
+fn query_blocks(query: &str) -> &str {
+  let query = query.trim();
+  let start = query.find('{').map_or(0, |i| i + 1);
+  let end = query.rfind('}').unwrap_or_else(|| query.len());
+  &query[start..end]
+}
+
+/**
+ * Node with its stored values starting at aggregates, for set_json. The new
+ * node is "x" in the uids returned by mutate_with_counts.
+ */
+pub fn new_node_json<T, A>(node: &T, aggregates: &A) -> Result<serde_json::Value, Error>
+where
+  T: Node + Serialize,
+  A: Serialize,
+{
+  let type_name = T::db_type_name();
+  let mut dict = serde_json::Map::new();
+  if let serde_json::Value::Object(fields) = serde_json::to_value(node)? {
+    for (key, value) in fields {
+      dict.insert(format!("{}.{}", type_name, key), value);
+    }
+  }
+  if let serde_json::Value::Object(fields) = serde_json::to_value(aggregates)? {
+    dict.extend(fields);
+  }
+  dict.insert("uid".into(), "_:x".into());
+  dict.insert("dgraph.type".into(), type_name.into());
+  Ok(serde_json::Value::Object(dict))
+}
+
+/// Uid of the node created from new_node_json
+pub fn created_uid(uids: &HashMap<String, String>) -> Result<i64, Error> {
+  match uids.get("x") {
+    Some(uid) => Ok(i64::from_str_radix(uid.trim_start_matches("0x"), 16)?),
+    None => failure::bail!("Failed to create node."),
+  }
+}
+
+/**
+ * Mutation setting the fields of form that aren't null on node id of type T
+ */
+pub fn update_mutation<T, F>(id: i64, form: &F) -> Result<Mutation, Error>
+where
+  T: Node,
+  F: Serialize,
+{
+  let mut dict = serde_json::Map::new();
+  if let serde_json::Value::Object(fields) = serde_json::to_value(form)? {
+    for (key, value) in fields.into_iter().filter(|(_, v)| !v.is_null()) {
+      dict.insert(format!("{}.{}", T::db_type_name(), key), value);
+    }
+  }
+  dict.insert("uid".into(), format!("{:#x}", id).into());
+
+  let mut mu = Mutation::new();
+  mu.set_set_json(&dict)?;
+  Ok(mu)
+}
+
+/// Edge with its facets as an nquad, unset (null) facets are left out
+pub fn edge_nquad<T: Edge + Serialize>(edge: &T) -> Result<String, Error> {
+  let facets = match serde_json::to_value(edge)? {
+    serde_json::Value::Object(fields) => fields
+      .iter()
+      .filter(|(_, value)| !value.is_null())
+      .map(|(key, value)| format!("{}={}", key, value))
+      .collect::<Vec<_>>()
+      .join(","),
+    _ => String::new(),
+  };
+  Ok(format!(
+    "uid({}) <{}> uid({}) ({}) .",
+    edge.from(),
+    T::db_type_name(),
+    edge.to(),
+    facets
+  ))
+}
+
+/**
+ * Delete the nodes whose DateTime pred is before then, and that match filter
+ * if given, in one upsert. The root function uses the index of pred, so the
+ * cleanup jobs don't read every node of the type.
+ *
+ * @return number of deleted nodes
+ */
+pub async fn delete_older(
+  conn: &dgraph::Client,
+  pred: &str,
+  before: chrono::NaiveDateTime,
+  filter: Option<&str>,
+) -> Result<usize, Error> {
+  #[derive(Deserialize)]
+  struct Matched {
+    old: Vec<serde_json::Value>,
+  }
+
+  let q = format!(
+    r#"query {{
+      old as old(func: lt({pred}, "{before}")){filter} {{
+        uid
+      }}
+    }}"#,
+    pred = pred,
+    before = before.format("%Y-%m-%dT%H:%M:%S"),
+    filter = filter.map_or(String::new(), |f| format!(" @filter({})", f)),
+  );
+  let mut mu = Mutation::new();
+  mu.set_cond("@if(gt(len(old), 0))");
+  mu.set_delete_nquads("uid(old) * * .");
+
+  let mut txn = conn.new_mutated_txn();
+  let resp = txn.upsert(q, vec![mu]).await?;
+  let matched: Matched = resp.try_into()?;
+  txn.commit().await?;
+  Ok(matched.old.len())
+}
+
+#[derive(Debug, Deserialize)]
+struct EdgeSource {
+  #[serde(default)]
+  votes: Vec<VoteRow>,
+}
+
+/**
+ * The edges pred from from_id to to_id, with their score facet if they have
+ * one. Empty if there is no such edge.
+ */
+async fn edges_between<Q: Query>(
+  txn: &mut Q,
+  from_id: i64,
+  pred: &str,
+  to_id: i64,
+) -> Result<Vec<VoteRow>, Error> {
+  let q = format!(
+    r#"
+    nodeList(func: uid({from_id})) {{
+      votes: {pred} @facets(score) @filter(uid({to_id})) {{
+        uid
+      }}
+    }}"#,
+    from_id = from_id,
+    pred = pred,
+    to_id = to_id
+  );
+
+  let resp = txn.query(q).await?;
+
+  let sources: NodeList<EdgeSource> = resp.try_into()?;
+  Ok(sources.all.into_iter().flat_map(|source| source.votes).collect())
+}
+
+/// Score of the vote edge pred from from_id to user_id, if there is one
+pub async fn vote_score<Q: Query>(
+  txn: &mut Q,
+  from_id: i64,
+  pred: &str,
+  user_id: i64,
+) -> Result<Option<i16>, Error> {
+  let votes = edges_between(txn, from_id, pred, user_id).await?;
+  Ok(votes.first().map(|vote| vote.score))
+}
+
+/// A vote of a user, on the post or comment id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserVote {
+  pub id: i64,
+  pub score: i16,
+}
+
+/**
+ * The votes of user through the vote edge pred, read from its reverse edge so
+ * only what the user voted on is loaded
+ */
+pub async fn votes_of_user(conn: &dgraph::Client, pred: &str, user_id: i64) -> Result<Vec<UserVote>, Error> {
+  #[derive(Deserialize)]
+  struct VoteTarget {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    uid: i64,
+    #[serde(rename = "votes|score", default)]
+    score: i16,
+  }
+  #[derive(Deserialize)]
+  struct Voter {
+    #[serde(default)]
+    votes: Vec<VoteTarget>,
+  }
+
+  let q = format!(
+    r#"
+    nodeList(func: uid({user_id})) {{
+      votes: ~{pred} @facets(score) {{
+        uid
+      }}
+    }}"#,
+    user_id = user_id,
+    pred = pred,
+  );
+
+  let txn = conn.new_read_only_txn();
+  let resp = txn.query(q).await?;
+  let voters: NodeList<Voter> = resp.try_into()?;
+  Ok(
+    voters
+      .all
+      .into_iter()
+      .flat_map(|voter| voter.votes)
+      .map(|vote| UserVote {
+        id: vote.uid,
+        score: vote.score,
+      })
+      .collect(),
+  )
+}
+
+pub async fn edge_exists<Q: Query>(
+  txn: &mut Q,
+  from_id: i64,
+  pred: &str,
+  to_id: i64,
+) -> Result<bool, Error> {
+  Ok(!edges_between(txn, from_id, pred, to_id).await?.is_empty())
+}
+
+//#############################################################################
+// Stored values, by node type
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PostAggregates {
+  #[serde(rename = "Post.score", default)]
+  pub score: i64,
+  #[serde(rename = "Post.upvotes", default)]
+  pub upvotes: i64,
+  #[serde(rename = "Post.downvotes", default)]
+  pub downvotes: i64,
+  #[serde(rename = "Post.numberOfComments", default)]
+  pub number_of_comments: i64,
+  /// When the post or its latest comment was published
+  #[serde(rename = "Post.newestActivityTime", default)]
+  pub newest_activity_time: Option<chrono::NaiveDateTime>,
+  /// See db::controversy_rank, refreshed with the hot rank
+  #[serde(rename = "Post.controversyRank", default)]
+  pub controversy_rank: f64,
+}
+
+impl PostAggregates {
+  pub const SCORE: &'static str = "Post.score";
+  pub const UPVOTES: &'static str = "Post.upvotes";
+  pub const DOWNVOTES: &'static str = "Post.downvotes";
+  pub const NUMBER_OF_COMMENTS: &'static str = "Post.numberOfComments";
+  pub const NEWEST_ACTIVITY_TIME: &'static str = "Post.newestActivityTime";
+  pub const CONTROVERSY_RANK: &'static str = "Post.controversyRank";
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommentAggregates {
+  #[serde(rename = "Comment.score", default)]
+  pub score: i64,
+  #[serde(rename = "Comment.upvotes", default)]
+  pub upvotes: i64,
+  #[serde(rename = "Comment.downvotes", default)]
+  pub downvotes: i64,
+  /// See db::controversy_rank, refreshed with the hot rank
+  #[serde(rename = "Comment.controversyRank", default)]
+  pub controversy_rank: f64,
+}
+
+impl CommentAggregates {
+  pub const SCORE: &'static str = "Comment.score";
+  pub const UPVOTES: &'static str = "Comment.upvotes";
+  pub const DOWNVOTES: &'static str = "Comment.downvotes";
+  pub const CONTROVERSY_RANK: &'static str = "Comment.controversyRank";
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserAggregates {
+  #[serde(rename = "User.numberOfPosts", default)]
+  pub number_of_posts: i64,
+  #[serde(rename = "User.postScore", default)]
+  pub post_score: i64,
+  #[serde(rename = "User.numberOfComments", default)]
+  pub number_of_comments: i64,
+  #[serde(rename = "User.commentScore", default)]
+  pub comment_score: i64,
+}
+
+impl UserAggregates {
+  pub const NUMBER_OF_POSTS: &'static str = "User.numberOfPosts";
+  pub const POST_SCORE: &'static str = "User.postScore";
+  pub const NUMBER_OF_COMMENTS: &'static str = "User.numberOfComments";
+  pub const COMMENT_SCORE: &'static str = "User.commentScore";
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommunityAggregates {
+  #[serde(rename = "Community.numberOfSubscribers", default)]
+  pub number_of_subscribers: i64,
+  #[serde(rename = "Community.numberOfPosts", default)]
+  pub number_of_posts: i64,
+  #[serde(rename = "Community.numberOfComments", default)]
+  pub number_of_comments: i64,
+}
+
+impl CommunityAggregates {
+  pub const NUMBER_OF_SUBSCRIBERS: &'static str = "Community.numberOfSubscribers";
+  pub const NUMBER_OF_POSTS: &'static str = "Community.numberOfPosts";
+  pub const NUMBER_OF_COMMENTS: &'static str = "Community.numberOfComments";
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SiteAggregates {
+  #[serde(rename = "Site.numberOfUsers", default)]
+  pub number_of_users: i64,
+  #[serde(rename = "Site.numberOfPosts", default)]
+  pub number_of_posts: i64,
+  #[serde(rename = "Site.numberOfComments", default)]
+  pub number_of_comments: i64,
+  #[serde(rename = "Site.numberOfCommunities", default)]
+  pub number_of_communities: i64,
+}
+
+impl SiteAggregates {
+  pub const NUMBER_OF_USERS: &'static str = "Site.numberOfUsers";
+  pub const NUMBER_OF_POSTS: &'static str = "Site.numberOfPosts";
+  pub const NUMBER_OF_COMMENTS: &'static str = "Site.numberOfComments";
+  pub const NUMBER_OF_COMMUNITIES: &'static str = "Site.numberOfCommunities";
+}
+
+//#############################################################################
+// Recount
+
+#[derive(Debug, Deserialize)]
+struct VoteRow {
+  #[serde(rename = "votes|score", default)]
+  score: i16,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostRow {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  uid: i64,
+  #[serde(rename = "Post.creatorId")]
+  creator_id: i64,
+  #[serde(rename = "Post.communityId")]
+  community_id: i64,
+  #[serde(rename = "Post.removed", default)]
+  removed: bool,
+  #[serde(rename = "Post.held", default)]
+  held: bool,
+  #[serde(rename = "Post.deleted", default)]
+  deleted: bool,
+  #[serde(rename = "Post.published")]
+  published: chrono::NaiveDateTime,
+  #[serde(default)]
+  votes: Vec<VoteRow>,
+}
+
+const POST_FIELDS: &str = "Post.creatorId Post.communityId Post.removed Post.held Post.deleted \
+  Post.published votes: Post.UserLike @facets(score) { uid }";
+
+#[derive(Debug, Deserialize)]
+struct CommentRow {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  uid: i64,
+  #[serde(rename = "Comment.creatorId")]
+  creator_id: i64,
+  #[serde(rename = "Comment.postId")]
+  post_id: i64,
+  #[serde(rename = "Comment.removed", default)]
+  removed: bool,
+  #[serde(rename = "Comment.held", default)]
+  held: bool,
+  #[serde(rename = "Comment.deleted", default)]
+  deleted: bool,
+  #[serde(rename = "Comment.published")]
+  published: chrono::NaiveDateTime,
+  #[serde(default)]
+  votes: Vec<VoteRow>,
+}
+
+const COMMENT_FIELDS: &str = "Comment.creatorId Comment.postId Comment.removed Comment.held \
+  Comment.deleted Comment.published votes: Comment.UserLike @facets(score) { uid }";
+
+#[derive(Debug, Deserialize)]
+struct CommunityRow {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  uid: i64,
+  #[serde(rename = "Community.removed", default)]
+  removed: bool,
+  #[serde(rename = "Community.deleted", default)]
+  deleted: bool,
+  #[serde(default)]
+  subscribers: i64,
+}
+
+const COMMUNITY_FIELDS: &str = "Community.removed Community.deleted subscribers: count(Follower)";
+
+#[derive(Debug, Deserialize)]
+struct UidRow {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  uid: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecountPage<T> {
+  #[serde(default = "Vec::new")]
+  rows: Vec<T>,
+}
+
+/// Nodes recount reads and writes at once
+const RECOUNT_BATCH: usize = 1000;
+
+/**
+ * The next RECOUNT_BATCH nodes of type with a uid above after, with fields
+ */
+async fn recount_page<T: DeserializeOwned>(
+  conn: &dgraph::Client,
+  type_name: &str,
+  fields: &str,
+  after: i64,
+) -> Result<Vec<T>, Error> {
+  let q = format!(
+    r#"query {{
+      rows(func: type({type_name}), first: {first}, after: {after:#x}) {{
+        uid
+        {fields}
+      }}
+    }}"#,
+    type_name = type_name,
+    first = RECOUNT_BATCH,
+    after = after,
+    fields = fields,
+  );
+
+  let txn = conn.new_read_only_txn();
+  let resp = txn.query(q).await?;
+  let page: RecountPage<T> = resp.try_into()?;
+  Ok(page.rows)
+}
+
+/// Overwrite the stored values of nodes
+async fn write_aggregates<T: Serialize>(
+  conn: &dgraph::Client,
+  nodes: &[(i64, T)],
+) -> Result<(), Error> {
+  if nodes.is_empty() {
+    return Ok(());
+  }
+  let values = nodes
+    .iter()
+    .map(|(id, aggregates)| with_uid(*id, aggregates))
+    .collect::<Result<Vec<_>, _>>()?;
+
+  let mut mu = Mutation::new();
+  mu.set_set_json(&values)?;
+  let mut txn = conn.new_mutated_txn();
+  txn.mutate(mu).await?;
+  txn.commit().await?;
+  Ok(())
+}
+
+/// What recount adds up across batches: the values of a node that depend on
+/// nodes of other types
+#[derive(Debug, Default)]
+struct Tally {
+  /// Number of counted comments and when the newest was published, by post
+  post_comments: HashMap<i64, (i64, chrono::NaiveDateTime)>,
+  users: HashMap<i64, UserAggregates>,
+  communities: HashMap<i64, CommunityAggregates>,
+  site: SiteAggregates,
+}
+
+impl Tally {
+  fn vote_aggregates(votes: &[VoteRow]) -> (i64, i64, i64) {
+    let (mut score, mut upvotes, mut downvotes) = (0, 0, 0);
+    for vote in votes {
+      let (up, down) = CountChange::votes(vote.score);
+      score += i64::from(vote.score);
+      upvotes += up;
+      downvotes += down;
+    }
+    (score, upvotes, downvotes)
+  }
+
+  /// Values of a batch of comments, adding them to their posts and creators
+  fn add_comments(&mut self, rows: &[CommentRow]) -> Vec<(i64, CommentAggregates)> {
+    let mut batch = Vec::new();
+    for comment in rows {
+      let (score, upvotes, downvotes) = Self::vote_aggregates(&comment.votes);
+
+      let creator = self.users.entry(comment.creator_id).or_default();
+      creator.comment_score += score;
+      if counted(comment.removed || comment.held, comment.deleted) {
+        creator.number_of_comments += 1;
+        let post = self
+          .post_comments
+          .entry(comment.post_id)
+          .or_insert((0, comment.published));
+        post.0 += 1;
+        post.1 = post.1.max(comment.published);
+        self.site.number_of_comments += 1;
+      }
+      batch.push((
+        comment.uid,
+        CommentAggregates {
+          score,
+          upvotes,
+          downvotes,
+          controversy_rank: controversy_rank(upvotes, downvotes),
+        },
+      ));
+    }
+    batch
+  }
+
+  /// Values of a batch of posts, adding them to their communities and
+  /// creators. Needs all comments added first.
+  fn add_posts(&mut self, rows: &[PostRow]) -> Vec<(i64, PostAggregates)> {
+    let mut batch = Vec::new();
+    for post in rows {
+      let (score, upvotes, downvotes) = Self::vote_aggregates(&post.votes);
+      let (number_of_comments, newest_comment) = self
+        .post_comments
+        .get(&post.uid)
+        .map_or((0, None), |&(count, newest)| (count, Some(newest)));
+
+      let creator = self.users.entry(post.creator_id).or_default();
+      creator.post_score += score;
+      let community = self.communities.entry(post.community_id).or_default();
+      community.number_of_comments += number_of_comments;
+      if counted(post.removed || post.held, post.deleted) {
+        creator.number_of_posts += 1;
+        community.number_of_posts += 1;
+        self.site.number_of_posts += 1;
+      }
+      batch.push((
+        post.uid,
+        PostAggregates {
+          score,
+          upvotes,
+          downvotes,
+          number_of_comments,
+          newest_activity_time: Some(post.published).max(newest_comment),
+          controversy_rank: controversy_rank(upvotes, downvotes),
+        },
+      ));
+    }
+    batch
+  }
+
+  /// Values of a batch of communities. Needs all posts added first.
+  fn add_communities(&mut self, rows: &[CommunityRow]) -> Vec<(i64, CommunityAggregates)> {
+    let mut batch = Vec::new();
+    for community in rows {
+      if counted(community.removed, community.deleted) {
+        self.site.number_of_communities += 1;
+      }
+      let aggregates = CommunityAggregates {
+        number_of_subscribers: community.subscribers,
+        ..self.communities.remove(&community.uid).unwrap_or_default()
+      };
+      batch.push((community.uid, aggregates));
+    }
+    batch
+  }
+
+  /// Values of a batch of users. Needs all posts and comments added first.
+  fn add_users(&mut self, rows: &[UidRow]) -> Vec<(i64, UserAggregates)> {
+    self.site.number_of_users += rows.len() as i64;
+    rows
+      .iter()
+      .map(|user| (user.uid, self.users.remove(&user.uid).unwrap_or_default()))
+      .collect()
+  }
+}
+
+/// Number of nodes recount wrote, by type
+#[derive(Debug, Default, PartialEq)]
+pub struct Recounted {
+  pub posts: usize,
+  pub comments: usize,
+  pub users: usize,
+  pub communities: usize,
+}
+
+/// Node with its stored values, for set_json
+fn with_uid<T: Serialize>(id: i64, aggregates: &T) -> Result<serde_json::Value, Error> {
+  let mut value = serde_json::to_value(aggregates)?;
+  value
+    .as_object_mut()
+    .expect("Aggregates serialize to a map")
+    .insert("uid".into(), format!("{:#x}", id).into());
+  Ok(value)
+}
+
+/**
+ * Rebuild every stored score and count from the votes, content and follows
+ * in the database, overwriting what is there. For new instances, imports, and
+ * anything that changed the data without going through mutate_with_counts.
+ *
+ * Goes through the nodes of each type in batches by uid, writing the values
+ * of each batch before reading the next one. Comments come first, then the
+ * posts they add to, then the communities and users both add to.
+ */
+pub async fn recount(conn: &dgraph::Client) -> Result<Recounted, Error> {
+  let mut tally = Tally::default();
+  let mut recounted = Recounted::default();
+
+  let mut after = 0;
+  loop {
+    let rows: Vec<CommentRow> = recount_page(conn, "Comment", COMMENT_FIELDS, after).await?;
+    after = match rows.last() {
+      Some(row) => row.uid,
+      None => break,
+    };
+    write_aggregates(conn, &tally.add_comments(&rows)).await?;
+    recounted.comments += rows.len();
+  }
+
+  let mut after = 0;
+  loop {
+    let rows: Vec<PostRow> = recount_page(conn, "Post", POST_FIELDS, after).await?;
+    after = match rows.last() {
+      Some(row) => row.uid,
+      None => break,
+    };
+    write_aggregates(conn, &tally.add_posts(&rows)).await?;
+    recounted.posts += rows.len();
+  }
+
+  let mut after = 0;
+  loop {
+    let rows: Vec<CommunityRow> = recount_page(conn, "Community", COMMUNITY_FIELDS, after).await?;
+    after = match rows.last() {
+      Some(row) => row.uid,
+      None => break,
+    };
+    write_aggregates(conn, &tally.add_communities(&rows)).await?;
+    recounted.communities += rows.len();
+  }
+
+  let mut after = 0;
+  loop {
+    let rows: Vec<UidRow> = recount_page(conn, "User", "", after).await?;
+    after = match rows.last() {
+      Some(row) => row.uid,
+      None => break,
+    };
+    write_aggregates(conn, &tally.add_users(&rows)).await?;
+    recounted.users += rows.len();
+  }
+
+  let sites: Vec<UidRow> = recount_page(conn, "Site", "", 0).await?;
+  let sites: Vec<(i64, &SiteAggregates)> = sites.iter().map(|site| (site.uid, &tally.site)).collect();
+  write_aggregates(conn, &sites).await?;
+
+  Ok(recounted)
+}
+
+//#############################################################################
+// Ranks
+
+/// Stored hot rank, see db::hot_rank
+pub const POST_HOT_RANK: &str = "Post.hotRank";
+pub const COMMENT_HOT_RANK: &str = "Comment.hotRank";
+
+#[derive(Debug, Deserialize)]
+struct RankRow {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  uid: i64,
+  #[serde(default)]
+  score: i64,
+  #[serde(default)]
+  upvotes: i64,
+  #[serde(default)]
+  downvotes: i64,
+  published: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RankRows {
+  #[serde(default)]
+  posts: Vec<RankRow>,
+  #[serde(default)]
+  comments: Vec<RankRow>,
+}
+
+/**
+ * Store the hot and controversy ranks as of now for posts and comments
+ * published since then. Older content keeps its last ranks, its hot rank has
+ * decayed to about 0 by then and it hardly gets votes anymore. recount sets
+ * the controversy rank of all content.
+ *
+ * @return number of updated posts and comments
+ */
+pub async fn refresh_ranks(
+  conn: &dgraph::Client,
+  since: chrono::NaiveDateTime,
+  now: chrono::NaiveDateTime,
+) -> Result<usize, Error> {
+  let q = format!(
+    r#"query {{
+      posts(func: ge(Post.published, "{since}")) @filter(type(Post)) {{
+        uid
+        score: Post.score
+        upvotes: Post.upvotes
+        downvotes: Post.downvotes
+        published: Post.published
+      }}
+      comments(func: ge(Comment.published, "{since}")) @filter(type(Comment)) {{
+        uid
+        score: Comment.score
+        upvotes: Comment.upvotes
+        downvotes: Comment.downvotes
+        published: Comment.published
+      }}
+    }}"#,
+    since = since.format("%Y-%m-%dT%H:%M:%S")
+  );
+
+  let txn = conn.new_read_only_txn();
+  let resp = txn.query(q).await?;
+  let rows: RankRows = resp.try_into()?;
+
+  let rank = |hot_pred: &str, controversy_pred: &str, row: &RankRow| {
+    let mut node = serde_json::Map::new();
+    node.insert("uid".into(), format!("{:#x}", row.uid).into());
+    node.insert(hot_pred.into(), hot_rank(row.score, row.published, now).into());
+    node.insert(
+      controversy_pred.into(),
+      controversy_rank(row.upvotes, row.downvotes).into(),
+    );
+    serde_json::Value::Object(node)
+  };
+  let nodes: Vec<serde_json::Value> = rows
+    .posts
+    .iter()
+    .map(|row| rank(POST_HOT_RANK, PostAggregates::CONTROVERSY_RANK, row))
+    .chain(
+      rows
+        .comments
+        .iter()
+        .map(|row| rank(COMMENT_HOT_RANK, CommentAggregates::CONTROVERSY_RANK, row)),
+    )
+    .collect();
+  if nodes.is_empty() {
+    return Ok(0);
+  }
+
+  let mut mu = Mutation::new();
+  mu.set_set_json(&nodes)?;
+  let mut txn = conn.new_mutated_txn();
+  txn.mutate(mu).await?;
+  txn.commit().await?;
+
+  Ok(nodes.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::NaiveDate;
+
+  fn time(hour: u32) -> chrono::NaiveDateTime {
+    NaiveDate::from_ymd(2020, 1, 1).and_hms(hour, 0, 0)
+  }
+
+  fn votes(scores: &[i16]) -> Vec<VoteRow> {
+    scores.iter().map(|&score| VoteRow { score }).collect()
+  }
+
+  fn post(uid: i64, creator_id: i64, deleted: bool, scores: &[i16]) -> PostRow {
+    PostRow {
+      uid,
+      creator_id,
+      community_id: 100,
+      removed: false,
+      held: false,
+      deleted,
+      published: time(1),
+      votes: votes(scores),
+    }
+  }
+
+  fn comment(uid: i64, post_id: i64, hour: u32, scores: &[i16]) -> CommentRow {
+    CommentRow {
+      uid,
+      creator_id: 2,
+      post_id,
+      removed: false,
+      held: false,
+      deleted: false,
+      published: time(hour),
+      votes: votes(scores),
+    }
+  }
+
+  #[test]
+  fn test_query_blocks() {
+    assert_eq!(query_blocks("query {\n  a as var(func: uid(0x1))\n}").trim(), "a as var(func: uid(0x1))");
+    assert_eq!(query_blocks(" query { a(func: uid(0x1)) { uid } } ").trim(), "a(func: uid(0x1)) { uid }");
+  }
+
+  #[test]
+  fn test_merges_changes_into_one_upsert() {
+    let mut changes = CountChange::user_count(1);
+    changes.extend(CountChange::subscriber_count(7, 1));
+    changes.extend(CountChange::subscriber_count(7, 1));
+    changes.extend(CountChange::community_count(0));
+
+    let (query, nquads) = count_upsert(&changes).unwrap();
+    assert_eq!(
+      query,
+      "query {\n\
+      \x20 c0 as var(func: type(Site)) { v0 as Site.numberOfUsers n0 as math(v0 + 1) }\n\
+      \x20 z0 as var(func: type(Site)) @filter(NOT has(Site.numberOfUsers))\n\
+      \x20 c1 as var(func: uid(7)) { v1 as Community.numberOfSubscribers n1 as math(v1 + 2) }\n\
+      \x20 z1 as var(func: uid(7)) @filter(NOT has(Community.numberOfSubscribers))\n\
+      }"
+    );
+    assert_eq!(
+      nquads,
+      "uid(c0) <Site.numberOfUsers> val(n0) .\n\
+      uid(z0) <Site.numberOfUsers> \"1\" .\n\
+      uid(c1) <Community.numberOfSubscribers> val(n1) .\n\
+      uid(z1) <Community.numberOfSubscribers> \"2\" .\n"
+    );
+
+    // Follow and unfollow cancel out
+    let mut changes = CountChange::subscriber_count(7, 1);
+    changes.extend(CountChange::subscriber_count(7, -1));
+    assert_eq!(count_upsert(&changes), None);
+  }
+
+  #[test]
+  fn test_vote_changes() {
+    let row = post(1, 2, false, &[]);
+    let post = Post {
+      id: row.uid,
+      name: "post".into(),
+      url: None,
+      body: None,
+      creator_id: row.creator_id,
+      community_id: row.community_id,
+      removed: false,
+      held: false,
+      locked: false,
+      published: row.published,
+      updated: None,
+      deleted: false,
+      nsfw: false,
+      stickied: false,
+      embed_title: None,
+      embed_description: None,
+      embed_html: None,
+      thumbnail_url: None,
+    };
+
+    // Upvote turned into a downvote
+    let deltas: Vec<i64> = CountChange::post_vote(&post, 1, -1)
+      .iter()
+      .map(|c| c.delta)
+      .collect();
+    assert_eq!(deltas, vec![-2, -1, 1, -2]);
+
+    let hidden = CountChange::visibility((false, false), (false, true), |delta| {
+      CountChange::post_count(&post, delta)
+    });
+    assert!(hidden.iter().all(|c| c.delta == -1));
+    assert!(CountChange::visibility((true, false), (true, true), |delta| {
+      CountChange::post_count(&post, delta)
+    })
+    .is_empty());
+  }
+
+  #[test]
+  fn test_tally() {
+    let mut tally = Tally::default();
+    let comments: HashMap<i64, CommentAggregates> = tally
+      .add_comments(&[
+        comment(10, 1, 5, &[1]),
+        comment(11, 1, 3, &[-1, -1]),
+        comment(12, 3, 4, &[]),
+      ])
+      .into_iter()
+      .collect();
+    let posts: HashMap<i64, PostAggregates> = tally
+      .add_posts(&[post(1, 2, false, &[1, 1, -1]), post(3, 2, true, &[1])])
+      .into_iter()
+      .collect();
+    let communities = tally.add_communities(&[CommunityRow {
+      uid: 100,
+      removed: false,
+      deleted: false,
+      subscribers: 4,
+    }]);
+    let users: HashMap<i64, UserAggregates> = tally
+      .add_users(&[UidRow { uid: 2 }, UidRow { uid: 5 }])
+      .into_iter()
+      .collect();
+
+    assert_eq!(
+      posts[&1],
+      PostAggregates {
+        score: 1,
+        upvotes: 2,
+        downvotes: 1,
+        number_of_comments: 2,
+        newest_activity_time: Some(time(5)),
+        controversy_rank: controversy_rank(2, 1),
+      }
+    );
+    assert_eq!(comments[&11].score, -2);
+    // Only downvotes, nothing controversial about it
+    assert_eq!(comments[&11].controversy_rank, 0.0);
+    // Deleted posts keep their score, but aren't counted
+    assert_eq!(
+      users[&2],
+      UserAggregates {
+        number_of_posts: 1,
+        post_score: 2,
+        number_of_comments: 3,
+        comment_score: -1,
+      }
+    );
+    assert_eq!(users[&5], UserAggregates::default());
+    assert_eq!(
+      communities,
+      vec![(
+        100,
+        CommunityAggregates {
+          number_of_subscribers: 4,
+          number_of_posts: 1,
+          number_of_comments: 3,
+        }
+      )]
+    );
+    assert_eq!(
+      tally.site,
+      SiteAggregates {
+        number_of_users: 2,
+        number_of_posts: 1,
+        number_of_comments: 3,
+        number_of_communities: 1,
+      }
+    );
+  }
+
+  #[test]
+  fn test_tally_skips_held_content() {
+    let mut held = post(1, 2, false, &[]);
+    held.held = true;
+    let mut tally = Tally::default();
+    tally.add_posts(&[held]);
+
+    assert_eq!(tally.users[&2].number_of_posts, 0);
+    assert_eq!(tally.site.number_of_posts, 0);
+  }
+}
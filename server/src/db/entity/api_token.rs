@@ -0,0 +1,153 @@
+use crate::db::*;
+use crate::{generate_random_string, sha256_hex};
+use std::str::FromStr;
+
+/// Prefix of API tokens, which tells them apart from session jwts
+pub const API_TOKEN_PREFIX: &str = "lemmy_";
+
+/// What an API token may be used for
+#[derive(EnumString, ToString, Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+#[strum(serialize_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ApiScope {
+  /// Read content, also what only the user can see (inbox, saved)
+  Read,
+  /// Create and edit posts, comments and private messages
+  Post,
+  /// Vote on posts and comments
+  Vote,
+  /// Mod actions in communities the user moderates
+  Moderate,
+  /// Admin actions, only for tokens of admins
+  Admin,
+}
+
+/**
+ * Personal API token for bots and integrations.
+ *
+ * Unlike a session, an API token is limited to its scopes and can be revoked
+ * on its own. It is shown to the user once at creation; afterwards requests
+ * are matched against its sha256 hash, and `last_used` is bumped.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiToken {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub user_id: i64,
+  /// Set by the user, eg the name of the bot
+  pub name: String,
+  pub scopes: Vec<String>,
+  pub token_encrypted: String,
+  pub published: chrono::NaiveDateTime,
+  pub expires: Option<chrono::NaiveDateTime>,
+  pub last_used: Option<chrono::NaiveDateTime>,
+}
+
+impl Node for ApiToken {
+  fn db_type_name() -> &'static str {
+    ApiToken::GDB_TYPE
+  }
+}
+
+impl ApiToken {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "ApiToken";
+
+  /**
+   * Create a new API token for user.
+   *
+   * @return (node, token) : the token itself is only returned here
+   */
+  pub async fn create_for_user(
+    conn: &dgraph::Client,
+    for_user_id: i64,
+    name: &str,
+    scopes: &[ApiScope],
+    expires: Option<chrono::NaiveDateTime>,
+  ) -> Result<(Self, String), Error> {
+    let token = format!("{}{}", API_TOKEN_PREFIX, generate_random_string());
+
+    let mut node = ApiToken {
+      id: 0,
+      user_id: for_user_id,
+      name: name.to_owned(),
+      scopes: scopes.iter().map(|s| s.to_string()).collect(),
+      token_encrypted: sha256_hex(&token),
+      published: chrono::Utc::now().naive_utc(),
+      expires,
+      last_used: None,
+    };
+
+    create_node::<ApiToken>(conn, &mut node).await?;
+    Ok((node, token))
+  }
+
+  /**
+   * Read an unexpired token and record that it was used
+   */
+  pub async fn use_token(conn: &dgraph::Client, token: &str) -> Result<Self, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "tokenEncrypted");
+    let pred_repr = format!("{:?}", sha256_hex(token));
+
+    let node = find_node::<ApiToken>(conn, &pred_name, &pred_repr).await?;
+
+    let now = chrono::Utc::now().naive_utc();
+    if node.is_expired_at(now) {
+      failure::bail!("API token expired");
+    }
+
+    let mut dict = serde_json::Map::new();
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "lastUsed"),
+      serde_json::to_value(now)?,
+    );
+    update_node_dict::<Self>(conn, node.id, &serde_json::Value::Object(dict)).await
+  }
+
+  /**
+   * List the tokens of user
+   */
+  pub async fn list_for_user(conn: &dgraph::Client, for_user_id: i64) -> Result<Vec<Self>, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "userId");
+    find_nodes::<ApiToken>(conn, &pred_name, &for_user_id.to_string()).await
+  }
+
+  /**
+   * Revoke a token of user
+   */
+  pub async fn revoke(conn: &dgraph::Client, for_user_id: i64, token_id: i64) -> Result<usize, Error> {
+    let token = read_node::<ApiToken>(conn, token_id).await?;
+    if token.user_id != for_user_id {
+      failure::bail!("API token belongs to another user");
+    }
+    delete_node(conn, token.id).await
+  }
+
+  /**
+   * Revoke all tokens of user
+   */
+  pub async fn delete_for_user(conn: &dgraph::Client, for_user_id: i64) -> Result<usize, Error> {
+    let tokens = Self::list_for_user(conn, for_user_id).await?;
+    for token in &tokens {
+      delete_node(conn, token.id).await?;
+    }
+    Ok(tokens.len())
+  }
+
+  pub fn is_expired_at(&self, now: chrono::NaiveDateTime) -> bool {
+    self.expires.map_or(false, |expires| expires <= now)
+  }
+
+  /// Scopes of the token, unknown ones are ignored
+  pub fn api_scopes(&self) -> Vec<ApiScope> {
+    self
+      .scopes
+      .iter()
+      .filter_map(|s| ApiScope::from_str(s).ok())
+      .collect()
+  }
+}
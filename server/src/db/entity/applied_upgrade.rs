@@ -0,0 +1,60 @@
+use crate::db::*;
+
+/**
+ * Data migration that ran on this graph, for the steps of bootstrap::upgrade
+ * that must only happen once, like seeding data admins may delete later.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct AppliedUpgrade {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub name: String,
+  pub applied: chrono::NaiveDateTime,
+}
+
+impl Node for AppliedUpgrade {
+  fn db_type_name() -> &'static str {
+    AppliedUpgrade::GDB_TYPE
+  }
+}
+
+impl AppliedUpgrade {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "AppliedUpgrade";
+
+  fn pred(field: &str) -> String {
+    format!("{}.{}", Self::GDB_TYPE, field)
+  }
+
+  /**
+   * Query block `done` of an upsert, empty until upgrade name was applied.
+   * Condition the upgrade's mutations on `eq(len(done), 0)` and add
+   * mark_applied, so replicas starting together apply it once.
+   */
+  pub fn done_query(name: &str) -> String {
+    format!(
+      "done as done(func: eq({name_pred}, {name:?})) {{ uid }}",
+      name_pred = Self::pred("name"),
+      name = name,
+    )
+  }
+
+  /// Mutation recording that upgrade name was applied, see done_query
+  pub fn mark_applied(name: &str) -> Mutation {
+    let mut mu = Mutation::new();
+    mu.set_cond("@if(eq(len(done), 0))");
+    mu.set_set_nquads(format!(
+      "_:upgrade <dgraph.type> \"{t}\" .\n_:upgrade <{name_pred}> {name:?} .\n_:upgrade <{applied_pred}> \"{applied}\" .",
+      t = Self::GDB_TYPE,
+      name_pred = Self::pred("name"),
+      name = name,
+      applied_pred = Self::pred("applied"),
+      applied = chrono::Utc::now().naive_utc().format("%Y-%m-%dT%H:%M:%S"),
+    ));
+    mu
+  }
+}
@@ -0,0 +1,229 @@
+use crate::db::*;
+
+/**
+ * Edge <User> <User.blocksUser> <User>
+ *
+ * Content of the blocked user is hidden from the blocking user, and the
+ * blocked user can't send them private messages or mention them.
+ */
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserBlock {
+  #[serde(skip)]
+  pub user_id: i64,
+  #[serde(skip)]
+  pub target_id: i64,
+  pub published: chrono::NaiveDateTime,
+}
+
+#[derive(Clone, Debug)]
+pub struct UserBlockForm {
+  pub user_id: i64,
+  pub target_id: i64,
+}
+
+impl Edge for UserBlock {
+  fn from(&self) -> i64 {
+    self.user_id
+  }
+  fn to(&self) -> i64 {
+    self.target_id
+  }
+  fn db_type_name() -> &'static str {
+    "User.blocksUser"
+  }
+}
+
+impl From<UserBlockForm> for UserBlock {
+  fn from(form: UserBlockForm) -> Self {
+    UserBlock {
+      user_id: form.user_id,
+      target_id: form.target_id,
+      published: chrono::Utc::now().naive_utc(),
+    }
+  }
+}
+
+impl UserBlock {
+  pub async fn block(conn: &dgraph::Client, form: &UserBlockForm) -> Result<Self, Error> {
+    let edge: Self = form.to_owned().into();
+    create_edge::<UserBlock, UserBlockForm>(conn, &edge).await?;
+    Ok(edge)
+  }
+
+  pub async fn unblock(conn: &dgraph::Client, form: &UserBlockForm) -> Result<usize, Error> {
+    delete_edges(
+      conn,
+      Some(form.user_id),
+      Some(form.target_id),
+      Some(Self::db_type_name()),
+    )
+    .await
+  }
+
+  /// Whether user_id blocked target_id
+  pub async fn is_blocked(
+    conn: &dgraph::Client,
+    user_id: i64,
+    target_id: i64,
+  ) -> Result<bool, Error> {
+    let blocked = blocked_ids(conn, user_id, Self::db_type_name()).await?;
+    Ok(blocked.contains(&target_id))
+  }
+}
+
+//#############################################################################
+
+/**
+ * Edge <User> <User.blocksCommunity> <Community>
+ *
+ * Posts and comments in the community are hidden from the blocking user.
+ */
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommunityBlock {
+  #[serde(skip)]
+  pub user_id: i64,
+  #[serde(skip)]
+  pub community_id: i64,
+  pub published: chrono::NaiveDateTime,
+}
+
+#[derive(Clone, Debug)]
+pub struct CommunityBlockForm {
+  pub user_id: i64,
+  pub community_id: i64,
+}
+
+impl Edge for CommunityBlock {
+  fn from(&self) -> i64 {
+    self.user_id
+  }
+  fn to(&self) -> i64 {
+    self.community_id
+  }
+  fn db_type_name() -> &'static str {
+    "User.blocksCommunity"
+  }
+}
+
+impl From<CommunityBlockForm> for CommunityBlock {
+  fn from(form: CommunityBlockForm) -> Self {
+    CommunityBlock {
+      user_id: form.user_id,
+      community_id: form.community_id,
+      published: chrono::Utc::now().naive_utc(),
+    }
+  }
+}
+
+impl CommunityBlock {
+  pub async fn block(conn: &dgraph::Client, form: &CommunityBlockForm) -> Result<Self, Error> {
+    let edge: Self = form.to_owned().into();
+    create_edge::<CommunityBlock, CommunityBlockForm>(conn, &edge).await?;
+    Ok(edge)
+  }
+
+  pub async fn unblock(conn: &dgraph::Client, form: &CommunityBlockForm) -> Result<usize, Error> {
+    delete_edges(
+      conn,
+      Some(form.user_id),
+      Some(form.community_id),
+      Some(Self::db_type_name()),
+    )
+    .await
+  }
+}
+
+//#############################################################################
+
+/**
+ * Users and communities blocked by a user, for filtering listings.
+ */
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockList {
+  pub user_ids: Vec<i64>,
+  pub community_ids: Vec<i64>,
+}
+
+impl BlockList {
+  pub async fn for_user(conn: &dgraph::Client, for_user_id: i64) -> Result<Self, Error> {
+    Ok(BlockList {
+      user_ids: blocked_ids(conn, for_user_id, UserBlock::db_type_name()).await?,
+      community_ids: blocked_ids(conn, for_user_id, CommunityBlock::db_type_name()).await?,
+    })
+  }
+
+  /// Whether content by creator_id in community_id is hidden
+  pub fn hides(&self, creator_id: i64, community_id: i64) -> bool {
+    self.user_ids.contains(&creator_id) || self.community_ids.contains(&community_id)
+  }
+
+  /// Blocked user ids, with the id type of the views
+  pub fn view_user_ids(&self) -> Vec<i32> {
+    self.user_ids.iter().map(|&id| id as i32).collect()
+  }
+
+  /// Blocked community ids, with the id type of the views
+  pub fn view_community_ids(&self) -> Vec<i32> {
+    self.community_ids.iter().map(|&id| id as i32).collect()
+  }
+}
+
+#[derive(Debug, Deserialize)]
+struct BlockTarget {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  uid: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlockTargets {
+  #[serde(default)]
+  targets: Vec<BlockTarget>,
+}
+
+/// Uids at the end of the block edges pred of user
+async fn blocked_ids(conn: &dgraph::Client, for_user_id: i64, pred: &str) -> Result<Vec<i64>, Error> {
+  let q = format!(
+    r#"
+    nodeList(func: uid({user_id})) {{
+      targets: {pred} {{
+        uid
+      }}
+    }}"#,
+    user_id = for_user_id,
+    pred = pred
+  );
+
+  let txn = conn.new_read_only_txn();
+  let resp = txn.query(q).await?;
+
+  let users: NodeList<BlockTargets> = resp.try_into()?;
+  Ok(
+    users
+      .all
+      .into_iter()
+      .flat_map(|user| user.targets)
+      .map(|target| target.uid)
+      .collect(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_hides() {
+    let blocks = BlockList {
+      user_ids: vec![2, 3],
+      community_ids: vec![10],
+    };
+
+    assert!(blocks.hides(2, 11));
+    assert!(blocks.hides(4, 10));
+    assert!(!blocks.hides(4, 11));
+    assert!(!BlockList::default().hides(2, 10));
+    assert_eq!(blocks.view_user_ids(), vec![2, 3]);
+  }
+}
@@ -1,3 +1,4 @@
+use super::aggregates::*;
 use super::post::Post;
 use crate::db::*;
 
@@ -15,6 +16,9 @@ pub struct Comment {
   pub parent_id: Option<i32>,
   pub content: String,
   pub removed: bool,
+  /// Matched a `Hold` content filter, hidden until a mod approves or removes it
+  #[serde(default)]
+  pub held: bool,
   pub read: bool,
   pub published: chrono::NaiveDateTime,
   pub updated: Option<chrono::NaiveDateTime>,
@@ -29,6 +33,7 @@ pub struct CommentForm {
   pub parent_id: Option<i32>,
   pub content: String,
   pub removed: Option<bool>,
+  pub held: Option<bool>,
   pub read: Option<bool>,
   pub updated: Option<chrono::NaiveDateTime>,
   pub deleted: Option<bool>,
@@ -37,6 +42,72 @@ pub struct CommentForm {
 impl Comment {
   /// Dgraph type
   const GDB_TYPE: &'static str = "Comment";
+
+  /**
+   * Create comment, counting it for its post, community, creator and the
+   * site, and making it the latest activity on the post. Held or removed
+   * comments are neither counted nor activity.
+   */
+  pub async fn create(conn: &dgraph::Client, form: &CommentForm) -> Result<Self, Error> {
+    let post = Post::read(conn, form.post_id).await?;
+    let mut comment: Comment = form.to_owned().into();
+    let mut nodes = vec![new_node_json(&comment, &CommentAggregates::default())?];
+    let changes = if counted(comment.removed || comment.held, comment.deleted) {
+      nodes.push(serde_json::json!({
+        "uid": format!("{:#x}", post.id),
+        (PostAggregates::NEWEST_ACTIVITY_TIME): comment.published,
+      }));
+      CountChange::comment_count(&comment, post.community_id, 1)
+    } else {
+      Vec::new()
+    };
+
+    let mut mu = Mutation::new();
+    mu.set_set_json(&nodes)?;
+    let uids = mutate_with_counts(conn, mu, &changes).await?;
+    comment.id = created_uid(&uids)?;
+    Ok(comment)
+  }
+
+  /**
+   * Read comment by id
+   */
+  pub async fn read(conn: &dgraph::Client, id: i64) -> Result<Self, Error> {
+    read_node::<Comment>(conn, id).await
+  }
+
+  /**
+   * Update comment. Deleting, removing, holding or restoring it updates the counts.
+   */
+  pub async fn update(conn: &dgraph::Client, id: i64, form: &CommentForm) -> Result<Self, Error> {
+    transact_with_counts(conn, |txn| {
+      let form = form.to_owned();
+      Box::pin(async move {
+        let orig = read_node_in_txn::<Comment>(txn, id).await?;
+        let removed = form.removed.unwrap_or(orig.removed);
+        let held = form.held.unwrap_or(orig.held);
+        let deleted = form.deleted.unwrap_or(orig.deleted);
+        let post = read_node_in_txn::<Post>(txn, orig.post_id).await?;
+        let changes = CountChange::visibility(
+          (orig.removed || orig.held, orig.deleted),
+          (removed || held, deleted),
+          |delta| CountChange::comment_count(&orig, post.community_id, delta),
+        );
+
+        // Always writing the flags makes concurrent updates conflict
+        let form = CommentForm {
+          removed: Some(removed),
+          held: Some(held),
+          deleted: Some(deleted),
+          ..form
+        };
+        let mu = update_mutation::<Comment, CommentForm>(id, &form)?;
+        Ok(TxnWrites::new((), mu, changes))
+      })
+    })
+    .await?;
+    Self::read(conn, id).await
+  }
 }
 
 impl Node for Comment {
@@ -55,6 +126,7 @@ impl From<CommentForm> for Comment {
         parent_id: form.parent_id,
         content: form.content,
         removed: form.removed.unwrap_or(false),
+        held: form.held.unwrap_or(false),
         read: form.read.unwrap_or(false),
         updated: form.updated,
         deleted: form.deleted.unwrap_or(false),
@@ -158,7 +230,56 @@ impl CommentLike {
   }
 }
 
-// impl CrudEdge<CommentLikeForm> for CommentLike {}
+impl CommentLike {
+  /**
+   * Vote on comment, replacing an earlier vote of the user
+   */
+  pub async fn like(conn: &dgraph::Client, form: &CommentLikeForm) -> Result<Self, Error> {
+    transact_with_counts(conn, |txn| {
+      let form = form.to_owned();
+      Box::pin(async move {
+        let comment = read_node_in_txn::<Comment>(txn, form.comment_id).await?;
+        let old_score = vote_score(txn, form.comment_id, Self::GDB_TYPE, form.user_id)
+          .await?
+          .unwrap_or(0);
+        let like: Self = form.into();
+
+        let mut mu = Mutation::new();
+        mu.set_set_nquads(edge_nquad(&like)?);
+        let changes = CountChange::comment_vote(&comment, old_score, like.score);
+        Ok(TxnWrites::new(like, mu, changes))
+      })
+    })
+    .await
+  }
+
+  /**
+   * Take back the vote of the user on comment, if there is one
+   */
+  pub async fn remove(conn: &dgraph::Client, form: &CommentLikeForm) -> Result<usize, Error> {
+    transact_with_counts(conn, |txn| {
+      let form = form.to_owned();
+      Box::pin(async move {
+        let old_score = vote_score(txn, form.comment_id, Self::GDB_TYPE, form.user_id).await?;
+        let old_score = match old_score {
+          Some(score) => score,
+          None => return Ok(TxnWrites::none(0)),
+        };
+        let comment = read_node_in_txn::<Comment>(txn, form.comment_id).await?;
+
+        let mut mu = Mutation::new();
+        mu.set_delete_nquads(format!(
+          "uid({}) <{}> uid({}) .",
+          form.comment_id,
+          Self::GDB_TYPE,
+          form.user_id
+        ));
+        Ok(TxnWrites::new(1, mu, CountChange::comment_vote(&comment, old_score, 0)))
+      })
+    })
+    .await
+  }
+}
 
 
 // ############################################################################
@@ -1,3 +1,4 @@
+use super::aggregates::*;
 use crate::db::*;
 
 #[derive(PartialEq, Debug, Serialize, Deserialize, Setters, CopyGetters)]
@@ -91,6 +92,53 @@ impl Community {
     Ok(res)
   }
 
+  /**
+   * Create community, counting it for the site
+   */
+  pub async fn create(conn: &dgraph::Client, form: &CommunityForm) -> Result<Self, Error> {
+    let mut community: Community = form.to_owned().into();
+    let changes = if counted(community.removed, community.deleted) {
+      CountChange::community_count(1)
+    } else {
+      Vec::new()
+    };
+
+    let mut mu = Mutation::new();
+    mu.set_set_json(&new_node_json(&community, &CommunityAggregates::default())?)?;
+    let uids = mutate_with_counts(conn, mu, &changes).await?;
+    community.id = created_uid(&uids)?;
+    Ok(community)
+  }
+
+  /// Mark community as removed (or restore it)
+  pub async fn update_removed(
+    conn: &dgraph::Client,
+    community_id: i64,
+    removed: bool,
+  ) -> Result<Self, Error> {
+    transact_with_counts(conn, |txn| {
+      Box::pin(async move {
+        let orig = read_node_in_txn::<Self>(txn, community_id).await?;
+        let changes = CountChange::visibility(
+          (orig.removed, orig.deleted),
+          (removed, orig.deleted),
+          CountChange::community_count,
+        );
+
+        let mut dict = serde_json::Map::new();
+        let pred_name = format!("{}.{}", Self::GDB_TYPE, "removed");
+        dict.insert(pred_name, serde_json::Value::Bool(removed));
+        dict.insert("uid".into(), format!("{:#x}", community_id).into());
+
+        let mut mu = Mutation::new();
+        mu.set_set_json(&dict)?;
+        Ok(TxnWrites::new((), mu, changes))
+      })
+    })
+    .await?;
+    read_node::<Self>(conn, community_id).await
+  }
+
   pub fn get_url(&self) -> String {
     format!("https://{}/c/{}", Settings::get().hostname, self.name)
   }
@@ -146,6 +194,33 @@ impl From<CommunityModeratorForm> for CommunityModerator {
 }
 
 impl CommunityModerator {
+  /**
+   * Whether user moderates community.
+   */
+  pub async fn is_moderator(
+    conn: &dgraph::Client,
+    for_community_id: i64,
+    for_user_id: i64,
+  ) -> Result<bool, Error> {
+    let q = format!(
+      r#"
+      nodeList(func: uid({community_id})) {{
+        moderators: {pred} @filter(uid({user_id})) {{
+          uid
+        }}
+      }}"#,
+      community_id = for_community_id,
+      pred = Self::db_type_name(),
+      user_id = for_user_id
+    );
+
+    let txn = conn.new_read_only_txn();
+    let resp = txn.query(q).await?;
+
+    let communities: NodeList<ModeratorMatch> = resp.try_into()?;
+    Ok(communities.all.iter().any(|c| !c.moderators.is_empty()))
+  }
+
   /**
    * Delete all moderators for community.
    */
@@ -164,6 +239,12 @@ impl CommunityModerator {
   }
 }
 
+#[derive(Debug, Deserialize)]
+struct ModeratorMatch {
+  #[serde(default)]
+  moderators: Vec<serde_json::Value>,
+}
+
 // impl CrudEdge<CommunityModeratorForm> for CommunityModerator {}
 
 //#############################################################################
@@ -206,7 +287,33 @@ impl From<CommunityUserBanForm> for CommunityUserBan {
   }
 }
 
-// impl CrudEdge<CommunityUserBanForm> for CommunityUserBan {}
+impl CommunityUserBan {
+  pub async fn ban(conn: &dgraph::Client, form: &CommunityUserBanForm) -> Result<Self, Error> {
+    let edge: Self = form.to_owned().into();
+    create_edge::<CommunityUserBan, CommunityUserBanForm>(conn, &edge).await?;
+    Ok(edge)
+  }
+
+  pub async fn unban(conn: &dgraph::Client, form: &CommunityUserBanForm) -> Result<usize, Error> {
+    delete_edges(
+      conn,
+      Some(form.community_id),
+      Some(form.user_id),
+      Some(Self::db_type_name()),
+    )
+    .await
+  }
+
+  /// Whether user_id is banned from community_id
+  pub async fn is_banned(
+    conn: &dgraph::Client,
+    community_id: i64,
+    user_id: i64,
+  ) -> Result<bool, Error> {
+    let mut txn = conn.new_read_only_txn();
+    edge_exists(&mut txn, community_id, Self::db_type_name(), user_id).await
+  }
+}
 
 //#############################################################################
 
@@ -250,4 +357,50 @@ impl From<CommunityFollowerForm> for CommunityFollower {
   }
 }
 
-// impl CrudEdge<CommunityFollowerForm> for CommunityFollower {}
+impl CommunityFollower {
+  /**
+   * Follow community, counting the user as a subscriber
+   */
+  pub async fn follow(conn: &dgraph::Client, form: &CommunityFollowerForm) -> Result<Self, Error> {
+    transact_with_counts(conn, |txn| {
+      let form = form.to_owned();
+      Box::pin(async move {
+        let follower: Self = form.to_owned().into();
+        if edge_exists(txn, form.community_id, Self::db_type_name(), form.user_id).await? {
+          return Ok(TxnWrites::none(follower));
+        }
+
+        let mut mu = Mutation::new();
+        mu.set_set_nquads(edge_nquad(&follower)?);
+        let changes = CountChange::subscriber_count(form.community_id, 1);
+        Ok(TxnWrites::new(follower, mu, changes))
+      })
+    })
+    .await
+  }
+
+  /**
+   * Stop following community
+   */
+  pub async fn ignore(conn: &dgraph::Client, form: &CommunityFollowerForm) -> Result<usize, Error> {
+    transact_with_counts(conn, |txn| {
+      let form = form.to_owned();
+      Box::pin(async move {
+        if !edge_exists(txn, form.community_id, Self::db_type_name(), form.user_id).await? {
+          return Ok(TxnWrites::none(0));
+        }
+
+        let mut mu = Mutation::new();
+        mu.set_delete_nquads(format!(
+          "uid({}) <{}> uid({}) .",
+          form.community_id,
+          Self::db_type_name(),
+          form.user_id
+        ));
+        let changes = CountChange::subscriber_count(form.community_id, -1);
+        Ok(TxnWrites::new(1, mu, changes))
+      })
+    })
+    .await
+  }
+}
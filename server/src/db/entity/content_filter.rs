@@ -0,0 +1,235 @@
+use super::aggregates::new_node_json;
+use super::applied_upgrade::AppliedUpgrade;
+use crate::db::*;
+
+/// Kind of text a content filter looks at
+#[derive(
+  EnumString, ToString, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+  juniper::GraphQLEnum,
+)]
+pub enum FilterTarget {
+  Username,
+  Title,
+  Body,
+  CommunityName,
+}
+
+/// What happens to text a filter matches. Ordered by severity: when several rules match,
+/// the strictest action wins.
+#[derive(
+  EnumString, ToString, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+  juniper::GraphQLEnum,
+)]
+pub enum FilterAction {
+  /// Replace the matched text and let it through
+  Replace,
+  /// Let it through, but held for the mods until one approves or removes it
+  Hold,
+  /// Refuse the text, naming the matches
+  Reject,
+}
+
+/**
+ * Admin-managed rule that rejects, rewrites or holds back matching text.
+ *
+ * Rules without a community apply to the whole site, the others only to content
+ * posted in that community. See crate::content_filter for the compiled matcher.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentFilter {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  /// A word matched on word boundaries, or a regex if `is_regex` is set
+  pub pattern: String,
+  pub is_regex: bool,
+  pub community_id: Option<i64>,
+  pub targets: Vec<FilterTarget>,
+  pub action: FilterAction,
+  /// Text put in place of matches by `Replace` rules, defaults to DEFAULT_REPLACEMENT
+  pub replacement: Option<String>,
+  /// None for the default rules, see seed_defaults
+  pub creator_id: Option<i64>,
+  pub published: chrono::NaiveDateTime,
+  pub updated: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ContentFilterForm {
+  pub pattern: String,
+  pub is_regex: bool,
+  pub community_id: Option<i64>,
+  pub targets: Vec<FilterTarget>,
+  pub action: FilterAction,
+  pub replacement: Option<String>,
+}
+
+impl Node for ContentFilter {
+  fn db_type_name() -> &'static str {
+    ContentFilter::GDB_TYPE
+  }
+}
+
+impl ContentFilter {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "ContentFilter";
+
+  pub const DEFAULT_REPLACEMENT: &'static str = "*removed*";
+
+  /// Name of the upgrade that seeds the default rules
+  const SEED_UPGRADE: &'static str = "seed_content_filters";
+
+  fn from_form(creator_id: Option<i64>, form: &ContentFilterForm) -> Self {
+    ContentFilter {
+      id: 0,
+      pattern: form.pattern.to_owned(),
+      is_regex: form.is_regex,
+      community_id: form.community_id,
+      targets: form.targets.to_owned(),
+      action: form.action,
+      replacement: form.replacement.to_owned(),
+      creator_id,
+      published: chrono::Utc::now().naive_utc(),
+      updated: None,
+    }
+  }
+
+  /**
+   * Create filter rule
+   */
+  pub async fn create(
+    conn: &dgraph::Client,
+    creator_id: i64,
+    form: &ContentFilterForm,
+  ) -> Result<Self, Error> {
+    let mut node = Self::from_form(Some(creator_id), form);
+    create_node::<ContentFilter>(conn, &mut node).await?;
+    Ok(node)
+  }
+
+  /**
+   * Create the rules a new instance starts with, once per graph: after admins
+   * deleted them, they don't come back. Graphs that already have rules only
+   * record that the defaults were seeded.
+   *
+   * @return number of created rules
+   */
+  pub async fn seed_defaults(
+    conn: &dgraph::Client,
+    forms: &[ContentFilterForm],
+  ) -> Result<usize, Error> {
+    #[derive(Deserialize)]
+    struct Seeded {
+      done: Vec<serde_json::Value>,
+      existing: Vec<serde_json::Value>,
+    }
+
+    let q = format!(
+      r#"query {{
+        {done}
+        existing as existing(func: type({t}), first: 1) {{ uid }}
+      }}"#,
+      done = AppliedUpgrade::done_query(Self::SEED_UPGRADE),
+      t = Self::GDB_TYPE,
+    );
+
+    let mut nodes = Vec::new();
+    for (i, form) in forms.iter().enumerate() {
+      let mut node = new_node_json(&Self::from_form(None, form), &serde_json::Map::new())?;
+      node["uid"] = format!("_:filter{}", i).into();
+      nodes.push(node);
+    }
+    let mut create = Mutation::new();
+    create.set_cond("@if(eq(len(done), 0) AND eq(len(existing), 0))");
+    create.set_set_json(&nodes)?;
+
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn
+      .upsert(q, vec![create, AppliedUpgrade::mark_applied(Self::SEED_UPGRADE)])
+      .await?;
+    let seeded: Seeded = resp.try_into()?;
+    txn.commit().await?;
+
+    if seeded.done.is_empty() && seeded.existing.is_empty() {
+      Ok(forms.len())
+    } else {
+      Ok(0)
+    }
+  }
+
+  /**
+   * Read filter rule by id
+   */
+  pub async fn read(conn: &dgraph::Client, id: i64) -> Result<Self, Error> {
+    read_node::<ContentFilter>(conn, id).await
+  }
+
+  /**
+   * List all filter rules, oldest first
+   */
+  pub async fn list_all(conn: &dgraph::Client) -> Result<Vec<Self>, Error> {
+    let mut filters = list_nodes::<ContentFilter>(conn).await?;
+    filters.sort_by_key(|f| f.published);
+    Ok(filters)
+  }
+
+  /**
+   * Replace pattern, scope, targets and action of a filter rule
+   */
+  pub async fn update(
+    conn: &dgraph::Client,
+    id: i64,
+    form: &ContentFilterForm,
+  ) -> Result<Self, Error> {
+    let mut dict = serde_json::Map::new();
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "pattern"),
+      serde_json::Value::from(form.pattern.to_owned()),
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "isRegex"),
+      serde_json::Value::Bool(form.is_regex),
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "communityId"),
+      form.community_id.map_or(serde_json::Value::Null, serde_json::Value::from),
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "targets"),
+      serde_json::to_value(&form.targets)?,
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "action"),
+      serde_json::to_value(form.action)?,
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "replacement"),
+      form
+        .replacement
+        .to_owned()
+        .map_or(serde_json::Value::Null, serde_json::Value::from),
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "updated"),
+      serde_json::to_value(chrono::Utc::now().naive_utc())?,
+    );
+
+    update_node_dict::<Self>(conn, id, &serde_json::Value::Object(dict)).await
+  }
+
+  /**
+   * Delete filter rule
+   */
+  pub async fn delete(conn: &dgraph::Client, id: i64) -> Result<usize, Error> {
+    delete_node(conn, id).await
+  }
+
+  /// Whether the rule looks at this kind of text in this community
+  pub fn applies_to(&self, target: FilterTarget, community_id: Option<i64>) -> bool {
+    self.targets.contains(&target)
+      && (self.community_id.is_none() || self.community_id == community_id)
+  }
+}
@@ -0,0 +1,239 @@
+use crate::db::*;
+use crate::{generate_random_string, sha256_hex};
+
+#[derive(EnumString, ToString, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum DataExportStatus {
+  /// Waiting for the build_data_exports job
+  Pending,
+  Ready,
+  Failed,
+}
+
+/**
+ * Archive of a user's personal data.
+ *
+ * The user requests an export, the build_data_exports job writes the archive
+ * to a file in `data_export.directory`. The user then fetches single-use
+ * download links for it until it expires after `data_export.expiry_hours`. A
+ * user can request one export every `data_export.interval_hours`.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct DataExport {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub user_id: i64,
+  pub status: DataExportStatus,
+  /// sha256 hash of the current download token, removed once it is used
+  pub token_encrypted: Option<String>,
+  /// Name of the archive in the export directory
+  pub file_name: String,
+  pub published: chrono::NaiveDateTime,
+}
+
+impl Node for DataExport {
+  fn db_type_name() -> &'static str {
+    DataExport::GDB_TYPE
+  }
+}
+
+impl DataExport {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "DataExport";
+
+  fn pred(field: &str) -> String {
+    format!("{}.{}", Self::GDB_TYPE, field)
+  }
+
+  /**
+   * Request an export of user, to be stored as file_name.
+   * Fails if the previous export is younger than `data_export.interval_hours`.
+   */
+  pub async fn create_for_user(
+    conn: &dgraph::Client,
+    for_user_id: i64,
+    file_name: &str,
+  ) -> Result<Self, Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let interval = chrono::Duration::hours(Settings::get().data_export.interval_hours);
+
+    let previous = Self::list_for_user(conn, for_user_id).await?;
+    if previous.iter().any(|e| e.is_rate_limited_at(now, interval)) {
+      failure::bail!("data_export_limit_reached");
+    }
+
+    let mut node = DataExport {
+      id: 0,
+      user_id: for_user_id,
+      status: DataExportStatus::Pending,
+      token_encrypted: None,
+      file_name: file_name.to_owned(),
+      published: now,
+    };
+
+    create_node::<DataExport>(conn, &mut node).await?;
+    Ok(node)
+  }
+
+  /**
+   * Exports waiting for their archive
+   */
+  pub async fn list_pending(conn: &dgraph::Client) -> Result<Vec<Self>, Error> {
+    let pred_repr = format!("{:?}", DataExportStatus::Pending.to_string());
+    find_nodes::<DataExport>(conn, &Self::pred("status"), &pred_repr).await
+  }
+
+  pub async fn update_status(
+    conn: &dgraph::Client,
+    export_id: i64,
+    status: DataExportStatus,
+  ) -> Result<Self, Error> {
+    let mut dict = serde_json::Map::new();
+    dict.insert(Self::pred("status"), serde_json::Value::from(status.to_string()));
+    update_node_dict::<Self>(conn, export_id, &serde_json::Value::Object(dict)).await
+  }
+
+  /**
+   * New download token for the ready export, replacing the previous one.
+   *
+   * @return the token, it is only returned here
+   */
+  pub async fn issue_download_token(conn: &dgraph::Client, export_id: i64) -> Result<String, Error> {
+    let token = generate_random_string();
+    let mut dict = serde_json::Map::new();
+    dict.insert(Self::pred("tokenEncrypted"), serde_json::Value::from(sha256_hex(&token)));
+    update_node_dict::<Self>(conn, export_id, &serde_json::Value::Object(dict)).await?;
+    Ok(token)
+  }
+
+  /**
+   * Read the unexpired export of a download token and remove the token, so
+   * the link only works once.
+   */
+  pub async fn use_download_token(conn: &dgraph::Client, token: &str) -> Result<Self, Error> {
+    #[derive(Deserialize)]
+    struct Found {
+      export: Vec<DataExport>,
+    }
+
+    let q = format!(
+      r#"query {{
+        export as export(func: eq({token_pred}, {hash:?})) @filter(type({type_name})) {{
+          uid
+          {type_name}.userId
+          {type_name}.status
+          {type_name}.fileName
+          {type_name}.published
+        }}
+      }}"#,
+      token_pred = Self::pred("tokenEncrypted"),
+      hash = sha256_hex(token),
+      type_name = Self::GDB_TYPE,
+    );
+    let mut mu = Mutation::new();
+    mu.set_cond("@if(eq(len(export), 1))");
+    mu.set_delete_nquads(format!("uid(export) <{}> * .", Self::pred("tokenEncrypted")));
+
+    // Downloads racing with the same token delete the same predicate, so only
+    // one of them commits
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.upsert(q, vec![mu]).await?;
+    let found: Found = resp.try_into()?;
+    let export = match found.export.into_iter().next() {
+      Some(export) => export,
+      None => failure::bail!("Unknown download token"),
+    };
+    txn.commit().await?;
+
+    if !export.is_ready_at(chrono::Utc::now().naive_utc()) {
+      failure::bail!("Data export expired");
+    }
+    Ok(export)
+  }
+
+  /**
+   * The latest export of user, if there is one
+   */
+  pub async fn latest_for_user(conn: &dgraph::Client, for_user_id: i64) -> Result<Option<Self>, Error> {
+    let exports = Self::list_for_user(conn, for_user_id).await?;
+    Ok(exports.into_iter().max_by_key(|e| e.published))
+  }
+
+  pub async fn list_for_user(conn: &dgraph::Client, for_user_id: i64) -> Result<Vec<Self>, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "userId");
+    find_nodes::<DataExport>(conn, &pred_name, &for_user_id.to_string()).await
+  }
+
+  /**
+   * All expired exports, their archives can be deleted
+   */
+  pub async fn list_expired(conn: &dgraph::Client) -> Result<Vec<Self>, Error> {
+    let expired_before = chrono::Utc::now().naive_utc() - Self::lifetime();
+    let q = format!(
+      r#"query {{
+        nodeList(func: lt({pred}, "{before}")) {{
+          uid
+          expand(_all_)
+        }}
+      }}"#,
+      pred = Self::pred("published"),
+      before = expired_before.format("%Y-%m-%dT%H:%M:%S"),
+    );
+
+    let txn = conn.new_read_only_txn();
+    let resp = txn.query(q).await?;
+    let exports: NodeList<DataExport> = resp.try_into()?;
+    Ok(exports.all)
+  }
+
+  pub async fn delete(conn: &dgraph::Client, export_id: i64) -> Result<usize, Error> {
+    delete_node(conn, export_id).await
+  }
+
+  /// When the download link stops working
+  pub fn expires(&self) -> chrono::NaiveDateTime {
+    self.published + Self::lifetime()
+  }
+
+  /// Whether the archive can be downloaded
+  pub fn is_ready_at(&self, now: chrono::NaiveDateTime) -> bool {
+    self.status == DataExportStatus::Ready && !self.is_expired_at(now, Self::lifetime())
+  }
+
+  fn lifetime() -> chrono::Duration {
+    chrono::Duration::hours(Settings::get().data_export.expiry_hours)
+  }
+}
+
+impl Published for DataExport {
+  fn published(&self) -> chrono::NaiveDateTime {
+    self.published
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn export_with_status(status: DataExportStatus) -> DataExport {
+    DataExport {
+      id: 0,
+      user_id: 1,
+      status,
+      token_encrypted: None,
+      file_name: "1_nope.json".into(),
+      published: chrono::Utc::now().naive_utc(),
+    }
+  }
+
+  #[test]
+  fn test_only_built_exports_are_ready() {
+    let now = chrono::Utc::now().naive_utc();
+
+    assert!(!export_with_status(DataExportStatus::Pending).is_ready_at(now));
+    assert!(!export_with_status(DataExportStatus::Failed).is_ready_at(now));
+  }
+}
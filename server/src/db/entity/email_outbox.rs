@@ -0,0 +1,230 @@
+use super::aggregates::delete_older;
+use crate::db::*;
+
+#[derive(EnumString, ToString, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum EmailStatus {
+  /// Waiting for its next attempt
+  Pending,
+  /// Handed to the transport. Stays so if recording the outcome failed, the
+  /// email may have gone out and isn't sent again.
+  Sending,
+  Sent,
+  /// Gave up after too many attempts
+  Failed,
+}
+
+/**
+ * Rendered email waiting to be sent, or the record of one that was.
+ *
+ * Requests only queue emails, the send_emails job delivers them, see
+ * crate::email.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEmail {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub to_email: String,
+  pub to_name: String,
+  pub subject: String,
+  pub text_body: String,
+  pub html_body: String,
+  pub status: EmailStatus,
+  /// Failed attempts so far
+  #[serde(default)]
+  pub attempts: i32,
+  pub next_attempt: chrono::NaiveDateTime,
+  pub last_error: Option<String>,
+  pub published: chrono::NaiveDateTime,
+  pub sent: Option<chrono::NaiveDateTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxEmailForm {
+  pub to_email: String,
+  pub to_name: String,
+  pub subject: String,
+  pub text_body: String,
+  pub html_body: String,
+}
+
+impl From<OutboxEmailForm> for OutboxEmail {
+  fn from(form: OutboxEmailForm) -> Self {
+    let now = chrono::Utc::now().naive_utc();
+    OutboxEmail {
+      id: 0,
+      to_email: form.to_email,
+      to_name: form.to_name,
+      subject: form.subject,
+      text_body: form.text_body,
+      html_body: form.html_body,
+      status: EmailStatus::Pending,
+      attempts: 0,
+      next_attempt: now,
+      last_error: None,
+      published: now,
+      sent: None,
+    }
+  }
+}
+
+impl Node for OutboxEmail {
+  fn db_type_name() -> &'static str {
+    OutboxEmail::GDB_TYPE
+  }
+}
+
+impl OutboxEmail {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "OutboxEmail";
+
+  fn pred(field: &str) -> String {
+    format!("{}.{}", Self::GDB_TYPE, field)
+  }
+
+  /**
+   * Queue an email, due right away
+   */
+  pub async fn create(conn: &dgraph::Client, form: &OutboxEmailForm) -> Result<Self, Error> {
+    let mut node: Self = form.to_owned().into();
+    create_node::<Self>(conn, &mut node).await?;
+    Ok(node)
+  }
+
+  /**
+   * Pending emails due by now, oldest attempt first, at most limit of them
+   */
+  pub async fn list_due(
+    conn: &dgraph::Client,
+    now: chrono::NaiveDateTime,
+    limit: usize,
+  ) -> Result<Vec<Self>, Error> {
+    let q = format!(
+      r#"query {{
+        all(func: eq({status_pred}, "{pending}"), orderasc: {next_pred}, first: {limit})
+          @filter(type({t}) AND le({next_pred}, "{now}")) {{
+          uid
+          expand(_all_)
+        }}
+      }}"#,
+      status_pred = Self::pred("status"),
+      pending = EmailStatus::Pending.to_string(),
+      next_pred = Self::pred("nextAttempt"),
+      t = Self::GDB_TYPE,
+      limit = limit,
+      now = now.format("%Y-%m-%dT%H:%M:%S"),
+    );
+    let resp = conn.new_read_only_txn().query(q).await?;
+    let emails: NodeList<Self> = resp.try_into()?;
+    Ok(emails.all)
+  }
+
+  /**
+   * Take a pending email for an attempt, before handing it to the transport.
+   * Its bodies are cleared right away, they may hold tokens like password
+   * reset links, and an email left sending would keep them. The caller has
+   * them from list_due, mark_attempt_failed writes them back for a retry.
+   *
+   * @return false if it isn't pending anymore
+   */
+  pub async fn mark_sending(conn: &dgraph::Client, id: i64) -> Result<bool, Error> {
+    #[derive(Deserialize)]
+    struct Pending {
+      pending: Vec<serde_json::Value>,
+    }
+
+    let q = format!(
+      r#"query {{
+        pending as pending(func: uid({id})) @filter(eq({status_pred}, "{pending}")) {{
+          uid
+        }}
+      }}"#,
+      id = id,
+      status_pred = Self::pred("status"),
+      pending = EmailStatus::Pending.to_string(),
+    );
+    let mut dict = Self::cleared_bodies();
+    dict.insert("uid".into(), "uid(pending)".into());
+    dict.insert(Self::pred("status"), serde_json::to_value(EmailStatus::Sending)?);
+    let mut update = Mutation::new();
+    update.set_cond("@if(eq(len(pending), 1))");
+    update.set_set_json(&serde_json::Value::Object(dict))?;
+
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.upsert(q, vec![update]).await?;
+    let found: Pending = resp.try_into()?;
+    if found.pending.is_empty() {
+      return Ok(false);
+    }
+    txn.commit().await?;
+    Ok(true)
+  }
+
+  /**
+   * Record that the email went out.
+   */
+  pub async fn mark_sent(conn: &dgraph::Client, id: i64) -> Result<Self, Error> {
+    let mut dict = serde_json::Map::new();
+    dict.insert(Self::pred("status"), serde_json::to_value(EmailStatus::Sent)?);
+    dict.insert(
+      Self::pred("sent"),
+      serde_json::to_value(chrono::Utc::now().naive_utc())?,
+    );
+
+    update_node_dict::<Self>(conn, id, &serde_json::Value::Object(dict)).await
+  }
+
+  /**
+   * Record a failed attempt of email. It is pending again until next_attempt,
+   * with the bodies mark_sending cleared, or fails for good if there is none.
+   */
+  pub async fn mark_attempt_failed(
+    conn: &dgraph::Client,
+    email: &OutboxEmail,
+    attempts: i32,
+    next_attempt: Option<chrono::NaiveDateTime>,
+    error: &str,
+  ) -> Result<Self, Error> {
+    let mut dict = serde_json::Map::new();
+    dict.insert(Self::pred("attempts"), serde_json::Value::from(attempts));
+    dict.insert(Self::pred("lastError"), serde_json::Value::from(error));
+    match next_attempt {
+      Some(next_attempt) => {
+        dict.insert(Self::pred("status"), serde_json::to_value(EmailStatus::Pending)?);
+        dict.insert(Self::pred("nextAttempt"), serde_json::to_value(next_attempt)?);
+        dict.insert(Self::pred("textBody"), serde_json::Value::from(email.text_body.to_owned()));
+        dict.insert(Self::pred("htmlBody"), serde_json::Value::from(email.html_body.to_owned()));
+      }
+      None => {
+        dict.insert(Self::pred("status"), serde_json::to_value(EmailStatus::Failed)?);
+      }
+    }
+
+    update_node_dict::<Self>(conn, email.id, &serde_json::Value::Object(dict)).await
+  }
+
+  /// Empty text and html bodies, for emails being sent
+  fn cleared_bodies() -> serde_json::Map<String, serde_json::Value> {
+    let mut dict = serde_json::Map::new();
+    dict.insert(Self::pred("textBody"), serde_json::Value::from(""));
+    dict.insert(Self::pred("htmlBody"), serde_json::Value::from(""));
+    dict
+  }
+
+  /**
+   * Delete sent and failed emails queued before then, they hold addresses and
+   * subjects. Emails left sending, whose outcome couldn't be recorded, too.
+   *
+   * @return number of deleted emails
+   */
+  pub async fn delete_done_before(
+    conn: &dgraph::Client,
+    before: chrono::NaiveDateTime,
+  ) -> Result<usize, Error> {
+    let not_pending = format!("NOT eq({}, {:?})", Self::pred("status"), EmailStatus::Pending.to_string());
+    delete_older(conn, &Self::pred("published"), before, Some(&not_pending)).await
+  }
+}
@@ -0,0 +1,110 @@
+use super::aggregates::delete_older;
+use crate::db::*;
+use crate::{generate_random_string, sha256_hex};
+
+/**
+ * Pending verification of a user's email address.
+ *
+ * The address is stored with the token, so a link mailed to an old address
+ * can't verify a newer one. Links expire after `email_verification_token_hours`.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailVerification {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub user_id: i64,
+  pub email: String,
+  pub token_encrypted: String,
+  pub published: chrono::NaiveDateTime,
+}
+
+impl Node for EmailVerification {
+  fn db_type_name() -> &'static str {
+    EmailVerification::GDB_TYPE
+  }
+}
+
+impl EmailVerification {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "EmailVerification";
+
+  /**
+   * Create a verification for the email address of user. Earlier pending
+   * verifications of the user are replaced.
+   *
+   * @return (node, token) : the token itself is only returned here
+   */
+  pub async fn create_for_user(
+    conn: &dgraph::Client,
+    for_user_id: i64,
+    email: &str,
+  ) -> Result<(Self, String), Error> {
+    Self::delete_for_user(conn, for_user_id).await?;
+
+    let token = generate_random_string();
+    let mut node = EmailVerification {
+      id: 0,
+      user_id: for_user_id,
+      email: email.to_owned(),
+      token_encrypted: sha256_hex(&token),
+      published: chrono::Utc::now().naive_utc(),
+    };
+
+    create_node::<EmailVerification>(conn, &mut node).await?;
+    Ok((node, token))
+  }
+
+  /**
+   * Read unexpired verification from token
+   */
+  pub async fn read_from_token(conn: &dgraph::Client, token: &str) -> Result<Self, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "tokenEncrypted");
+    let pred_repr = format!("{:?}", sha256_hex(token));
+
+    let verification = find_node::<EmailVerification>(conn, &pred_name, &pred_repr).await?;
+
+    if verification.is_expired_at(chrono::Utc::now().naive_utc(), Self::lifetime()) {
+      delete_node(conn, verification.id).await?;
+      failure::bail!("Email verification token expired");
+    }
+    Ok(verification)
+  }
+
+  /**
+   * Delete all expired verifications.
+   *
+   * @return number of deleted verifications
+   */
+  pub async fn delete_expired(conn: &dgraph::Client) -> Result<usize, Error> {
+    let expired_before = chrono::Utc::now().naive_utc() - Self::lifetime();
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "published");
+    delete_older(conn, &pred_name, expired_before, None).await
+  }
+
+  /**
+   * Delete all pending verifications of user
+   */
+  pub async fn delete_for_user(conn: &dgraph::Client, for_user_id: i64) -> Result<usize, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "userId");
+    let nodes = find_nodes::<EmailVerification>(conn, &pred_name, &for_user_id.to_string()).await?;
+
+    for node in &nodes {
+      delete_node(conn, node.id).await?;
+    }
+    Ok(nodes.len())
+  }
+
+  fn lifetime() -> chrono::Duration {
+    chrono::Duration::hours(Settings::get().email_verification_token_hours)
+  }
+}
+
+impl Published for EmailVerification {
+  fn published(&self) -> chrono::NaiveDateTime {
+    self.published
+  }
+}
@@ -0,0 +1,96 @@
+use crate::db::*;
+
+/**
+ * Account of a user at an OpenID Connect provider, identified by the
+ * provider's `sub` claim. A user can link one account per provider.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalIdentity {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub user_id: i64,
+  /// Name of the provider in the config
+  pub provider: String,
+  pub subject: String,
+  pub published: chrono::NaiveDateTime,
+}
+
+impl Node for ExternalIdentity {
+  fn db_type_name() -> &'static str {
+    ExternalIdentity::GDB_TYPE
+  }
+}
+
+impl ExternalIdentity {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "ExternalIdentity";
+
+  /**
+   * Link the provider account to user
+   */
+  pub async fn create(
+    conn: &dgraph::Client,
+    for_user_id: i64,
+    provider: &str,
+    subject: &str,
+  ) -> Result<Self, Error> {
+    let mut node = ExternalIdentity {
+      id: 0,
+      user_id: for_user_id,
+      provider: provider.to_owned(),
+      subject: subject.to_owned(),
+      published: chrono::Utc::now().naive_utc(),
+    };
+
+    create_node::<ExternalIdentity>(conn, &mut node).await?;
+    Ok(node)
+  }
+
+  /**
+   * Find the identity for a provider account
+   */
+  pub async fn find_by_subject(
+    conn: &dgraph::Client,
+    provider: &str,
+    subject: &str,
+  ) -> Result<Self, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "subject");
+    let pred_repr = format!("{:?}", subject);
+
+    // Subjects are only unique per provider
+    find_nodes::<ExternalIdentity>(conn, &pred_name, &pred_repr)
+      .await?
+      .into_iter()
+      .find(|identity| identity.provider == provider)
+      .ok_or_else(|| format_err!("No {} identity for subject", provider))
+  }
+
+  /**
+   * List the identities linked to user
+   */
+  pub async fn list_for_user(conn: &dgraph::Client, for_user_id: i64) -> Result<Vec<Self>, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "userId");
+    find_nodes::<ExternalIdentity>(conn, &pred_name, &for_user_id.to_string()).await
+  }
+
+  /**
+   * Unlink the provider account from user
+   */
+  pub async fn delete_for_user(
+    conn: &dgraph::Client,
+    for_user_id: i64,
+    provider: &str,
+  ) -> Result<usize, Error> {
+    let mut deleted = 0;
+    for identity in Self::list_for_user(conn, for_user_id).await? {
+      if identity.provider == provider {
+        deleted += delete_node(conn, identity.id).await?;
+      }
+    }
+    Ok(deleted)
+  }
+}
@@ -0,0 +1,152 @@
+use crate::db::*;
+use crate::{generate_random_string, sha256_hex};
+
+/**
+ * Invite code to register on a site with closed registration.
+ *
+ * A code can be used `max_uses` times until it expires. Admins see the code
+ * once when they create it, registrations look it up by its sha256 hash.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteCode {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub creator_id: i64,
+  pub code_encrypted: String,
+  pub max_uses: i32,
+  pub uses: i32,
+  pub published: chrono::NaiveDateTime,
+  pub expires: Option<chrono::NaiveDateTime>,
+}
+
+impl Node for InviteCode {
+  fn db_type_name() -> &'static str {
+    InviteCode::GDB_TYPE
+  }
+}
+
+impl InviteCode {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "InviteCode";
+
+  /**
+   * Create a new invite code.
+   *
+   * @return (node, code) : the code itself is only returned here
+   */
+  pub async fn create_for_user(
+    conn: &dgraph::Client,
+    for_creator_id: i64,
+    max_uses: i32,
+    expires: Option<chrono::NaiveDateTime>,
+  ) -> Result<(Self, String), Error> {
+    let code = generate_random_string();
+
+    let mut node = InviteCode {
+      id: 0,
+      creator_id: for_creator_id,
+      code_encrypted: sha256_hex(&code),
+      max_uses,
+      uses: 0,
+      published: chrono::Utc::now().naive_utc(),
+      expires,
+    };
+
+    create_node::<InviteCode>(conn, &mut node).await?;
+    Ok((node, code))
+  }
+
+  /**
+   * Query blocks and nquads counting a use of the code, for an upsert with
+   * the condition `@if(eq(len(invite), 1))`. The `invite` block only matches
+   * a code that is still valid.
+   *
+   * Registrations racing for the last use both write the count, so only one
+   * of them commits.
+   */
+  pub fn use_upsert(code: &str, now: chrono::NaiveDateTime) -> (String, String) {
+    let uses = format!("{}.{}", Self::GDB_TYPE, "uses");
+    let query = format!(
+      r#"query {{
+        found as var(func: eq({type_name}.codeEncrypted, {hash:?})) @filter(type({type_name})) {{
+          uses as {uses}
+          max_uses as {type_name}.maxUses
+          uses_left as math(max_uses - uses)
+          next_uses as math(uses + 1)
+        }}
+        invite as invite(func: uid(found))
+          @filter(gt(val(uses_left), 0) AND (NOT has({type_name}.expires) OR gt({type_name}.expires, {now:?}))) {{
+          uid
+        }}
+      }}"#,
+      type_name = Self::GDB_TYPE,
+      hash = sha256_hex(code.trim()),
+      uses = uses,
+      now = now.format("%Y-%m-%dT%H:%M:%S").to_string(),
+    );
+    let nquads = format!("uid(invite) <{}> val(next_uses) .", uses);
+    (query, nquads)
+  }
+
+  /**
+   * List the codes created by user
+   */
+  pub async fn list_for_user(conn: &dgraph::Client, for_creator_id: i64) -> Result<Vec<Self>, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "creatorId");
+    find_nodes::<InviteCode>(conn, &pred_name, &for_creator_id.to_string()).await
+  }
+
+  /**
+   * Delete a code created by user
+   */
+  pub async fn delete_for_user(
+    conn: &dgraph::Client,
+    for_creator_id: i64,
+    invite_id: i64,
+  ) -> Result<usize, Error> {
+    let invite = read_node::<InviteCode>(conn, invite_id).await?;
+    if invite.creator_id != for_creator_id {
+      failure::bail!("Invite code belongs to another user");
+    }
+    delete_node(conn, invite.id).await
+  }
+
+  pub fn is_valid_at(&self, now: chrono::NaiveDateTime) -> bool {
+    self.uses < self.max_uses && self.expires.map_or(true, |expires| expires > now)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Duration;
+
+  #[test]
+  fn test_is_valid() {
+    let now = chrono::Utc::now().naive_utc();
+    let mut invite = InviteCode {
+      id: 0,
+      creator_id: 1,
+      code_encrypted: sha256_hex("nope"),
+      max_uses: 2,
+      uses: 1,
+      published: now,
+      expires: None,
+    };
+    assert!(invite.is_valid_at(now));
+
+    invite.uses = 2;
+    assert!(!invite.is_valid_at(now));
+
+    invite.uses = 0;
+    invite.expires = Some(now - Duration::minutes(1));
+    assert!(!invite.is_valid_at(now));
+
+    invite.expires = Some(now + Duration::days(7));
+    assert!(invite.is_valid_at(now));
+  }
+}
@@ -0,0 +1,234 @@
+use crate::db::*;
+
+#[derive(
+  EnumString, ToString, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq,
+  juniper::GraphQLEnum,
+)]
+pub enum JobStatus {
+  Running,
+  Succeeded,
+  Failed,
+}
+
+/**
+ * Schedule state of a background job, one node per job name.
+ *
+ * All server replicas run the scheduler. Before running a job, a replica takes
+ * the lease by writing itself as holder, which only succeeds if the job is due
+ * and nobody else holds an unexpired lease. The holder renews the lease while
+ * the job runs. See crate::scheduler.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct JobLease {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub job_name: String,
+  /// Replica that took the lease last
+  pub holder: Option<String>,
+  pub lease_expires: Option<chrono::NaiveDateTime>,
+  /// The job is due from then on, right away if not set
+  pub next_run: Option<chrono::NaiveDateTime>,
+  pub status: Option<JobStatus>,
+  /// Result or error of the last run
+  pub message: Option<String>,
+  /// Attempts the last run took, including retries
+  #[serde(default)]
+  pub attempts: i32,
+  pub last_started: Option<chrono::NaiveDateTime>,
+  pub last_finished: Option<chrono::NaiveDateTime>,
+}
+
+impl Node for JobLease {
+  fn db_type_name() -> &'static str {
+    JobLease::GDB_TYPE
+  }
+}
+
+impl JobLease {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "JobLease";
+
+  fn pred(field: &str) -> String {
+    format!("{}.{}", Self::GDB_TYPE, field)
+  }
+
+  /**
+   * Read the lease of job, if it ever ran
+   */
+  pub async fn find(conn: &dgraph::Client, job_name: &str) -> Result<Option<Self>, Error> {
+    let pred = Self::pred("jobName");
+    let leases = find_nodes::<JobLease>(conn, &pred, &format!("{:?}", job_name)).await?;
+    Ok(leases.into_iter().next())
+  }
+
+  /**
+   * List leases of all jobs, by name
+   */
+  pub async fn list_all(conn: &dgraph::Client) -> Result<Vec<Self>, Error> {
+    let mut leases = list_nodes::<JobLease>(conn).await?;
+    leases.sort_by(|a, b| a.job_name.cmp(&b.job_name));
+    Ok(leases)
+  }
+
+  /**
+   * Take the lease of job for holder until now + lease_for, if the job is
+   * due and not held by another replica. Creates the lease of a job that never
+   * ran. Returns None if another replica has it or the job isn't due.
+   */
+  pub async fn acquire(
+    conn: &dgraph::Client,
+    job_name: &str,
+    holder: &str,
+    now: chrono::NaiveDateTime,
+    lease_for: chrono::Duration,
+  ) -> Result<Option<Self>, Error> {
+    let lease_expires = now + lease_for;
+    let q = format!(
+      r#"query {{
+        existing as var(func: eq({job_name_pred}, {job_name:?}))
+        free as var(func: uid(existing))
+          @filter((NOT has({expires_pred}) OR le({expires_pred}, "{now}"))
+            AND (NOT has({next_pred}) OR le({next_pred}, "{now}")))
+      }}"#,
+      job_name_pred = Self::pred("jobName"),
+      expires_pred = Self::pred("leaseExpires"),
+      next_pred = Self::pred("nextRun"),
+      job_name = job_name,
+      now = now.format("%Y-%m-%dT%H:%M:%S"),
+    );
+
+    let take = |subject: &str| {
+      format!(
+        "{s} <{holder_pred}> {holder:?} .\n\
+         {s} <{expires_pred}> \"{expires}\" .\n\
+         {s} <{status_pred}> \"{status}\" .\n\
+         {s} <{started_pred}> \"{now}\" .\n",
+        s = subject,
+        holder_pred = Self::pred("holder"),
+        holder = holder,
+        expires_pred = Self::pred("leaseExpires"),
+        expires = lease_expires.format("%Y-%m-%dT%H:%M:%S"),
+        status_pred = Self::pred("status"),
+        status = JobStatus::Running.to_string(),
+        started_pred = Self::pred("lastStarted"),
+        now = now.format("%Y-%m-%dT%H:%M:%S"),
+      )
+    };
+
+    let mut update = Mutation::new();
+    update.set_cond("@if(eq(len(free), 1))");
+    update.set_set_nquads(take("uid(free)"));
+
+    let mut create = Mutation::new();
+    create.set_cond("@if(eq(len(existing), 0))");
+    create.set_set_nquads(format!(
+      "_:lease <dgraph.type> \"{t}\" .\n_:lease <{job_name_pred}> {job_name:?} .\n{rest}",
+      t = Self::GDB_TYPE,
+      job_name_pred = Self::pred("jobName"),
+      job_name = job_name,
+      rest = take("_:lease"),
+    ));
+
+    // Replicas racing for the same lease write the same predicates, so all
+    // but one of them fail to commit
+    let mut txn = conn.new_mutated_txn();
+    txn.upsert(q, vec![update, create]).await?;
+    if txn.commit().await.is_err() {
+      return Ok(None);
+    }
+
+    match Self::find(conn, job_name).await? {
+      Some(lease) if lease.holder.as_deref() == Some(holder)
+        && lease.lease_expires == Some(lease_expires) =>
+      {
+        Ok(Some(lease))
+      }
+      _ => Ok(None),
+    }
+  }
+
+  /**
+   * Extend the lease of holder until lease_expires, while its run goes on.
+   *
+   * @return false if holder lost the lease to another replica
+   */
+  pub async fn renew(
+    conn: &dgraph::Client,
+    id: i64,
+    holder: &str,
+    lease_expires: chrono::NaiveDateTime,
+  ) -> Result<bool, Error> {
+    let mut dict = serde_json::Map::new();
+    dict.insert(Self::pred("leaseExpires"), serde_json::to_value(lease_expires)?);
+    Self::update_if_held(conn, id, holder, dict).await
+  }
+
+  /**
+   * Record the outcome of a run and release the lease until next_run. Does
+   * nothing if holder lost the lease, the replica that has it now finishes.
+   *
+   * @return false if holder lost the lease to another replica
+   */
+  pub async fn finish(
+    conn: &dgraph::Client,
+    id: i64,
+    holder: &str,
+    status: JobStatus,
+    message: &str,
+    attempts: i32,
+    next_run: chrono::NaiveDateTime,
+  ) -> Result<bool, Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let mut dict = serde_json::Map::new();
+    dict.insert(Self::pred("leaseExpires"), serde_json::to_value(now)?);
+    dict.insert(Self::pred("nextRun"), serde_json::to_value(next_run)?);
+    dict.insert(Self::pred("status"), serde_json::to_value(status)?);
+    dict.insert(Self::pred("message"), serde_json::Value::from(message));
+    dict.insert(Self::pred("attempts"), serde_json::Value::from(attempts));
+    dict.insert(Self::pred("lastFinished"), serde_json::to_value(now)?);
+    Self::update_if_held(conn, id, holder, dict).await
+  }
+
+  /// Set the predicates in dict on lease id if holder has it
+  async fn update_if_held(
+    conn: &dgraph::Client,
+    id: i64,
+    holder: &str,
+    mut dict: serde_json::Map<String, serde_json::Value>,
+  ) -> Result<bool, Error> {
+    #[derive(Deserialize)]
+    struct Held {
+      held: Vec<serde_json::Value>,
+    }
+
+    let q = format!(
+      r#"query {{
+        held as held(func: uid({id})) @filter(eq({holder_pred}, {holder:?})) {{
+          uid
+        }}
+      }}"#,
+      id = id,
+      holder_pred = Self::pred("holder"),
+      holder = holder,
+    );
+    dict.insert("uid".into(), "uid(held)".into());
+    let mut update = Mutation::new();
+    update.set_cond("@if(eq(len(held), 1))");
+    update.set_set_json(&serde_json::Value::Object(dict))?;
+
+    // Taking the lease writes the holder, so a replica taking it meanwhile
+    // makes this fail to commit
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.upsert(q, vec![update]).await?;
+    let found: Held = resp.try_into()?;
+    if found.held.is_empty() {
+      return Ok(false);
+    }
+    txn.commit().await?;
+    Ok(true)
+  }
+}
@@ -1,10 +1,26 @@
+pub mod aggregates;
+pub mod api_token;
+pub mod applied_upgrade;
+pub mod block;
 pub mod category;
 pub mod comment;
 pub mod community;
+pub mod content_filter;
+pub mod data_export;
+pub mod email_outbox;
+pub mod email_verification;
+pub mod external_identity;
+pub mod invite_code;
+pub mod job_lease;
 pub mod moderator;
+pub mod oidc_login;
 pub mod password_reset_request;
 pub mod post;
 pub mod private_message;
+pub mod refresh_token;
+pub mod registration_application;
+pub mod revision;
 pub mod site;
+pub mod used_captcha;
 pub mod user_mention;
 pub mod user;
\ No newline at end of file
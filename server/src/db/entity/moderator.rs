@@ -1,3 +1,4 @@
+use super::aggregates::edge_nquad;
 use crate::db::*;
 
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
@@ -234,6 +235,26 @@ impl From<ModRemoveCommunityForm> for ModRemoveCommunity {
   }
 }
 
+impl ModRemoveCommunity {
+  /**
+   * Log a community removal or restore. Like ModBan, the log is an edge, from
+   * the community to the mod.
+   */
+  pub async fn create(
+    conn: &dgraph::Client,
+    form: &ModRemoveCommunityForm,
+  ) -> Result<Self, Error> {
+    let edge: Self = form.to_owned().into();
+    let mut mu = Mutation::new();
+    mu.set_set_nquads(edge_nquad(&edge)?);
+
+    let mut txn = conn.new_mutated_txn();
+    txn.mutate(mu).await?;
+    txn.commit().await?;
+    Ok(edge)
+  }
+}
+
 // impl CrudEdge<ModRemoveCommunityForm> for ModRemoveCommunity {}
 
 //#############################################################################
@@ -246,11 +267,8 @@ pub struct ModBanFromCommunity {
   #[serde(skip_serializing)]
   #[getset(get_copy = "pub with_prefix", set = "pub")]
   pub id: i64,
-  #[serde(skip)]
   pub mod_user_id: i64,
-  #[serde(skip)]
   pub other_user_id: i64,
-  #[serde(skip)]
   pub community_id: i64,
   pub reason: Option<String>,
   pub banned: Option<bool>,
@@ -272,6 +290,25 @@ pub struct ModBanFromCommunityForm {
 impl ModBanFromCommunity {
   /// Dgraph type
   const GDB_TYPE: &'static str = "ModBanFromCommunity";
+
+  /**
+   * Log a community ban or unban
+   */
+  pub async fn create(
+    conn: &dgraph::Client,
+    form: &ModBanFromCommunityForm,
+  ) -> Result<Self, Error> {
+    let mut node: Self = form.to_owned().into();
+    create_node::<Self>(conn, &mut node).await?;
+    Ok(node)
+  }
+
+  /**
+   * The whole community ban log, see crate::jobs
+   */
+  pub async fn list(conn: &dgraph::Client) -> Result<Vec<Self>, Error> {
+    list_nodes::<Self>(conn).await
+  }
 }
 
 impl Node for ModBanFromCommunity {
@@ -341,13 +378,68 @@ impl From<ModBanForm> for ModBan {
       other_user_id: form.other_user_id,
       reason: form.reason,
       banned: form.banned,
-      expires: Some(
-        chrono::Utc::now().naive_utc() + chrono::Duration::days(365)),
+      expires: form.expires,
       when_: chrono::Utc::now().naive_utc(),
     }
   }
 }
 
+impl ModBan {
+  /**
+   * Log a site ban or unban. The log is an edge from the banned user to the
+   * mod, so a later entry by the same mod replaces the earlier one.
+   */
+  pub async fn create(conn: &dgraph::Client, form: &ModBanForm) -> Result<Self, Error> {
+    let edge: Self = form.to_owned().into();
+    let mut mu = Mutation::new();
+    mu.set_set_nquads(edge_nquad(&edge)?);
+
+    let mut txn = conn.new_mutated_txn();
+    txn.mutate(mu).await?;
+    txn.commit().await?;
+    Ok(edge)
+  }
+
+  /**
+   * Users banned from the site, with their ban log, see crate::jobs
+   */
+  pub async fn list_banned_users(conn: &dgraph::Client) -> Result<Vec<BannedUser>, Error> {
+    let q = format!(
+      r#"query {{
+        all(func: eq(User.banned, true)) @filter(type(User)) {{
+          uid
+          bans: {pred} @facets(banned, expires, when) {{ uid }}
+        }}
+      }}"#,
+      pred = Self::db_type_name()
+    );
+    let resp = conn.new_read_only_txn().query(q).await?;
+    let users: NodeList<BannedUser> = resp.try_into()?;
+    Ok(users.all)
+  }
+}
+
+/// Facets of a ModBan edge, and the mod it points to
+#[derive(Debug, Clone, Deserialize)]
+pub struct ModBanEntry {
+  #[serde(rename = "uid", deserialize_with = "deserialize_number_from_string")]
+  pub mod_user_id: i64,
+  #[serde(rename = "bans|banned", default)]
+  pub banned: Option<bool>,
+  #[serde(rename = "bans|expires", default)]
+  pub expires: Option<chrono::NaiveDateTime>,
+  #[serde(rename = "bans|when")]
+  pub when_: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BannedUser {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  pub uid: i64,
+  #[serde(default)]
+  pub bans: Vec<ModBanEntry>,
+}
+
 // impl CrudEdge<ModBanForm> for ModBan {}
 
 //#############################################################################
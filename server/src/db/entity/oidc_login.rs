@@ -0,0 +1,210 @@
+use super::aggregates::delete_older;
+use crate::db::*;
+use crate::{generate_random_string, sha256_hex};
+
+/**
+ * Login at an OpenID Connect provider, from the redirect to the provider until
+ * it redirects back to the callback, on whichever server that lands.
+ *
+ * Looked up by the hash of the state parameter. The state is also in a signed
+ * cookie of the browser that started the login, see crate::oidc.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct OidcPendingLogin {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub state_encrypted: String,
+  /// Name of the provider in the config
+  pub provider: String,
+  pub code_verifier: String,
+  pub nonce: String,
+  /// Set when a logged in user links the provider account from their settings
+  pub link_user_id: Option<i64>,
+  pub expires: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcPendingLoginForm {
+  pub provider: String,
+  pub code_verifier: String,
+  pub nonce: String,
+  pub link_user_id: Option<i64>,
+}
+
+impl Node for OidcPendingLogin {
+  fn db_type_name() -> &'static str {
+    OidcPendingLogin::GDB_TYPE
+  }
+}
+
+impl OidcPendingLogin {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "OidcPendingLogin";
+
+  /**
+   * Store a login for state until now + lifetime
+   */
+  pub async fn create(
+    conn: &dgraph::Client,
+    state: &str,
+    form: &OidcPendingLoginForm,
+    lifetime: chrono::Duration,
+  ) -> Result<Self, Error> {
+    let mut node = OidcPendingLogin {
+      id: 0,
+      state_encrypted: sha256_hex(state),
+      provider: form.provider.to_owned(),
+      code_verifier: form.code_verifier.to_owned(),
+      nonce: form.nonce.to_owned(),
+      link_user_id: form.link_user_id,
+      expires: chrono::Utc::now().naive_utc() + lifetime,
+    };
+
+    create_node::<OidcPendingLogin>(conn, &mut node).await?;
+    Ok(node)
+  }
+
+  /**
+   * Read the unexpired login of state and delete it, so the state can't be
+   * used again
+   */
+  pub async fn consume(conn: &dgraph::Client, state: &str) -> Result<Self, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "stateEncrypted");
+    consume_node(conn, &pred_name, &sha256_hex(state), |login: &Self| login.expires).await
+  }
+
+  /**
+   * Delete logins nobody finished.
+   *
+   * @return number of deleted logins
+   */
+  pub async fn delete_expired(conn: &dgraph::Client) -> Result<usize, Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "expires");
+    delete_older(conn, &pred_name, now, None).await
+  }
+}
+
+/**
+ * Code the front end exchanges once for a session, after a login with a
+ * provider. The callback redirects to the front end with this code instead of
+ * the tokens, which would stay in the browser history.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct OidcLoginCode {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub user_id: i64,
+  pub code_encrypted: String,
+  pub expires: chrono::NaiveDateTime,
+}
+
+impl Node for OidcLoginCode {
+  fn db_type_name() -> &'static str {
+    OidcLoginCode::GDB_TYPE
+  }
+}
+
+impl OidcLoginCode {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "OidcLoginCode";
+
+  /**
+   * Create a code for user, valid until now + lifetime.
+   *
+   * @return (node, code) : the code itself is only returned here
+   */
+  pub async fn create_for_user(
+    conn: &dgraph::Client,
+    for_user_id: i64,
+    lifetime: chrono::Duration,
+  ) -> Result<(Self, String), Error> {
+    let code = generate_random_string();
+    let mut node = OidcLoginCode {
+      id: 0,
+      user_id: for_user_id,
+      code_encrypted: sha256_hex(&code),
+      expires: chrono::Utc::now().naive_utc() + lifetime,
+    };
+
+    create_node::<OidcLoginCode>(conn, &mut node).await?;
+    Ok((node, code))
+  }
+
+  /**
+   * Read the unexpired code and delete it, so it can't be exchanged again
+   */
+  pub async fn consume(conn: &dgraph::Client, code: &str) -> Result<Self, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "codeEncrypted");
+    consume_node(conn, &pred_name, &sha256_hex(code), |code: &Self| code.expires).await
+  }
+
+  /**
+   * Delete codes nobody exchanged.
+   *
+   * @return number of deleted codes
+   */
+  pub async fn delete_expired(conn: &dgraph::Client) -> Result<usize, Error> {
+    let now = chrono::Utc::now().naive_utc();
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "expires");
+    delete_older(conn, &pred_name, now, None).await
+  }
+}
+
+/**
+ * Read the node of type T whose pred is hash and delete it in the same
+ * transaction, unless it expired. Callbacks racing with the same value delete
+ * the same node, so only one of them commits. Expired nodes are left to the
+ * cleanup_tokens job.
+ */
+async fn consume_node<T>(
+  conn: &dgraph::Client,
+  pred: &str,
+  hash: &str,
+  expires: fn(&T) -> chrono::NaiveDateTime,
+) -> Result<T, Error>
+where
+  T: Node + DeserializeOwned,
+{
+  #[derive(Deserialize)]
+  #[serde(bound = "T: DeserializeOwned")]
+  struct Found<T> {
+    found: Vec<T>,
+  }
+
+  let q = format!(
+    r#"query {{
+      found as found(func: eq({pred}, {hash:?})) @filter(type({type_name})) {{
+        uid
+        expand(_all_)
+      }}
+    }}"#,
+    pred = pred,
+    hash = hash,
+    type_name = T::db_type_name(),
+  );
+  let mut mu = Mutation::new();
+  mu.set_cond("@if(eq(len(found), 1))");
+  mu.set_delete_nquads("uid(found) * * .");
+
+  let mut txn = conn.new_mutated_txn();
+  let resp = txn.upsert(q, vec![mu]).await?;
+  let found: Found<T> = resp.try_into()?;
+  let node = match found.found.into_iter().next() {
+    Some(node) => node,
+    None => failure::bail!("Unknown {}", T::db_type_name()),
+  };
+  if expires(&node) < chrono::Utc::now().naive_utc() {
+    failure::bail!("{} expired", T::db_type_name());
+  }
+  txn.commit().await?;
+  Ok(node)
+}
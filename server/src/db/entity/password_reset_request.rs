@@ -1,9 +1,20 @@
+use super::aggregates::{created_uid, delete_older, new_node_json};
 use crate::db::*;
-use sha2::{Digest, Sha256};
+use crate::sha256_hex;
 
+/**
+ * Pending password reset of a user.
+ *
+ * The mailed link carries the token, the node keeps its sha256 hash. A user
+ * has at most one pending request: asking for a new one replaces it, resetting
+ * the password deletes it, and it expires after `password_reset_token_minutes`.
+ */
 #[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
 #[serde(rename_all = "camelCase")]
 pub struct PasswordResetRequest {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
   #[getset(get_copy = "pub with_prefix", set = "pub")]
   pub id: i64,
   pub user_id: i64,
@@ -29,6 +40,12 @@ impl From<PasswordResetRequestForm> for PasswordResetRequest {
   }
 }
 
+/// A user asked for another password reset before `password_reset_interval_seconds`
+/// passed
+#[derive(Fail, Debug)]
+#[fail(display = "password_reset_limit_reached")]
+pub struct PasswordResetLimitReached;
+
 impl Node for PasswordResetRequest {
   fn db_type_name() -> &'static str {
     PasswordResetRequest::GDB_TYPE
@@ -43,114 +60,200 @@ impl PasswordResetRequest {
   /// Dgraph type
   const GDB_TYPE: &'static str = "PasswordResetRequest";
 
+  fn pred(field: &str) -> String {
+    format!("{}.{}", Self::GDB_TYPE, field)
+  }
+
   /**
-   * Create request from token
+   * Create request from token, replacing any pending request of the user.
+   * Fails with PasswordResetLimitReached if the pending request is younger than
+   * `password_reset_interval_seconds`.
    */
   pub async fn create_token(
       conn: &dgraph::Client,
       from_user_id: i64,
       token: &str
     ) -> Result<Self, Error> {
+    #[derive(Deserialize)]
+    struct Recent {
+      recent: Vec<serde_json::Value>,
+    }
 
-    let mut hasher = Sha256::new();
-    hasher.input(token);
-    let token_hash: String = PasswordResetRequest::bytes_to_hex(
-                                hasher.result().to_vec());
-
-    let mut form = PasswordResetRequest {
+    let now = chrono::Utc::now().naive_utc();
+    let interval = chrono::Duration::seconds(Settings::get().password_reset_interval_seconds);
+    let mut request = PasswordResetRequest {
       id: 0,
       user_id: from_user_id,
-      token_encrypted: token_hash,
-      published: chrono::Utc::now().naive_utc(),
+      token_encrypted: sha256_hex(token),
+      published: now,
     };
 
-    let uid = create_node::<PasswordResetRequest>(conn, &mut form).await?;
-    Ok(form)
+    let q = format!(
+      r#"query {{
+        pending as pending(func: eq({user_pred}, {user_id})) @filter(type({type_name})) {{
+          uid
+        }}
+        recent as recent(func: uid(pending)) @filter(gt({published_pred}, "{since}")) {{
+          uid
+        }}
+      }}"#,
+      user_pred = Self::pred("userId"),
+      user_id = from_user_id,
+      type_name = Self::GDB_TYPE,
+      published_pred = Self::pred("published"),
+      since = (now - interval).format("%Y-%m-%dT%H:%M:%S"),
+    );
+    let mut replace = Mutation::new();
+    replace.set_cond("@if(eq(len(recent), 0))");
+    replace.set_delete_nquads("uid(pending) * * .");
+    replace.set_set_json(&new_node_json(&request, &serde_json::Map::new())?)?;
+
+    // The user id is an @id predicate, so requests racing for the same user
+    // conflict and only one of them commits
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.upsert(q, vec![replace]).await?;
+    let uids = resp.uids.clone();
+    let found: Recent = resp.try_into()?;
+    if !found.recent.is_empty() {
+      return Err(PasswordResetLimitReached.into());
+    }
+    txn.commit().await?;
+
+    request.id = created_uid(&uids)?;
+    Ok(request)
   }
 
   /**
-   * Read request from token
+   * Read unexpired request from token
    */
   pub async fn read_from_token(conn: &dgraph::Client, token: &str) -> Result<Self, Error> {
-    let mut hasher = Sha256::new();
-    hasher.input(token);
-    let token_hash: String = PasswordResetRequest::bytes_to_hex(
-                                hasher.result().to_vec());
+    let token_hash = sha256_hex(token);
 
-    let pred_name = "tokenEncrypted";
-    let pred_repr = format!("{:?}", token);
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "tokenEncrypted");
+    let pred_repr = format!("{:?}", token_hash);
 
-    let nodes = find_nodes::<PasswordResetRequest>(
-      conn, pred_name, &pred_repr).await?;
+    let request = find_node::<PasswordResetRequest>(conn, &pred_name, &pred_repr).await?;
 
-    if nodes.len() != 1 {
-      failure::bail!(format!("Expected 1 node but found {}", nodes.len()));
+    if request.is_expired_at(chrono::Utc::now().naive_utc(), Self::lifetime()) {
+      delete_node(conn, request.id).await?;
+      failure::bail!("Password reset token expired");
     }
-    Ok(nodes[0])
+    Ok(request)
   }
 
-  fn bytes_to_hex(bytes: Vec<u8>) -> String {
-    let mut str = String::new();
-    for byte in bytes {
-      str = format!("{}{:02x}", str, byte);
+  /**
+   * Read the unexpired request of token and delete it in the same transaction,
+   * so the token can't be used again
+   */
+  pub async fn consume_token(conn: &dgraph::Client, token: &str) -> Result<Self, Error> {
+    #[derive(Deserialize)]
+    struct Found {
+      request: Vec<PasswordResetRequest>,
+    }
+
+    let q = format!(
+      r#"query {{
+        request as request(func: eq({token_pred}, {hash:?})) @filter(type({type_name})) {{
+          uid
+          expand(_all_)
+        }}
+      }}"#,
+      token_pred = Self::pred("tokenEncrypted"),
+      hash = sha256_hex(token),
+      type_name = Self::GDB_TYPE,
+    );
+    let mut mu = Mutation::new();
+    mu.set_cond("@if(eq(len(request), 1))");
+    mu.set_delete_nquads("uid(request) * * .");
+
+    // Password changes racing with the same token delete the same node, so
+    // only one of them commits
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.upsert(q, vec![mu]).await?;
+    let found: Found = resp.try_into()?;
+    let request = match found.request.into_iter().next() {
+      Some(request) => request,
+      None => failure::bail!("Unknown password reset token"),
+    };
+    // Left to the cleanup_tokens job
+    if request.is_expired_at(chrono::Utc::now().naive_utc(), Self::lifetime()) {
+      failure::bail!("Password reset token expired");
     }
-    str
+    txn.commit().await?;
+    Ok(request)
+  }
+
+  /**
+   * Delete all expired requests.
+   *
+   * @return number of deleted requests
+   */
+  pub async fn delete_expired(conn: &dgraph::Client) -> Result<usize, Error> {
+    let expired_before = chrono::Utc::now().naive_utc() - Self::lifetime();
+    delete_older(conn, &Self::pred("published"), expired_before, None).await
+  }
+
+  fn lifetime() -> chrono::Duration {
+    chrono::Duration::minutes(Settings::get().password_reset_token_minutes)
   }
 }
 
-// #[cfg(test)]
-// mod tests {
-//   use super::super::user::*;
-//   use super::*;
-
-//   #[test]
-//   fn test_crud() {
-//     let conn = dgraph::Client::new(vec!["http://localhost:9080"])
-//                   .expect("connected client");
-
-//     let new_user = UserForm {
-//       name: "thommy prw".into(),
-//       fedi_name: "rrf".into(),
-//       preferred_username: None,
-//       password_encrypted: "nope".into(),
-//       email: None,
-//       matrix_user_id: None,
-//       avatar: None,
-//       admin: false,
-//       banned: false,
-//       updated: None,
-//       show_nsfw: false,
-//       theme: "darkly".into(),
-//       default_sort_type: SortType::Hot as i16,
-//       default_listing_type: ListingType::Subscribed as i16,
-//       lang: "browser".into(),
-//       show_avatars: true,
-//       send_notifications_to_email: false,
-//     };
-
-//     let inserted_user = User_::create(&conn, &new_user).unwrap();
-
-//     let token = "nope";
-//     let token_encrypted_ = "ca3704aa0b06f5954c79ee837faa152d84d6b2d42838f0637a15eda8337dbdce";
-
-//     let inserted_password_reset_request =
-//       PasswordResetRequest::create_token(&conn, inserted_user.id, token).unwrap();
-
-//     let expected_password_reset_request = PasswordResetRequest {
-//       id: inserted_password_reset_request.id,
-//       user_id: inserted_user.id,
-//       token_encrypted: token_encrypted_.to_string(),
-//       published: inserted_password_reset_request.published,
-//     };
-
-//     let read_password_reset_request = PasswordResetRequest::read_from_token(&conn, token).unwrap();
-//     let num_deleted = User_::delete(&conn, inserted_user.id).unwrap();
-
-//     assert_eq!(expected_password_reset_request, read_password_reset_request);
-//     assert_eq!(
-//       expected_password_reset_request,
-//       inserted_password_reset_request
-//     );
-//     assert_eq!(1, num_deleted);
-//   }
-// }
+impl Published for PasswordResetRequest {
+  fn published(&self) -> chrono::NaiveDateTime {
+    self.published
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request_published(published: chrono::NaiveDateTime) -> PasswordResetRequest {
+    PasswordResetRequest {
+      id: 0,
+      user_id: 1,
+      token_encrypted: sha256_hex("nope"),
+      published,
+    }
+  }
+
+  /// Needs a running dgraph, run with `cargo test -- --ignored`
+  #[actix_rt::test]
+  #[ignore]
+  async fn test_token_replay_and_expiry() {
+    let conn = dgraph::Client::new(vec!["http://localhost:9080"]).expect("connected client");
+    let user_id = 4242;
+    let token = "nope";
+
+    let inserted = PasswordResetRequest::create_token(&conn, user_id, token)
+      .await
+      .unwrap();
+    assert_eq!(
+      inserted.token_encrypted,
+      "ca3704aa0b06f5954c79ee837faa152d84d6b2d42838f0637a15eda8337dbdce"
+    );
+
+    // A second request right away is refused
+    assert!(PasswordResetRequest::create_token(&conn, user_id, "other")
+      .await
+      .is_err());
+
+    // The token works once
+    let consumed = PasswordResetRequest::consume_token(&conn, token).await.unwrap();
+    assert_eq!(consumed.user_id, user_id);
+    assert!(PasswordResetRequest::consume_token(&conn, token).await.is_err());
+
+    // An expired token is refused and cleaned up
+    let mut expired = request_published(
+      chrono::Utc::now().naive_utc() - PasswordResetRequest::lifetime(),
+    );
+    expired.token_encrypted = sha256_hex("expired");
+    create_node::<PasswordResetRequest>(&conn, &mut expired)
+      .await
+      .unwrap();
+    assert!(PasswordResetRequest::read_from_token(&conn, "expired")
+      .await
+      .is_err());
+    assert_eq!(PasswordResetRequest::delete_expired(&conn).await.unwrap(), 0);
+  }
+}
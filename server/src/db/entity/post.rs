@@ -1,3 +1,4 @@
+use super::aggregates::*;
 use crate::db::*;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
@@ -14,6 +15,9 @@ pub struct Post {
   pub creator_id: i64,
   pub community_id: i64,
   pub removed: bool,
+  /// Matched a `Hold` content filter, hidden until a mod approves or removes it
+  #[serde(default)]
+  pub held: bool,
   pub locked: bool,
   pub published: chrono::NaiveDateTime,
   pub updated: Option<chrono::NaiveDateTime>,
@@ -35,6 +39,7 @@ pub struct PostForm {
   pub creator_id: i64,
   pub community_id: i64,
   pub removed: Option<bool>,
+  pub held: Option<bool>,
   pub locked: Option<bool>,
   pub updated: Option<chrono::NaiveDateTime>,
   pub deleted: Option<bool>,
@@ -49,6 +54,67 @@ pub struct PostForm {
 impl Post {
   /// Dgraph type
   const GDB_TYPE: &'static str = "Post";
+
+  /**
+   * Create post, counting it for its community, creator and the site
+   */
+  pub async fn create(conn: &dgraph::Client, form: &PostForm) -> Result<Self, Error> {
+    let mut post: Post = form.to_owned().into();
+    let aggregates = PostAggregates {
+      newest_activity_time: Some(post.published),
+      ..Default::default()
+    };
+    let changes = if counted(post.removed || post.held, post.deleted) {
+      CountChange::post_count(&post, 1)
+    } else {
+      Vec::new()
+    };
+
+    let mut mu = Mutation::new();
+    mu.set_set_json(&new_node_json(&post, &aggregates)?)?;
+    let uids = mutate_with_counts(conn, mu, &changes).await?;
+    post.id = created_uid(&uids)?;
+    Ok(post)
+  }
+
+  /**
+   * Read post by id
+   */
+  pub async fn read(conn: &dgraph::Client, id: i64) -> Result<Self, Error> {
+    read_node::<Post>(conn, id).await
+  }
+
+  /**
+   * Update post. Deleting, removing, holding or restoring it updates the counts.
+   */
+  pub async fn update(conn: &dgraph::Client, id: i64, form: &PostForm) -> Result<Self, Error> {
+    transact_with_counts(conn, |txn| {
+      let form = form.to_owned();
+      Box::pin(async move {
+        let orig = read_node_in_txn::<Post>(txn, id).await?;
+        let removed = form.removed.unwrap_or(orig.removed);
+        let held = form.held.unwrap_or(orig.held);
+        let deleted = form.deleted.unwrap_or(orig.deleted);
+        let changes = CountChange::visibility(
+          (orig.removed || orig.held, orig.deleted),
+          (removed || held, deleted),
+          |delta| CountChange::post_count(&orig, delta),
+        );
+
+        // Always writing the flags makes concurrent updates conflict
+        let form = PostForm {
+          removed: Some(removed),
+          held: Some(held),
+          deleted: Some(deleted),
+          ..form
+        };
+        let mu = update_mutation::<Post, PostForm>(id, &form)?;
+        Ok(TxnWrites::new((), mu, changes))
+      })
+    })
+    .await?;
+    Self::read(conn, id).await
+  }
 }
 
 impl Node for Post {
@@ -67,6 +133,7 @@ impl From<PostForm> for Post {
         creator_id: form.creator_id,
         community_id: form.community_id,
         removed: form.removed.unwrap_or(false),
+        held: form.held.unwrap_or(false),
         locked: form.locked.unwrap_or(false),
         updated: form.updated,
         deleted: form.deleted.unwrap_or(false),
@@ -132,7 +199,56 @@ impl From<PostLikeForm> for PostLike {
   }
 }
 
-// impl CrudEdge<PostLikeForm> for PostLike {}
+impl PostLike {
+  /**
+   * Vote on post, replacing an earlier vote of the user
+   */
+  pub async fn like(conn: &dgraph::Client, form: &PostLikeForm) -> Result<Self, Error> {
+    transact_with_counts(conn, |txn| {
+      let form = form.to_owned();
+      Box::pin(async move {
+        let post = read_node_in_txn::<Post>(txn, form.post_id).await?;
+        let old_score = vote_score(txn, form.post_id, Self::db_type_name(), form.user_id)
+          .await?
+          .unwrap_or(0);
+        let like: Self = form.into();
+
+        let mut mu = Mutation::new();
+        mu.set_set_nquads(edge_nquad(&like)?);
+        let changes = CountChange::post_vote(&post, old_score, like.score);
+        Ok(TxnWrites::new(like, mu, changes))
+      })
+    })
+    .await
+  }
+
+  /**
+   * Take back the vote of the user on post, if there is one
+   */
+  pub async fn remove(conn: &dgraph::Client, form: &PostLikeForm) -> Result<usize, Error> {
+    transact_with_counts(conn, |txn| {
+      let form = form.to_owned();
+      Box::pin(async move {
+        let old_score = vote_score(txn, form.post_id, Self::db_type_name(), form.user_id).await?;
+        let old_score = match old_score {
+          Some(score) => score,
+          None => return Ok(TxnWrites::none(0)),
+        };
+        let post = read_node_in_txn::<Post>(txn, form.post_id).await?;
+
+        let mut mu = Mutation::new();
+        mu.set_delete_nquads(format!(
+          "uid({}) <{}> uid({}) .",
+          form.post_id,
+          Self::db_type_name(),
+          form.user_id
+        ));
+        Ok(TxnWrites::new(1, mu, CountChange::post_vote(&post, old_score, 0)))
+      })
+    })
+    .await
+  }
+}
 
 
 //#############################################################################
@@ -0,0 +1,174 @@
+use super::aggregates::delete_older;
+use crate::db::*;
+use crate::{generate_random_string, sha256_hex};
+
+/**
+ * Refresh token for a login session.
+ *
+ * Each refresh marks the used token rotated and issues a new one, so a token
+ * can only be used once. Rotated tokens are kept until they expire: using one
+ * again means it was stolen, see User_::refresh_session. The node keeps the
+ * sha256 hash, and the token version of the user, so bumping the version ends
+ * the session.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshToken {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub user_id: i64,
+  pub token_encrypted: String,
+  /// Token version of the user when the token was issued
+  pub token_version: i32,
+  pub published: chrono::NaiveDateTime,
+  pub expires: chrono::NaiveDateTime,
+  /// When the token was exchanged for a new one
+  #[serde(default)]
+  pub rotated: Option<chrono::NaiveDateTime>,
+}
+
+/// Outcome of RefreshToken::rotate
+#[derive(Debug, PartialEq)]
+pub enum RefreshTokenUse {
+  /// First use, the token is rotated now
+  Rotated(RefreshToken),
+  /// The token was rotated before
+  Reused(RefreshToken),
+}
+
+impl Node for RefreshToken {
+  fn db_type_name() -> &'static str {
+    RefreshToken::GDB_TYPE
+  }
+}
+
+impl RefreshToken {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "RefreshToken";
+
+  fn pred(field: &str) -> String {
+    format!("{}.{}", Self::GDB_TYPE, field)
+  }
+
+  /**
+   * Create a new refresh token for user.
+   *
+   * @return (node, token) : the token itself is only returned here
+   */
+  pub async fn create_for_user(
+    conn: &dgraph::Client,
+    for_user_id: i64,
+    token_version: i32,
+  ) -> Result<(Self, String), Error> {
+    let token = generate_random_string();
+    let published = chrono::Utc::now().naive_utc();
+    let lifetime = chrono::Duration::days(Settings::get().refresh_token_days);
+
+    let mut node = RefreshToken {
+      id: 0,
+      user_id: for_user_id,
+      token_encrypted: sha256_hex(&token),
+      token_version,
+      published,
+      expires: published + lifetime,
+      rotated: None,
+    };
+
+    create_node::<RefreshToken>(conn, &mut node).await?;
+    Ok((node, token))
+  }
+
+  /**
+   * Read an unexpired refresh token.
+   */
+  pub async fn read_from_token(conn: &dgraph::Client, token: &str) -> Result<Self, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "tokenEncrypted");
+    let pred_repr = format!("{:?}", sha256_hex(token));
+
+    let node = find_node::<RefreshToken>(conn, &pred_name, &pred_repr).await?;
+
+    if node.expires < chrono::Utc::now().naive_utc() {
+      delete_node(conn, node.id).await?;
+      failure::bail!("Refresh token expired");
+    }
+    Ok(node)
+  }
+
+  /**
+   * Mark an unexpired refresh token rotated, in the same transaction that
+   * looks it up. Refreshes racing with the same token both write rotated, so
+   * only one of them commits.
+   */
+  pub async fn rotate(conn: &dgraph::Client, token: &str) -> Result<RefreshTokenUse, Error> {
+    #[derive(Deserialize)]
+    struct Found {
+      token: Vec<RefreshToken>,
+    }
+
+    let now = chrono::Utc::now().naive_utc();
+    let q = format!(
+      r#"query {{
+        token(func: eq({token_pred}, {hash:?})) @filter(type({type_name})) {{
+          uid
+          expand(_all_)
+        }}
+        unused as unused(func: eq({token_pred}, {hash:?}))
+          @filter(type({type_name}) AND NOT has({rotated_pred})) {{
+          uid
+        }}
+      }}"#,
+      token_pred = Self::pred("tokenEncrypted"),
+      hash = sha256_hex(token),
+      type_name = Self::GDB_TYPE,
+      rotated_pred = Self::pred("rotated"),
+    );
+    let mut dict = serde_json::Map::new();
+    dict.insert("uid".into(), "uid(unused)".into());
+    dict.insert(Self::pred("rotated"), serde_json::to_value(now)?);
+    let mut mu = Mutation::new();
+    mu.set_cond("@if(eq(len(unused), 1))");
+    mu.set_set_json(&serde_json::Value::Object(dict))?;
+
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.upsert(q, vec![mu]).await?;
+    let found: Found = resp.try_into()?;
+    let node = match found.token.into_iter().next() {
+      Some(node) => node,
+      None => failure::bail!("Unknown refresh token"),
+    };
+    if node.rotated.is_some() {
+      return Ok(RefreshTokenUse::Reused(node));
+    }
+    if node.expires < now {
+      failure::bail!("Refresh token expired");
+    }
+    txn.commit().await?;
+    Ok(RefreshTokenUse::Rotated(node))
+  }
+
+  /**
+   * Delete all refresh tokens of user, i.e. log out all sessions.
+   */
+  pub async fn delete_for_user(conn: &dgraph::Client, for_user_id: i64) -> Result<usize, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "userId");
+    let tokens = find_nodes::<RefreshToken>(conn, &pred_name, &for_user_id.to_string()).await?;
+
+    for token in &tokens {
+      delete_node(conn, token.id).await?;
+    }
+    Ok(tokens.len())
+  }
+
+  /**
+   * Delete all expired refresh tokens, rotated ones too.
+   *
+   * @return number of deleted tokens
+   */
+  pub async fn delete_expired(conn: &dgraph::Client) -> Result<usize, Error> {
+    let now = chrono::Utc::now().naive_utc();
+    delete_older(conn, &Self::pred("expires"), now, None).await
+  }
+}
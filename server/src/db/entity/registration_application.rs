@@ -0,0 +1,119 @@
+use crate::db::*;
+
+/**
+ * Application of a new user on a site that requires one to register.
+ *
+ * The user is created right away but can't log in until an admin approves
+ * the application. Resolved applications are kept for the record.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationApplication {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub user_id: i64,
+  /// Answer to the site's application question
+  pub answer: String,
+  /// Admin who approved or denied the application
+  pub admin_id: Option<i64>,
+  pub approved: bool,
+  pub deny_reason: Option<String>,
+  pub published: chrono::NaiveDateTime,
+  pub updated: Option<chrono::NaiveDateTime>,
+}
+
+impl Node for RegistrationApplication {
+  fn db_type_name() -> &'static str {
+    RegistrationApplication::GDB_TYPE
+  }
+}
+
+impl RegistrationApplication {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "RegistrationApplication";
+
+  /**
+   * Create application of user
+   */
+  pub async fn create(conn: &dgraph::Client, for_user_id: i64, answer: &str) -> Result<Self, Error> {
+    let mut node = RegistrationApplication {
+      id: 0,
+      user_id: for_user_id,
+      answer: answer.to_owned(),
+      admin_id: None,
+      approved: false,
+      deny_reason: None,
+      published: chrono::Utc::now().naive_utc(),
+      updated: None,
+    };
+
+    create_node::<RegistrationApplication>(conn, &mut node).await?;
+    Ok(node)
+  }
+
+  /**
+   * Read application by id
+   */
+  pub async fn read(conn: &dgraph::Client, id: i64) -> Result<Self, Error> {
+    read_node::<RegistrationApplication>(conn, id).await
+  }
+
+  /**
+   * Find the application of user
+   */
+  pub async fn find_for_user(conn: &dgraph::Client, for_user_id: i64) -> Result<Self, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "userId");
+    find_node::<RegistrationApplication>(conn, &pred_name, &for_user_id.to_string()).await
+  }
+
+  /**
+   * List applications, oldest first, optionally only those still waiting for an admin
+   */
+  pub async fn list(conn: &dgraph::Client, unresolved_only: bool) -> Result<Vec<Self>, Error> {
+    let mut applications: Vec<Self> = list_nodes::<RegistrationApplication>(conn)
+      .await?
+      .into_iter()
+      .filter(|a| !unresolved_only || !a.is_resolved())
+      .collect();
+    applications.sort_by_key(|a| a.published);
+    Ok(applications)
+  }
+
+  /**
+   * Approve or deny application
+   */
+  pub async fn resolve(
+    conn: &dgraph::Client,
+    id: i64,
+    admin_id: i64,
+    approved: bool,
+    deny_reason: Option<&str>,
+  ) -> Result<Self, Error> {
+    let mut dict = serde_json::Map::new();
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "adminId"),
+      serde_json::Value::from(admin_id),
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "approved"),
+      serde_json::Value::Bool(approved),
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "denyReason"),
+      deny_reason.map_or(serde_json::Value::Null, serde_json::Value::from),
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "updated"),
+      serde_json::to_value(chrono::Utc::now().naive_utc())?,
+    );
+
+    update_node_dict::<Self>(conn, id, &serde_json::Value::Object(dict)).await
+  }
+
+  pub fn is_resolved(&self) -> bool {
+    self.admin_id.is_some()
+  }
+}
@@ -0,0 +1,138 @@
+use super::comment::Comment;
+use super::post::Post;
+use crate::db::*;
+
+/**
+ * Version of a post as it was before an edit.
+ *
+ * A revision is saved every time an edit changes the name, url or body, so
+ * the revisions of a post and the post itself make up its full history.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct PostRevision {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  /// Scalar id, indexed in the schema for list_for_post
+  pub post_id: i64,
+  pub name: String,
+  pub url: Option<String>,
+  pub body: Option<String>,
+  /// User whose edit replaced this version
+  pub editor_id: i64,
+  /// When this version was replaced
+  pub published: chrono::NaiveDateTime,
+}
+
+impl Node for PostRevision {
+  fn db_type_name() -> &'static str {
+    PostRevision::GDB_TYPE
+  }
+}
+
+impl PostRevision {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "PostRevision";
+
+  /**
+   * Save the current version of post before editor changes it
+   */
+  pub async fn record(conn: &dgraph::Client, post: &Post, editor_id: i64) -> Result<Self, Error> {
+    let mut node = PostRevision {
+      id: 0,
+      post_id: post.id,
+      name: post.name.to_owned(),
+      url: post.url.to_owned(),
+      body: post.body.to_owned(),
+      editor_id,
+      published: chrono::Utc::now().naive_utc(),
+    };
+
+    create_node::<PostRevision>(conn, &mut node).await?;
+    Ok(node)
+  }
+
+  /**
+   * Revisions of post, oldest first
+   */
+  pub async fn list_for_post(conn: &dgraph::Client, for_post_id: i64) -> Result<Vec<Self>, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "postId");
+    let mut revisions =
+      find_nodes::<PostRevision>(conn, &pred_name, &for_post_id.to_string()).await?;
+    revisions.sort_by_key(|r| r.published);
+    Ok(revisions)
+  }
+
+  /// Whether an edit to these values has to be recorded
+  pub fn is_changed_by(post: &Post, name: &str, url: &Option<String>, body: &Option<String>) -> bool {
+    post.name != name || &post.url != url || &post.body != body
+  }
+}
+
+/**
+ * Version of a comment as it was before an edit. See PostRevision.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct CommentRevision {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  /// Scalar id, indexed in the schema for list_for_comment
+  pub comment_id: i64,
+  pub content: String,
+  /// User whose edit replaced this version
+  pub editor_id: i64,
+  /// When this version was replaced
+  pub published: chrono::NaiveDateTime,
+}
+
+impl Node for CommentRevision {
+  fn db_type_name() -> &'static str {
+    CommentRevision::GDB_TYPE
+  }
+}
+
+impl CommentRevision {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "CommentRevision";
+
+  /**
+   * Save the current version of comment before editor changes it
+   */
+  pub async fn record(
+    conn: &dgraph::Client,
+    comment: &Comment,
+    editor_id: i64,
+  ) -> Result<Self, Error> {
+    let mut node = CommentRevision {
+      id: 0,
+      comment_id: comment.id,
+      content: comment.content.to_owned(),
+      editor_id,
+      published: chrono::Utc::now().naive_utc(),
+    };
+
+    create_node::<CommentRevision>(conn, &mut node).await?;
+    Ok(node)
+  }
+
+  /**
+   * Revisions of comment, oldest first
+   */
+  pub async fn list_for_comment(
+    conn: &dgraph::Client,
+    for_comment_id: i64,
+  ) -> Result<Vec<Self>, Error> {
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "commentId");
+    let mut revisions =
+      find_nodes::<CommentRevision>(conn, &pred_name, &for_comment_id.to_string()).await?;
+    revisions.sort_by_key(|r| r.published);
+    Ok(revisions)
+  }
+}
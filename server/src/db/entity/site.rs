@@ -16,6 +16,24 @@ pub struct Site {
   pub enable_downvotes: bool,
   pub open_registration: bool,
   pub enable_nsfw: bool,
+  /// Admins and moderators must enable two-factor authentication
+  #[serde(default)]
+  pub require_totp_for_mods: bool,
+  /// Users must verify their email address before posting
+  #[serde(default)]
+  pub require_email_verification: bool,
+  /// With closed registration, new users can apply by answering application_question
+  #[serde(default)]
+  pub require_application: bool,
+  pub application_question: Option<String>,
+  /// New users have to solve a captcha of captcha_difficulty (easy, medium or hard)
+  #[serde(default)]
+  pub captcha_enabled: bool,
+  #[serde(default = "default_captcha_difficulty")]
+  pub captcha_difficulty: String,
+  /// Everyone can see what edits changed, not only authors, mods and admins
+  #[serde(default)]
+  pub public_edit_history: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -28,6 +46,28 @@ pub struct SiteForm {
   pub enable_downvotes: bool,
   pub open_registration: bool,
   pub enable_nsfw: bool,
+  /// Admins and moderators must enable two-factor authentication
+  #[serde(default)]
+  pub require_totp_for_mods: bool,
+  /// Users must verify their email address before posting
+  #[serde(default)]
+  pub require_email_verification: bool,
+  /// With closed registration, new users can apply by answering application_question
+  #[serde(default)]
+  pub require_application: bool,
+  pub application_question: Option<String>,
+  /// New users have to solve a captcha of captcha_difficulty (easy, medium or hard)
+  #[serde(default)]
+  pub captcha_enabled: bool,
+  #[serde(default = "default_captcha_difficulty")]
+  pub captcha_difficulty: String,
+  /// Everyone can see what edits changed, not only authors, mods and admins
+  #[serde(default)]
+  pub public_edit_history: bool,
+}
+
+fn default_captcha_difficulty() -> String {
+  "medium".into()
 }
 
 impl Site {
@@ -52,6 +92,13 @@ impl From<SiteForm> for Site {
         enable_downvotes: form.enable_downvotes,
         open_registration: form.open_registration,
         enable_nsfw: form.enable_downvotes,
+        require_totp_for_mods: form.require_totp_for_mods,
+        require_email_verification: form.require_email_verification,
+        require_application: form.require_application,
+        application_question: form.application_question,
+        captcha_enabled: form.captcha_enabled,
+        captcha_difficulty: form.captcha_difficulty,
+        public_edit_history: form.public_edit_history,
         published: chrono::Utc::now().naive_utc(),
       }
   }
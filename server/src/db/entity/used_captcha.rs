@@ -0,0 +1,90 @@
+use super::aggregates::delete_older;
+use crate::db::*;
+
+/**
+ * Nonce of a solved registration captcha, kept until the challenge expires so
+ * it can't be solved again on any replica. See crate::captcha_challenge.
+ */
+#[derive(Debug, Deserialize, Serialize, PartialEq, Setters, CopyGetters)]
+#[serde(rename_all = "camelCase")]
+pub struct UsedCaptcha {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  #[serde(rename(deserialize = "uid"))]
+  #[serde(skip_serializing)]
+  #[getset(get_copy = "pub with_prefix", set = "pub")]
+  pub id: i64,
+  pub nonce: String,
+  pub expires: chrono::NaiveDateTime,
+}
+
+impl Node for UsedCaptcha {
+  fn db_type_name() -> &'static str {
+    UsedCaptcha::GDB_TYPE
+  }
+}
+
+impl UsedCaptcha {
+  /// Dgraph type
+  const GDB_TYPE: &'static str = "UsedCaptcha";
+
+  fn pred(field: &str) -> String {
+    format!("{}.{}", Self::GDB_TYPE, field)
+  }
+
+  /**
+   * Record that the challenge with nonce was solved.
+   *
+   * @return false if it already was
+   */
+  pub async fn mark_used(
+    conn: &dgraph::Client,
+    nonce: &str,
+    expires: chrono::NaiveDateTime,
+  ) -> Result<bool, Error> {
+    #[derive(Deserialize)]
+    struct Existing {
+      existing: Vec<serde_json::Value>,
+    }
+
+    let q = format!(
+      r#"query {{
+        existing as existing(func: eq({nonce_pred}, {nonce:?})) {{
+          uid
+        }}
+      }}"#,
+      nonce_pred = Self::pred("nonce"),
+      nonce = nonce,
+    );
+    let mut create = Mutation::new();
+    create.set_cond("@if(eq(len(existing), 0))");
+    create.set_set_nquads(format!(
+      "_:used <dgraph.type> \"{t}\" .\n_:used <{nonce_pred}> {nonce:?} .\n_:used <{expires_pred}> \"{expires}\" .",
+      t = Self::GDB_TYPE,
+      nonce_pred = Self::pred("nonce"),
+      nonce = nonce,
+      expires_pred = Self::pred("expires"),
+      expires = expires.format("%Y-%m-%dT%H:%M:%S"),
+    ));
+
+    // The nonce is an @id predicate, so registrations racing with the same
+    // challenge conflict and only one of them commits
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.upsert(q, vec![create]).await?;
+    let found: Existing = resp.try_into()?;
+    if !found.existing.is_empty() {
+      return Ok(false);
+    }
+    Ok(txn.commit().await.is_ok())
+  }
+
+  /**
+   * Delete the nonces of expired challenges, which can't be solved anymore
+   * anyway.
+   *
+   * @return number of deleted nonces
+   */
+  pub async fn delete_expired(conn: &dgraph::Client) -> Result<usize, Error> {
+    let now = chrono::Utc::now().naive_utc();
+    delete_older(conn, &Self::pred("expires"), now, None).await
+  }
+}
@@ -1,6 +1,10 @@
+use super::aggregates::*;
+use super::api_token::ApiToken;
+use super::invite_code::InviteCode;
+use super::refresh_token::{RefreshToken, RefreshTokenUse};
 use crate::db::*;
+use crate::password::hash_password;
 use crate::{is_email_regex, Settings};
-use bcrypt::{hash, DEFAULT_COST};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, TokenData, Validation};
 
 #[derive(PartialEq, Debug, Serialize, Deserialize, Setters, CopyGetters)]
@@ -29,6 +33,30 @@ pub struct User_ {
   pub show_avatars: bool,
   pub send_notifications_to_email: bool,
   pub matrix_user_id: Option<String>,
+  /// Incremented to invalidate all tokens issued before
+  #[serde(default)]
+  pub token_version: i32,
+  /// Base32 secret for two-factor authentication, set on enrollment
+  #[serde(default)]
+  pub totp_secret: Option<String>,
+  /// Only true once the user confirmed the enrollment with a valid code
+  #[serde(default)]
+  pub totp_enabled: bool,
+  /// Hashes of the unused recovery codes
+  #[serde(default)]
+  pub totp_recovery_codes: Vec<String>,
+  /// Time step of the last accepted one-time password, codes up to it are used
+  #[serde(default)]
+  pub totp_last_step: Option<i64>,
+  /// The current email address was confirmed through a verification link
+  #[serde(default)]
+  pub email_verified: bool,
+  /// Account is used by a bot, shown next to its name
+  #[serde(default)]
+  pub bot_account: bool,
+  /// Registered through an application which no admin approved yet
+  #[serde(default)]
+  pub registration_pending: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -76,6 +104,14 @@ impl From<UserForm> for User_ {
       show_avatars: form.show_avatars,
       send_notifications_to_email: form.send_notifications_to_email,
       matrix_user_id: form.matrix_user_id,
+      token_version: 0,
+      totp_secret: None,
+      totp_enabled: false,
+      totp_recovery_codes: Vec::new(),
+      totp_last_step: None,
+      email_verified: false,
+      bot_account: false,
+      registration_pending: false,
       published: chrono::Utc::now().naive_utc(),
     }
   }
@@ -84,22 +120,63 @@ impl From<UserForm> for User_ {
 impl User_ {
 
   /// DB type name
-  const GDB_TYPE: &'static str = "Site";
+  const GDB_TYPE: &'static str = "User";
 
   /**
    * Register new user
    */
   pub async fn register(conn: &dgraph::Client, form: &UserForm) -> Result<Self, Error> {
     let mut edited_user: User_ = form.clone().into();
-    let password_hash =
-      hash(&form.password_encrypted, DEFAULT_COST).expect("Couldn't hash password");
+    let password_hash = hash_password(&form.password_encrypted)?;
     
     edited_user.password_encrypted = password_hash;
-    let uid = create_node::<User_>(conn, &mut edited_user).await;
-    match uid {
-      Ok(i) => Ok(edited_user),
-      Err(e) => Err(e),
+    let mut mu = Mutation::new();
+    mu.set_set_json(&new_node_json(&edited_user, &UserAggregates::default())?)?;
+    let uids = mutate_with_counts(conn, mu, &CountChange::user_count(1)).await?;
+    edited_user.id = created_uid(&uids)?;
+    Ok(edited_user)
+  }
+
+  /**
+   * Register a user with an invite code, counting the use of the code in the
+   * same transaction.
+   *
+   * @return None if the code is invalid, expired or used up
+   */
+  pub async fn register_with_invite(
+    conn: &dgraph::Client,
+    form: &UserForm,
+    invite_code: &str,
+  ) -> Result<Option<Self>, Error> {
+    #[derive(Deserialize)]
+    struct Invite {
+      invite: Vec<serde_json::Value>,
     }
+
+    let mut edited_user: User_ = form.clone().into();
+    edited_user.password_encrypted = hash_password(&form.password_encrypted)?;
+
+    let (query, invite_nquads) = InviteCode::use_upsert(invite_code, chrono::Utc::now().naive_utc());
+    let mut create = Mutation::new();
+    create.set_set_json(&new_node_json(&edited_user, &UserAggregates::default())?)?;
+    let mut use_invite = Mutation::new();
+    use_invite.set_set_nquads(invite_nquads);
+
+    let resp = upsert_with_counts(
+      conn,
+      &query,
+      "@if(eq(len(invite), 1))",
+      vec![create, use_invite],
+      &CountChange::user_count(1),
+    )
+    .await?;
+    let uids = resp.uids.clone();
+    let found: Invite = resp.try_into()?;
+    if found.invite.is_empty() {
+      return Ok(None);
+    }
+    edited_user.id = created_uid(&uids)?;
+    Ok(Some(edited_user))
   }
 
   /**
@@ -110,16 +187,209 @@ impl User_ {
     user_id: i64,
     new_password: &str,
   ) -> Result<Self, Error> {
-    let password_hash = hash(new_password, DEFAULT_COST).expect("Couldn't hash password");
+    let password_hash = hash_password(new_password)?;
 
     let mut dict = serde_json::Map::new();
-    let pred_name = format!("{}.{}", Self::GDB_TYPE, "password_encrypted");
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "passwordEncrypted");
     dict.insert(pred_name, serde_json::Value::String(password_hash));
 
     update_node_dict::<Self>(conn, user_id,
       &serde_json::Value::Object(dict)).await
   }
 
+  /**
+   * Invalidate all access, refresh and API tokens of user. Used on password
+   * change, ban, account deletion and "log out all sessions".
+   */
+  pub async fn bump_token_version(conn: &dgraph::Client, user_id: i64) -> Result<Self, Error> {
+    let user = read_node::<Self>(conn, user_id).await?;
+    let token_version = user.token_version + 1;
+
+    let mut dict = serde_json::Map::new();
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "tokenVersion");
+    dict.insert(pred_name, serde_json::Value::from(token_version));
+
+    let updated_user = update_node_dict::<Self>(conn, user_id,
+      &serde_json::Value::Object(dict)).await?;
+
+    RefreshToken::delete_for_user(conn, user_id).await?;
+    ApiToken::delete_for_user(conn, user_id).await?;
+
+    Ok(updated_user)
+  }
+
+  /**
+   * Set two-factor authentication state of user. The recovery codes replace
+   * the previous ones.
+   */
+  pub async fn update_totp(
+    conn: &dgraph::Client,
+    user_id: i64,
+    totp_secret: Option<&str>,
+    totp_enabled: bool,
+    recovery_code_hashes: &[String],
+  ) -> Result<Self, Error> {
+    // Setting a list predicate adds to it, so remove the old codes first
+    let codes_pred = format!("{}.{}", Self::GDB_TYPE, "totpRecoveryCodes");
+    delete_edges(conn, Some(user_id), None, Some(&codes_pred)).await?;
+
+    let mut dict = serde_json::Map::new();
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "totpSecret"),
+      totp_secret.map_or(serde_json::Value::Null, serde_json::Value::from),
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "totpEnabled"),
+      serde_json::Value::Bool(totp_enabled),
+    );
+    dict.insert(codes_pred, serde_json::Value::from(recovery_code_hashes.to_vec()));
+
+    update_node_dict::<Self>(conn, user_id,
+      &serde_json::Value::Object(dict)).await
+  }
+
+  /**
+   * Mark the one-time passwords of user up to time step as used. Returns false
+   * if step was used already, also when a concurrent login used it first.
+   */
+  pub async fn use_totp_step(conn: &dgraph::Client, user_id: i64, step: i64) -> Result<bool, Error> {
+    #[derive(Deserialize)]
+    struct Unused {
+      unused: Vec<serde_json::Value>,
+    }
+
+    let pred = format!("{}.{}", Self::GDB_TYPE, "totpLastStep");
+    let q = format!(
+      r#"query {{
+        unused as unused(func: uid({id})) @filter(NOT has({pred}) OR lt({pred}, {step})) {{
+          uid
+        }}
+      }}"#,
+      id = user_id,
+      pred = pred,
+      step = step,
+    );
+    let mut mu = Mutation::new();
+    mu.set_cond("@if(eq(len(unused), 1))");
+    mu.set_set_nquads(format!("uid(unused) <{}> \"{}\" .", pred, step));
+
+    // Logins racing with the same code write the same predicate, so only one
+    // of them commits
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.upsert(q, vec![mu]).await?;
+    let found: Unused = resp.try_into()?;
+    if found.unused.is_empty() {
+      return Ok(false);
+    }
+    Ok(txn.commit().await.is_ok())
+  }
+
+  /**
+   * Set the email address of user and whether it is verified
+   */
+  pub async fn update_email(
+    conn: &dgraph::Client,
+    user_id: i64,
+    email: &str,
+    email_verified: bool,
+  ) -> Result<Self, Error> {
+    let mut dict = serde_json::Map::new();
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "email"),
+      serde_json::Value::from(email),
+    );
+    dict.insert(
+      format!("{}.{}", Self::GDB_TYPE, "emailVerified"),
+      serde_json::Value::Bool(email_verified),
+    );
+
+    update_node_dict::<Self>(conn, user_id,
+      &serde_json::Value::Object(dict)).await
+  }
+
+  /**
+   * Mark the email addresses of users from before email verification as
+   * verified, so they keep password reset. Users registered since always have
+   * email_verified set.
+   *
+   * @return number of updated users
+   */
+  pub async fn backfill_email_verified(conn: &dgraph::Client) -> Result<usize, Error> {
+    #[derive(Deserialize)]
+    struct Unset {
+      unset: Vec<serde_json::Value>,
+    }
+
+    let verified_pred = format!("{}.{}", Self::GDB_TYPE, "emailVerified");
+    let q = format!(
+      r#"query {{
+        unset as unset(func: type({t})) @filter(has({email_pred}) AND NOT has({verified_pred})) {{
+          uid
+        }}
+      }}"#,
+      t = Self::GDB_TYPE,
+      email_pred = format!("{}.{}", Self::GDB_TYPE, "email"),
+      verified_pred = verified_pred,
+    );
+    let mut mu = Mutation::new();
+    mu.set_cond("@if(gt(len(unset), 0))");
+    mu.set_set_nquads(format!("uid(unset) <{}> \"true\" .", verified_pred));
+
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.upsert(q, vec![mu]).await?;
+    let unset: Unset = resp.try_into()?;
+    txn.commit().await?;
+    Ok(unset.unset.len())
+  }
+
+  /**
+   * Mark user as bot account or not
+   */
+  pub async fn update_bot_account(
+    conn: &dgraph::Client,
+    user_id: i64,
+    bot_account: bool,
+  ) -> Result<Self, Error> {
+    let mut dict = serde_json::Map::new();
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "botAccount");
+    dict.insert(pred_name, serde_json::Value::Bool(bot_account));
+
+    update_node_dict::<Self>(conn, user_id,
+      &serde_json::Value::Object(dict)).await
+  }
+
+  /**
+   * Set whether user still waits for approval of their registration
+   */
+  pub async fn update_registration_pending(
+    conn: &dgraph::Client,
+    user_id: i64,
+    registration_pending: bool,
+  ) -> Result<Self, Error> {
+    let mut dict = serde_json::Map::new();
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "registrationPending");
+    dict.insert(pred_name, serde_json::Value::Bool(registration_pending));
+
+    update_node_dict::<Self>(conn, user_id,
+      &serde_json::Value::Object(dict)).await
+  }
+
+  /**
+   * Ban or unban user
+   */
+  pub async fn update_banned(
+    conn: &dgraph::Client,
+    user_id: i64,
+    banned: bool,
+  ) -> Result<Self, Error> {
+    let mut dict = serde_json::Map::new();
+    let pred_name = format!("{}.{}", Self::GDB_TYPE, "banned");
+    dict.insert(pred_name, serde_json::Value::Bool(banned));
+
+    update_node_dict::<Self>(conn, user_id,
+      &serde_json::Value::Object(dict)).await
+  }
+
   /**
    * Get User from name.
    */
@@ -150,6 +420,9 @@ pub struct Claims {
   pub id: i64,
   pub username: String,
   pub iss: String,
+  /// Expiry as unix timestamp
+  pub exp: i64,
+  pub token_version: i32,
   pub show_nsfw: bool,
   pub theme: String,
   pub default_sort_type: i16,
@@ -162,17 +435,24 @@ pub struct Claims {
 impl Claims {
 
   /**
-   * Decode and validate JSON Web Token
+   * Decode and validate JSON Web Token. Tokens issued before the user's token
+   * version was bumped are refused, by any server and after restarts.
    */
-  pub fn decode(jwt: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
-    let v = Validation {
-      validate_exp: false,
-      ..Validation::default()
-    };
+  pub async fn decode(conn: &dgraph::Client, jwt: &str) -> Result<TokenData<Claims>, Error> {
+    let token_data = Self::verify(jwt)?;
+    let user = read_node::<User_>(conn, token_data.claims.id).await?;
+    if token_data.claims.token_version != user.token_version {
+      failure::bail!("Token was revoked");
+    }
+    Ok(token_data)
+  }
+
+  /// Check signature and expiry of jwt only
+  fn verify(jwt: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
     decode::<Claims>(
       &jwt,
       &DecodingKey::from_secret(Settings::get().jwt_secret.as_ref()),
-      &v,
+      &Validation::default(),
     )
   }
 }
@@ -182,13 +462,26 @@ type Jwt = String;
 impl User_ {
 
   /**
-   * Generate JSON Web Token
+   * Generate short-lived JSON Web Token (access token)
    */
   pub fn jwt(&self) -> Jwt {
-    let my_claims = Claims {
+    encode(
+      &Header::default(),
+      &self.claims(),
+      &EncodingKey::from_secret(Settings::get().jwt_secret.as_ref()),
+    )
+    .unwrap()
+  }
+
+  /// Claims of a new access token for the user
+  pub fn claims(&self) -> Claims {
+    let expires = chrono::Utc::now() + chrono::Duration::minutes(Settings::get().access_token_minutes);
+    Claims {
       id: self.id,
       username: self.name.to_owned(),
       iss: self.fedi_name.to_owned(),
+      exp: expires.timestamp(),
+      token_version: self.token_version,
       show_nsfw: self.show_nsfw,
       theme: self.theme.to_owned(),
       default_sort_type: self.default_sort_type,
@@ -196,13 +489,7 @@ impl User_ {
       lang: self.lang.to_owned(),
       avatar: self.avatar.to_owned(),
       show_avatars: self.show_avatars.to_owned(),
-    };
-    encode(
-      &Header::default(),
-      &my_claims,
-      &EncodingKey::from_secret(Settings::get().jwt_secret.as_ref()),
-    )
-    .unwrap()
+    }
   }
 
   pub async fn find_by_username(conn: &dgraph::Client, username: &str) -> Result<Self, Error> {
@@ -231,8 +518,48 @@ impl User_ {
   }
 
   pub async fn find_by_jwt(conn: &dgraph::Client, jwt: &str) -> Result<Self, Error> {
-    let claims: Claims = Claims::decode(&jwt).expect("Invalid token").claims;
-    read_node::<Self>(conn, claims.id).await
+    let claims: Claims = Claims::verify(&jwt)?.claims;
+    let user = read_node::<Self>(conn, claims.id).await?;
+
+    if claims.token_version != user.token_version {
+      failure::bail!("Token was revoked");
+    }
+    Ok(user)
+  }
+
+  /**
+   * Issue a new access token and refresh token for a login session.
+   */
+  pub async fn create_session(&self, conn: &dgraph::Client) -> Result<(Jwt, String), Error> {
+    let (_node, refresh_token) =
+      RefreshToken::create_for_user(conn, self.id, self.token_version).await?;
+    Ok((self.jwt(), refresh_token))
+  }
+
+  /**
+   * Exchange a refresh token for a new session. The used refresh token can't be
+   * used again: if it is, either the client or someone who stole the token
+   * already refreshed, so all sessions of the user are revoked.
+   */
+  pub async fn refresh_session(
+    conn: &dgraph::Client,
+    refresh_token: &str,
+  ) -> Result<(Self, Jwt, String), Error> {
+    let token = match RefreshToken::rotate(conn, refresh_token).await? {
+      RefreshTokenUse::Rotated(token) => token,
+      RefreshTokenUse::Reused(token) => {
+        Self::bump_token_version(conn, token.user_id).await?;
+        failure::bail!("Refresh token was reused, revoked all sessions");
+      }
+    };
+
+    let user = read_node::<Self>(conn, token.user_id).await?;
+    if user.banned || token.token_version != user.token_version {
+      failure::bail!("Token was revoked");
+    }
+
+    let (jwt, new_refresh_token) = user.create_session(conn).await?;
+    Ok((user, jwt, new_refresh_token))
   }
 }
 
@@ -1,3 +1,4 @@
+use super::aggregates::edge_nquad;
 use crate::db::*;
 use super::comment::Comment;
 
@@ -42,9 +43,276 @@ impl From<UserMentionForm> for UserMention {
   }
 }
 
+impl UserMention {
+  /**
+   * Mention recipient in comment, or mark an existing mention read or unread
+   */
+  pub async fn create(conn: &dgraph::Client, form: &UserMentionForm) -> Result<Self, Error> {
+    let edge: Self = form.to_owned().into();
+    create_edge::<UserMention, UserMentionForm>(conn, &edge).await?;
+    Ok(edge)
+  }
+
+  /**
+   * Users mentioned in comment
+   */
+  pub async fn list_for_comment(
+    conn: &dgraph::Client,
+    for_comment_id: i64,
+  ) -> Result<Vec<Self>, Error> {
+    let mentions = mentions_from(conn, for_comment_id, Self::db_type_name()).await?;
+    Ok(
+      mentions
+        .into_iter()
+        .map(|m| UserMention {
+          recipient_id: m.recipient_id,
+          comment_id: for_comment_id,
+          read: m.read,
+          published: m.published,
+        })
+        .collect(),
+    )
+  }
+
+  pub async fn delete(
+    conn: &dgraph::Client,
+    for_comment_id: i64,
+    for_recipient_id: i64,
+  ) -> Result<usize, Error> {
+    delete_edges(
+      conn,
+      Some(for_comment_id),
+      Some(for_recipient_id),
+      Some(Self::db_type_name()),
+    )
+    .await
+  }
+}
 
 // impl CrudEdge<UserMentionForm> for UserMention {}
 
+//#############################################################################
+
+/**
+ * Edge <Post> <Post.MentionsUser> <User>, like UserMention for post bodies
+ */
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PostMention {
+  #[serde(skip)]
+  pub recipient_id: i64,
+  #[serde(skip)]
+  pub post_id: i64,
+  pub read: bool,
+  pub published: chrono::NaiveDateTime,
+}
+
+#[derive(Clone, Debug)]
+pub struct PostMentionForm {
+  pub recipient_id: i64,
+  pub post_id: i64,
+  pub read: Option<bool>,
+}
+
+impl Edge for PostMention {
+  fn from(&self) -> i64 {
+    self.post_id
+  }
+  fn to(&self) -> i64 {
+    self.recipient_id
+  }
+  fn db_type_name() -> &'static str {
+    "Post.MentionsUser"
+  }
+}
+
+impl From<PostMentionForm> for PostMention {
+  fn from(form: PostMentionForm) -> Self {
+    PostMention {
+      recipient_id: form.recipient_id,
+      post_id: form.post_id,
+      read: form.read.unwrap_or(false),
+      published: chrono::Utc::now().naive_utc(),
+    }
+  }
+}
+
+impl PostMention {
+  /**
+   * Mention recipient in post. This resets the facets of an existing mention,
+   * use mark_read to change only its read state.
+   */
+  pub async fn create(conn: &dgraph::Client, form: &PostMentionForm) -> Result<Self, Error> {
+    let edge: Self = form.to_owned().into();
+    create_edge::<PostMention, PostMentionForm>(conn, &edge).await?;
+    Ok(edge)
+  }
+
+  /**
+   * Mark the recipient's mention in post read or unread. Only the read facet
+   * changes, published is written back as it was.
+   */
+  pub async fn mark_read(
+    conn: &dgraph::Client,
+    for_post_id: i64,
+    for_recipient_id: i64,
+    read: bool,
+  ) -> Result<(), Error> {
+    let q = format!(
+      r#"
+      nodeList(func: uid({post_id})) {{
+        uid
+        mentions: {pred} @facets(read, published) @filter(uid({recipient_id})) {{
+          uid
+        }}
+      }}"#,
+      post_id = for_post_id,
+      pred = Self::db_type_name(),
+      recipient_id = for_recipient_id
+    );
+
+    // Read in the same txn as the write, so a racing write to the edge
+    // conflicts instead of being overwritten
+    let mut txn = conn.new_mutated_txn();
+    let resp = txn.query(q).await?;
+    let posts: NodeList<MentionSource> = resp.try_into()?;
+    let mention = match posts.all.into_iter().flat_map(|post| post.mentions).next() {
+      Some(mention) => mention,
+      None => return Ok(()),
+    };
+
+    let edge = PostMention {
+      recipient_id: for_recipient_id,
+      post_id: for_post_id,
+      read,
+      published: mention.published,
+    };
+    let mut mu = Mutation::new();
+    mu.set_set_nquads(edge_nquad(&edge)?);
+    txn.mutate(mu).await?;
+    txn.commit().await?;
+    Ok(())
+  }
+
+  /**
+   * Users mentioned in post
+   */
+  pub async fn list_for_post(conn: &dgraph::Client, for_post_id: i64) -> Result<Vec<Self>, Error> {
+    let mentions = mentions_from(conn, for_post_id, Self::db_type_name()).await?;
+    Ok(
+      mentions
+        .into_iter()
+        .map(|m| PostMention {
+          recipient_id: m.recipient_id,
+          post_id: for_post_id,
+          read: m.read,
+          published: m.published,
+        })
+        .collect(),
+    )
+  }
+
+  /**
+   * Posts mentioning recipient, newest first
+   */
+  pub async fn list_for_recipient(
+    conn: &dgraph::Client,
+    for_recipient_id: i64,
+    unread_only: bool,
+  ) -> Result<Vec<Self>, Error> {
+    let q = format!(
+      r#"
+      nodeList(func: has({pred})) @cascade {{
+        uid
+        mentions: {pred} @facets(read, published) @filter(uid({recipient_id})) {{
+          uid
+        }}
+      }}"#,
+      pred = Self::db_type_name(),
+      recipient_id = for_recipient_id
+    );
+
+    let txn = conn.new_read_only_txn();
+    let resp = txn.query(q).await?;
+
+    let posts: NodeList<MentionSource> = resp.try_into()?;
+    let mut mentions: Vec<Self> = posts
+      .all
+      .into_iter()
+      .flat_map(|post| {
+        let post_id = post.uid;
+        post.mentions.into_iter().map(move |m| PostMention {
+          recipient_id: m.recipient_id,
+          post_id,
+          read: m.read,
+          published: m.published,
+        })
+      })
+      .filter(|m| !unread_only || !m.read)
+      .collect();
+    mentions.sort_by(|a, b| b.published.cmp(&a.published));
+    Ok(mentions)
+  }
+
+  pub async fn delete(
+    conn: &dgraph::Client,
+    for_post_id: i64,
+    for_recipient_id: i64,
+  ) -> Result<usize, Error> {
+    delete_edges(
+      conn,
+      Some(for_post_id),
+      Some(for_recipient_id),
+      Some(Self::db_type_name()),
+    )
+    .await
+  }
+}
+
+/// Target of a mention edge, with the facets of the edge
+#[derive(Debug, Deserialize)]
+struct MentionTarget {
+  #[serde(rename = "uid", deserialize_with = "deserialize_number_from_string")]
+  recipient_id: i64,
+  #[serde(rename = "mentions|read", default)]
+  read: bool,
+  #[serde(rename = "mentions|published")]
+  published: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+struct MentionSource {
+  #[serde(deserialize_with = "deserialize_number_from_string")]
+  uid: i64,
+  #[serde(default)]
+  mentions: Vec<MentionTarget>,
+}
+
+/// Recipients of the mention edges pred out of node from_id
+async fn mentions_from(
+  conn: &dgraph::Client,
+  from_id: i64,
+  pred: &str,
+) -> Result<Vec<MentionTarget>, Error> {
+  let q = format!(
+    r#"
+    nodeList(func: uid({from_id})) {{
+      uid
+      mentions: {pred} @facets(read, published) {{
+        uid
+      }}
+    }}"#,
+    from_id = from_id,
+    pred = pred
+  );
+
+  let txn = conn.new_read_only_txn();
+  let resp = txn.query(q).await?;
+
+  let sources: NodeList<MentionSource> = resp.try_into()?;
+  Ok(sources.all.into_iter().flat_map(|source| source.mentions).collect())
+}
+
 // #[cfg(test)]
 // mod tests {
 //   use super::super::comment::*;
@@ -14,19 +14,22 @@ use dgraph_tonic::{Client, Query, Mutation, Mutate};
 use getset::{Setters, Getters, CopyGetters};
 
 pub mod common;
+pub use common::published::*;
 pub use common::traits::*;
 
 // NOTE: Alternative (subdir without module) is to declare .rs files
 //       as "pub mod file" and annotate with path macro attribute
 mod entity;
 pub use entity::{
-  category, comment, community, moderator, password_reset_request,
-  post, private_message, site, user, user_mention,
+  aggregates, api_token, applied_upgrade, block, category, comment, community, content_filter,
+  data_export, email_outbox, email_verification, external_identity, invite_code, job_lease,
+  moderator, oidc_login, password_reset_request, post, private_message, refresh_token,
+  registration_application, revision, site, used_captcha, user, user_mention,
 };
 
 mod query;
 pub use query::{
-  comment_view, community_view, moderator_views, post_view,
+  comment_tree, comment_view, community_view, moderator_views, post_view,
   private_message_view, site_view, user_mention_view, user_view,
 };
 
@@ -51,6 +54,7 @@ impl<T> MaybeOptional<T> for Option<T> {
 }
 
 
+/// Stored as i16 in user settings, so new sorts go at the end
 #[derive(EnumString, ToString, Debug, Serialize, Deserialize)]
 pub enum SortType {
   Hot,
@@ -60,6 +64,15 @@ pub enum SortType {
   TopMonth,
   TopYear,
   TopAll,
+  /// Posts by their latest comment, comments like Hot
+  Active,
+  /// Evenly split votes first, see controversy_rank
+  Controversial,
+  /// Posts by number of comments, comments like Top
+  MostComments,
+  /// Posts that have comments by their latest one, comments like New
+  NewComments,
+  Old,
 }
 
 #[derive(EnumString, ToString, Debug, Serialize, Deserialize)]
@@ -79,6 +92,11 @@ pub enum SearchType {
   Url,
 }
 
+/// Connect to the Dgraph cluster given in the settings.
+pub fn establish_connection(settings: &Settings) -> Result<dgraph::Client, Error> {
+  Ok(dgraph::Client::new(vec![settings.get_dgraph_url()])?)
+}
+
 pub fn fuzzy_search(q: &str) -> String {
   let replaced = q.replace(" ", "%");
   format!("%{}%", replaced)
@@ -91,12 +109,57 @@ pub fn limit_and_offset(page: Option<i64>, limit: Option<i64>) -> (i64, i64) {
   (limit, offset)
 }
 
+/**
+ * Rank for the Controversial sort: the total number of votes, raised to the
+ * ratio of the smaller to the larger side. Many votes split evenly rank
+ * highest, anything without votes on both sides ranks 0. Stored on posts and
+ * comments by recount and the refresh_ranks job, see db::aggregates.
+ */
+pub fn controversy_rank(upvotes: i64, downvotes: i64) -> f64 {
+  if upvotes <= 0 || downvotes <= 0 {
+    return 0.0;
+  }
+  let balance = upvotes.min(downvotes) as f64 / upvotes.max(downvotes) as f64;
+  ((upvotes + downvotes) as f64).powf(balance)
+}
+
+/**
+ * Rank for the Hot sort: the score, decaying with age. The refresh_ranks job
+ * stores it on recent posts and comments, see crate::jobs.
+ */
+pub fn hot_rank(score: i64, published: chrono::NaiveDateTime, now: chrono::NaiveDateTime) -> i32 {
+  let hours = (now - published).num_seconds().max(0) as f64 / 3600.0;
+  let votes = ((score + 3).max(1) as f64).log10();
+  (10000.0 * votes / (hours + 2.0).powf(1.8)).floor() as i32
+}
+
 #[cfg(test)]
 mod tests {
-  use super::fuzzy_search;
+  use super::{controversy_rank, fuzzy_search, hot_rank};
   #[test]
   fn test_fuzzy_search() {
     let test = "This is a fuzzy search";
     assert_eq!(fuzzy_search(test), "%This%is%a%fuzzy%search%".to_string());
   }
+
+  #[test]
+  fn test_controversy_rank() {
+    assert_eq!(controversy_rank(10, 0), 0.0);
+    assert_eq!(controversy_rank(0, 10), 0.0);
+    assert_eq!(controversy_rank(5, 5), 10.0);
+    assert_eq!(controversy_rank(10, 5), controversy_rank(5, 10));
+    // Even splits beat lopsided ones, more votes beat fewer
+    assert!(controversy_rank(50, 50) > controversy_rank(90, 10));
+    assert!(controversy_rank(50, 50) > controversy_rank(5, 5));
+  }
+
+  #[test]
+  fn test_hot_rank() {
+    let now = chrono::NaiveDate::from_ymd(2020, 6, 1).and_hms(12, 0, 0);
+    assert_eq!(hot_rank(1, now, now), 1728);
+    assert!(hot_rank(1, now - chrono::Duration::hours(5), now) < 1728);
+    assert!(hot_rank(20, now, now) > 1728);
+    // Heavily downvoted content bottoms out at 0 instead of going negative
+    assert_eq!(hot_rank(-10, now, now), 0);
+  }
 }
@@ -0,0 +1,332 @@
+/**
+ * Comment threads as trees, built from Comment.parent.
+ *
+ * Trees are cut off at a maximum depth and after a number of children per
+ * comment. Where comments were left out, a MoreComments cursor says which
+ * subtree to fetch next.
+ */
+use crate::db::comment_view::CommentView;
+use indextree::{Arena, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Depth of trees when the client doesn't ask for less
+pub const DEFAULT_MAX_DEPTH: usize = 6;
+/// Children shown per comment when the client doesn't ask for less
+pub const DEFAULT_MAX_CHILDREN: usize = 50;
+/// Upper bound for both limits
+const MAX_LIMIT: usize = 200;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TreeLimits {
+  pub max_depth: usize,
+  pub max_children: usize,
+}
+
+impl TreeLimits {
+  /// Limits asked for by a client, clamped to what the server allows
+  pub fn new(max_depth: Option<i64>, max_children: Option<i64>) -> Self {
+    let clamp = |limit: Option<i64>, default: usize| {
+      limit.map_or(default, |l| (l.max(1) as usize).min(MAX_LIMIT))
+    };
+    TreeLimits {
+      max_depth: clamp(max_depth, DEFAULT_MAX_DEPTH),
+      max_children: clamp(max_children, DEFAULT_MAX_CHILDREN),
+    }
+  }
+}
+
+impl Default for TreeLimits {
+  fn default() -> Self {
+    TreeLimits::new(None, None)
+  }
+}
+
+/// Cursor for comments left out of a tree
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MoreComments {
+  /// Comment whose children were left out, none for top level comments of the post
+  pub parent_id: Option<i32>,
+  /// Number of children already shown
+  pub offset: i64,
+  /// Number of children left out
+  pub count: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CommentNode {
+  pub comment: CommentView,
+  pub children: Vec<CommentNode>,
+  /// Set when children of this comment were left out
+  pub more: Option<MoreComments>,
+}
+
+/// Comments of one post, linked by parent
+pub struct CommentTree {
+  arena: Arena<CommentView>,
+  ids: HashMap<i32, NodeId>,
+  roots: Vec<NodeId>,
+}
+
+impl CommentTree {
+  /**
+   * Links comments to their parents, keeping the order they come in among
+   * siblings. Comments whose parent isn't in the list (removed, or by a
+   * blocked user) are shown at the top level, and so is the comment closing
+   * a cycle of parents in broken data.
+   */
+  pub fn new(comments: Vec<CommentView>) -> Self {
+    let mut arena = Arena::new();
+    let mut ids = HashMap::new();
+    let mut order = Vec::new();
+    for comment in comments {
+      let comment_id = comment.id;
+      let node_id = arena.new_node(comment);
+      ids.insert(comment_id, node_id);
+      order.push(node_id);
+    }
+
+    let mut roots = Vec::new();
+    for node_id in order {
+      let parent = arena[node_id]
+        .get()
+        .parent_id
+        .and_then(|parent_id| ids.get(&parent_id))
+        .copied();
+      // Fails for a comment that is its own parent or an ancestor of it
+      let appended =
+        parent.map_or(false, |parent| parent.checked_append(node_id, &mut arena).is_ok());
+      if !appended {
+        roots.push(node_id);
+      }
+    }
+
+    CommentTree { arena, ids, roots }
+  }
+
+  /**
+   * Top level comments of the post from offset on, with their replies.
+   */
+  pub fn top_level(
+    &self,
+    offset: usize,
+    limits: &TreeLimits,
+  ) -> (Vec<CommentNode>, Option<MoreComments>) {
+    self.nodes(self.roots.iter().copied(), None, offset, 1, limits)
+  }
+
+  /**
+   * Subtree rooted at comment_id, its children from offset on. None if the
+   * comment isn't in the tree.
+   */
+  pub fn subtree(
+    &self,
+    comment_id: i32,
+    offset: usize,
+    limits: &TreeLimits,
+  ) -> Option<CommentNode> {
+    let node_id = *self.ids.get(&comment_id)?;
+    let (children, more) = self.nodes(
+      node_id.children(&self.arena),
+      Some(comment_id),
+      offset,
+      1,
+      limits,
+    );
+    Some(CommentNode {
+      comment: self.arena[node_id].get().to_owned(),
+      children,
+      more,
+    })
+  }
+
+  /// Siblings from offset on, up to max_children, at depth
+  fn nodes(
+    &self,
+    siblings: impl Iterator<Item = NodeId>,
+    parent_id: Option<i32>,
+    offset: usize,
+    depth: usize,
+    limits: &TreeLimits,
+  ) -> (Vec<CommentNode>, Option<MoreComments>) {
+    let siblings: Vec<NodeId> = siblings.skip(offset).collect();
+    let nodes = siblings
+      .iter()
+      .take(limits.max_children)
+      .map(|&node_id| self.node(node_id, depth, limits))
+      .collect();
+    let more = more_comments(parent_id, offset, siblings.len(), limits.max_children);
+    (nodes, more)
+  }
+
+  fn node(&self, node_id: NodeId, depth: usize, limits: &TreeLimits) -> CommentNode {
+    let comment = self.arena[node_id].get().to_owned();
+    if depth >= limits.max_depth {
+      // Too deep, the whole subtree goes behind a cursor
+      let count = node_id.children(&self.arena).count();
+      return CommentNode {
+        more: more_comments(Some(comment.id), 0, count, 0),
+        comment,
+        children: Vec::new(),
+      };
+    }
+
+    let (children, more) = self.nodes(
+      node_id.children(&self.arena),
+      Some(comment.id),
+      0,
+      depth + 1,
+      limits,
+    );
+    CommentNode {
+      comment,
+      children,
+      more,
+    }
+  }
+}
+
+fn more_comments(
+  parent_id: Option<i32>,
+  offset: usize,
+  remaining: usize,
+  shown: usize,
+) -> Option<MoreComments> {
+  if remaining <= shown {
+    return None;
+  }
+  Some(MoreComments {
+    parent_id,
+    offset: (offset + shown) as i64,
+    count: (remaining - shown) as i64,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn comment(id: i32, parent_id: Option<i32>) -> CommentView {
+    CommentView {
+      id,
+      creator_id: 1,
+      post_id: 1,
+      parent_id,
+      content: format!("comment {}", id),
+      removed: false,
+      held: false,
+      read: false,
+      published: chrono::NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0),
+      updated: None,
+      deleted: false,
+      community_id: 1,
+      community_name: "main".into(),
+      banned: false,
+      banned_from_community: false,
+      creator_name: "user".into(),
+      creator_avatar: None,
+      score: 1,
+      upvotes: 1,
+      downvotes: 0,
+      hot_rank: 0,
+      user_id: None,
+      my_vote: None,
+      subscribed: None,
+      saved: None,
+    }
+  }
+
+  fn ids(nodes: &[CommentNode]) -> Vec<i32> {
+    nodes.iter().map(|n| n.comment.id).collect()
+  }
+
+  /// 1 -> 2 -> 3 -> 4, and 5 and 6 at the top level
+  fn thread() -> CommentTree {
+    CommentTree::new(vec![
+      comment(1, None),
+      comment(2, Some(1)),
+      comment(3, Some(2)),
+      comment(4, Some(3)),
+      comment(5, None),
+      comment(6, None),
+    ])
+  }
+
+  #[test]
+  fn test_builds_tree_in_order() {
+    let limits = TreeLimits::default();
+    let (nodes, more) = thread().top_level(0, &limits);
+    assert_eq!(ids(&nodes), vec![1, 5, 6]);
+    assert_eq!(ids(&nodes[0].children), vec![2]);
+    assert_eq!(ids(&nodes[0].children[0].children), vec![3]);
+    assert_eq!(more, None);
+  }
+
+  #[test]
+  fn test_depth_limit() {
+    let limits = TreeLimits::new(Some(2), None);
+    let (nodes, _) = thread().top_level(0, &limits);
+    let second = &nodes[0].children[0];
+    assert_eq!(second.comment.id, 2);
+    assert!(second.children.is_empty());
+    assert_eq!(
+      second.more,
+      Some(MoreComments {
+        parent_id: Some(2),
+        offset: 0,
+        count: 1
+      })
+    );
+  }
+
+  #[test]
+  fn test_child_limit_and_continuation() {
+    let tree = thread();
+    let limits = TreeLimits::new(None, Some(2));
+    let (nodes, more) = tree.top_level(0, &limits);
+    assert_eq!(ids(&nodes), vec![1, 5]);
+    let more = more.unwrap();
+    assert_eq!(more.parent_id, None);
+    assert_eq!((more.offset, more.count), (2, 1));
+
+    let (rest, more) = tree.top_level(more.offset as usize, &limits);
+    assert_eq!(ids(&rest), vec![6]);
+    assert_eq!(more, None);
+  }
+
+  #[test]
+  fn test_subtree_and_orphans() {
+    let tree = CommentTree::new(vec![comment(2, Some(1)), comment(3, Some(2))]);
+    let limits = TreeLimits::default();
+
+    // 1 isn't there, so 2 is shown at the top level
+    let (nodes, _) = tree.top_level(0, &limits);
+    assert_eq!(ids(&nodes), vec![2]);
+
+    let subtree = tree.subtree(2, 0, &limits).unwrap();
+    assert_eq!(ids(&subtree.children), vec![3]);
+    assert!(tree.subtree(1, 0, &limits).is_none());
+  }
+
+  #[test]
+  fn test_parent_cycle() {
+    // 1 and 2 are each other's parent, 3 is its own
+    let tree = CommentTree::new(vec![
+      comment(1, Some(2)),
+      comment(2, Some(1)),
+      comment(3, Some(3)),
+    ]);
+    let limits = TreeLimits::default();
+
+    let (nodes, _) = tree.top_level(0, &limits);
+    assert_eq!(ids(&nodes), vec![2, 3]);
+    assert_eq!(ids(&nodes[0].children), vec![1]);
+  }
+
+  #[test]
+  fn test_limits_are_clamped() {
+    let limits = TreeLimits::new(Some(0), Some(100_000));
+    assert_eq!(limits.max_depth, 1);
+    assert_eq!(limits.max_children, MAX_LIMIT);
+  }
+}
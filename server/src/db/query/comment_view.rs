@@ -1,4 +1,5 @@
 use crate::db::*;
+use crate::db::block::BlockList;
 use dgraph_monkey::builder::{
   QueryBuilder, DgraphFunction as DFn
 };
@@ -14,6 +15,7 @@ pub struct CommentView {
   pub parent_id: Option<i32>,
   pub content: String,
   pub removed: bool,
+  pub held: bool,
   pub read: bool,
   pub published: chrono::NaiveDateTime,
   pub updated: Option<chrono::NaiveDateTime>,
@@ -45,6 +47,8 @@ pub struct CommentQueryBuilder<'a> {
   search_term: Option<String>,
   my_user_id: Option<i32>,
   saved_only: bool,
+  held_only: bool,
+  subtree_of: Option<(i32, usize)>,
   page: Option<i64>,
   limit: Option<i64>,
 }
@@ -110,6 +114,8 @@ impl<'a> CommentQueryBuilder<'a> {
       search_term: None,
       my_user_id: None,
       saved_only: false,
+      held_only: false,
+      subtree_of: None,
       page: None,
       limit: None,
     }
@@ -155,6 +161,18 @@ impl<'a> CommentQueryBuilder<'a> {
     self
   }
 
+  /// Only the comments held by a content filter, for the mod queue
+  pub fn held_only(mut self, held_only: bool) -> Self {
+    self.held_only = held_only;
+    self
+  }
+
+  /// Only the comment and its replies down to depth, to load more of a thread
+  pub fn subtree_of(mut self, comment_id: i32, depth: usize) -> Self {
+    self.subtree_of = Some((comment_id, depth));
+    self
+  }
+
   pub fn page<T: MaybeOptional<i64>>(mut self, page: T) -> Self {
     self.page = page.get_optional();
     self
@@ -237,7 +255,22 @@ impl<'a> CommentQueryBuilder<'a> {
                                        .collect::<Vec<_>>()
                                        .join(", ");
 
-    let candidates = format!("uid({})", uid_filters);
+    // The comment and its replies, level by level, i.e.:
+    // [Comment] <Comment.children> [Comment] <Comment.children> [Comment] ...
+    // The other filters are about whole posts, this already is a part of one.
+    let candidates = match self.subtree_of {
+      Some((comment_id, depth)) => {
+        let levels = (0..=depth).map(|i| format!("subtree_{}", i)).collect::<Vec<_>>();
+        let var = query.add_query_var(Some(levels[0].to_owned()))
+                       .root_query(DFn::uid(comment_id));
+        for level in &levels[1..] {
+          var.edge_as("Comment.children", level);
+        }
+        var.pop(depth);
+        format!("uid({})", levels.join(", "))
+      }
+      None => format!("uid({})", uid_filters),
+    };
     query = query.root_query(DFn::uid(candidates));
 
     // Hot rank calculation
@@ -247,8 +280,8 @@ impl<'a> CommentQueryBuilder<'a> {
     // Filter by data and and order/sort
     query = match self.sort {
       SortType::Hot => query
-        .order_by(hot_rank.desc())
-        .then_order_by(published.desc()),
+        .order_by("hotRank", "desc")
+        .then_order_by("published", "desc"),
       SortType::New => query.order_by("published", "desc"),
       SortType::TopAll => query.order_by("score", "desc"),
       SortType::TopYear => query
@@ -267,7 +300,17 @@ impl<'a> CommentQueryBuilder<'a> {
         .filter("gt", "published",
         (naive_now() - chrono::Duration::days(1)).to_string())
           .order_by("score", "desc"),
-      // _ => query.order_by(published.desc()),
+      // Comments have no replies of their own to sort by, these fall back
+      // to the closest sort for comments
+      SortType::Active => query
+        .order_by("hotRank", "desc")
+        .then_order_by("published", "desc"),
+      SortType::Controversial => query
+        .order_by("controversyRank", "desc")
+        .then_order_by("published", "desc"),
+      SortType::MostComments => query.order_by("score", "desc"),
+      SortType::NewComments => query.order_by("published", "desc"),
+      SortType::Old => query.order_by("published", "asc"),
     };
 
     
@@ -356,8 +399,32 @@ impl<'a> CommentQueryBuilder<'a> {
                                        .collect::<Vec<_>>()
                                        .join(", ");
 
+    // Comments of blocked users, and in blocked communities.
+    // Added after counting the filters, they are excluded from the candidates.
+    if let Some(my_user_id) = self.my_user_id {
+      query.add_query_var(None)
+           .root_query(DFn::uid(my_user_id))
+           .edge("User.blocksUser")
+           .edge_as("User.comments", "blocked_1")
+           .pop(2);
+      query.add_query_var(None)
+           .root_query(DFn::uid(my_user_id))
+           .edge("User.blocksCommunity")
+           .edge("Community.posts")
+           .edge_as("Post.comments", "blocked_2")
+           .pop(3);
+    }
+
     let candidates = format!("uid({})", uid_filters);
     query = query.root_query(DFn::uid(candidates));
+    if self.my_user_id.is_some() {
+      query.filter_with("NOT uid(blocked_1, blocked_2)");
+    }
+    // Like removed ones, held comments are hidden on the front side, the mod
+    // queue lists only them
+    if self.held_only {
+      query.filter_with("eq(Comment.held, true)");
+    }
 
     // Hot rank calculation
     // https://github.com/LemmyNet/lemmy/blob/397f65c81ef17f4c7e5e2847155347ae1377e25b/server/migrations/2019-03-30-212058_create_post_view/up.sql
@@ -366,8 +433,8 @@ impl<'a> CommentQueryBuilder<'a> {
     // Filter by data and and order/sort
     query = match self.sort {
       SortType::Hot => query
-        .order_by(hot_rank.desc())
-        .then_order_by(published.desc()),
+        .order_by("hotRank", "desc")
+        .then_order_by("published", "desc"),
       SortType::New => query.order_by("published", "desc"),
       SortType::TopAll => query.order_by("score", "desc"),
       SortType::TopYear => query
@@ -386,7 +453,17 @@ impl<'a> CommentQueryBuilder<'a> {
         .filter("gt", "published",
         (naive_now() - chrono::Duration::days(1)).to_string())
           .order_by("score", "desc"),
-      // _ => query.order_by(published.desc()),
+      // Comments have no replies of their own to sort by, these fall back
+      // to the closest sort for comments
+      SortType::Active => query
+        .order_by("hotRank", "desc")
+        .then_order_by("published", "desc"),
+      SortType::Controversial => query
+        .order_by("controversyRank", "desc")
+        .then_order_by("published", "desc"),
+      SortType::MostComments => query.order_by("score", "desc"),
+      SortType::NewComments => query.order_by("published", "desc"),
+      SortType::Old => query.order_by("published", "asc"),
     };
 
     
@@ -401,6 +478,29 @@ impl<'a> CommentQueryBuilder<'a> {
   }
 }
 
+impl CommentView {
+  pub fn read(
+    conn: &dgraph_tonic::Client,
+    from_comment_id: i32,
+    my_user_id: Option<i32>,
+  ) -> Result<Self, Error> {
+    use super::comment_view::comment_mview::dsl::*;
+    use diesel::prelude::*;
+
+    let mut query = comment_mview.into_boxed();
+
+    query = query.filter(id.eq(from_comment_id));
+
+    if let Some(my_user_id) = my_user_id {
+      query = query.filter(user_id.eq(my_user_id));
+    } else {
+      query = query.filter(user_id.is_null());
+    };
+
+    query.first::<Self>(conn)
+  }
+}
+
 
 #[derive(
   PartialEq, Debug, Serialize, Deserialize, Clone,
@@ -485,11 +585,14 @@ impl<'a> ReplyQueryBuilder<'a> {
 
     let mut query = self.query;
 
+    let blocks = BlockList::for_user(self.conn, self.for_user_id.into())?;
     query = query
       .filter(user_id.eq(self.for_user_id))
       .filter(recipient_id.eq(self.for_user_id))
       .filter(deleted.eq(false))
-      .filter(removed.eq(false));
+      .filter(removed.eq(false))
+      .filter(creator_id.ne_all(blocks.view_user_ids()))
+      .filter(community_id.ne_all(blocks.view_community_ids()));
 
     if self.unread_only {
       query = query.filter(read.eq(false));
@@ -511,6 +614,10 @@ impl<'a> ReplyQueryBuilder<'a> {
       SortType::TopDay => query
         .filter(published.gt(now - 1.days()))
         .order_by(score.desc()),
+      SortType::Controversial => query
+        .order_by(controversy_rank.desc())
+        .then_order_by(published.desc()),
+      SortType::Old => query.order_by(published.asc()),
       _ => query.order_by(published.desc()),
     };
 
@@ -577,6 +684,7 @@ mod tests {
       body: None,
       community_id: inserted_community.id,
       removed: None,
+      held: None,
       deleted: None,
       locked: None,
       stickied: None,
@@ -596,6 +704,7 @@ mod tests {
       post_id: inserted_post.id,
       parent_id: None,
       removed: None,
+      held: None,
       deleted: None,
       read: None,
       updated: None,
@@ -621,6 +730,7 @@ mod tests {
       community_name: inserted_community.name.to_owned(),
       parent_id: None,
       removed: false,
+      held: false,
       deleted: false,
       read: false,
       banned: false,
@@ -648,6 +758,7 @@ mod tests {
       community_name: inserted_community.name.to_owned(),
       parent_id: None,
       removed: false,
+      held: false,
       deleted: false,
       read: false,
       banned: false,
@@ -119,6 +119,7 @@ impl<'a> CommunityQueryBuilder<'a> {
           .filter(user_id.is_null())
       }
       SortType::New => query = query.order_by(published.desc()).filter(user_id.is_null()),
+      SortType::Old => query = query.order_by(published.asc()).filter(user_id.is_null()),
       SortType::TopAll => match self.from_user_id {
         Some(from_user_id) => {
           query = query
@@ -1,6 +1,7 @@
 /**
  * Queries and views of the database.
  */
+pub mod comment_tree;
 pub mod comment_view;
 pub mod community_view;
 pub mod moderator_views;
@@ -1,5 +1,6 @@
 use super::post_view::post_mview::BoxedQuery;
 use crate::db::*;
+use crate::db::block::BlockList;
 
 
 // The faked schema since diesel doesn't do views
@@ -18,6 +19,7 @@ pub struct PostView {
   pub creator_id: i32,
   pub community_id: i32,
   pub removed: bool,
+  pub held: bool,
   pub locked: bool,
   pub published: chrono::NaiveDateTime,
   pub updated: Option<chrono::NaiveDateTime>,
@@ -62,6 +64,7 @@ pub struct PostQueryBuilder<'a> {
   show_nsfw: bool,
   saved_only: bool,
   unread_only: bool,
+  held_only: bool,
   page: Option<i64>,
   limit: Option<i64>,
 }
@@ -85,6 +88,7 @@ impl<'a> PostQueryBuilder<'a> {
       show_nsfw: true,
       saved_only: false,
       unread_only: false,
+      held_only: false,
       page: None,
       limit: None,
     }
@@ -140,6 +144,12 @@ impl<'a> PostQueryBuilder<'a> {
     self
   }
 
+  /// Only the posts held by a content filter, for the mod queue
+  pub fn held_only(mut self, held_only: bool) -> Self {
+    self.held_only = held_only;
+    self
+  }
+
   pub fn page<T: MaybeOptional<i64>>(mut self, page: T) -> Self {
     self.page = page.get_optional();
     self
@@ -193,11 +203,29 @@ impl<'a> PostQueryBuilder<'a> {
       SortType::TopDay => query
         .filter(published.gt(now - 1.days()))
         .then_order_by(score.desc()),
+      SortType::Active => query
+        .then_order_by(newest_activity_time.desc())
+        .then_order_by(published.desc()),
+      SortType::Controversial => query
+        .then_order_by(controversy_rank.desc())
+        .then_order_by(published.desc()),
+      SortType::MostComments => query
+        .then_order_by(number_of_comments.desc())
+        .then_order_by(published.desc()),
+      SortType::NewComments => query
+        .filter(number_of_comments.gt(0))
+        .then_order_by(newest_activity_time.desc()),
+      SortType::Old => query.then_order_by(published.asc()),
     };
 
     // The view lets you pass a null user_id, if you're not logged in
     query = if let Some(my_user_id) = self.my_user_id {
-      query.filter(user_id.eq(my_user_id))
+      // Hide posts of blocked users and communities
+      let blocks = BlockList::for_user(self.conn, my_user_id.into())?;
+      query
+        .filter(user_id.eq(my_user_id))
+        .filter(creator_id.ne_all(blocks.view_user_ids()))
+        .filter(community_id.ne_all(blocks.view_community_ids()))
     } else {
       query.filter(user_id.is_null())
     };
@@ -228,6 +256,14 @@ impl<'a> PostQueryBuilder<'a> {
       query = query.filter(read.eq(false));
     };
 
+    // Held posts are listed in the mod queue, apart from removed ones, and to
+    // their creator
+    if self.held_only {
+      query = query.filter(held.eq(true));
+    } else if self.for_creator_id.is_none() {
+      query = query.filter(held.eq(false));
+    }
+
     let (limit, offset) = limit_and_offset(self.page, self.limit);
     query = query
       .limit(limit)
@@ -322,6 +358,7 @@ mod tests {
       creator_id: inserted_user.id,
       community_id: inserted_community.id,
       removed: None,
+      held: None,
       deleted: None,
       locked: None,
       stickied: None,
@@ -372,6 +409,7 @@ mod tests {
       banned_from_community: false,
       community_id: inserted_community.id,
       removed: false,
+      held: false,
       deleted: false,
       locked: false,
       stickied: false,
@@ -405,6 +443,7 @@ mod tests {
       url: None,
       body: None,
       removed: false,
+      held: false,
       deleted: false,
       locked: false,
       stickied: false,
@@ -1,4 +1,5 @@
 use crate::db::*;
+use crate::db::block::BlockList;
 
 
 // The faked schema since diesel doesn't do views
@@ -29,6 +30,7 @@ pub struct PrivateMessageQueryBuilder<'a> {
   query: super::private_message_view::private_message_mview::BoxedQuery<'a, Pg>,
   for_recipient_id: i32,
   unread_only: bool,
+  show_blocked: bool,
   page: Option<i64>,
   limit: Option<i64>,
 }
@@ -44,6 +46,7 @@ impl<'a> PrivateMessageQueryBuilder<'a> {
       query,
       for_recipient_id,
       unread_only: false,
+      show_blocked: false,
       page: None,
       limit: None,
     }
@@ -54,6 +57,12 @@ impl<'a> PrivateMessageQueryBuilder<'a> {
     self
   }
 
+  /// Include messages from users the recipient blocked, eg for their data export
+  pub fn show_blocked(mut self, show_blocked: bool) -> Self {
+    self.show_blocked = show_blocked;
+    self
+  }
+
   pub fn page<T: MaybeOptional<i64>>(mut self, page: T) -> Self {
     self.page = page.get_optional();
     self
@@ -84,6 +93,11 @@ impl<'a> PrivateMessageQueryBuilder<'a> {
       )
     }
 
+    if !self.show_blocked {
+      let blocks = BlockList::for_user(self.conn, self.for_recipient_id.into())?;
+      query = query.filter(creator_id.ne_all(blocks.view_user_ids()));
+    }
+
     let (limit, offset) = limit_and_offset(self.page, self.limit);
 
     query
@@ -15,6 +15,13 @@ pub struct SiteView {
   pub enable_downvotes: bool,
   pub open_registration: bool,
   pub enable_nsfw: bool,
+  pub require_totp_for_mods: bool,
+  pub require_email_verification: bool,
+  pub require_application: bool,
+  pub application_question: Option<String>,
+  pub captcha_enabled: bool,
+  pub captcha_difficulty: String,
+  pub public_edit_history: bool,
   pub creator_name: String,
   pub creator_avatar: Option<String>,
   pub number_of_users: i64,
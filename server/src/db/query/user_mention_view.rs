@@ -1,4 +1,5 @@
 use crate::db::*;
+use crate::db::block::BlockList;
 
 
 // The faked schema since diesel doesn't do views
@@ -93,9 +94,12 @@ impl<'a> UserMentionQueryBuilder<'a> {
       query = query.filter(read.eq(false));
     }
 
+    let blocks = BlockList::for_user(self.conn, self.for_user_id.into())?;
     query = query
       .filter(user_id.eq(self.for_user_id))
-      .filter(recipient_id.eq(self.for_user_id));
+      .filter(recipient_id.eq(self.for_user_id))
+      .filter(creator_id.ne_all(blocks.view_user_ids()))
+      .filter(community_id.ne_all(blocks.view_community_ids()));
 
     query = match self.sort {
       SortType::Hot => query
@@ -115,7 +119,11 @@ impl<'a> UserMentionQueryBuilder<'a> {
       SortType::TopDay => query
         .filter(published.gt(now - 1.days()))
         .order_by(score.desc()),
-      // _ => query.order_by(published.desc()),
+      SortType::Controversial => query
+        .order_by(controversy_rank.desc())
+        .then_order_by(published.desc()),
+      SortType::Old => query.order_by(published.asc()),
+      _ => query.order_by(published.desc()),
     };
 
     let (limit, offset) = limit_and_offset(self.page, self.limit);
@@ -18,6 +18,7 @@ pub struct UserView {
   pub fedi_name: String,
   pub admin: bool,
   pub banned: bool,
+  pub bot_account: bool,
   pub show_avatars: bool,
   pub send_notifications_to_email: bool,
   pub published: chrono::NaiveDateTime,
@@ -96,6 +97,9 @@ impl<'a> UserQueryBuilder<'a> {
       SortType::TopDay => query
         .filter(published.gt(now - 1.days()))
         .order_by(comment_score.desc()),
+      SortType::Old => query.order_by(published.asc()),
+      // Users have no votes or comments of their own to sort by
+      _ => query.order_by(comment_score.desc()),
     };
 
     let (limit, offset) = limit_and_offset(self.page, self.limit);
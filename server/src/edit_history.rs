@@ -0,0 +1,270 @@
+/**
+ * Edit history of posts and comments.
+ *
+ * Authors, moderators of the community and admins see every earlier version
+ * with its editor. Everyone else only gets to see the lines each edit changed,
+ * only if the site turned on public_edit_history, and never for removed,
+ * deleted or held content.
+ */
+use crate::db::{
+  comment::Comment,
+  community::CommunityModerator,
+  post::Post,
+  revision::{CommentRevision, PostRevision},
+  user::User_,
+  *,
+};
+use dgraph_tonic as dgraph;
+use failure::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, juniper::GraphQLEnum)]
+pub enum DiffKind {
+  Unchanged,
+  Added,
+  Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
+pub struct DiffLine {
+  pub kind: DiffKind,
+  pub text: String,
+}
+
+/// One edit: the version it replaced and what it changed
+#[derive(Debug, Clone, PartialEq, juniper::GraphQLObject)]
+pub struct EditRevision {
+  /// Null unless the viewer may see the full history
+  pub editor_id: Option<juniper::ID>,
+  pub edited: chrono::NaiveDateTime,
+  /// The replaced version, null unless the viewer may see the full history
+  pub name: Option<String>,
+  pub url: Option<String>,
+  pub body: Option<String>,
+  /// From the replaced version to the one after it
+  pub diff: Vec<DiffLine>,
+}
+
+/// Line by line changes from old to new
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+  diff::lines(old, new)
+    .into_iter()
+    .map(|line| match line {
+      diff::Result::Left(text) => DiffLine {
+        kind: DiffKind::Removed,
+        text: text.to_owned(),
+      },
+      diff::Result::Both(text, _) => DiffLine {
+        kind: DiffKind::Unchanged,
+        text: text.to_owned(),
+      },
+      diff::Result::Right(text) => DiffLine {
+        kind: DiffKind::Added,
+        text: text.to_owned(),
+      },
+    })
+    .collect()
+}
+
+/// The diff as shown to the viewer: without `full`, only the changed lines
+fn visible_diff(diff: Vec<DiffLine>, full: bool) -> Vec<DiffLine> {
+  if full {
+    return diff;
+  }
+  diff
+    .into_iter()
+    .filter(|line| line.kind != DiffKind::Unchanged)
+    .collect()
+}
+
+/// A post as one document, so a single diff shows changes to all fields
+fn post_text(name: &str, url: &Option<String>, body: &Option<String>) -> String {
+  let mut parts = vec![name];
+  parts.extend(url.as_deref());
+  parts.extend(body.as_deref());
+  parts.join("\n\n")
+}
+
+/**
+ * Edits of post, oldest first. Without `full`, only dates and the changed
+ * lines of the diffs are filled in.
+ */
+pub fn post_history(post: &Post, revisions: &[PostRevision], full: bool) -> Vec<EditRevision> {
+  let current = post_text(&post.name, &post.url, &post.body);
+  revisions
+    .iter()
+    .enumerate()
+    .map(|(i, revision)| {
+      let next = match revisions.get(i + 1) {
+        Some(next) => post_text(&next.name, &next.url, &next.body),
+        None => current.to_owned(),
+      };
+      EditRevision {
+        editor_id: if full { Some(revision.editor_id.to_string().into()) } else { None },
+        edited: revision.published,
+        name: if full { Some(revision.name.to_owned()) } else { None },
+        url: if full { revision.url.to_owned() } else { None },
+        body: if full { revision.body.to_owned() } else { None },
+        diff: visible_diff(
+          diff_lines(&post_text(&revision.name, &revision.url, &revision.body), &next),
+          full,
+        ),
+      }
+    })
+    .collect()
+}
+
+/**
+ * Edits of comment, oldest first. See post_history.
+ */
+pub fn comment_history(
+  comment: &Comment,
+  revisions: &[CommentRevision],
+  full: bool,
+) -> Vec<EditRevision> {
+  revisions
+    .iter()
+    .enumerate()
+    .map(|(i, revision)| {
+      let next = revisions
+        .get(i + 1)
+        .map_or(&comment.content, |next| &next.content);
+      EditRevision {
+        editor_id: if full { Some(revision.editor_id.to_string().into()) } else { None },
+        edited: revision.published,
+        name: None,
+        url: None,
+        body: if full { Some(revision.content.to_owned()) } else { None },
+        diff: visible_diff(diff_lines(&revision.content, next), full),
+      }
+    })
+    .collect()
+}
+
+/**
+ * Whether viewer may see earlier versions and editors of content by creator
+ * in community: the author, moderators of the community and admins may.
+ */
+pub async fn can_view_full_history(
+  conn: &dgraph::Client,
+  viewer_id: Option<i64>,
+  creator_id: i64,
+  community_id: i64,
+) -> Result<bool, Error> {
+  let viewer_id = match viewer_id {
+    Some(viewer_id) => viewer_id,
+    None => return Ok(false),
+  };
+  if viewer_id == creator_id {
+    return Ok(true);
+  }
+  if CommunityModerator::is_moderator(conn, community_id, viewer_id).await? {
+    return Ok(true);
+  }
+  Ok(read_node::<User_>(conn, viewer_id).await?.admin)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::NaiveDate;
+
+  fn post(name: &str, body: &str) -> Post {
+    Post {
+      id: 1,
+      name: name.into(),
+      url: None,
+      body: Some(body.into()),
+      creator_id: 2,
+      community_id: 3,
+      removed: false,
+      held: false,
+      locked: false,
+      published: NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0),
+      updated: Some(NaiveDate::from_ymd(2020, 1, 3).and_hms(0, 0, 0)),
+      deleted: false,
+      nsfw: false,
+      stickied: false,
+      embed_title: None,
+      embed_description: None,
+      embed_html: None,
+      thumbnail_url: None,
+    }
+  }
+
+  fn revision(name: &str, body: &str, day: u32) -> PostRevision {
+    PostRevision {
+      id: 10 + i64::from(day),
+      post_id: 1,
+      name: name.into(),
+      url: None,
+      body: Some(body.into()),
+      editor_id: 4,
+      published: NaiveDate::from_ymd(2020, 1, day).and_hms(0, 0, 0),
+    }
+  }
+
+  #[test]
+  fn test_diff_lines() {
+    let diff = diff_lines("one\ntwo\nthree", "one\n2\nthree");
+    assert_eq!(
+      diff,
+      vec![
+        DiffLine { kind: DiffKind::Unchanged, text: "one".into() },
+        DiffLine { kind: DiffKind::Removed, text: "two".into() },
+        DiffLine { kind: DiffKind::Added, text: "2".into() },
+        DiffLine { kind: DiffKind::Unchanged, text: "three".into() },
+      ]
+    );
+  }
+
+  #[test]
+  fn test_post_history_diffs_against_next_version() {
+    let revisions = vec![revision("Title", "first", 1), revision("Title", "second", 2)];
+    let history = post_history(&post("New title", "second"), &revisions, true);
+
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0].body.as_deref(), Some("first"));
+    assert!(history[0]
+      .diff
+      .contains(&DiffLine { kind: DiffKind::Added, text: "second".into() }));
+    // The last edit only changed the title
+    let changed: Vec<&DiffLine> = history[1]
+      .diff
+      .iter()
+      .filter(|line| line.kind != DiffKind::Unchanged)
+      .collect();
+    assert_eq!(
+      changed,
+      vec![
+        &DiffLine { kind: DiffKind::Removed, text: "Title".into() },
+        &DiffLine { kind: DiffKind::Added, text: "New title".into() },
+      ]
+    );
+  }
+
+  #[test]
+  fn test_public_history_hides_versions() {
+    let revisions = vec![revision("Title", "first", 1)];
+    let history = post_history(&post("Title", "second"), &revisions, false);
+
+    assert_eq!(history[0].editor_id, None);
+    assert_eq!(history[0].name, None);
+    assert_eq!(history[0].body, None);
+    assert!(!history[0].diff.is_empty());
+  }
+
+  #[test]
+  fn test_public_history_only_has_changed_lines() {
+    let revisions = vec![revision("Title", "first", 1)];
+    let history = post_history(&post("Title", "second"), &revisions, false);
+
+    // The unchanged title of the old version isn't in the public diff
+    assert_eq!(
+      history[0].diff,
+      vec![
+        DiffLine { kind: DiffKind::Removed, text: "first".into() },
+        DiffLine { kind: DiffKind::Added, text: "second".into() },
+      ]
+    );
+  }
+}
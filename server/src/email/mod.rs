@@ -0,0 +1,132 @@
+/**
+ * Outbound email.
+ *
+ * Requests render an `EmailTemplate` and queue the result as an `OutboxEmail`
+ * node. The send_emails job delivers due emails through the configured
+ * transport and retries failed sends with backoff, so a slow or unreachable
+ * mail server neither holds up requests nor loses emails.
+ */
+use crate::db::email_outbox::{OutboxEmail, OutboxEmailForm};
+use crate::naive_now;
+use crate::settings::Settings;
+use actix_web::web;
+use dgraph_tonic as dgraph;
+use failure::Error;
+use log::{error, warn};
+
+pub mod template;
+mod transport;
+
+pub use template::{EmailTemplate, RenderedEmail};
+
+/// Attempts before an email is given up on
+const MAX_ATTEMPTS: i32 = 10;
+/// Wait before the first retry, doubled for each further retry
+const FIRST_RETRY_MINUTES: i64 = 1;
+/// Emails sent by one run of the send_emails job at most
+const SEND_BATCH_SIZE: usize = 50;
+/// How long sent and failed emails are kept
+pub const KEEP_DAYS: i64 = 7;
+
+/**
+ * Queue template for a recipient, rendered in their language (user setting
+ * lang). Fails with "no_email_setup" if the instance can't send email.
+ */
+pub async fn queue(
+  conn: &dgraph::Client,
+  to_email: &str,
+  to_name: &str,
+  lang: &str,
+  template: &EmailTemplate<'_>,
+) -> Result<OutboxEmail, Error> {
+  let settings = Settings::get();
+  if settings.email.is_none() {
+    bail!("no_email_setup");
+  }
+
+  let rendered = template.render(lang, &settings.hostname);
+  let form = OutboxEmailForm {
+    to_email: to_email.to_owned(),
+    to_name: to_name.to_owned(),
+    subject: rendered.subject,
+    text_body: rendered.text,
+    html_body: rendered.html,
+  };
+  OutboxEmail::create(conn, &form).await
+}
+
+/// Wait before the next attempt after a failed one, None to give up
+pub fn retry_delay(attempts: i32) -> Option<chrono::Duration> {
+  if attempts >= MAX_ATTEMPTS {
+    return None;
+  }
+  let doublings = (attempts - 1).max(0) as u32;
+  Some(chrono::Duration::minutes(FIRST_RETRY_MINUTES * 2i64.pow(doublings)))
+}
+
+/**
+ * Send the queued emails that are due, see the send_emails job.
+ *
+ * @return summary of the run
+ */
+pub async fn send_due(conn: &dgraph::Client) -> Result<String, Error> {
+  let settings = Settings::get();
+  let config = match &settings.email {
+    Some(config) => config.to_owned(),
+    None => return Ok("No email setup".into()),
+  };
+
+  let mut sent = 0;
+  let mut failed = 0;
+  for email in OutboxEmail::list_due(conn, naive_now(), SEND_BATCH_SIZE).await? {
+    let id = email.id;
+    let attempts = email.attempts + 1;
+    let config = config.to_owned();
+    let hostname = settings.hostname.to_owned();
+
+    // Marked first, so an email whose outcome can't be recorded isn't sent
+    // again by the next run
+    if !OutboxEmail::mark_sending(conn, id).await? {
+      continue;
+    }
+    let message = email.to_owned();
+    match web::block(move || transport::send(&config, &hostname, &message)).await {
+      Ok(()) => {
+        // It went out, the next emails still have to
+        if let Err(e) = OutboxEmail::mark_sent(conn, id).await {
+          error!("Couldn't record that email {} was sent: {}", id, e);
+        }
+        sent += 1;
+      }
+      Err(e) => {
+        let next_attempt = retry_delay(attempts).map(|delay| naive_now() + delay);
+        match next_attempt {
+          Some(at) => warn!("Couldn't send email {}, retrying at {}: {}", id, at, e),
+          None => error!("Couldn't send email {}, gave up after {} attempts: {}", id, attempts, e),
+        }
+        let error = e.to_string();
+        if let Err(e) = OutboxEmail::mark_attempt_failed(conn, &email, attempts, next_attempt, &error).await
+        {
+          error!("Couldn't record the failed attempt of email {}: {}", id, e);
+        }
+        failed += 1;
+      }
+    }
+  }
+
+  Ok(format!("Sent {} emails, {} failed", sent, failed))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_retry_delay() {
+    assert_eq!(retry_delay(1), Some(chrono::Duration::minutes(1)));
+    assert_eq!(retry_delay(2), Some(chrono::Duration::minutes(2)));
+    assert_eq!(retry_delay(5), Some(chrono::Duration::minutes(16)));
+    assert_eq!(retry_delay(MAX_ATTEMPTS - 1), Some(chrono::Duration::minutes(256)));
+    assert_eq!(retry_delay(MAX_ATTEMPTS), None);
+  }
+}
@@ -0,0 +1,435 @@
+/**
+ * The emails the server sends, rendered as text and html in the recipient's
+ * language.
+ *
+ * Phrases are kept per language in a `Phrases` table with {name}
+ * placeholders. Languages without a table fall back to English.
+ */
+use crate::markdown::escape_html;
+use crate::markdown_to_html;
+
+pub enum EmailTemplate<'a> {
+  PasswordReset {
+    username: &'a str,
+    token: &'a str,
+  },
+  VerifyEmail {
+    username: &'a str,
+    token: &'a str,
+  },
+  CommentReply {
+    author: &'a str,
+    /// Markdown
+    content: &'a str,
+  },
+  PostReply {
+    author: &'a str,
+    content: &'a str,
+  },
+  Mention {
+    author: &'a str,
+    content: &'a str,
+  },
+  PrivateMessage {
+    author: &'a str,
+    content: &'a str,
+  },
+  /// To admins, about a new registration application
+  NewApplication {
+    username: &'a str,
+  },
+  ApplicationApproved {
+    username: &'a str,
+    site_name: &'a str,
+  },
+  ApplicationDenied {
+    username: &'a str,
+    site_name: &'a str,
+    /// Markdown
+    reason: Option<&'a str>,
+  },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedEmail {
+  pub subject: String,
+  pub text: String,
+  pub html: String,
+}
+
+impl<'a> EmailTemplate<'a> {
+  /**
+   * Render for a recipient with user setting lang, with links to hostname
+   */
+  pub fn render(&self, lang: &str, hostname: &str) -> RenderedEmail {
+    let p = phrases(lang);
+    let base = format!("https://{}", hostname);
+    let inbox = || Some((p.inbox_action.to_string(), format!("{}/inbox", base)));
+
+    let parts = match *self {
+      EmailTemplate::PasswordReset { username, token } => Parts {
+        subject: fill(p.password_reset_subject, &[("username", username)]),
+        heading: fill(p.password_reset_heading, &[("username", username)]),
+        intro: p.password_reset_intro.to_string(),
+        content: None,
+        action: Some((
+          p.password_reset_action.to_string(),
+          format!("{}/password_change/{}", base, token),
+        )),
+      },
+      EmailTemplate::VerifyEmail { username, token } => Parts {
+        subject: fill(p.verify_email_subject, &[("username", username)]),
+        heading: fill(p.verify_email_heading, &[("username", username)]),
+        intro: p.verify_email_intro.to_string(),
+        content: None,
+        action: Some((
+          p.verify_email_action.to_string(),
+          format!("{}/verify_email/{}", base, token),
+        )),
+      },
+      EmailTemplate::CommentReply { author, content }
+      | EmailTemplate::PostReply { author, content } => Parts {
+        subject: fill(p.reply_subject, &[("hostname", hostname), ("author", author)]),
+        heading: match self {
+          EmailTemplate::CommentReply { .. } => p.comment_reply_heading,
+          _ => p.post_reply_heading,
+        }
+        .to_string(),
+        intro: fill(p.reply_intro, &[("author", author)]),
+        content: Some(content.to_string()),
+        action: inbox(),
+      },
+      EmailTemplate::Mention { author, content } => Parts {
+        subject: fill(p.mention_subject, &[("hostname", hostname), ("author", author)]),
+        heading: p.mention_heading.to_string(),
+        intro: fill(p.mention_intro, &[("author", author)]),
+        content: Some(content.to_string()),
+        action: inbox(),
+      },
+      EmailTemplate::PrivateMessage { author, content } => Parts {
+        subject: fill(
+          p.private_message_subject,
+          &[("hostname", hostname), ("author", author)],
+        ),
+        heading: p.private_message_heading.to_string(),
+        intro: fill(p.private_message_intro, &[("author", author)]),
+        content: Some(content.to_string()),
+        action: inbox(),
+      },
+      EmailTemplate::NewApplication { username } => Parts {
+        subject: fill(p.application_subject, &[("username", username)]),
+        heading: fill(p.application_heading, &[("username", username)]),
+        intro: String::new(),
+        content: None,
+        action: Some((p.application_action.to_string(), format!("{}/admin", base))),
+      },
+      EmailTemplate::ApplicationApproved {
+        username,
+        site_name,
+      } => Parts {
+        subject: fill(p.approved_subject, &[("site", site_name)]),
+        heading: fill(p.approved_heading, &[("username", username)]),
+        intro: p.approved_intro.to_string(),
+        content: None,
+        action: Some((p.approved_action.to_string(), format!("{}/login", base))),
+      },
+      EmailTemplate::ApplicationDenied {
+        username,
+        site_name,
+        reason,
+      } => Parts {
+        subject: fill(p.denied_subject, &[("site", site_name)]),
+        heading: fill(p.denied_heading, &[("username", username)]),
+        intro: p.denied_intro.to_string(),
+        content: Some(reason.unwrap_or(p.denied_no_reason).to_string()),
+        action: None,
+      },
+    };
+    parts.render()
+  }
+}
+
+/// The pieces every email is laid out from
+struct Parts {
+  subject: String,
+  heading: String,
+  intro: String,
+  /// Quoted markdown, eg the reply
+  content: Option<String>,
+  /// Label and url of the link to act on the email
+  action: Option<(String, String)>,
+}
+
+impl Parts {
+  fn render(self) -> RenderedEmail {
+    let mut text = vec![self.heading.clone()];
+    let mut html = vec![format!("<h1>{}</h1>", escape_html(&self.heading))];
+
+    if !self.intro.is_empty() {
+      text.push(self.intro.clone());
+      html.push(format!("<p>{}</p>", escape_html(&self.intro)));
+    }
+    if let Some(content) = &self.content {
+      let quoted: Vec<String> = content.lines().map(|line| format!("> {}", line)).collect();
+      text.push(quoted.join("\n"));
+      html.push(format!("<blockquote>{}</blockquote>", markdown_to_html(content)));
+    }
+    if let Some((label, url)) = &self.action {
+      text.push(format!("{}: {}", label, url));
+      html.push(format!(
+        "<p><a href=\"{}\">{}</a></p>",
+        escape_html(url),
+        escape_html(label)
+      ));
+    }
+
+    RenderedEmail {
+      subject: self.subject,
+      text: text.join("\n\n") + "\n",
+      html: html.join("\n") + "\n",
+    }
+  }
+}
+
+/**
+ * Replace the {name} placeholders of phrase with values, in one pass so
+ * placeholders inside values stay as they are. Unknown ones are kept.
+ */
+fn fill(phrase: &str, values: &[(&str, &str)]) -> String {
+  let mut filled = String::with_capacity(phrase.len());
+  let mut rest = phrase;
+  while let Some(start) = rest.find('{') {
+    filled.push_str(&rest[..start]);
+    let after = &rest[start + 1..];
+    let value = after.find('}').and_then(|end| {
+      values
+        .iter()
+        .find(|(name, _)| *name == &after[..end])
+        .map(|(_, value)| (end, value))
+    });
+    match value {
+      Some((end, value)) => {
+        filled.push_str(value);
+        rest = &after[end + 1..];
+      }
+      None => {
+        filled.push('{');
+        rest = after;
+      }
+    }
+  }
+  filled.push_str(rest);
+  filled
+}
+
+/// Phrase table of the language of user setting lang, eg "de" or "fr-CA"
+fn phrases(lang: &str) -> &'static Phrases {
+  let language = lang
+    .split(|c| c == '-' || c == '_')
+    .next()
+    .unwrap_or("")
+    .to_lowercase();
+  match language.as_str() {
+    "de" => &DE,
+    "fr" => &FR,
+    _ => &EN,
+  }
+}
+
+struct Phrases {
+  password_reset_subject: &'static str,
+  password_reset_heading: &'static str,
+  password_reset_intro: &'static str,
+  password_reset_action: &'static str,
+  verify_email_subject: &'static str,
+  verify_email_heading: &'static str,
+  verify_email_intro: &'static str,
+  verify_email_action: &'static str,
+  reply_subject: &'static str,
+  comment_reply_heading: &'static str,
+  post_reply_heading: &'static str,
+  reply_intro: &'static str,
+  mention_subject: &'static str,
+  mention_heading: &'static str,
+  mention_intro: &'static str,
+  private_message_subject: &'static str,
+  private_message_heading: &'static str,
+  private_message_intro: &'static str,
+  inbox_action: &'static str,
+  application_subject: &'static str,
+  application_heading: &'static str,
+  application_action: &'static str,
+  approved_subject: &'static str,
+  approved_heading: &'static str,
+  approved_intro: &'static str,
+  approved_action: &'static str,
+  denied_subject: &'static str,
+  denied_heading: &'static str,
+  denied_intro: &'static str,
+  denied_no_reason: &'static str,
+}
+
+static EN: Phrases = Phrases {
+  password_reset_subject: "Password reset for {username}",
+  password_reset_heading: "Password reset request for {username}",
+  password_reset_intro: "Someone asked to reset the password of your account. If it wasn't you, you can ignore this email.",
+  password_reset_action: "Reset your password",
+  verify_email_subject: "Verify your email address for {username}",
+  verify_email_heading: "Email verification for {username}",
+  verify_email_intro: "Confirm that this address belongs to your account.",
+  verify_email_action: "Verify your email address",
+  reply_subject: "{hostname} - Reply from {author}",
+  comment_reply_heading: "Comment reply",
+  post_reply_heading: "Post reply",
+  reply_intro: "{author} replied to you:",
+  mention_subject: "{hostname} - Mentioned by {author}",
+  mention_heading: "User mention",
+  mention_intro: "{author} mentioned you:",
+  private_message_subject: "{hostname} - Private message from {author}",
+  private_message_heading: "Private message",
+  private_message_intro: "{author} sent you a message:",
+  inbox_action: "Go to your inbox",
+  application_subject: "New registration application from {username}",
+  application_heading: "{username} applied to join",
+  application_action: "Review the application",
+  approved_subject: "Your registration on {site} was approved",
+  approved_heading: "Welcome, {username}",
+  approved_intro: "You can log in now.",
+  approved_action: "Log in",
+  denied_subject: "Your registration on {site} was denied",
+  denied_heading: "Sorry, {username}",
+  denied_intro: "The admins gave this reason:",
+  denied_no_reason: "No reason was given.",
+};
+
+static DE: Phrases = Phrases {
+  password_reset_subject: "Passwort zurücksetzen für {username}",
+  password_reset_heading: "Anfrage zum Zurücksetzen des Passworts von {username}",
+  password_reset_intro: "Jemand hat angefragt, das Passwort deines Kontos zurückzusetzen. Falls du das nicht warst, kannst du diese E-Mail ignorieren.",
+  password_reset_action: "Passwort zurücksetzen",
+  verify_email_subject: "Bestätige deine E-Mail-Adresse für {username}",
+  verify_email_heading: "E-Mail-Bestätigung für {username}",
+  verify_email_intro: "Bestätige, dass diese Adresse zu deinem Konto gehört.",
+  verify_email_action: "E-Mail-Adresse bestätigen",
+  reply_subject: "{hostname} - Antwort von {author}",
+  comment_reply_heading: "Antwort auf deinen Kommentar",
+  post_reply_heading: "Antwort auf deinen Beitrag",
+  reply_intro: "{author} hat dir geantwortet:",
+  mention_subject: "{hostname} - Erwähnt von {author}",
+  mention_heading: "Erwähnung",
+  mention_intro: "{author} hat dich erwähnt:",
+  private_message_subject: "{hostname} - Private Nachricht von {author}",
+  private_message_heading: "Private Nachricht",
+  private_message_intro: "{author} hat dir eine Nachricht geschickt:",
+  inbox_action: "Zum Posteingang",
+  application_subject: "Neuer Registrierungsantrag von {username}",
+  application_heading: "{username} möchte beitreten",
+  application_action: "Antrag prüfen",
+  approved_subject: "Deine Registrierung bei {site} wurde angenommen",
+  approved_heading: "Willkommen, {username}",
+  approved_intro: "Du kannst dich jetzt anmelden.",
+  approved_action: "Anmelden",
+  denied_subject: "Deine Registrierung bei {site} wurde abgelehnt",
+  denied_heading: "Tut uns leid, {username}",
+  denied_intro: "Die Admins haben folgenden Grund angegeben:",
+  denied_no_reason: "Es wurde kein Grund angegeben.",
+};
+
+static FR: Phrases = Phrases {
+  password_reset_subject: "Réinitialisation du mot de passe de {username}",
+  password_reset_heading: "Demande de réinitialisation du mot de passe de {username}",
+  password_reset_intro: "Quelqu'un a demandé à réinitialiser le mot de passe de votre compte. Si ce n'était pas vous, vous pouvez ignorer cet e-mail.",
+  password_reset_action: "Réinitialiser votre mot de passe",
+  verify_email_subject: "Vérifiez votre adresse e-mail pour {username}",
+  verify_email_heading: "Vérification de l'adresse e-mail de {username}",
+  verify_email_intro: "Confirmez que cette adresse appartient à votre compte.",
+  verify_email_action: "Vérifier votre adresse e-mail",
+  reply_subject: "{hostname} - Réponse de {author}",
+  comment_reply_heading: "Réponse à votre commentaire",
+  post_reply_heading: "Réponse à votre publication",
+  reply_intro: "{author} vous a répondu :",
+  mention_subject: "{hostname} - Mentionné par {author}",
+  mention_heading: "Mention",
+  mention_intro: "{author} vous a mentionné :",
+  private_message_subject: "{hostname} - Message privé de {author}",
+  private_message_heading: "Message privé",
+  private_message_intro: "{author} vous a envoyé un message :",
+  inbox_action: "Voir votre boîte de réception",
+  application_subject: "Nouvelle demande d'inscription de {username}",
+  application_heading: "{username} souhaite rejoindre l'instance",
+  application_action: "Examiner la demande",
+  approved_subject: "Votre inscription sur {site} a été acceptée",
+  approved_heading: "Bienvenue, {username}",
+  approved_intro: "Vous pouvez maintenant vous connecter.",
+  approved_action: "Se connecter",
+  denied_subject: "Votre inscription sur {site} a été refusée",
+  denied_heading: "Désolé, {username}",
+  denied_intro: "Les admins ont donné la raison suivante :",
+  denied_no_reason: "Aucune raison n'a été donnée.",
+};
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_fill() {
+    assert_eq!(fill("Hi {name}!", &[("name", "bob")]), "Hi bob!");
+    assert_eq!(fill("{a}{b}", &[("a", "{b}"), ("b", "x")]), "{b}x");
+    assert_eq!(fill("{unknown} {", &[("a", "x")]), "{unknown} {");
+  }
+
+  #[test]
+  fn test_render_password_reset() {
+    let template = EmailTemplate::PasswordReset {
+      username: "bob",
+      token: "abc",
+    };
+    let email = template.render("en", "example.com");
+    assert_eq!(email.subject, "Password reset for bob");
+    assert!(email
+      .text
+      .contains("Reset your password: https://example.com/password_change/abc"));
+    assert!(email
+      .html
+      .contains("<a href=\"https://example.com/password_change/abc\">Reset your password</a>"));
+
+    assert_eq!(
+      template.render("de", "example.com").subject,
+      "Passwort zurücksetzen für bob"
+    );
+    assert_eq!(
+      template.render("fr-CA", "example.com").subject,
+      "Réinitialisation du mot de passe de bob"
+    );
+    // The default lang setting, and languages without phrases
+    assert_eq!(template.render("browser", "example.com"), email);
+    assert_eq!(template.render("eo", "example.com"), email);
+  }
+
+  #[test]
+  fn test_render_reply() {
+    let email = EmailTemplate::CommentReply {
+      author: "<b>alice</b>",
+      content: "line one\n**line two**",
+    }
+    .render("en", "example.com");
+    assert_eq!(email.subject, "example.com - Reply from <b>alice</b>");
+    assert!(email.text.contains("> line one\n> **line two**"));
+    assert!(email.html.contains("&lt;b&gt;alice&lt;/b&gt; replied to you:"));
+    assert!(email.html.contains("<strong>line two</strong>"));
+    assert!(email.text.ends_with("Go to your inbox: https://example.com/inbox\n"));
+  }
+
+  #[test]
+  fn test_render_denied_without_reason() {
+    let email = EmailTemplate::ApplicationDenied {
+      username: "bob",
+      site_name: "Lemmy",
+      reason: None,
+    }
+    .render("en", "example.com");
+    assert_eq!(email.subject, "Your registration on Lemmy was denied");
+    assert!(email.text.contains("> No reason was given."));
+  }
+}
@@ -0,0 +1,65 @@
+/**
+ * Delivery of queued emails, over smtp or into files for local testing.
+ *
+ * Sending blocks, run it on a thread pool.
+ */
+use crate::db::email_outbox::OutboxEmail;
+use crate::settings::{EmailConfig, EmailTransport};
+use failure::Error;
+use lettre::smtp::authentication::{Credentials, Mechanism};
+use lettre::smtp::extension::ClientId;
+use lettre::smtp::ConnectionReuseParameters;
+use lettre::{ClientSecurity, SendableEmail, SmtpClient, Transport};
+use lettre_email::Email;
+use std::fs;
+use std::path::Path;
+
+/// Send email with both its text and html body, hostname greets the smtp server
+pub fn send(config: &EmailConfig, hostname: &str, email: &OutboxEmail) -> Result<(), Error> {
+  let message = Email::builder()
+    .to((email.to_email.as_str(), email.to_name.as_str()))
+    .from(config.smtp_from_address.to_owned())
+    .subject(email.subject.as_str())
+    .alternative(email.html_body.as_str(), email.text_body.as_str())
+    .build()
+    .map_err(|e| format_err!("Couldn't build email: {}", e))?;
+
+  match config.transport {
+    EmailTransport::Smtp => send_smtp(config, hostname, message.into()),
+    EmailTransport::File => {
+      let directory = config.file_directory.as_deref().unwrap_or("emails");
+      write_file(Path::new(directory), email.id, message.into())
+    }
+  }
+}
+
+fn send_smtp(config: &EmailConfig, hostname: &str, message: SendableEmail) -> Result<(), Error> {
+  let mailer = if config.use_tls {
+    SmtpClient::new_simple(&config.smtp_server)?
+  } else {
+    SmtpClient::new(&config.smtp_server, ClientSecurity::None)?
+  }
+  .hello_name(ClientId::Domain(hostname.to_owned()))
+  .smtp_utf8(true)
+  .authentication_mechanism(Mechanism::Plain)
+  .connection_reuse(ConnectionReuseParameters::ReuseUnlimited);
+  let mailer = if let (Some(login), Some(password)) = (&config.smtp_login, &config.smtp_password)
+  {
+    mailer.credentials(Credentials::new(login.to_owned(), password.to_owned()))
+  } else {
+    mailer
+  };
+
+  let mut transport = mailer.transport();
+  let result = transport.send(message);
+  transport.close();
+
+  result.map(|_| ()).map_err(|e| format_err!("{}", e))
+}
+
+/// Write the message as <id>.eml, which mail clients can open
+fn write_file(directory: &Path, id: i64, message: SendableEmail) -> Result<(), Error> {
+  fs::create_dir_all(directory)?;
+  fs::write(directory.join(format!("{}.eml", id)), message.message_to_string()?)?;
+  Ok(())
+}
@@ -0,0 +1,229 @@
+/**
+ * The built-in background jobs, see crate::scheduler.
+ */
+use crate::data_export;
+use crate::email;
+use crate::db::aggregates;
+use crate::db::community::{CommunityUserBan, CommunityUserBanForm};
+use crate::db::email_outbox::OutboxEmail;
+use crate::db::email_verification::EmailVerification;
+use crate::db::moderator::{ModBan, ModBanForm, ModBanFromCommunity, ModBanFromCommunityForm};
+use crate::db::oidc_login::{OidcLoginCode, OidcPendingLogin};
+use crate::db::password_reset_request::PasswordResetRequest;
+use crate::db::refresh_token::RefreshToken;
+use crate::db::used_captcha::UsedCaptcha;
+use crate::db::user::User_;
+use crate::naive_now;
+use crate::scheduler::{Job, Schedule};
+use crate::settings::Settings;
+use chrono::NaiveDateTime;
+use dgraph_tonic as dgraph;
+use failure::Error;
+use std::collections::HashMap;
+
+/// Content older than this keeps its last hot and controversy ranks
+const HOT_RANK_DAYS: i64 = 7;
+
+/// The built-in jobs, with their configured schedules
+pub fn builtin(settings: &Settings) -> Result<Vec<Job>, Error> {
+  Ok(vec![
+    Job {
+      name: "refresh_ranks",
+      schedule: Schedule::parse(&settings.jobs.refresh_ranks)?,
+      run: |conn| Box::pin(async move { refresh_ranks(&conn).await }),
+    },
+    Job {
+      name: "expire_bans",
+      schedule: Schedule::parse(&settings.jobs.expire_bans)?,
+      run: |conn| Box::pin(async move { expire_bans(&conn).await }),
+    },
+    Job {
+      name: "cleanup_tokens",
+      schedule: Schedule::parse(&settings.jobs.cleanup_tokens)?,
+      run: |conn| Box::pin(async move { cleanup_tokens(&conn).await }),
+    },
+    Job {
+      name: "build_data_exports",
+      schedule: Schedule::parse(&settings.jobs.build_data_exports)?,
+      run: |conn| Box::pin(async move { build_data_exports(&conn).await }),
+    },
+    Job {
+      name: "send_emails",
+      schedule: Schedule::parse(&settings.jobs.send_emails)?,
+      run: |conn| Box::pin(async move { email::send_due(&conn).await }),
+    },
+  ])
+}
+
+async fn refresh_ranks(conn: &dgraph::Client) -> Result<String, Error> {
+  let now = naive_now();
+  let since = now - chrono::Duration::days(HOT_RANK_DAYS);
+  let updated = aggregates::refresh_ranks(conn, since, now).await?;
+  Ok(format!("Refreshed the ranks of {} posts and comments", updated))
+}
+
+/// A ban or unban in a ban log
+#[derive(Debug, Clone, PartialEq)]
+pub struct BanLogEntry {
+  pub mod_user_id: i64,
+  pub banned: bool,
+  pub expires: Option<NaiveDateTime>,
+  pub when_: NaiveDateTime,
+}
+
+/// Reason of the log entries written when a ban expires
+const BAN_EXPIRED_REASON: &str = "Ban expired";
+
+/**
+ * The ban in effect according to a ban log, if it has expired by now: the
+ * latest entry is a ban that set an expiry which has passed. Bans without an
+ * expiry never lift on their own.
+ */
+pub fn expired_ban(log: &[BanLogEntry], now: NaiveDateTime) -> Option<&BanLogEntry> {
+  log
+    .iter()
+    .max_by_key(|entry| entry.when_)
+    .filter(|latest| latest.banned && latest.expires.map_or(false, |expires| expires <= now))
+}
+
+async fn expire_bans(conn: &dgraph::Client) -> Result<String, Error> {
+  let now = naive_now();
+
+  let mut site_bans = 0;
+  for user in ModBan::list_banned_users(conn).await? {
+    let log: Vec<BanLogEntry> = user
+      .bans
+      .iter()
+      .map(|entry| BanLogEntry {
+        mod_user_id: entry.mod_user_id,
+        banned: entry.banned.unwrap_or(false),
+        expires: entry.expires,
+        when_: entry.when_,
+      })
+      .collect();
+    if let Some(ban) = expired_ban(&log, now) {
+      User_::update_banned(conn, user.uid, false).await?;
+      // Logged like an unban by the mod who set the expiry
+      let form = ModBanForm {
+        mod_user_id: ban.mod_user_id,
+        other_user_id: user.uid,
+        reason: Some(BAN_EXPIRED_REASON.into()),
+        banned: Some(false),
+        expires: None,
+      };
+      ModBan::create(conn, &form).await?;
+      site_bans += 1;
+    }
+  }
+
+  // The community ban log is one node per entry, by community and user
+  let mut logs: HashMap<(i64, i64), Vec<BanLogEntry>> = HashMap::new();
+  for entry in ModBanFromCommunity::list(conn).await? {
+    logs
+      .entry((entry.community_id, entry.other_user_id))
+      .or_default()
+      .push(BanLogEntry {
+        mod_user_id: entry.mod_user_id,
+        banned: entry.banned.unwrap_or(false),
+        expires: entry.expires,
+        when_: entry.when_,
+      });
+  }
+  let mut community_bans = 0;
+  for ((community_id, user_id), log) in &logs {
+    let ban = match expired_ban(log, now) {
+      Some(ban) => ban,
+      None => continue,
+    };
+    if !CommunityUserBan::is_banned(conn, *community_id, *user_id).await? {
+      continue;
+    }
+    let form = CommunityUserBanForm {
+      community_id: *community_id,
+      user_id: *user_id,
+    };
+    CommunityUserBan::unban(conn, &form).await?;
+    let form = ModBanFromCommunityForm {
+      mod_user_id: ban.mod_user_id,
+      other_user_id: *user_id,
+      community_id: *community_id,
+      reason: Some(BAN_EXPIRED_REASON.into()),
+      banned: Some(false),
+      expires: None,
+    };
+    ModBanFromCommunity::create(conn, &form).await?;
+    community_bans += 1;
+  }
+
+  Ok(format!(
+    "Lifted {} site bans and {} community bans",
+    site_bans, community_bans
+  ))
+}
+
+async fn build_data_exports(conn: &dgraph::Client) -> Result<String, Error> {
+  let built = data_export::build_pending(conn).await?;
+  Ok(format!("Built {} data exports", built))
+}
+
+async fn cleanup_tokens(conn: &dgraph::Client) -> Result<String, Error> {
+  let reset_requests = PasswordResetRequest::delete_expired(conn).await?;
+  let verifications = EmailVerification::delete_expired(conn).await?;
+  let refresh_tokens = RefreshToken::delete_expired(conn).await?;
+  let exports = data_export::delete_expired(conn).await?;
+  let oidc_logins =
+    OidcPendingLogin::delete_expired(conn).await? + OidcLoginCode::delete_expired(conn).await?;
+  let captchas = UsedCaptcha::delete_expired(conn).await?;
+  let keep_emails_since = naive_now() - chrono::Duration::days(email::KEEP_DAYS);
+  let emails = OutboxEmail::delete_done_before(conn, keep_emails_since).await?;
+  Ok(format!(
+    "Deleted {} password reset requests, {} email verifications, {} refresh tokens, {} data exports, {} OIDC logins, {} solved captchas and {} emails",
+    reset_requests, verifications, refresh_tokens, exports, oidc_logins, captchas, emails
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entry(banned: bool, expires_in_hours: Option<i64>, hours_ago: i64) -> BanLogEntry {
+    let now = chrono::NaiveDate::from_ymd(2020, 6, 1).and_hms(12, 0, 0);
+    let when_ = now - chrono::Duration::hours(hours_ago);
+    BanLogEntry {
+      mod_user_id: 1,
+      banned,
+      expires: expires_in_hours.map(|hours| when_ + chrono::Duration::hours(hours)),
+      when_,
+    }
+  }
+
+  #[test]
+  fn test_expired_ban() {
+    let now = chrono::NaiveDate::from_ymd(2020, 6, 1).and_hms(12, 0, 0);
+    assert!(expired_ban(&[], now).is_none());
+    // Expired 2 hours ago
+    assert!(expired_ban(&[entry(true, Some(3), 5)], now).is_some());
+    // Still running
+    assert!(expired_ban(&[entry(true, Some(10), 5)], now).is_none());
+    // Permanent
+    assert!(expired_ban(&[entry(true, None, 500)], now).is_none());
+    // Lifted by hand already
+    assert!(expired_ban(&[entry(true, Some(3), 5), entry(false, None, 4)], now).is_none());
+    // Banned again without expiry after a temporary ban, in any order
+    assert!(expired_ban(&[entry(true, None, 1), entry(true, Some(3), 5)], now).is_none());
+    // A later temporary ban replaces an earlier permanent one
+    assert!(expired_ban(&[entry(true, None, 50), entry(true, Some(1), 5)], now).is_some());
+  }
+
+  #[test]
+  fn test_expired_ban_is_latest_entry() {
+    let now = chrono::NaiveDate::from_ymd(2020, 6, 1).and_hms(12, 0, 0);
+    let earlier = BanLogEntry {
+      mod_user_id: 2,
+      ..entry(true, Some(1), 10)
+    };
+    let latest = entry(true, Some(3), 5);
+    let log = [latest.clone(), earlier];
+    assert_eq!(expired_ban(&log, now), Some(&latest));
+  }
+}
@@ -31,28 +31,37 @@ pub extern crate getset;
 // TODO: export new modules in lib.rs
 pub mod api;
 // pub mod apub;
+pub mod bootstrap;
+pub mod captcha_challenge;
+pub mod cli;
+pub mod content_filter;
+pub mod data_export;
 pub mod db;
+pub mod edit_history;
+pub mod email;
+pub mod jobs;
+pub mod markdown;
+pub mod oidc;
+pub mod password;
 pub mod rate_limit;
 pub mod routes;
+pub mod scheduler;
 // pub mod schema;
 pub mod settings;
+pub mod totp;
 pub mod version;
 // pub mod websocket;
 
 use actix_web::dev::ConnectionInfo;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use isahc::prelude::*;
-use lettre::smtp::authentication::{Credentials, Mechanism};
-use lettre::smtp::extension::ClientId;
-use lettre::smtp::ConnectionReuseParameters;
-use lettre::{ClientSecurity, SmtpClient, Transport};
-use lettre_email::Email;
 use log::error;
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use regex::{Regex, RegexBuilder};
+use regex::Regex;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
 use crate::settings::Settings;
 
@@ -99,89 +108,57 @@ pub fn is_image_content_type(test: &str) -> Result<(), failure::Error> {
   }
 }
 
-pub fn remove_slurs(test: &str) -> String {
-  SLUR_REGEX.replace_all(test, "*removed*").to_string()
-}
-
-pub fn slur_check(test: &str) -> Result<(), Vec<&str>> {
-  let mut matches: Vec<&str> = SLUR_REGEX.find_iter(test).map(|mat| mat.as_str()).collect();
-
-  // Unique
-  matches.sort_unstable();
-  matches.dedup();
-
-  if matches.is_empty() {
-    Ok(())
-  } else {
-    Err(matches)
-  }
-}
-
-pub fn slurs_vec_to_str(slurs: Vec<&str>) -> String {
+/// Error message naming the matches of a rejecting content filter
+pub fn slurs_vec_to_str(slurs: &[String]) -> String {
   let start = "No slurs - ";
   let combined = &slurs.join(", ");
   [start, combined].concat()
 }
 
-pub fn extract_usernames(test: &str) -> Vec<&str> {
-  let mut matches: Vec<&str> = USERNAME_MATCHES_REGEX
-    .find_iter(test)
-    .map(|mat| mat.as_str())
+/// Names of local users mentioned in markdown text, see markdown::extract_mentions
+pub fn extract_usernames(text: &str) -> Vec<String> {
+  let mut names: Vec<String> = markdown::extract_mentions(text)
+    .into_iter()
+    .filter(|mention| mention.instance.is_none() || mention.is_local(&Settings::get().hostname))
+    .map(|mention| mention.name)
     .collect();
 
-  // Unique
-  matches.sort_unstable();
-  matches.dedup();
+  // Unique, /u/bob and @bob@hostname are the same user
+  names.sort_unstable();
+  names.dedup();
+  names
+}
 
-  // Remove /u/
-  matches.iter().map(|t| &t[3..]).collect()
+/// Usernames an edit from old_text to new_text starts and stops mentioning
+pub fn mention_changes(old_text: &str, new_text: &str) -> (Vec<String>, Vec<String>) {
+  let old_names = extract_usernames(old_text);
+  let new_names = extract_usernames(new_text);
+  let added = new_names
+    .iter()
+    .filter(|name| !old_names.contains(name))
+    .cloned()
+    .collect();
+  let removed = old_names
+    .iter()
+    .filter(|name| !new_names.contains(name))
+    .cloned()
+    .collect();
+  (added, removed)
 }
 
 pub fn generate_random_string() -> String {
   thread_rng().sample_iter(&Alphanumeric).take(30).collect()
 }
 
-pub fn send_email(
-  subject: &str,
-  to_email: &str,
-  to_username: &str,
-  html: &str,
-) -> Result<(), String> {
-  let email_config = Settings::get().email.ok_or("no_email_setup")?;
-
-  let email = Email::builder()
-    .to((to_email, to_username))
-    .from(email_config.smtp_from_address.to_owned())
-    .subject(subject)
-    .html(html)
-    .build()
-    .unwrap();
-
-  let mailer = if email_config.use_tls {
-    SmtpClient::new_simple(&email_config.smtp_server).unwrap()
-  } else {
-    SmtpClient::new(&email_config.smtp_server, ClientSecurity::None).unwrap()
-  }
-  .hello_name(ClientId::Domain(Settings::get().hostname))
-  .smtp_utf8(true)
-  .authentication_mechanism(Mechanism::Plain)
-  .connection_reuse(ConnectionReuseParameters::ReuseUnlimited);
-  let mailer = if let (Some(login), Some(password)) =
-    (&email_config.smtp_login, &email_config.smtp_password)
-  {
-    mailer.credentials(Credentials::new(login.to_owned(), password.to_owned()))
-  } else {
-    mailer
-  };
-
-  let mut transport = mailer.transport();
-  let result = transport.send(email.into());
-  transport.close();
-
-  match result {
-    Ok(_) => Ok(()),
-    Err(e) => Err(e.to_string()),
-  }
+/// Hex encoded sha256 hash, used to store tokens without storing the token itself
+pub fn sha256_hex(token: &str) -> String {
+  let mut hasher = Sha256::new();
+  hasher.input(token);
+  hasher
+    .result()
+    .iter()
+    .map(|byte| format!("{:02x}", byte))
+    .collect()
 }
 
 #[derive(Deserialize, Debug)]
@@ -267,8 +244,9 @@ fn fetch_iframely_and_pictshare_data(
   }
 }
 
+/// Render markdown to sanitized html, see the markdown module
 pub fn markdown_to_html(text: &str) -> String {
-  comrak::markdown_to_html(text, &comrak::ComrakOptions::default())
+  markdown::render(text)
 }
 
 pub fn get_ip(conn_info: &ConnectionInfo) -> String {
@@ -302,8 +280,8 @@ macro_rules! strfy {
 #[cfg(test)]
 mod tests {
   use crate::{
-    extract_usernames, is_email_regex, is_image_content_type, is_valid_username, remove_slurs,
-    slur_check, slurs_vec_to_str,
+    extract_usernames, is_email_regex, is_image_content_type, is_valid_username, mention_changes,
+    sha256_hex,
   };
 
   #[test]
@@ -331,38 +309,32 @@ mod tests {
   }
 
   #[test]
-  fn test_slur_filter() {
-    let test =
-      "coons test dindu ladyboy tranny retardeds. Capitalized Niggerz. This is a bunch of other safe text.";
-    let slur_free = "No slurs here";
-    assert_eq!(
-      remove_slurs(&test),
-      "*removed* test *removed* *removed* *removed* *removed*. Capitalized *removed*. This is a bunch of other safe text."
-        .to_string()
+  fn test_extract_usernames() {
+    let usernames = extract_usernames("this is a user mention for [/u/testme](/u/testme) and thats all. Oh [/u/another](/u/another) user. And the first again [/u/testme](/u/testme) okay");
+    let expected = vec!["another", "testme"];
+    assert_eq!(usernames, expected);
+  }
+
+  #[test]
+  fn test_mention_changes() {
+    let (added, removed) = mention_changes(
+      "hi [/u/alice](/u/alice) and [/u/bob](/u/bob)",
+      "hi [/u/bob](/u/bob) and [/u/carol](/u/carol)",
     );
+    assert_eq!(added, vec!["carol"]);
+    assert_eq!(removed, vec!["alice"]);
 
-    let has_slurs_vec = vec![
-      "Niggerz",
-      "coons",
-      "dindu",
-      "ladyboy",
-      "retardeds",
-      "tranny",
-    ];
-    let has_slurs_err_str = "No slurs - Niggerz, coons, dindu, ladyboy, retardeds, tranny";
-
-    assert_eq!(slur_check(test), Err(has_slurs_vec));
-    assert_eq!(slur_check(slur_free), Ok(()));
-    if let Err(slur_vec) = slur_check(test) {
-      assert_eq!(&slurs_vec_to_str(slur_vec), has_slurs_err_str);
-    }
+    let (added, removed) = mention_changes("", "[/u/bob](/u/bob)");
+    assert_eq!(added, vec!["bob"]);
+    assert!(removed.is_empty());
   }
 
   #[test]
-  fn test_extract_usernames() {
-    let usernames = extract_usernames("this is a user mention for [/u/testme](/u/testme) and thats all. Oh [/u/another](/u/another) user. And the first again [/u/testme](/u/testme) okay");
-    let expected = vec!["another", "testme"];
-    assert_eq!(usernames, expected);
+  fn test_sha256_hex() {
+    assert_eq!(
+      sha256_hex("nope"),
+      "ca3704aa0b06f5954c79ee837faa152d84d6b2d42838f0637a15eda8337dbdce"
+    );
   }
 
   // These helped with testing
@@ -379,17 +351,9 @@ mod tests {
   //   let res_other = fetch_pictshare("https://upload.wikimedia.org/wikipedia/en/2/27/The_Mandalorian_logo.jpgaoeu");
   //   assert!(res_other.is_err());
   // }
-
-  // #[test]
-  // fn test_send_email() {
-  //  let result =  send_email("not a subject", "test_email@gmail.com", "ur user", "<h1>HI there</h1>");
-  //   assert!(result.is_ok());
-  // }
 }
 
 lazy_static! {
   static ref EMAIL_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9.!#$%&’*+/=?^_`{|}~-]+@[a-zA-Z0-9-]+(?:\.[a-zA-Z0-9-]+)*$").unwrap();
-  static ref SLUR_REGEX: Regex = RegexBuilder::new(r"(fag(g|got|tard)?|maricos?|cock\s?sucker(s|ing)?|nig(\b|g?(a|er)?(s|z)?)\b|dindu(s?)|mudslime?s?|kikes?|mongoloids?|towel\s*heads?|\bspi(c|k)s?\b|\bchinks?|niglets?|beaners?|\bnips?\b|\bcoons?\b|jungle\s*bunn(y|ies?)|jigg?aboo?s?|\bpakis?\b|rag\s*heads?|gooks?|cunts?|bitch(es|ing|y)?|puss(y|ies?)|twats?|feminazis?|whor(es?|ing)|\bslut(s|t?y)?|\btrann?(y|ies?)|ladyboy(s?)|\b(b|re|r)tard(ed)?s?)").case_insensitive(true).build().unwrap();
-  static ref USERNAME_MATCHES_REGEX: Regex = Regex::new(r"/u/[a-zA-Z][0-9a-zA-Z_]*").unwrap();
   static ref VALID_USERNAME_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9_]{3,20}$").unwrap();
 }
@@ -11,14 +11,21 @@ use actix_web::http::{header::CACHE_CONTROL, HeaderValue};
 use actix_web::*;
 
 use lemmy_server::{
+  bootstrap,
+  cli::Command,
+  content_filter,
+  db::establish_connection,
+  jobs,
   rate_limit::{rate_limiter::RateLimiter, RateLimit},
-  routes::{api, index}, // federation, feeds, index, nodeinfo, webfinger},
+  routes::{api, export, index, oidc}, // federation, feeds, index, nodeinfo, webfinger},
+  scheduler::Scheduler,
   settings::Settings,
   // websocket::server::*,
 };
 
+use log::info;
 use regex::Regex;
-use std::{io, sync::Arc};
+use std::{env, io, process, sync::Arc};
 use tokio::sync::Mutex;
 
 lazy_static! {
@@ -33,8 +40,58 @@ lazy_static! {
 #[actix_rt::main]
 async fn main() -> io::Result<()> {
   env_logger::init();
+
+  // Admin subcommands run once and exit instead of starting the server
+  let args: Vec<String> = env::args().skip(1).collect();
+  match Command::parse(&args) {
+    Ok(Some(command)) => {
+      if let Err(e) = command.run().await {
+        eprintln!("{}", e);
+        process::exit(1);
+      }
+      return Ok(());
+    }
+    Ok(None) => {}
+    Err(e) => {
+      eprintln!("{}", e);
+      process::exit(2);
+    }
+  }
+
+  // A configured jwt_secret_file is created on first start
+  Settings::generate_missing_secrets()
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
   let settings = Settings::get();
 
+  // Pick up edits to the config file without a restart
+  Settings::watch_config_file();
+
+  let conn = establish_connection(&settings)
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+  // Create the admin, site and main community from the setup block on first start
+  bootstrap::run(&conn, &settings)
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Bootstrap failed: {}", e)))?;
+  bootstrap::upgrade(&conn)
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Upgrade failed: {}", e)))?;
+
+  // Content filters are checked from memory, don't serve unfiltered content
+  let filter_count = content_filter::reload(&conn)
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("Loading content filters failed: {}", e)))?;
+  info!("Loaded {} content filters", filter_count);
+  // Admins change the rules through any server
+  content_filter::watch(conn.clone());
+
+  // Rank refresh, ban expiry and cleanups, see the jobs module
+  if settings.jobs.enabled {
+    let jobs = jobs::builtin(&settings)
+      .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    Scheduler::new(conn.clone(), jobs, settings.jobs.retries).start();
+  }
+
   // // Set up the rate limiter
   // let rate_limiter = RateLimit {
   //   rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
@@ -55,7 +112,7 @@ async fn main() -> io::Result<()> {
     App::new()
       .wrap_fn(add_cache_headers)
       .wrap(middleware::Logger::default())
-      // .data(pool.clone())
+      .data(conn.clone())
       // .data(server.clone())
       // The routes
       // .configure(move |cfg| api::config(cfg, &rate_limiter))
@@ -64,6 +121,8 @@ async fn main() -> io::Result<()> {
       // .configure(federation::config)
       // .configure(feeds::config)
       .configure(index::config)
+      .configure(oidc::config)
+      .configure(export::config)
       // .configure(nodeinfo::config)
       // .configure(webfinger::config)
       // static files
@@ -73,7 +132,7 @@ async fn main() -> io::Result<()> {
       ))
       .service(actix_files::Files::new(
         "/docs",
-        settings.front_end_dir + "/documentation",
+        settings.front_end_dir.to_owned() + "/documentation",
       ))
   })
   .bind((settings.bind, settings.port))?
@@ -0,0 +1,477 @@
+/**
+ * Lemmy specific markdown, on top of what comrak parses:
+ *
+ * - `::: spoiler title` blocks, closed by a `:::` line
+ * - `~subscript~` (comrak already does `^superscript^`)
+ * - links to `/u/name`, `@name@instance`, `/c/name` and `!name@instance`
+ * - bare http(s) urls, instead of comrak's autolinker, which would turn
+ *   `@name@instance` into a mailto link
+ *
+ * Mentions are extracted with the same parser, so a mention that notifies
+ * a user is exactly one that renders as a link to them.
+ */
+use comrak::arena_tree::Node;
+use comrak::nodes::{Ast, AstNode, NodeLink, NodeValue};
+use comrak::{format_html, parse_document, Arena, ComrakOptions};
+use regex::Regex;
+use std::cell::RefCell;
+
+/// Placeholders for <sub> and </sub> while comrak renders, from the unicode private use area
+const SUB_OPEN: char = '\u{E000}';
+const SUB_CLOSE: char = '\u{E001}';
+
+lazy_static! {
+  static ref REFERENCE_REGEX: Regex = Regex::new(concat!(
+    r"(?P<url>https?://[^\s<>]*[^\s<>.,:;!?'\x22)\]])",
+    r"|/u/(?P<user>[a-zA-Z0-9_]{3,20})\b",
+    r"|@(?P<mention>[a-zA-Z0-9_]{3,20})@(?P<mention_host>[a-zA-Z0-9-]+(?:\.[a-zA-Z0-9-]+)+)",
+    r"|/c/(?P<community>[a-z0-9_]{3,20})\b",
+    r"|!(?P<remote_community>[a-z0-9_]{3,20})@(?P<community_host>[a-zA-Z0-9-]+(?:\.[a-zA-Z0-9-]+)+)",
+  ))
+  .unwrap();
+}
+
+/// A mentioned user, instance is None for /u/name
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Mention {
+  pub name: String,
+  pub instance: Option<String>,
+}
+
+impl Mention {
+  pub fn is_local(&self, local_host: &str) -> bool {
+    self.instance.as_ref().map_or(true, |i| i == local_host)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Reference {
+  Url(String),
+  User(Mention),
+  Community { name: String, instance: Option<String> },
+}
+
+impl Reference {
+  /// Local references are relative, the sanitizer makes them absolute
+  fn url(&self, local_host: &str) -> String {
+    let link = |kind: &str, name: &str, instance: &Option<String>| match instance {
+      Some(instance) if instance != local_host => format!("https://{}/{}/{}", instance, kind, name),
+      _ => format!("/{}/{}", kind, name),
+    };
+    match self {
+      Reference::Url(url) => url.to_owned(),
+      Reference::User(mention) => link("u", &mention.name, &mention.instance),
+      Reference::Community { name, instance } => link("c", name, instance),
+    }
+  }
+}
+
+/// References in text, with their byte ranges
+fn find_references(text: &str) -> Vec<(std::ops::Range<usize>, Reference)> {
+  let mut references = Vec::new();
+  for captures in REFERENCE_REGEX.captures_iter(text) {
+    let whole = captures.get(0).unwrap();
+
+    // No lookbehind in regex: foo/u/bar or foo@bar@baz.com are not references
+    let preceded_by_word = text[..whole.start()]
+      .chars()
+      .last()
+      .map_or(false, |c| c.is_alphanumeric() || "_/@!.".contains(c));
+    if preceded_by_word {
+      continue;
+    }
+
+    let group = |name: &str| captures.name(name).map(|m| m.as_str().to_owned());
+    let reference = if let Some(url) = group("url") {
+      Reference::Url(url)
+    } else if let Some(name) = group("user") {
+      Reference::User(Mention { name, instance: None })
+    } else if let Some(name) = group("mention") {
+      Reference::User(Mention {
+        name,
+        instance: group("mention_host"),
+      })
+    } else if let Some(name) = group("community") {
+      Reference::Community { name, instance: None }
+    } else if let Some(name) = group("remote_community") {
+      Reference::Community {
+        name,
+        instance: group("community_host"),
+      }
+    } else {
+      continue;
+    };
+    references.push((whole.range(), reference));
+  }
+  references
+}
+
+enum Segment {
+  Markdown(String),
+  Spoiler { title: String, body: String },
+}
+
+fn is_fence(line: &str) -> bool {
+  let line = line.trim_start();
+  line.starts_with("```") || line.starts_with("~~~")
+}
+
+/// Split text at spoiler blocks, which can't be nested
+fn split_spoilers(text: &str) -> Vec<Segment> {
+  let mut segments = Vec::new();
+  let mut current = String::new();
+  let mut spoiler_title: Option<String> = None;
+  let mut in_code = false;
+
+  for line in text.lines() {
+    if is_fence(line) {
+      in_code = !in_code;
+    }
+    let trimmed = line.trim();
+    if !in_code && spoiler_title.is_none() && trimmed.starts_with(":::") {
+      let rest = trimmed[3..].trim_start();
+      if rest.starts_with("spoiler") {
+        segments.push(Segment::Markdown(std::mem::take(&mut current)));
+        spoiler_title = Some(rest["spoiler".len()..].trim().to_owned());
+        continue;
+      }
+    }
+    if !in_code && spoiler_title.is_some() && trimmed == ":::" {
+      segments.push(Segment::Spoiler {
+        title: spoiler_title.take().unwrap_or_default(),
+        body: std::mem::take(&mut current),
+      });
+      continue;
+    }
+    current.push_str(line);
+    current.push('\n');
+  }
+
+  // An unclosed spoiler runs to the end
+  segments.push(match spoiler_title {
+    Some(title) => Segment::Spoiler { title, body: current },
+    None => Segment::Markdown(current),
+  });
+  segments
+}
+
+/// Replace `~sub~` with placeholders, outside of code and urls
+fn mark_subscripts(text: &str) -> String {
+  let mut out = String::with_capacity(text.len());
+  let mut in_code = false;
+  for line in text.lines() {
+    if is_fence(line) {
+      in_code = !in_code;
+      out.push_str(line);
+    } else if in_code {
+      out.push_str(line);
+    } else {
+      out.push_str(&mark_subscripts_inline(line));
+    }
+    out.push('\n');
+  }
+  out
+}
+
+fn mark_subscripts_inline(line: &str) -> String {
+  let chars: Vec<char> = line.chars().collect();
+  let is_single_tilde = |i: usize| {
+    chars[i] == '~'
+      && (i == 0 || chars[i - 1] != '~')
+      && chars.get(i + 1).map_or(true, |&c| c != '~')
+  };
+  // Copied as is: code spans, link destinations and urls
+  let verbatim_end = |i: usize| -> Option<usize> {
+    if chars[i] == '`' {
+      let run = chars[i..].iter().take_while(|&&c| c == '`').count();
+      let mut j = i + run;
+      while j < chars.len() {
+        let closing = chars[j..].iter().take_while(|&&c| c == '`').count();
+        if closing == run {
+          return Some(j + run);
+        }
+        j += closing.max(1);
+      }
+      return Some(i + run);
+    }
+    if chars[i] == ']' && chars.get(i + 1) == Some(&'(') {
+      return chars[i..].iter().position(|&c| c == ')').map(|p| i + p + 1);
+    }
+    let rest: String = chars[i..chars.len().min(i + 8)].iter().collect();
+    if rest.starts_with("http://") || rest.starts_with("https://") {
+      let len = chars[i..].iter().take_while(|c| !c.is_whitespace()).count();
+      return Some(i + len);
+    }
+    None
+  };
+
+  let mut out = String::with_capacity(line.len());
+  let mut i = 0;
+  while i < chars.len() {
+    if chars[i] == '\\' {
+      out.extend(chars[i..chars.len().min(i + 2)].iter());
+      i += 2;
+      continue;
+    }
+    if let Some(end) = verbatim_end(i) {
+      out.extend(chars[i..end].iter());
+      i = end;
+      continue;
+    }
+    if is_single_tilde(i) {
+      let close = (i + 1..chars.len()).find(|&j| chars[j] == '~');
+      if let Some(close) = close {
+        let content = &chars[i + 1..close];
+        if !content.is_empty() && !content.iter().any(|c| c.is_whitespace()) && is_single_tilde(close) {
+          out.push(SUB_OPEN);
+          out.extend(content.iter());
+          out.push(SUB_CLOSE);
+          i = close + 1;
+          continue;
+        }
+      }
+    }
+    out.push(chars[i]);
+    i += 1;
+  }
+  out
+}
+
+fn text_of<'a>(node: &'a AstNode<'a>) -> Option<String> {
+  match &node.data.borrow().value {
+    NodeValue::Text(text) => Some(String::from_utf8_lossy(text).into_owned()),
+    _ => None,
+  }
+}
+
+fn is_in_link<'a>(node: &'a AstNode<'a>) -> bool {
+  node.ancestors().any(|n| match n.data.borrow().value {
+    NodeValue::Link(_) | NodeValue::Image(_) => true,
+    _ => false,
+  })
+}
+
+fn new_node<'a>(arena: &'a Arena<AstNode<'a>>, value: NodeValue) -> &'a AstNode<'a> {
+  arena.alloc(Node::new(RefCell::new(Ast::new(value))))
+}
+
+/// Text nodes of the document, adjacent ones merged so references aren't split up
+fn text_nodes<'a>(root: &'a AstNode<'a>) -> Vec<&'a AstNode<'a>> {
+  let nodes: Vec<_> = root.descendants().filter(|n| text_of(n).is_some()).collect();
+  let mut merged = Vec::new();
+  for node in nodes {
+    if node.parent().is_none() {
+      // Merged into its previous sibling already
+      continue;
+    }
+    while let Some(next) = node.next_sibling() {
+      let next_text = match text_of(next) {
+        Some(text) => text,
+        None => break,
+      };
+      if let NodeValue::Text(text) = &mut node.data.borrow_mut().value {
+        text.extend_from_slice(next_text.as_bytes());
+      }
+      next.detach();
+    }
+    merged.push(node);
+  }
+  merged
+}
+
+/**
+ * Text nodes outside of links and images, with the references in their text.
+ * These are the references that get linked.
+ */
+fn references_in<'a>(
+  root: &'a AstNode<'a>,
+) -> Vec<(&'a AstNode<'a>, String, Vec<(std::ops::Range<usize>, Reference)>)> {
+  text_nodes(root)
+    .into_iter()
+    .filter(|node| !is_in_link(node))
+    .filter_map(|node| {
+      let text = text_of(node).unwrap_or_default();
+      let references = find_references(&text);
+      if references.is_empty() {
+        None
+      } else {
+        Some((node, text, references))
+      }
+    })
+    .collect()
+}
+
+/// Replace references in text nodes with links
+fn link_references<'a>(arena: &'a Arena<AstNode<'a>>, root: &'a AstNode<'a>, local_host: &str) {
+  for (node, text, references) in references_in(root) {
+    let mut position = 0;
+    for (range, reference) in references {
+      if range.start > position {
+        let before = text[position..range.start].as_bytes().to_vec();
+        node.insert_before(new_node(arena, NodeValue::Text(before)));
+      }
+      let link = new_node(
+        arena,
+        NodeValue::Link(NodeLink {
+          url: reference.url(local_host).into_bytes(),
+          title: Vec::new(),
+        }),
+      );
+      link.append(new_node(arena, NodeValue::Text(text[range.clone()].as_bytes().to_vec())));
+      node.insert_before(link);
+      position = range.end;
+    }
+    if position < text.len() {
+      node.insert_before(new_node(arena, NodeValue::Text(text[position..].as_bytes().to_vec())));
+    }
+    node.detach();
+  }
+}
+
+/// Escape text for html content and double quoted attributes
+pub(crate) fn escape_html(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// Parse a segment of markdown, rendering and extract_mentions both go through here
+fn parse_segment<'a>(arena: &'a Arena<AstNode<'a>>, text: &str, options: &ComrakOptions) -> &'a AstNode<'a> {
+  parse_document(arena, &mark_subscripts(text), options)
+}
+
+/// Markdown segments of text, without our placeholders in case the text has them
+fn segments(text: &str) -> Vec<Segment> {
+  split_spoilers(&text.replace(|c| c == SUB_OPEN || c == SUB_CLOSE, ""))
+}
+
+fn render_segment(text: &str, options: &ComrakOptions, local_host: &str) -> String {
+  let arena = Arena::new();
+  let root = parse_segment(&arena, text, options);
+  link_references(&arena, root, local_host);
+
+  let mut html = Vec::new();
+  if format_html(root, options, &mut html).is_err() {
+    return String::new();
+  }
+  String::from_utf8_lossy(&html)
+    .replace(SUB_OPEN, "<sub>")
+    .replace(SUB_CLOSE, "</sub>")
+}
+
+/**
+ * Render markdown with the extensions to html, which still has to be sanitized.
+ * References to local_host are rendered as relative links.
+ */
+pub fn render_extended(text: &str, options: &ComrakOptions, local_host: &str) -> String {
+  segments(text)
+    .into_iter()
+    .map(|segment| match segment {
+      Segment::Markdown(markdown) => render_segment(&markdown, options, local_host),
+      Segment::Spoiler { title, body } => format!(
+        "<details><summary>{}</summary>\n{}</details>\n",
+        escape_html(&title),
+        render_segment(&body, options, local_host)
+      ),
+    })
+    .collect()
+}
+
+/// Users mentioned in text, without duplicates
+pub fn extract_mentions(text: &str, options: &ComrakOptions) -> Vec<Mention> {
+  let mut mentions = Vec::new();
+  for segment in segments(text) {
+    let markdown = match segment {
+      Segment::Markdown(markdown) => markdown,
+      Segment::Spoiler { body, .. } => body,
+    };
+    let arena = Arena::new();
+    let root = parse_segment(&arena, &markdown, options);
+    for (_node, _text, references) in references_in(root) {
+      for (_range, reference) in references {
+        if let Reference::User(mention) = reference {
+          mentions.push(mention);
+        }
+      }
+    }
+  }
+  mentions.sort_unstable();
+  mentions.dedup();
+  mentions
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn options() -> ComrakOptions {
+    ComrakOptions {
+      ext_strikethrough: true,
+      ext_superscript: true,
+      ..ComrakOptions::default()
+    }
+  }
+
+  fn render(text: &str) -> String {
+    render_extended(text, &options(), "example.com")
+  }
+
+  #[test]
+  fn test_spoiler() {
+    let html = render("before\n::: spoiler big <secret>\nthe *answer*\n:::\nafter");
+    assert!(html.contains("<p>before</p>"));
+    assert!(html.contains("<details><summary>big &lt;secret&gt;</summary>"));
+    assert!(html.contains("<p>the <em>answer</em></p>\n</details>"));
+    assert!(html.contains("<p>after</p>"));
+
+    let html = render("```\n::: spoiler no\n```");
+    assert!(!html.contains("<details>"));
+  }
+
+  #[test]
+  fn test_sub_and_superscript() {
+    assert_eq!(render("H~2~O and E=mc^2^"), "<p>H<sub>2</sub>O and E=mc<sup>2</sup></p>\n");
+    assert_eq!(render("~~gone~~"), "<p><del>gone</del></p>\n");
+    assert_eq!(render("`a~b~c`"), "<p><code>a~b~c</code></p>\n");
+    assert!(!render("\u{E000}x").contains("<sub>"));
+  }
+
+  #[test]
+  fn test_references() {
+    let html = render("hi /u/bob and @alice@lemmy.ml in /c/main and !rust@other.org.");
+    assert!(html.contains("<a href=\"/u/bob\">/u/bob</a>"));
+    assert!(html.contains("<a href=\"https://lemmy.ml/u/alice\">@alice@lemmy.ml</a>"));
+    assert!(html.contains("<a href=\"/c/main\">/c/main</a>"));
+    assert!(html.contains("<a href=\"https://other.org/c/rust\">!rust@other.org</a>."));
+
+    let html = render("@me@example.com https://example.com/u/bob?x=1, [/u/carl](/u/carl)");
+    assert!(html.contains("<a href=\"/u/me\">@me@example.com</a>"));
+    assert!(html.contains("<a href=\"https://example.com/u/bob?x=1\">https://example.com/u/bob?x=1</a>,"));
+    assert_eq!(html.matches("<a ").count(), 3);
+
+    assert!(!render("mail bob@example.com or see foo/u/bob").contains("<a "));
+  }
+
+  #[test]
+  fn test_extract_mentions() {
+    let mentions = extract_mentions(
+      "/u/bob, @alice@lemmy.ml and [/u/bob](/u/bob)\n::: spoiler\n@carl@example.com\n:::\n`/u/nope`",
+      &options(),
+    );
+    let names: Vec<_> = mentions.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, vec!["alice", "bob", "carl"]);
+    assert!(mentions[1].is_local("example.com"));
+    assert!(!mentions[0].is_local("example.com"));
+    assert!(mentions[2].is_local("example.com"));
+
+    // Like rendering, link texts and images don't mention anyone
+    let mentions = extract_mentions(
+      "[/u/dave](https://example.org) ![@erin@lemmy.ml](/pic.png) H~/u/frank~",
+      &options(),
+    );
+    let names: Vec<_> = mentions.iter().map(|m| m.name.as_str()).collect();
+    assert_eq!(names, vec!["frank"]);
+  }
+}
@@ -0,0 +1,223 @@
+/**
+ * Markdown rendering for all html the server emits (rss feeds, emails).
+ *
+ * Rendered html is sanitized against an allowlist of tags and attributes,
+ * relative links are made absolute to this instance, and every link gets
+ * rel="nofollow noopener noreferrer". Renders of posts and comments are
+ * cached per revision and text, until the config is reloaded. Lemmy's own
+ * syntax is in the extensions module.
+ */
+use crate::settings::Settings;
+use crate::sha256_hex;
+use ammonia::{Builder, Url, UrlRelative};
+use comrak::ComrakOptions;
+use lru::LruCache;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+mod extensions;
+pub(crate) use extensions::escape_html;
+pub use extensions::Mention;
+
+/// Number of rendered revisions kept in memory
+const RENDER_CACHE_SIZE: usize = 2000;
+
+const ALLOWED_TAGS: &[&str] = &[
+  "a", "b", "blockquote", "br", "code", "del", "details", "em", "h1", "h2", "h3", "h4", "h5",
+  "h6", "hr", "i", "img", "li", "ol", "p", "pre", "s", "strong", "sub", "summary", "sup", "table",
+  "tbody", "td", "th", "thead", "tr", "ul",
+];
+const ALLOWED_URL_SCHEMES: &[&str] = &["http", "https", "mailto"];
+const LINK_REL: &str = "nofollow noopener noreferrer";
+
+lazy_static! {
+  static ref RENDER_CACHE: Mutex<LruCache<CacheKey, String>> =
+    Mutex::new(LruCache::new(RENDER_CACHE_SIZE));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentKind {
+  Post,
+  Comment,
+}
+
+/**
+ * A version of a post or comment. Edits change `updated`, so a new
+ * revision is rendered, and the old one drops out of the cache.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Revision {
+  pub kind: ContentKind,
+  pub id: i64,
+  pub revision: chrono::NaiveDateTime,
+}
+
+impl Revision {
+  pub fn post(id: i64, published: chrono::NaiveDateTime, updated: Option<chrono::NaiveDateTime>) -> Self {
+    Revision {
+      kind: ContentKind::Post,
+      id,
+      revision: updated.unwrap_or(published),
+    }
+  }
+
+  pub fn comment(id: i64, published: chrono::NaiveDateTime, updated: Option<chrono::NaiveDateTime>) -> Self {
+    Revision {
+      kind: ContentKind::Comment,
+      id,
+      revision: updated.unwrap_or(published),
+    }
+  }
+}
+
+/**
+ * Key of a cached render. The hash of the text catches changes that don't
+ * bump `updated`, like a mod removing content or the author editing within the
+ * same second.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+  revision: Revision,
+  text_hash: String,
+}
+
+fn comrak_options() -> ComrakOptions {
+  ComrakOptions {
+    ext_strikethrough: true,
+    ext_table: true,
+    // Urls are linked by the extensions, along with mentions
+    ext_autolink: false,
+    ext_tagfilter: true,
+    ext_superscript: true,
+    ..ComrakOptions::default()
+  }
+}
+
+fn sanitizer(base_url: &Url) -> Builder<'static> {
+  let mut tag_attributes = std::collections::HashMap::new();
+  tag_attributes.insert("a", ["href", "title"].iter().cloned().collect::<HashSet<_>>());
+  tag_attributes.insert("img", ["src", "alt", "title"].iter().cloned().collect());
+  tag_attributes.insert("td", ["align"].iter().cloned().collect());
+  tag_attributes.insert("th", ["align"].iter().cloned().collect());
+
+  let mut builder = Builder::empty();
+  builder
+    .tags(ALLOWED_TAGS.iter().cloned().collect())
+    .tag_attributes(tag_attributes)
+    .url_schemes(ALLOWED_URL_SCHEMES.iter().cloned().collect())
+    .url_relative(UrlRelative::RewriteWithBase(base_url.to_owned()))
+    .link_rel(Some(LINK_REL));
+  builder
+}
+
+/// Render markdown to sanitized html, with links relative to base_url
+fn render_with_base(text: &str, base_url: &Url) -> String {
+  let local_host = base_url.host_str().unwrap_or_default();
+  let html = extensions::render_extended(text, &comrak_options(), local_host);
+  sanitizer(base_url).clean(&html).to_string()
+}
+
+fn instance_url() -> Url {
+  let hostname = &Settings::get().hostname;
+  Url::parse(&format!("https://{}/", hostname)).unwrap_or_else(|_| {
+    Url::parse("https://localhost/").expect("valid fallback url")
+  })
+}
+
+/// Render markdown to sanitized html
+pub fn render(text: &str) -> String {
+  render_with_base(text, &instance_url())
+}
+
+/// Users mentioned in markdown text, exactly those rendered as links to them
+pub fn extract_mentions(text: &str) -> Vec<Mention> {
+  extensions::extract_mentions(text, &comrak_options())
+}
+
+/// Render a revision of a post or comment, from the cache if possible
+pub fn render_revision(revision: Revision, text: &str) -> String {
+  render_revision_with_base(revision, text, &instance_url())
+}
+
+fn render_revision_with_base(revision: Revision, text: &str, base_url: &Url) -> String {
+  let key = CacheKey {
+    revision,
+    text_hash: sha256_hex(text),
+  };
+  if let Some(html) = RENDER_CACHE.lock().unwrap().get(&key) {
+    return html.to_owned();
+  }
+
+  let html = render_with_base(text, base_url);
+  RENDER_CACHE.lock().unwrap().put(key, html.to_owned());
+  html
+}
+
+/**
+ * Drop all cached renders. Called when the config is reloaded, as links in
+ * them point to the hostname of the old config.
+ */
+pub fn clear_render_cache() {
+  RENDER_CACHE.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn render_test(text: &str) -> String {
+    render_with_base(text, &Url::parse("https://example.com/").unwrap())
+  }
+
+  #[test]
+  fn test_markdown() {
+    assert_eq!(render_test("**bold**"), "<p><strong>bold</strong></p>\n");
+    assert!(render_test("~~gone~~").contains("<del>gone</del>"));
+    assert!(render_test("| a |\n|---|\n| b |").contains("<td>b</td>"));
+  }
+
+  #[test]
+  fn test_sanitize() {
+    let html = render_test("<script>alert(1)</script> <b onclick=\"x()\">hi</b>");
+    assert!(!html.contains("script"));
+    assert!(!html.contains("onclick"));
+
+    let html = render_test("[x](javascript:alert(1))");
+    assert!(!html.contains("javascript"));
+
+    let html = render_test("<iframe src=\"https://evil.com\"></iframe>");
+    assert!(!html.contains("iframe"));
+  }
+
+  #[test]
+  fn test_links() {
+    let html = render_test("[out](https://other.org/page)");
+    assert!(html.contains("href=\"https://other.org/page\""));
+    assert!(html.contains("rel=\"nofollow noopener noreferrer\""));
+
+    let html = render_test("[home](/c/main)");
+    assert!(html.contains("href=\"https://example.com/c/main\""));
+
+    let html = render_test("see /c/main, by @bob@example.com");
+    assert!(html.contains("href=\"https://example.com/c/main\""));
+    assert!(html.contains("href=\"https://example.com/u/bob\""));
+  }
+
+  #[test]
+  fn test_render_cache_checks_text() {
+    let base_url = Url::parse("https://example.com/").unwrap();
+    let published = chrono::NaiveDate::from_ymd(2020, 1, 1).and_hms(0, 0, 0);
+    let revision = Revision::comment(-1, published, None);
+
+    let html = render_revision_with_base(revision, "first", &base_url);
+    assert!(html.contains("first"));
+    let html = render_revision_with_base(revision, "second", &base_url);
+    assert!(html.contains("second"));
+  }
+
+  #[test]
+  fn test_spoiler_allowed() {
+    let html = render_test("::: spoiler title\nhidden\n:::");
+    assert!(html.contains("<details><summary>title</summary>"));
+  }
+}
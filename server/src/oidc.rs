@@ -0,0 +1,473 @@
+/**
+ * Login with OpenID Connect providers, using the authorization code flow with PKCE.
+ *
+ * The endpoints and signing keys of a provider are read from its discovery
+ * document, so any compliant issuer works, including a local mock issuer for
+ * testing. A login in progress is stored in the graph, so the callback can land
+ * on any server. Its state is also kept in a cookie signed with the jwt secret,
+ * and the callback only accepts the state of the browser that started the
+ * login.
+ */
+use crate::db::oidc_login::{OidcPendingLogin, OidcPendingLoginForm};
+use crate::settings::{OidcProviderConfig, Settings};
+use crate::{generate_random_string, is_valid_username};
+use dgraph_tonic as dgraph;
+use failure::Error;
+use hmac::{Hmac, Mac};
+use isahc::prelude::*;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long the user has to log in at the provider, in minutes
+const LOGIN_TIMEOUT_MINUTES: i64 = 10;
+/// Allowed clock difference with the provider when checking token expiry
+const CLOCK_SKEW_SECONDS: i64 = 60;
+/// Cookie with the signed state of the login started by the browser
+pub const STATE_COOKIE: &str = "oidc_state";
+
+/// Provider as shown to clients
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OidcProviderInfo {
+  pub name: String,
+  pub display_name: String,
+}
+
+/// The parts of the discovery document we need
+#[derive(Deserialize, Debug)]
+struct ProviderMetadata {
+  issuer: String,
+  authorization_endpoint: String,
+  token_endpoint: String,
+  jwks_uri: String,
+}
+
+/// The signing keys of the provider
+#[derive(Deserialize, Debug)]
+struct Jwks {
+  keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Jwk {
+  kty: String,
+  kid: Option<String>,
+  /// RSA modulus and exponent, base64url encoded
+  n: Option<String>,
+  e: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+  id_token: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IdTokenClaims {
+  pub iss: String,
+  pub sub: String,
+  /// A single client id or a list of them
+  pub aud: serde_json::Value,
+  pub exp: i64,
+  pub nonce: Option<String>,
+  pub email: Option<String>,
+  pub email_verified: Option<bool>,
+  pub preferred_username: Option<String>,
+}
+
+/// Result of a finished login at the provider
+pub struct OidcLogin {
+  pub provider: String,
+  pub claims: IdTokenClaims,
+  pub link_user_id: Option<i64>,
+}
+
+/// Configured providers, for login buttons
+pub fn provider_infos() -> Vec<OidcProviderInfo> {
+  Settings::get()
+    .oidc_providers
+    .iter()
+    .map(|p| OidcProviderInfo {
+      name: p.name.to_owned(),
+      display_name: p.display_name.to_owned(),
+    })
+    .collect()
+}
+
+fn provider(name: &str) -> Result<OidcProviderConfig, Error> {
+  Settings::get()
+    .oidc_providers
+    .iter()
+    .find(|p| p.name == name)
+    .cloned()
+    .ok_or_else(|| format_err!("unknown_oidc_provider"))
+}
+
+fn redirect_uri(provider: &OidcProviderConfig) -> String {
+  match &provider.redirect_uri {
+    Some(redirect_uri) => redirect_uri.to_owned(),
+    None => format!(
+      "https://{}/oidc/{}/callback",
+      Settings::get().hostname,
+      provider.name
+    ),
+  }
+}
+
+async fn fetch_metadata(provider: &OidcProviderConfig) -> Result<ProviderMetadata, Error> {
+  let url = format!(
+    "{}/.well-known/openid-configuration",
+    provider.issuer.trim_end_matches('/')
+  );
+  let text = isahc::get_async(&url).await?.text_async().await?;
+  let metadata: ProviderMetadata = serde_json::from_str(&text)?;
+
+  if metadata.issuer.trim_end_matches('/') != provider.issuer.trim_end_matches('/') {
+    bail!("Issuer mismatch in {}", url);
+  }
+  Ok(metadata)
+}
+
+/// S256 code challenge for the PKCE code verifier (RFC 7636)
+pub fn pkce_challenge(code_verifier: &str) -> String {
+  let hash = Sha256::digest(code_verifier.as_bytes());
+  base64::encode_config(&hash, base64::URL_SAFE_NO_PAD)
+}
+
+fn encode(value: &str) -> String {
+  utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+pub fn form_urlencoded(params: &[(&str, &str)]) -> String {
+  params
+    .iter()
+    .map(|(key, value)| format!("{}={}", key, encode(value)))
+    .collect::<Vec<String>>()
+    .join("&")
+}
+
+fn state_mac(secret: &str, state: &str) -> HmacSha256 {
+  let mut mac = HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC can take key of any size");
+  mac.input(state.as_bytes());
+  mac
+}
+
+fn signed_state(secret: &str, state: &str) -> String {
+  let signature = state_mac(secret, state).result().code();
+  format!("{}.{}", state, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD))
+}
+
+fn check_signed_state(secret: &str, cookie_value: &str, state: &str) -> bool {
+  let mut parts = cookie_value.splitn(2, '.');
+  let (cookie_state, signature) = match (parts.next(), parts.next()) {
+    (Some(cookie_state), Some(signature)) => (cookie_state, signature),
+    _ => return false,
+  };
+  let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+    Ok(signature) => signature,
+    Err(_) => return false,
+  };
+  // verify compares in constant time
+  state_mac(secret, cookie_state).verify(&signature).is_ok() && cookie_state == state
+}
+
+/// Value of the state cookie: the state and its signature
+pub fn state_cookie_value(state: &str) -> String {
+  signed_state(&Settings::get().jwt_secret, state)
+}
+
+/// Whether the state cookie is signed by us and holds state
+pub fn check_state_cookie(cookie_value: &str, state: &str) -> bool {
+  check_signed_state(&Settings::get().jwt_secret, cookie_value, state)
+}
+
+/**
+ * Start a login at the provider.
+ *
+ * @return (url, state) : the url to redirect the user to, and the state to
+ * put in the state cookie
+ */
+pub async fn start_login(
+  conn: &dgraph::Client,
+  provider_name: &str,
+  link_user_id: Option<i64>,
+) -> Result<(String, String), Error> {
+  let provider = provider(provider_name)?;
+  let metadata = fetch_metadata(&provider).await?;
+
+  let state = generate_random_string();
+  let nonce = generate_random_string();
+  // 60 alphanumeric characters, RFC 7636 requires 43 to 128
+  let code_verifier = format!("{}{}", generate_random_string(), generate_random_string());
+  let code_challenge = pkce_challenge(&code_verifier);
+
+  let query = form_urlencoded(&[
+    ("response_type", "code"),
+    ("client_id", &provider.client_id),
+    ("redirect_uri", &redirect_uri(&provider)),
+    ("scope", &provider.scopes),
+    ("state", &state),
+    ("nonce", &nonce),
+    ("code_challenge", &code_challenge),
+    ("code_challenge_method", "S256"),
+  ]);
+
+  let form = OidcPendingLoginForm {
+    provider: provider.name,
+    code_verifier,
+    nonce,
+    link_user_id,
+  };
+  OidcPendingLogin::create(
+    conn,
+    &state,
+    &form,
+    chrono::Duration::minutes(LOGIN_TIMEOUT_MINUTES),
+  )
+  .await?;
+
+  let separator = if metadata.authorization_endpoint.contains('?') {
+    '&'
+  } else {
+    '?'
+  };
+  let url = format!("{}{}{}", metadata.authorization_endpoint, separator, query);
+  Ok((url, state))
+}
+
+/**
+ * Finish a login when the provider redirects back: exchange the code for an
+ * id token and check it. The caller checks the state cookie first.
+ */
+pub async fn finish_login(
+  conn: &dgraph::Client,
+  provider_name: &str,
+  code: &str,
+  state: &str,
+) -> Result<OidcLogin, Error> {
+  // The state is single-use
+  let pending = match OidcPendingLogin::consume(conn, state).await {
+    Ok(pending) => pending,
+    Err(_e) => bail!("invalid_oidc_state"),
+  };
+  if pending.provider != provider_name {
+    bail!("invalid_oidc_state");
+  }
+
+  let provider = provider(provider_name)?;
+  let metadata = fetch_metadata(&provider).await?;
+
+  let body = form_urlencoded(&[
+    ("grant_type", "authorization_code"),
+    ("code", code),
+    ("redirect_uri", &redirect_uri(&provider)),
+    ("client_id", &provider.client_id),
+    ("client_secret", &provider.client_secret),
+    ("code_verifier", &pending.code_verifier),
+  ]);
+  let mut response = Request::post(&metadata.token_endpoint)
+    .header("Content-Type", "application/x-www-form-urlencoded")
+    .header("Accept", "application/json")
+    .body(body)?
+    .send_async()
+    .await?;
+  if !response.status().is_success() {
+    bail!("Token request failed: {}", response.status());
+  }
+  let token: TokenResponse = serde_json::from_str(&response.text_async().await?)?;
+
+  let claims = verify_id_token(&metadata, &token.id_token).await?;
+  validate_claims(
+    &claims,
+    &metadata.issuer,
+    &provider.client_id,
+    &pending.nonce,
+    chrono::Utc::now().timestamp(),
+  )?;
+
+  Ok(OidcLogin {
+    provider: provider.name,
+    claims,
+    link_user_id: pending.link_user_id,
+  })
+}
+
+/// Check the signature of the id token with the RSA keys of the provider
+async fn verify_id_token(metadata: &ProviderMetadata, id_token: &str) -> Result<IdTokenClaims, Error> {
+  let header = jsonwebtoken::decode_header(id_token)?;
+  match header.alg {
+    Algorithm::RS256
+    | Algorithm::RS384
+    | Algorithm::RS512
+    | Algorithm::PS256
+    | Algorithm::PS384
+    | Algorithm::PS512 => {}
+    alg => bail!("Unsupported id token algorithm {:?}", alg),
+  }
+
+  let text = isahc::get_async(&metadata.jwks_uri).await?.text_async().await?;
+  let jwks: Jwks = serde_json::from_str(&text)?;
+  let key = select_key(&jwks.keys, header.kid.as_deref())?;
+  let (n, e) = match (&key.n, &key.e) {
+    (Some(n), Some(e)) => (n, e),
+    _ => bail!("Id token key without modulus or exponent"),
+  };
+
+  let validation = Validation {
+    algorithms: vec![header.alg],
+    leeway: CLOCK_SKEW_SECONDS as u64,
+    ..Validation::default()
+  };
+  let data = jsonwebtoken::decode::<IdTokenClaims>(
+    id_token,
+    &DecodingKey::from_rsa_components(n, e),
+    &validation,
+  )?;
+  Ok(data.claims)
+}
+
+/// The RSA key with kid, or the only RSA key if the token doesn't name one
+fn select_key<'a>(keys: &'a [Jwk], kid: Option<&str>) -> Result<&'a Jwk, Error> {
+  let mut rsa_keys = keys.iter().filter(|key| key.kty == "RSA");
+  let key = match kid {
+    Some(kid) => rsa_keys.find(|key| key.kid.as_deref() == Some(kid)),
+    None => match (rsa_keys.next(), rsa_keys.next()) {
+      (Some(key), None) => Some(key),
+      _ => None,
+    },
+  };
+  key.ok_or_else(|| format_err!("No matching key for the id token"))
+}
+
+pub fn validate_claims(
+  claims: &IdTokenClaims,
+  issuer: &str,
+  client_id: &str,
+  nonce: &str,
+  now: i64,
+) -> Result<(), Error> {
+  if claims.iss != issuer {
+    bail!("Id token issuer mismatch");
+  }
+  let audience_ok = match &claims.aud {
+    serde_json::Value::String(aud) => aud == client_id,
+    serde_json::Value::Array(auds) => auds.iter().any(|aud| aud == client_id),
+    _ => false,
+  };
+  if !audience_ok {
+    bail!("Id token audience mismatch");
+  }
+  if claims.exp + CLOCK_SKEW_SECONDS < now {
+    bail!("Id token expired");
+  }
+  if claims.nonce.as_deref() != Some(nonce) {
+    bail!("Id token nonce mismatch");
+  }
+  Ok(())
+}
+
+/// Username for an auto-registered user, based on the claims. Still has to be
+/// checked for collisions.
+pub fn username_candidate(claims: &IdTokenClaims) -> String {
+  let base = claims
+    .preferred_username
+    .as_deref()
+    .or_else(|| claims.email.as_deref().and_then(|e| e.split('@').next()))
+    .unwrap_or("");
+  let name: String = base
+    .chars()
+    .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+    .take(20)
+    .collect();
+
+  if is_valid_username(&name) {
+    name
+  } else {
+    format!("user_{}", &generate_random_string()[..8]).to_lowercase()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn claims() -> IdTokenClaims {
+    IdTokenClaims {
+      iss: "http://localhost:8081/default".into(),
+      sub: "1234".into(),
+      aud: serde_json::json!("lemmy"),
+      exp: 1000,
+      nonce: Some("n0nce".into()),
+      email: Some("jim.bob@example.com".into()),
+      email_verified: Some(true),
+      preferred_username: None,
+    }
+  }
+
+  #[test]
+  fn test_pkce_challenge() {
+    // RFC 7636 appendix B
+    assert_eq!(
+      pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+      "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+    );
+  }
+
+  #[test]
+  fn test_validate_claims() {
+    let issuer = "http://localhost:8081/default";
+    assert!(validate_claims(&claims(), issuer, "lemmy", "n0nce", 900).is_ok());
+    assert!(validate_claims(&claims(), issuer, "lemmy", "n0nce", 1000 + CLOCK_SKEW_SECONDS + 1).is_err());
+    assert!(validate_claims(&claims(), issuer, "lemmy", "other", 900).is_err());
+    assert!(validate_claims(&claims(), issuer, "other", "n0nce", 900).is_err());
+    assert!(validate_claims(&claims(), "http://evil", "lemmy", "n0nce", 900).is_err());
+
+    let mut multiple_audiences = claims();
+    multiple_audiences.aud = serde_json::json!(["other", "lemmy"]);
+    assert!(validate_claims(&multiple_audiences, issuer, "lemmy", "n0nce", 900).is_ok());
+  }
+
+  #[test]
+  fn test_check_signed_state() {
+    let cookie = signed_state("secret", "st4te");
+    assert!(check_signed_state("secret", &cookie, "st4te"));
+    assert!(!check_signed_state("secret", &cookie, "other"));
+    assert!(!check_signed_state("other secret", &cookie, "st4te"));
+    assert!(!check_signed_state("secret", "st4te", "st4te"));
+
+    let signature = cookie.splitn(2, '.').nth(1).unwrap();
+    let forged = format!("other.{}", signature);
+    assert!(!check_signed_state("secret", &forged, "other"));
+  }
+
+  #[test]
+  fn test_select_key() {
+    let key = |kty: &str, kid: &str| Jwk {
+      kty: kty.into(),
+      kid: Some(kid.into()),
+      n: Some("n".into()),
+      e: Some("AQAB".into()),
+    };
+    let keys = vec![key("EC", "a"), key("RSA", "b"), key("RSA", "c")];
+    assert_eq!(select_key(&keys, Some("c")).unwrap().kid.as_deref(), Some("c"));
+    assert!(select_key(&keys, Some("a")).is_err());
+    assert!(select_key(&keys, None).is_err());
+    assert!(select_key(&keys[..2], None).is_ok());
+  }
+
+  #[test]
+  fn test_username_candidate() {
+    assert_eq!(username_candidate(&claims()), "jimbob");
+
+    let mut with_username = claims();
+    with_username.preferred_username = Some("Jim_Bob".into());
+    assert_eq!(username_candidate(&with_username), "Jim_Bob");
+
+    let mut anonymous = claims();
+    anonymous.email = None;
+    assert!(username_candidate(&anonymous).starts_with("user_"));
+  }
+}
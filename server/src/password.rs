@@ -0,0 +1,195 @@
+/**
+ * Password hashing.
+ *
+ * New hashes use the algorithm from the `password_hashing` config. Verification
+ * picks the hasher from the prefix of the stored hash, so bcrypt hashes from
+ * before the switch to Argon2id keep working until they are rehashed at login.
+ */
+use crate::settings::{PasswordHashingConfig, Settings};
+use argon2::{Config, ThreadMode, Variant, Version};
+use failure::Error;
+use rand::{thread_rng, Rng};
+
+const ARGON2_SALT_BYTES: usize = 16;
+const ARGON2_HASH_BYTES: u32 = 32;
+
+pub trait PasswordHasher {
+  fn hash(&self, password: &str) -> Result<String, Error>;
+  fn verify(&self, password: &str, hash: &str) -> bool;
+  /// Whether the hash was created by this hasher with its current parameters
+  fn is_current(&self, hash: &str) -> bool;
+}
+
+pub struct Argon2idHasher {
+  pub memory_kib: u32,
+  pub iterations: u32,
+  pub parallelism: u32,
+}
+
+impl Argon2idHasher {
+  fn params(&self) -> String {
+    format!(
+      "m={},t={},p={}",
+      self.memory_kib, self.iterations, self.parallelism
+    )
+  }
+}
+
+impl PasswordHasher for Argon2idHasher {
+  fn hash(&self, password: &str) -> Result<String, Error> {
+    let salt: Vec<u8> = (0..ARGON2_SALT_BYTES).map(|_| thread_rng().gen()).collect();
+    let config = Config {
+      variant: Variant::Argon2id,
+      version: Version::Version13,
+      mem_cost: self.memory_kib,
+      time_cost: self.iterations,
+      lanes: self.parallelism,
+      thread_mode: ThreadMode::Sequential,
+      secret: &[],
+      ad: &[],
+      hash_length: ARGON2_HASH_BYTES,
+    };
+    Ok(argon2::hash_encoded(password.as_bytes(), &salt, &config)?)
+  }
+
+  fn verify(&self, password: &str, hash: &str) -> bool {
+    argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+  }
+
+  fn is_current(&self, hash: &str) -> bool {
+    // $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+    let parts: Vec<&str> = hash.split('$').collect();
+    parts.len() == 6 && parts[1] == "argon2id" && parts[2] == "v=19" && parts[3] == self.params()
+  }
+}
+
+pub struct BcryptHasher {
+  pub cost: u32,
+}
+
+impl PasswordHasher for BcryptHasher {
+  fn hash(&self, password: &str) -> Result<String, Error> {
+    Ok(bcrypt::hash(password, self.cost)?)
+  }
+
+  fn verify(&self, password: &str, hash: &str) -> bool {
+    bcrypt::verify(password, hash).unwrap_or(false)
+  }
+
+  fn is_current(&self, hash: &str) -> bool {
+    // $2b$12$<salt and hash>
+    is_bcrypt_hash(hash) && hash.get(4..6) == Some(format!("{:02}", self.cost).as_str())
+  }
+}
+
+fn is_bcrypt_hash(hash: &str) -> bool {
+  ["$2a$", "$2b$", "$2x$", "$2y$"]
+    .iter()
+    .any(|prefix| hash.starts_with(prefix))
+}
+
+/// The hasher for new passwords, as configured
+pub fn default_hasher(config: &PasswordHashingConfig) -> Box<dyn PasswordHasher> {
+  match config.algorithm.as_str() {
+    "bcrypt" => Box::new(BcryptHasher {
+      cost: config.bcrypt_cost,
+    }),
+    _ => Box::new(Argon2idHasher {
+      memory_kib: config.argon2_memory_kib,
+      iterations: config.argon2_iterations,
+      parallelism: config.argon2_parallelism,
+    }),
+  }
+}
+
+/// The hasher able to verify an existing hash, if the format is known
+fn hasher_for(hash: &str, config: &PasswordHashingConfig) -> Option<Box<dyn PasswordHasher>> {
+  if hash.starts_with("$argon2id$") {
+    Some(Box::new(Argon2idHasher {
+      memory_kib: config.argon2_memory_kib,
+      iterations: config.argon2_iterations,
+      parallelism: config.argon2_parallelism,
+    }))
+  } else if is_bcrypt_hash(hash) {
+    Some(Box::new(BcryptHasher {
+      cost: config.bcrypt_cost,
+    }))
+  } else {
+    None
+  }
+}
+
+/// Hash a new password with the configured algorithm
+pub fn hash_password(password: &str) -> Result<String, Error> {
+  default_hasher(&Settings::get().password_hashing).hash(password)
+}
+
+/// Check a password against a stored hash of any supported algorithm
+pub fn verify_password(password: &str, hash: &str) -> bool {
+  match hasher_for(hash, &Settings::get().password_hashing) {
+    Some(hasher) => hasher.verify(password, hash),
+    None => false,
+  }
+}
+
+/// Whether a stored hash should be replaced, because the algorithm or its
+/// parameters changed since it was created
+pub fn needs_rehash(hash: &str) -> bool {
+  !default_hasher(&Settings::get().password_hashing).is_current(hash)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fast_argon2() -> Argon2idHasher {
+    Argon2idHasher {
+      memory_kib: 64,
+      iterations: 1,
+      parallelism: 1,
+    }
+  }
+
+  #[test]
+  fn test_argon2id() {
+    let hasher = fast_argon2();
+    let hash = hasher.hash("hunter22").unwrap();
+    assert!(hash.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+    assert!(hasher.verify("hunter22", &hash));
+    assert!(!hasher.verify("hunter23", &hash));
+    assert!(hasher.is_current(&hash));
+
+    let stronger = Argon2idHasher {
+      iterations: 2,
+      ..fast_argon2()
+    };
+    assert!(!stronger.is_current(&hash));
+    assert!(stronger.verify("hunter22", &hash));
+  }
+
+  #[test]
+  fn test_bcrypt() {
+    let hasher = BcryptHasher { cost: 4 };
+    let hash = hasher.hash("hunter22").unwrap();
+    assert!(hasher.verify("hunter22", &hash));
+    assert!(hasher.is_current(&hash));
+    assert!(!BcryptHasher { cost: 5 }.is_current(&hash));
+    assert!(!fast_argon2().is_current(&hash));
+  }
+
+  #[test]
+  fn test_hasher_for() {
+    let config = PasswordHashingConfig {
+      algorithm: "argon2id".into(),
+      argon2_memory_kib: 64,
+      argon2_iterations: 1,
+      argon2_parallelism: 1,
+      bcrypt_cost: 4,
+    };
+    let bcrypt_hash = BcryptHasher { cost: 4 }.hash("hunter22").unwrap();
+    assert!(hasher_for(&bcrypt_hash, &config)
+      .unwrap()
+      .verify("hunter22", &bcrypt_hash));
+    assert!(hasher_for("plaintext", &config).is_none());
+  }
+}
@@ -1,7 +1,6 @@
 pub mod rate_limiter;
 
 use super::{IPAddr, Settings};
-use crate::api::APIError;
 use crate::get_ip;
 use crate::settings::RateLimitConfig;
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
@@ -64,15 +63,7 @@ impl RateLimited {
   where
     E: From<failure::Error>,
   {
-    let rate_limit: RateLimitConfig = actix_web::web::block(move || {
-      // needs to be in a web::block because the RwLock in settings is from stdlib
-      Ok(Settings::get().rate_limit) as Result<_, failure::Error>
-    })
-    .await
-    .map_err(|e| match e {
-      actix_web::error::BlockingError::Error(e) => e,
-      _ => APIError::err("Operation canceled").into(),
-    })?;
+    let rate_limit: RateLimitConfig = Settings::get().rate_limit.to_owned();
 
     // before
     {
@@ -0,0 +1,36 @@
+use super::*;
+use crate::data_export;
+use crate::db::data_export::DataExport;
+use dgraph_tonic as dgraph;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+  cfg.route("/export/{token}", web::get().to(download));
+}
+
+/**
+ * Serve a data export archive. The token comes from GetDataExport and only
+ * works once, so the link is useless once it leaks through history or logs.
+ */
+async fn download(token: web::Path<String>, db: web::Data<dgraph::Client>) -> HttpResponse {
+  let export = match DataExport::use_download_token(&db, &token).await {
+    Ok(export) => export,
+    Err(_e) => return HttpResponse::NotFound().finish(),
+  };
+
+  // Archives can be large, they are read off the worker thread
+  let path = data_export::archive_path(&export.file_name);
+  match web::block(move || std::fs::read(path)).await {
+    Ok(bytes) => HttpResponse::Ok()
+      .content_type(data_export::content_type(&export.file_name))
+      .header(
+        http::header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", export.file_name),
+      )
+      .header(http::header::CACHE_CONTROL, "no-store")
+      .body(bytes),
+    Err(e) => {
+      error!("Couldn't read data export {}: {}", export.file_name, e);
+      HttpResponse::NotFound().finish()
+    }
+  }
+}
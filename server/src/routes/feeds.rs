@@ -6,6 +6,7 @@ use crate::db::site_view::SiteView;
 use crate::db::user::{Claims, User_};
 use crate::db::user_mention_view::{UserMentionQueryBuilder, UserMentionView};
 use crate::db::{ListingType, SortType};
+use crate::markdown::{render_revision, Revision};
 
 #[derive(Deserialize)]
 pub struct Params {
@@ -174,7 +175,7 @@ fn get_feed_front(
   jwt: String,
 ) -> Result<ChannelBuilder, failure::Error> {
   let site_view = SiteView::read(&conn)?;
-  let user_id = Claims::decode(&jwt)?.claims.id;
+  let user_id = Claims::decode(&conn, &jwt)?.claims.id;
 
   let posts = PostQueryBuilder::create(&conn)
     .listing_type(ListingType::Subscribed)
@@ -199,7 +200,7 @@ fn get_feed_front(
 
 fn get_feed_inbox(conn: &PgConnection, jwt: String) -> Result<ChannelBuilder, failure::Error> {
   let site_view = SiteView::read(&conn)?;
-  let user_id = Claims::decode(&jwt)?.claims.id;
+  let user_id = Claims::decode(&conn, &jwt)?.claims.id;
 
   let sort = SortType::New;
 
@@ -239,7 +240,8 @@ fn create_reply_and_mention_items(
         r.post_id,
         r.id
       );
-      build_item(&r.creator_name, &r.published, &reply_url, &r.content)
+      let revision = Revision::comment(r.id.into(), r.published, r.updated);
+      build_item(&r.creator_name, &r.published, &reply_url, revision, &r.content)
     })
     .collect();
 
@@ -252,7 +254,8 @@ fn create_reply_and_mention_items(
         m.post_id,
         m.id
       );
-      build_item(&m.creator_name, &m.published, &mention_url, &m.content)
+      let revision = Revision::comment(m.id.into(), m.published, m.updated);
+      build_item(&m.creator_name, &m.published, &mention_url, revision, &m.content)
     })
     .collect();
 
@@ -260,7 +263,13 @@ fn create_reply_and_mention_items(
   reply_items
 }
 
-fn build_item(creator_name: &str, published: &NaiveDateTime, url: &str, content: &str) -> Item {
+fn build_item(
+  creator_name: &str,
+  published: &NaiveDateTime,
+  url: &str,
+  revision: Revision,
+  content: &str,
+) -> Item {
   let mut i = ItemBuilder::default();
   i.title(format!("Reply from {}", creator_name));
   let author_url = format!("https://{}/u/{}", Settings::get().hostname, creator_name);
@@ -275,7 +284,7 @@ fn build_item(creator_name: &str, published: &NaiveDateTime, url: &str, content:
   i.guid(guid.unwrap());
   i.link(url.to_owned());
   // TODO add images
-  let html = markdown_to_html(&content.to_string());
+  let html = render_revision(revision, content);
   i.description(html);
   i.build().unwrap()
 }
@@ -335,7 +344,7 @@ fn create_post_items(posts: Vec<PostView>) -> Vec<Item> {
     p.number_of_comments);
 
     if let Some(body) = p.body {
-      let html = markdown_to_html(&body);
+      let html = render_revision(Revision::post(p.id.into(), p.published, p.updated), &body);
       description.push_str(&html);
     }
 
@@ -8,6 +8,7 @@ pub fn config(cfg: &mut web::ServiceConfig) {
       web::get().to(index),
     )
     .route("/login", web::get().to(index))
+    .route("/oidc_login", web::get().to(index))
     .route("/create_post", web::get().to(index))
     .route("/create_community", web::get().to(index))
     .route("/create_private_message", web::get().to(index))
@@ -43,6 +44,6 @@ pub fn config(cfg: &mut web::ServiceConfig) {
 
 async fn index() -> Result<NamedFile, Error> {
   Ok(NamedFile::open(
-    Settings::get().front_end_dir + "/index.html",
+    Settings::get().front_end_dir.to_owned() + "/index.html",
   )?)
 }
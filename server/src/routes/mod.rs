@@ -2,7 +2,7 @@
 // use crate::db::site_view::SiteView;
 use crate::rate_limit::rate_limiter::RateLimiter;
 // use crate::websocket::{server::ChatServer, WebsocketInfo};
-use crate::{get_ip, markdown_to_html, version, Settings};
+use crate::{get_ip, version, Settings};
 use actix::prelude::*;
 use actix_files::NamedFile;
 use actix_web::{body::Body, error::ErrorBadRequest, web::Query, *};
@@ -24,9 +24,11 @@ pub type RateLimitParam = web::Data<Arc<Mutex<RateLimiter>>>;
 
 // FIXME: re-export modules
 pub mod api;
+pub mod export;
 // pub mod federation;
 // pub mod feeds;
 pub mod index;
 // pub mod nodeinfo;
+pub mod oidc;
 // pub mod webfinger;
 // pub mod websocket;
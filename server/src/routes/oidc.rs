@@ -0,0 +1,312 @@
+use super::*;
+use crate::api::auth::bearer_token;
+use crate::api::check_name;
+use crate::bootstrap::MAIN_COMMUNITY_NAME;
+use crate::db::{
+  community::{Community, CommunityFollower, CommunityFollowerForm},
+  content_filter::FilterTarget,
+  external_identity::ExternalIdentity,
+  oidc_login::OidcLoginCode,
+  registration_application::RegistrationApplication,
+  site::Site,
+  user::{Claims, UserForm, User_},
+  *,
+};
+use crate::{generate_random_string, is_valid_username};
+use crate::oidc::{self, OidcLogin, STATE_COOKIE};
+use actix_web::http::{
+  cookie::{Cookie, SameSite},
+  header::AUTHORIZATION,
+};
+use dgraph_tonic as dgraph;
+use failure::Error;
+
+/// Page of the front end that picks up the result of a login, from the url fragment
+const FRONT_END_RESULT_PATH: &str = "/oidc_login";
+/// How long the front end has to exchange the login code for a session
+const LOGIN_CODE_SECONDS: i64 = 60;
+/// Usernames tried for an auto-registered user before giving up
+const MAX_USERNAME_ATTEMPTS: usize = 10;
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+  cfg
+    .route("/oidc/{provider}/login", web::get().to(login))
+    .route("/oidc/{provider}/link", web::post().to(link_start))
+    .route("/oidc/{provider}/callback", web::get().to(callback))
+    .route("/oidc/exchange", web::post().to(exchange));
+}
+
+#[derive(Deserialize)]
+struct CallbackParams {
+  code: Option<String>,
+  state: Option<String>,
+  error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExchangeForm {
+  code: String,
+}
+
+fn state_cookie(value: &str) -> Cookie<'static> {
+  Cookie::build(STATE_COOKIE, value.to_owned())
+    .path("/oidc")
+    .http_only(true)
+    // Sent along when the provider redirects back, which is a top level navigation
+    .same_site(SameSite::Lax)
+    .secure(!Settings::get().hostname.starts_with("localhost"))
+    .finish()
+}
+
+fn redirect(location: &str) -> HttpResponse {
+  HttpResponse::Found()
+    .header(http::header::LOCATION, location)
+    .finish()
+}
+
+/// Send the browser to the front end with params in the url fragment
+fn redirect_result(params: &[(&str, &str)]) -> HttpResponse {
+  HttpResponse::Found()
+    .header(
+      http::header::LOCATION,
+      format!("{}#{}", FRONT_END_RESULT_PATH, oidc::form_urlencoded(params)),
+    )
+    .del_cookie(&state_cookie(""))
+    .finish()
+}
+
+async fn login(provider: web::Path<String>, db: web::Data<dgraph::Client>) -> HttpResponse {
+  match oidc::start_login(&db, &provider, None).await {
+    Ok((url, state)) => HttpResponse::Found()
+      .header(http::header::LOCATION, url)
+      .cookie(state_cookie(&oidc::state_cookie_value(&state)))
+      .finish(),
+    Err(e) => {
+      error!("Couldn't start {} login: {}", provider, e);
+      redirect_result(&[("error", "oidc_login_failed")])
+    }
+  }
+}
+
+/**
+ * Start linking a provider account to the logged in user. The front end sends
+ * the user to the returned url, the account is linked when the provider
+ * redirects back to the callback.
+ */
+async fn link_start(
+  req: HttpRequest,
+  provider: web::Path<String>,
+  db: web::Data<dgraph::Client>,
+) -> HttpResponse {
+  let jwt = req
+    .headers()
+    .get(AUTHORIZATION)
+    .and_then(|header| header.to_str().ok())
+    .and_then(bearer_token);
+  let claims = match jwt {
+    Some(jwt) => Claims::decode(&db, jwt).await,
+    None => Err(format_err!("not_logged_in")),
+  };
+  let user_id = match claims {
+    Ok(claims) => claims.claims.id,
+    Err(_e) => return HttpResponse::Unauthorized().json(json!({ "error": "not_logged_in" })),
+  };
+
+  match oidc::start_login(&db, &provider, Some(user_id)).await {
+    Ok((url, state)) => HttpResponse::Ok()
+      .cookie(state_cookie(&oidc::state_cookie_value(&state)))
+      .json(json!({ "authorization_url": url })),
+    Err(e) => {
+      error!("Couldn't start {} link: {}", provider, e);
+      HttpResponse::BadRequest().json(json!({ "error": "unknown_oidc_provider" }))
+    }
+  }
+}
+
+async fn callback(
+  req: HttpRequest,
+  provider: web::Path<String>,
+  params: Query<CallbackParams>,
+  db: web::Data<dgraph::Client>,
+) -> HttpResponse {
+  let (code, state) = match (&params.code, &params.state, &params.error) {
+    (Some(code), Some(state), None) => (code, state),
+    _ => return redirect_result(&[("error", "oidc_login_failed")]),
+  };
+
+  // Only the browser that started the login may finish it
+  let cookie_ok = req
+    .cookie(STATE_COOKIE)
+    .map_or(false, |cookie| oidc::check_state_cookie(cookie.value(), state));
+  if !cookie_ok {
+    return redirect_result(&[("error", "invalid_oidc_state")]);
+  }
+
+  let login = match oidc::finish_login(&db, &provider, code, state).await {
+    Ok(login) => login,
+    Err(e) => {
+      error!("Couldn't finish {} login: {}", provider, e);
+      return redirect_result(&[("error", "oidc_login_failed")]);
+    }
+  };
+
+  match login.link_user_id {
+    Some(user_id) => match link(&db, &login, user_id).await {
+      Ok(()) => redirect_result(&[("linked", &login.provider)]),
+      Err(e) => redirect_result(&[("error", &e.to_string())]),
+    },
+    None => match log_in(&db, &login).await {
+      Ok(code) => redirect_result(&[("code", &code)]),
+      Err(e) => redirect_result(&[("error", &e.to_string())]),
+    },
+  }
+}
+
+/// Exchange the one-time code from the callback for a session
+async fn exchange(form: web::Json<ExchangeForm>, db: web::Data<dgraph::Client>) -> HttpResponse {
+  match exchange_code(&db, &form.code).await {
+    Ok((jwt, refresh_token)) => HttpResponse::Ok().json(json!({
+      "jwt": jwt,
+      "refresh_token": refresh_token,
+    })),
+    Err(e) => HttpResponse::BadRequest().json(json!({ "error": e.to_string() })),
+  }
+}
+
+async fn exchange_code(conn: &dgraph::Client, code: &str) -> Result<(String, String), Error> {
+  let login_code = match OidcLoginCode::consume(conn, code).await {
+    Ok(login_code) => login_code,
+    Err(_e) => bail!("invalid_oidc_code"),
+  };
+
+  // The user could have been banned since the callback
+  let user = read_node::<User_>(conn, login_code.user_id).await?;
+  if user.banned {
+    bail!("site_ban");
+  }
+  user.create_session(conn).await
+}
+
+/// Link the provider account to a logged in user
+async fn link(conn: &dgraph::Client, login: &OidcLogin, user_id: i64) -> Result<(), Error> {
+  match ExternalIdentity::find_by_subject(conn, &login.provider, &login.claims.sub).await {
+    Ok(identity) if identity.user_id == user_id => {}
+    Ok(_) => bail!("oidc_account_already_linked"),
+    Err(_) => {
+      ExternalIdentity::delete_for_user(conn, user_id, &login.provider).await?;
+      ExternalIdentity::create(conn, user_id, &login.provider, &login.claims.sub).await?;
+    }
+  }
+  Ok(())
+}
+
+/**
+ * Log in the user linked to the provider account, registering one if needed.
+ *
+ * @return a one-time code for the exchange route
+ */
+async fn log_in(conn: &dgraph::Client, login: &OidcLogin) -> Result<String, Error> {
+  let user = match ExternalIdentity::find_by_subject(conn, &login.provider, &login.claims.sub).await {
+    Ok(identity) => read_node::<User_>(conn, identity.user_id).await?,
+    Err(_) => register(conn, login).await?,
+  };
+
+  if user.banned {
+    bail!("site_ban");
+  }
+  // Like Oper<Login>, users wait for an admin to approve their application
+  if user.registration_pending {
+    match RegistrationApplication::find_for_user(conn, user.id).await {
+      Ok(application) if application.is_resolved() => bail!("registration_denied"),
+      _ => bail!("registration_application_pending"),
+    }
+  }
+  // The provider doesn't replace the second factor the user set up here
+  if user.totp_enabled {
+    bail!("totp_enabled_use_password_login");
+  }
+
+  let (_login_code, code) =
+    OidcLoginCode::create_for_user(conn, user.id, chrono::Duration::seconds(LOGIN_CODE_SECONDS))
+      .await?;
+  Ok(code)
+}
+
+/// Register a new user for the provider account, with the checks of Oper<Register>
+async fn register(conn: &dgraph::Client, login: &OidcLogin) -> Result<User_, Error> {
+  let site = match list_nodes::<Site>(conn).await?.into_iter().next() {
+    Some(site) => site,
+    None => bail!("registration_closed"),
+  };
+  if !site.open_registration {
+    // There is no form for the application question here. Users apply by
+    // registering with a password, and link the provider once approved.
+    if site.require_application {
+      bail!("application_answer_required");
+    }
+    bail!("registration_closed");
+  }
+
+  let name = username_for(conn, login).await?;
+
+  let email_verified = login.claims.email_verified.unwrap_or(false);
+  let user_form = UserForm {
+    name,
+    fedi_name: Settings::get().hostname.to_owned(),
+    // Unverified addresses from the provider are not trusted with password resets
+    email: login.claims.email.to_owned().filter(|_| email_verified),
+    matrix_user_id: None,
+    avatar: None,
+    // Nobody knows this password, the user can set one through a password reset
+    password_encrypted: generate_random_string(),
+    preferred_username: None,
+    updated: None,
+    admin: false,
+    banned: false,
+    show_nsfw: false,
+    theme: "darkly".into(),
+    default_sort_type: SortType::Hot as i16,
+    default_listing_type: ListingType::Subscribed as i16,
+    lang: "browser".into(),
+    show_avatars: true,
+    send_notifications_to_email: false,
+  };
+
+  let mut user = User_::register(conn, &user_form).await?;
+  if let Some(email) = &user_form.email {
+    user = User_::update_email(conn, user.id, email, true).await?;
+  }
+  ExternalIdentity::create(conn, user.id, &login.provider, &login.claims.sub).await?;
+
+  // Sign them up for main community no matter what
+  if let Ok(main_community) = Community::read_from_name(conn, MAIN_COMMUNITY_NAME.to_string()).await {
+    let follower: CommunityFollower = CommunityFollowerForm {
+      community_id: main_community.id,
+      user_id: user.id,
+    }
+    .into();
+    create_edge::<CommunityFollower, CommunityFollowerForm>(conn, &follower).await?;
+  }
+
+  Ok(user)
+}
+
+/// A free username for the provider account that passes the checks of
+/// Oper<Register>. A name from the claims that doesn't is replaced by a random one.
+async fn username_for(conn: &dgraph::Client, login: &OidcLogin) -> Result<String, Error> {
+  let mut name = oidc::username_candidate(&login.claims);
+  for _ in 0..MAX_USERNAME_ATTEMPTS {
+    if is_valid_username(&name)
+      && check_name(&name, FilterTarget::Username).is_ok()
+      && User_::find_by_username(conn, &name).await.is_err()
+    {
+      return Ok(name);
+    }
+    name = oidc::username_candidate(&oidc::IdTokenClaims {
+      preferred_username: None,
+      email: None,
+      ..login.claims.clone()
+    });
+  }
+  bail!("invalid_username")
+}
@@ -0,0 +1,253 @@
+/**
+ * In-process scheduler for background jobs.
+ *
+ * Every server runs the scheduler, but each run of a job happens on one of
+ * them only: before running a due job, a server takes the job's `JobLease` in
+ * the database, and renews it until the run is over. A failed run is retried a few times with backoff, then the job
+ * waits for its next scheduled run. The outcome of the last run is stored on
+ * the lease, admins can read it with the getJobs query.
+ */
+use crate::db::job_lease::{JobLease, JobStatus};
+use crate::{generate_random_string, naive_now};
+use chrono::{Duration, NaiveDateTime};
+use dgraph_tonic as dgraph;
+use failure::Error;
+use futures::future::{self, Either};
+use log::{error, info, warn};
+use std::future::Future;
+use std::pin::Pin;
+
+/// How often the scheduler looks for due jobs
+const TICK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// A lease not renewed for this long expires, and the job may be started again
+/// elsewhere, ie when the server running it went away
+const LEASE_MINUTES: i64 = 15;
+/// How often the server running a job renews its lease
+const RENEW_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+/// Wait before the first retry of a failed run, doubled for each further retry
+const RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How often a job runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schedule {
+  every: Duration,
+}
+
+impl Schedule {
+  /**
+   * Parse an interval like "30s", "15m", "6h" or "1d", or one of the
+   * shorthands "@hourly" and "@daily".
+   */
+  pub fn parse(text: &str) -> Result<Self, Error> {
+    let every = match text.trim() {
+      "@hourly" => Duration::hours(1),
+      "@daily" => Duration::days(1),
+      interval => {
+        let unit_at = interval.char_indices().last().map_or(0, |(i, _)| i);
+        let (count, unit) = interval.split_at(unit_at);
+        let count: i64 = count
+          .parse()
+          .map_err(|_| format_err!("invalid schedule {:?}", text))?;
+        match unit {
+          "s" => Duration::seconds(count),
+          "m" => Duration::minutes(count),
+          "h" => Duration::hours(count),
+          "d" => Duration::days(count),
+          _ => bail!("invalid schedule {:?}", text),
+        }
+      }
+    };
+
+    if every < Duration::seconds(1) {
+      bail!("schedule {:?} must be at least one second", text);
+    }
+    Ok(Schedule { every })
+  }
+
+  /**
+   * The first run after t. Runs are at multiples of the interval since the
+   * unix epoch, so all servers agree on them whenever they started.
+   */
+  pub fn next_after(&self, t: NaiveDateTime) -> NaiveDateTime {
+    let every = self.every.num_seconds();
+    let next = (t.timestamp().div_euclid(every) + 1) * every;
+    NaiveDateTime::from_timestamp(next, 0)
+  }
+}
+
+/// A run of a job, resolving to a summary of what it did
+pub type JobFuture = Pin<Box<dyn Future<Output = Result<String, Error>>>>;
+
+pub struct Job {
+  /// Unique, the lease is stored under it
+  pub name: &'static str,
+  pub schedule: Schedule,
+  pub run: fn(dgraph::Client) -> JobFuture,
+}
+
+pub struct Scheduler {
+  conn: dgraph::Client,
+  jobs: Vec<Job>,
+  retries: u32,
+  /// Identifies this server as lease holder
+  holder: String,
+}
+
+impl Scheduler {
+  pub fn new(conn: dgraph::Client, jobs: Vec<Job>, retries: u32) -> Self {
+    Scheduler {
+      conn,
+      jobs,
+      retries,
+      holder: generate_random_string(),
+    }
+  }
+
+  /**
+   * Run due jobs from now on, in the background. Jobs run one after the
+   * other, so a slow job delays the others but never overlaps with itself.
+   */
+  pub fn start(self) {
+    actix_rt::spawn(async move {
+      let mut interval = actix_rt::time::interval(TICK_INTERVAL);
+      loop {
+        interval.tick().await;
+        for job in &self.jobs {
+          self.run_if_due(job).await;
+        }
+      }
+    });
+  }
+
+  async fn run_if_due(&self, job: &Job) {
+    let lease_for = Duration::minutes(LEASE_MINUTES);
+    let lease = match JobLease::acquire(&self.conn, job.name, &self.holder, naive_now(), lease_for)
+      .await
+    {
+      Ok(Some(lease)) => lease,
+      // Not due, or another server runs it
+      Ok(None) => return,
+      Err(e) => {
+        error!("Couldn't take the lease of job {}: {}", job.name, e);
+        return;
+      }
+    };
+
+    let (status, message, attempts) = self.run_holding_lease(job, lease.id).await;
+    let next_run = job.schedule.next_after(naive_now());
+    let finished = JobLease::finish(
+      &self.conn,
+      lease.id,
+      &self.holder,
+      status,
+      &message,
+      attempts,
+      next_run,
+    )
+    .await;
+    match finished {
+      Ok(true) => {}
+      Ok(false) => warn!("Lost the lease of job {} before it finished", job.name),
+      // The lease expires on its own, the job runs again after that
+      Err(e) => error!("Couldn't release the lease of job {}: {}", job.name, e),
+    }
+  }
+
+  /// Run job, renewing its lease until the run is over or the lease was lost
+  async fn run_holding_lease(&self, job: &Job, lease_id: i64) -> (JobStatus, String, i32) {
+    let run = self.run_with_retries(job);
+    let renew = self.renew_lease(job, lease_id);
+    futures::pin_mut!(run, renew);
+    match future::select(run, renew).await {
+      Either::Left((outcome, _)) => outcome,
+      // Lost the lease, the run goes on but can't record its outcome
+      Either::Right(((), run)) => run.await,
+    }
+  }
+
+  /// Renew the lease every RENEW_INTERVAL, returns once it was lost
+  async fn renew_lease(&self, job: &Job, lease_id: i64) {
+    let mut interval = actix_rt::time::interval(RENEW_INTERVAL);
+    // The first tick is right away, the lease was just taken
+    interval.tick().await;
+    loop {
+      interval.tick().await;
+      let lease_expires = naive_now() + Duration::minutes(LEASE_MINUTES);
+      match JobLease::renew(&self.conn, lease_id, &self.holder, lease_expires).await {
+        Ok(true) => {}
+        Ok(false) => {
+          warn!("Lost the lease of job {}, it may run elsewhere", job.name);
+          return;
+        }
+        // Retried at the next tick, before the lease expires
+        Err(e) => error!("Couldn't renew the lease of job {}: {}", job.name, e),
+      }
+    }
+  }
+
+  /// Status, message and number of attempts of the run
+  async fn run_with_retries(&self, job: &Job) -> (JobStatus, String, i32) {
+    let mut delay = RETRY_DELAY;
+    let mut attempts = 0;
+    loop {
+      attempts += 1;
+      match (job.run)(self.conn.clone()).await {
+        Ok(message) => {
+          info!("Job {} succeeded: {}", job.name, message);
+          return (JobStatus::Succeeded, message, attempts);
+        }
+        Err(e) if attempts <= self.retries as i32 => {
+          warn!(
+            "Job {} failed, retrying in {}s: {}",
+            job.name,
+            delay.as_secs(),
+            e
+          );
+          actix_rt::time::delay_for(delay).await;
+          delay *= 2;
+        }
+        Err(e) => {
+          error!("Job {} failed after {} attempts: {}", job.name, attempts, e);
+          return (JobStatus::Failed, e.to_string(), attempts);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_schedule() {
+    assert_eq!(Schedule::parse("30s").unwrap().every, Duration::seconds(30));
+    assert_eq!(Schedule::parse("15m").unwrap().every, Duration::minutes(15));
+    assert_eq!(Schedule::parse(" 6h ").unwrap().every, Duration::hours(6));
+    assert_eq!(Schedule::parse("1d").unwrap(), Schedule::parse("@daily").unwrap());
+    assert_eq!(Schedule::parse("60m").unwrap(), Schedule::parse("@hourly").unwrap());
+    assert!(Schedule::parse("").is_err());
+    assert!(Schedule::parse("15").is_err());
+    assert!(Schedule::parse("m").is_err());
+    assert!(Schedule::parse("0s").is_err());
+    assert!(Schedule::parse("-5m").is_err());
+    assert!(Schedule::parse("2w").is_err());
+    assert!(Schedule::parse("@weekly").is_err());
+    assert!(Schedule::parse("5ü").is_err());
+  }
+
+  #[test]
+  fn test_next_after() {
+    let at = |h, m, s| chrono::NaiveDate::from_ymd(2020, 6, 1).and_hms(h, m, s);
+    let quarter = Schedule::parse("15m").unwrap();
+    assert_eq!(quarter.next_after(at(12, 0, 0)), at(12, 15, 0));
+    assert_eq!(quarter.next_after(at(12, 7, 30)), at(12, 15, 0));
+    assert_eq!(quarter.next_after(at(12, 59, 59)), at(13, 0, 0));
+
+    let daily = Schedule::parse("@daily").unwrap();
+    assert_eq!(
+      daily.next_after(at(12, 0, 0)),
+      chrono::NaiveDate::from_ymd(2020, 6, 2).and_hms(0, 0, 0)
+    );
+  }
+}
@@ -1,14 +1,36 @@
-use config::{Config, ConfigError, Environment, File};
+use crate::scheduler::Schedule;
+use crate::{is_email_regex, is_valid_username};
+use arc_swap::ArcSwap;
+use config::{Config, ConfigError, Environment, File, FileFormat};
 use failure::Error;
-use serde::Deserialize;
+use log::{error, info, warn};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
 use std::env;
+use std::fmt;
 use std::fs;
+use std::io::Write;
 use std::net::IpAddr;
-use std::sync::RwLock;
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 static CONFIG_FILE_DEFAULTS: &str = "config/defaults.hjson";
 static CONFIG_FILE: &str = "config/config.hjson";
 
+/// Interval at which the config file is checked for modifications
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Secrets from example configs and docs, which must never be used in production
+const KNOWN_INSECURE_SECRETS: &[&str] = &["changeme", "password", "secret", "lemmy", "admin"];
+const MIN_JWT_SECRET_LENGTH: usize = 16;
+const MIN_PASSWORD_LENGTH: usize = 8;
+/// Length of a generated jwt secret
+const GENERATED_JWT_SECRET_LENGTH: usize = 64;
+const PASSWORD_HASH_ALGORITHMS: &[&str] = &["argon2id", "bcrypt"];
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Settings {
   pub setup: Option<Setup>,
@@ -16,10 +38,24 @@ pub struct Settings {
   pub hostname: String,
   pub bind: IpAddr,
   pub port: u16,
+  #[serde(default)]
   pub jwt_secret: String,
+  pub jwt_secret_file: Option<String>,
+  #[serde(default)]
+  pub allow_insecure_secrets: bool,
+  pub access_token_minutes: i64,
+  pub refresh_token_days: i64,
+  pub password_reset_token_minutes: i64,
+  pub password_reset_interval_seconds: i64,
+  pub email_verification_token_hours: i64,
+  pub password_hashing: PasswordHashingConfig,
   pub front_end_dir: String,
+  pub data_export: DataExportConfig,
+  pub jobs: JobsConfig,
   pub rate_limit: RateLimitConfig,
   pub email: Option<EmailConfig>,
+  #[serde(default)]
+  pub oidc_providers: Vec<OidcProviderConfig>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -30,6 +66,60 @@ pub struct Setup {
   pub site_name: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct PasswordHashingConfig {
+  /// "argon2id" or "bcrypt", used for new hashes
+  pub algorithm: String,
+  pub argon2_memory_kib: u32,
+  pub argon2_iterations: u32,
+  pub argon2_parallelism: u32,
+  pub bcrypt_cost: u32,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OidcProviderConfig {
+  /// Used in urls, eg /oidc/<name>/login
+  pub name: String,
+  /// Shown on the login button
+  pub display_name: String,
+  /// Issuer url, the provider metadata is read from <issuer>/.well-known/openid-configuration
+  pub issuer: String,
+  pub client_id: String,
+  #[serde(default)]
+  pub client_secret: String,
+  pub client_secret_file: Option<String>,
+  #[serde(default = "default_oidc_scopes")]
+  pub scopes: String,
+  /// Overrides https://<hostname>/oidc/<name>/callback, eg for local testing
+  pub redirect_uri: Option<String>,
+}
+
+fn default_oidc_scopes() -> String {
+  "openid email profile".into()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DataExportConfig {
+  /// Where export archives are written until they expire
+  pub directory: String,
+  pub interval_hours: i64,
+  pub expiry_hours: i64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JobsConfig {
+  /// Whether this server runs background jobs at all
+  pub enabled: bool,
+  /// Retries of a failed run before waiting for the next one
+  pub retries: u32,
+  /// Schedules, see scheduler::Schedule::parse
+  pub refresh_ranks: String,
+  pub expire_bans: String,
+  pub cleanup_tokens: String,
+  pub build_data_exports: String,
+  pub send_emails: String,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct RateLimitConfig {
   pub message: i32,
@@ -42,25 +132,97 @@ pub struct RateLimitConfig {
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct EmailConfig {
+  #[serde(default)]
+  pub transport: EmailTransport,
+  #[serde(default)]
   pub smtp_server: String,
   pub smtp_login: Option<String>,
   pub smtp_password: Option<String>,
+  pub smtp_password_file: Option<String>,
   pub smtp_from_address: String,
   pub use_tls: bool,
+  /// Where the file transport writes emails
+  pub file_directory: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailTransport {
+  Smtp,
+  /// Write emails to files instead of sending them, for local testing
+  File,
+}
+
+impl Default for EmailTransport {
+  fn default() -> Self {
+    EmailTransport::Smtp
+  }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Database {
   pub user: String,
+  #[serde(default)]
   pub password: String,
+  pub password_file: Option<String>,
   pub host: String,
   pub port: i32,
+  /// Port of the Dgraph admin endpoint, see `lemmy_server migrate`
+  pub admin_port: u16,
   pub database: String,
   pub pool_size: u32,
 }
 
+/// Error for a single invalid config field
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ConfigFieldError {
+  pub field: String,
+  pub message: String,
+}
+
+impl ConfigFieldError {
+  fn new(field: &str, message: &str) -> Self {
+    ConfigFieldError {
+      field: field.to_string(),
+      message: message.to_string(),
+    }
+  }
+}
+
+/// Error returned when a new config is rejected. The old config stays active. Displayed
+/// as JSON, which the API returns as is.
+#[derive(Fail, Debug)]
+pub enum ConfigLoadError {
+  Parse(String),
+  Invalid(Vec<ConfigFieldError>),
+  SecretFile(String),
+}
+
+impl fmt::Display for ConfigLoadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let json = match self {
+      ConfigLoadError::Parse(message) => {
+        serde_json::json!({ "error": "couldnt_parse_config", "message": message })
+      }
+      ConfigLoadError::Invalid(fields) => {
+        serde_json::json!({ "error": "invalid_config", "fields": fields })
+      }
+      ConfigLoadError::SecretFile(message) => {
+        serde_json::json!({ "error": "couldnt_read_secret_file", "message": message })
+      }
+    };
+    write!(f, "{}", json)
+  }
+}
+
+impl From<ConfigError> for ConfigLoadError {
+  fn from(e: ConfigError) -> Self {
+    ConfigLoadError::Parse(e.to_string())
+  }
+}
+
 lazy_static! {
-  static ref SETTINGS: RwLock<Settings> = RwLock::new(match Settings::init() {
+  static ref SETTINGS: ArcSwap<Settings> = ArcSwap::from_pointee(match Settings::init() {
     Ok(c) => c,
     Err(e) => panic!("{}", e),
   });
@@ -71,12 +233,21 @@ impl Settings {
   /// First, defaults are loaded from CONFIG_FILE_DEFAULTS, then these values can be overwritten
   /// from CONFIG_FILE (optional). Finally, values from the environment (with prefix LEMMY) are
   /// added to the config.
-  fn init() -> Result<Self, ConfigError> {
+  fn init() -> Result<Self, ConfigLoadError> {
+    let config_hjson = fs::read_to_string(CONFIG_FILE).ok();
+    Self::load(config_hjson.as_deref())
+  }
+
+  /// Builds and validates the config, using `config_hjson` instead of the contents of
+  /// CONFIG_FILE. Nothing is written or swapped in here.
+  fn load(config_hjson: Option<&str>) -> Result<Self, ConfigLoadError> {
     let mut s = Config::new();
 
     s.merge(File::with_name(CONFIG_FILE_DEFAULTS))?;
 
-    s.merge(File::with_name(CONFIG_FILE).required(false))?;
+    if let Some(data) = config_hjson {
+      s.merge(File::from_str(data, FileFormat::Hjson))?;
+    }
 
     // Add in settings from the environment (with a prefix of LEMMY)
     // Eg.. `LEMMY_DEBUG=1 ./target/app` would set the `debug` key
@@ -85,12 +256,318 @@ impl Settings {
     // https://github.com/mehcode/config-rs/issues/73
     s.merge(Environment::with_prefix("LEMMY").separator("__"))?;
 
-    s.try_into()
+    let mut settings: Settings = s.try_into()?;
+    settings.read_secret_files()?;
+
+    let errors = settings.validate();
+    if !errors.is_empty() {
+      return Err(ConfigLoadError::Invalid(errors));
+    }
+
+    Ok(settings)
   }
 
-  /// Returns the config as a struct.
-  pub fn get() -> Self {
-    SETTINGS.read().unwrap().to_owned()
+  /// Checks the values that parse fine but would break the server at runtime.
+  pub fn validate(&self) -> Vec<ConfigFieldError> {
+    let mut errors = Vec::new();
+
+    if self.hostname.trim().is_empty() {
+      errors.push(ConfigFieldError::new("hostname", "must not be empty"));
+    }
+    if self.port == 0 {
+      errors.push(ConfigFieldError::new("port", "must be greater than 0"));
+    }
+    if self.database.admin_port == 0 {
+      errors.push(ConfigFieldError::new("database.admin_port", "must be greater than 0"));
+    }
+    // An empty jwt_secret with a jwt_secret_file is generated at server start
+    if self.jwt_secret.is_empty() && self.jwt_secret_file.is_none() {
+      errors.push(ConfigFieldError::new("jwt_secret", "must not be empty"));
+    }
+    errors.append(&mut self.validate_secrets());
+    if self.access_token_minutes <= 0 {
+      errors.push(ConfigFieldError::new("access_token_minutes", "must be greater than 0"));
+    }
+    if self.refresh_token_days <= 0 {
+      errors.push(ConfigFieldError::new("refresh_token_days", "must be greater than 0"));
+    }
+    if self.password_reset_token_minutes <= 0 {
+      errors.push(ConfigFieldError::new(
+        "password_reset_token_minutes",
+        "must be greater than 0",
+      ));
+    }
+    if self.email_verification_token_hours <= 0 {
+      errors.push(ConfigFieldError::new(
+        "email_verification_token_hours",
+        "must be greater than 0",
+      ));
+    }
+    if self.password_reset_interval_seconds < 0 {
+      errors.push(ConfigFieldError::new(
+        "password_reset_interval_seconds",
+        "must not be negative",
+      ));
+    }
+    errors.append(&mut self.validate_password_hashing());
+    if self.data_export.directory.is_empty() {
+      errors.push(ConfigFieldError::new("data_export.directory", "must not be empty"));
+    }
+    if self.data_export.interval_hours < 0 {
+      errors.push(ConfigFieldError::new(
+        "data_export.interval_hours",
+        "must not be negative",
+      ));
+    }
+    if self.data_export.expiry_hours <= 0 {
+      errors.push(ConfigFieldError::new(
+        "data_export.expiry_hours",
+        "must be greater than 0",
+      ));
+    }
+    let schedules = [
+      ("jobs.refresh_ranks", &self.jobs.refresh_ranks),
+      ("jobs.expire_bans", &self.jobs.expire_bans),
+      ("jobs.cleanup_tokens", &self.jobs.cleanup_tokens),
+      ("jobs.build_data_exports", &self.jobs.build_data_exports),
+      ("jobs.send_emails", &self.jobs.send_emails),
+    ];
+    for (field, schedule) in schedules.iter() {
+      if let Err(e) = Schedule::parse(schedule) {
+        errors.push(ConfigFieldError::new(field, &e.to_string()));
+      }
+    }
+    if self.database.pool_size == 0 {
+      errors.push(ConfigFieldError::new(
+        "database.pool_size",
+        "must be greater than 0",
+      ));
+    }
+
+    let rate_limits = [
+      ("rate_limit.message", self.rate_limit.message),
+      ("rate_limit.message_per_second", self.rate_limit.message_per_second),
+      ("rate_limit.post", self.rate_limit.post),
+      ("rate_limit.post_per_second", self.rate_limit.post_per_second),
+      ("rate_limit.register", self.rate_limit.register),
+      ("rate_limit.register_per_second", self.rate_limit.register_per_second),
+    ];
+    for (field, value) in rate_limits.iter() {
+      if *value <= 0 {
+        errors.push(ConfigFieldError::new(field, "must be greater than 0"));
+      }
+    }
+
+    if let Some(email) = &self.email {
+      let file_directory = email.file_directory.as_deref().unwrap_or("");
+      if email.transport == EmailTransport::Smtp && email.smtp_server.trim().is_empty() {
+        errors.push(ConfigFieldError::new("email.smtp_server", "must not be empty"));
+      }
+      if email.transport == EmailTransport::File && file_directory.trim().is_empty() {
+        errors.push(ConfigFieldError::new(
+          "email.file_directory",
+          "must be set for the file transport",
+        ));
+      }
+      if !is_email_regex(&email.smtp_from_address) {
+        errors.push(ConfigFieldError::new(
+          "email.smtp_from_address",
+          "must be a valid email address",
+        ));
+      }
+    }
+
+    for (i, provider) in self.oidc_providers.iter().enumerate() {
+      let field = |name: &str| format!("oidc_providers[{}].{}", i, name);
+      if !is_valid_username(&provider.name) {
+        errors.push(ConfigFieldError::new(&field("name"), "invalid_username"));
+      }
+      if self.oidc_providers[..i].iter().any(|p| p.name == provider.name) {
+        errors.push(ConfigFieldError::new(&field("name"), "must be unique"));
+      }
+      if !provider.issuer.starts_with("https://") && !provider.issuer.starts_with("http://") {
+        errors.push(ConfigFieldError::new(&field("issuer"), "must be an http(s) url"));
+      }
+      if provider.client_id.is_empty() {
+        errors.push(ConfigFieldError::new(&field("client_id"), "must not be empty"));
+      }
+      if !provider.scopes.split_whitespace().any(|s| s == "openid") {
+        errors.push(ConfigFieldError::new(&field("scopes"), "must include openid"));
+      }
+    }
+
+    if let Some(setup) = &self.setup {
+      if !is_valid_username(&setup.admin_username) {
+        errors.push(ConfigFieldError::new("setup.admin_username", "invalid_username"));
+      }
+      if setup.admin_password.is_empty() {
+        errors.push(ConfigFieldError::new("setup.admin_password", "must not be empty"));
+      }
+      if setup.site_name.trim().is_empty() {
+        errors.push(ConfigFieldError::new("setup.site_name", "must not be empty"));
+      }
+    }
+
+    errors
+  }
+
+  fn validate_password_hashing(&self) -> Vec<ConfigFieldError> {
+    let mut errors = Vec::new();
+    let hashing = &self.password_hashing;
+
+    if !PASSWORD_HASH_ALGORITHMS.contains(&hashing.algorithm.as_str()) {
+      errors.push(ConfigFieldError::new(
+        "password_hashing.algorithm",
+        &format!("must be one of {}", PASSWORD_HASH_ALGORITHMS.join(", ")),
+      ));
+    }
+    if hashing.argon2_iterations == 0 {
+      errors.push(ConfigFieldError::new(
+        "password_hashing.argon2_iterations",
+        "must be greater than 0",
+      ));
+    }
+    if hashing.argon2_parallelism == 0 {
+      errors.push(ConfigFieldError::new(
+        "password_hashing.argon2_parallelism",
+        "must be greater than 0",
+      ));
+    }
+    // Argon2 needs at least 8 KiB of memory per lane
+    if hashing.argon2_memory_kib < 8 * hashing.argon2_parallelism.max(1) {
+      errors.push(ConfigFieldError::new(
+        "password_hashing.argon2_memory_kib",
+        "must be at least 8 times argon2_parallelism",
+      ));
+    }
+    if hashing.bcrypt_cost < 4 || hashing.bcrypt_cost > 31 {
+      errors.push(ConfigFieldError::new(
+        "password_hashing.bcrypt_cost",
+        "must be between 4 and 31",
+      ));
+    }
+
+    errors
+  }
+
+  /// Refuses default and too short secrets, unless allow_insecure_secrets is set for
+  /// local development.
+  fn validate_secrets(&self) -> Vec<ConfigFieldError> {
+    let mut errors = Vec::new();
+    if self.allow_insecure_secrets {
+      return errors;
+    }
+
+    let mut check = |field: &str, secret: &str, min_length: usize| {
+      if KNOWN_INSECURE_SECRETS.contains(&secret.to_lowercase().as_str()) {
+        errors.push(ConfigFieldError::new(field, "is a known default, set a real secret"));
+      } else if secret.len() < min_length {
+        errors.push(ConfigFieldError::new(
+          field,
+          &format!("must be at least {} characters", min_length),
+        ));
+      }
+    };
+
+    if !self.jwt_secret.is_empty() {
+      check("jwt_secret", &self.jwt_secret, MIN_JWT_SECRET_LENGTH);
+    }
+    if let Some(Some(smtp_password)) = self.email.as_ref().map(|e| &e.smtp_password) {
+      check("email.smtp_password", smtp_password, MIN_PASSWORD_LENGTH);
+    }
+
+    errors
+  }
+
+  /// Fills in secrets from their `*_file` options. A jwt_secret_file that doesn't exist
+  /// yet is left alone here, see generate_missing_secrets.
+  fn read_secret_files(&mut self) -> Result<(), ConfigLoadError> {
+    if let Some(path) = &self.database.password_file {
+      self.database.password = read_secret_file(path)?;
+    }
+
+    if let Some(email) = self.email.as_mut() {
+      if let Some(path) = &email.smtp_password_file {
+        email.smtp_password = Some(read_secret_file(path)?);
+      }
+    }
+
+    for provider in self.oidc_providers.iter_mut() {
+      if let Some(path) = &provider.client_secret_file {
+        provider.client_secret = read_secret_file(path)?;
+      }
+    }
+
+    if self.jwt_secret.is_empty() {
+      if let Some(path) = &self.jwt_secret_file {
+        if fs::metadata(path).is_ok() {
+          self.jwt_secret = read_secret_file(path)?;
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Creates a missing jwt_secret_file with a random secret and activates it, so the jwt
+  /// secret survives restarts without being configured. Only called at server start,
+  /// loading or checking the config never writes secrets.
+  pub fn generate_missing_secrets() -> Result<(), ConfigLoadError> {
+    let settings = Settings::get();
+    if !settings.jwt_secret.is_empty() {
+      return Ok(());
+    }
+    if let Some(path) = &settings.jwt_secret_file {
+      let mut new_settings = (*settings).clone();
+      new_settings.jwt_secret = generate_secret_file(path)?;
+      SETTINGS.store(Arc::new(new_settings));
+    }
+    Ok(())
+  }
+
+  /// Returns the current config. This is a cheap pointer copy, the config itself is
+  /// only replaced as a whole when it is reloaded.
+  pub fn get() -> Arc<Self> {
+    SETTINGS.load_full()
+  }
+
+  /// Loads and validates the config from disk without activating it.
+  pub fn check_config_file() -> Result<Self, ConfigLoadError> {
+    Settings::init()
+  }
+
+  /// Reloads the config from disk. If the new config doesn't parse or validate, the old
+  /// one stays active.
+  pub fn reload() -> Result<(), ConfigLoadError> {
+    let new_settings = Settings::init()?;
+    SETTINGS.store(Arc::new(new_settings));
+    // Cached html links to the old hostname
+    crate::markdown::clear_render_cache();
+    Ok(())
+  }
+
+  /// Spawns a thread which reloads the config whenever CONFIG_FILE is modified. The file's
+  /// mtime is polled every CONFIG_WATCH_INTERVAL, so changes take effect within that time.
+  pub fn watch_config_file() {
+    thread::spawn(|| {
+      let mut last_modified = Self::config_file_modified();
+      loop {
+        thread::sleep(CONFIG_WATCH_INTERVAL);
+        let modified = Self::config_file_modified();
+        if modified == last_modified {
+          continue;
+        }
+        last_modified = modified;
+        match Settings::reload() {
+          Ok(()) => info!("Reloaded config from {}", CONFIG_FILE),
+          Err(e) => error!("Keeping old config, couldn't reload {}: {}", CONFIG_FILE, e),
+        }
+      }
+    });
+  }
+
+  fn config_file_modified() -> Option<SystemTime> {
+    fs::metadata(CONFIG_FILE).and_then(|m| m.modified()).ok()
   }
 
   /// Returns the postgres connection url. If LEMMY_DATABASE_URL is set, that is used,
@@ -109,6 +586,15 @@ impl Settings {
     }
   }
 
+  /// Returns the Dgraph (gRPC) url. If LEMMY_DGRAPH_URL is set, that is used,
+  /// otherwise the url is generated from the database config.
+  pub fn get_dgraph_url(&self) -> String {
+    match env::var("LEMMY_DGRAPH_URL") {
+      Ok(url) => url,
+      Err(_) => format!("http://{}:{}", self.database.host, self.database.port),
+    }
+  }
+
   pub fn api_endpoint(&self) -> String {
     format!("{}/api/v1", self.hostname)
   }
@@ -117,17 +603,144 @@ impl Settings {
     Ok(fs::read_to_string(CONFIG_FILE)?)
   }
 
+  /// Validates the new config before writing it, so a broken config never reaches the
+  /// disk or replaces the running one.
   pub fn save_config_file(data: &str) -> Result<String, Error> {
-    fs::write(CONFIG_FILE, data)?;
-
-    // Reload the new settings
-    // From https://stackoverflow.com/questions/29654927/how-do-i-assign-a-string-to-a-mutable-static-variable/47181804#47181804
-    let mut new_settings = SETTINGS.write().unwrap();
-    *new_settings = match Settings::init() {
-      Ok(c) => c,
-      Err(e) => panic!("{}", e),
-    };
+    let new_settings = Settings::load(Some(data))?;
+
+    // Written next to the config and renamed over it, so a crash mid-write leaves the old
+    // file intact. A config bind mounted as a single file (see docker-compose.yml) can't
+    // be replaced, it is overwritten in place then.
+    let tmp_file = format!("{}.tmp", CONFIG_FILE);
+    fs::write(&tmp_file, data)?;
+    if let Err(e) = fs::rename(&tmp_file, CONFIG_FILE) {
+      warn!("Couldn't replace {}, writing it in place: {}", CONFIG_FILE, e);
+      fs::remove_file(&tmp_file)?;
+      fs::write(CONFIG_FILE, data)?;
+    }
+    SETTINGS.store(Arc::new(new_settings));
 
     Self::read_config_file()
   }
 }
+
+fn read_secret_file(path: &str) -> Result<String, ConfigLoadError> {
+  fs::read_to_string(path)
+    .map(|s| s.trim_end().to_string())
+    .map_err(|e| ConfigLoadError::SecretFile(format!("{}: {}", path, e)))
+}
+
+/// Writes a random secret to path, unless another server sharing the file got there first,
+/// in which case that one's secret is used.
+fn generate_secret_file(path: &str) -> Result<String, ConfigLoadError> {
+  let secret_file_error =
+    |e: std::io::Error| ConfigLoadError::SecretFile(format!("{}: {}", path, e));
+  let secret: String = thread_rng()
+    .sample_iter(&Alphanumeric)
+    .take(GENERATED_JWT_SECRET_LENGTH)
+    .collect();
+
+  // Written in full to a file of our own first, then linked to path, which fails if path
+  // exists. So a server starting at the same time never reads a half written secret.
+  let tmp_path = format!("{}.{}.tmp", path, std::process::id());
+  fs::OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .mode(0o600)
+    .open(&tmp_path)
+    .and_then(|mut file| file.write_all(secret.as_bytes()))
+    .map_err(secret_file_error)?;
+  let linked = fs::hard_link(&tmp_path, path);
+  fs::remove_file(&tmp_path).map_err(secret_file_error)?;
+
+  match linked {
+    Ok(()) => {
+      info!("Generated a new jwt secret in {}", path);
+      Ok(secret)
+    }
+    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => read_secret_file(path),
+    Err(e) => Err(secret_file_error(e)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{generate_secret_file, ConfigLoadError, Settings};
+
+  const DEV_CONFIG: &str = "{ allow_insecure_secrets: true }";
+
+  #[test]
+  fn test_validate_defaults() {
+    let settings = Settings::load(Some(DEV_CONFIG)).unwrap();
+    assert!(settings.validate().is_empty());
+  }
+
+  #[test]
+  fn test_load_doesnt_generate_secrets() {
+    let path = std::env::temp_dir().join("lemmy_test_missing_jwt_secret");
+    let config = format!(
+      "{{ allow_insecure_secrets: true, jwt_secret_file: {:?} }}",
+      path.to_str().unwrap()
+    );
+    let settings = Settings::load(Some(&config)).unwrap();
+    assert!(settings.jwt_secret.is_empty());
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn test_generate_secret_file_keeps_existing() {
+    let path = std::env::temp_dir().join("lemmy_test_generated_jwt_secret");
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+
+    let generated = generate_secret_file(path).unwrap();
+    // Like a second server starting at the same time
+    assert_eq!(generate_secret_file(path).unwrap(), generated);
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_reject_invalid_config() {
+    assert!(Settings::load(Some("{ allow_insecure_secrets: true, port: 0 }")).is_err());
+    assert!(Settings::load(Some("{ allow_insecure_secrets: true, rate_limit: { post: -1 } }")).is_err());
+    assert!(Settings::load(Some("{ hostname: ")).is_err());
+    assert!(Settings::load(Some("{ allow_insecure_secrets: true, password_hashing: { algorithm: \"md5\" } }")).is_err());
+    assert!(Settings::load(Some("{ allow_insecure_secrets: true, jobs: { expire_bans: \"weekly\" } }")).is_err());
+    assert!(Settings::load(Some("{ allow_insecure_secrets: true, hostname: \"example.com\" }")).is_ok());
+  }
+
+  #[test]
+  fn test_load_error_is_json() {
+    let error = Settings::load(Some("{ hostname: \"unterminated")).unwrap_err();
+    let json: serde_json::Value = serde_json::from_str(&error.to_string()).unwrap();
+    assert_eq!(json["error"], "couldnt_parse_config");
+
+    let error = ConfigLoadError::SecretFile("caf\u{e9} \"secret\"\u{0}".into());
+    let json: serde_json::Value = serde_json::from_str(&error.to_string()).unwrap();
+    assert_eq!(json["message"], "caf\u{e9} \"secret\"\u{0}");
+
+    let error = Settings::load(Some("{ allow_insecure_secrets: true, port: 0 }")).unwrap_err();
+    let json: serde_json::Value = serde_json::from_str(&error.to_string()).unwrap();
+    assert_eq!(json["fields"][0]["field"], "port");
+  }
+
+  #[test]
+  fn test_reject_insecure_secrets() {
+    let settings = Settings::load(Some(DEV_CONFIG)).unwrap();
+    let mut settings = Settings {
+      allow_insecure_secrets: false,
+      ..settings
+    };
+    assert!(settings.validate().is_empty());
+
+    settings.jwt_secret = "changeme".into();
+    assert_eq!(settings.validate()[0].field, "jwt_secret");
+
+    settings.jwt_secret = "tooshort".into();
+    assert_eq!(settings.validate()[0].field, "jwt_secret");
+
+    settings.jwt_secret = "changeme".into();
+    settings.allow_insecure_secrets = true;
+    assert!(settings.validate().is_empty());
+  }
+}
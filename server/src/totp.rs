@@ -0,0 +1,172 @@
+/**
+ * Time-based one-time passwords (RFC 6238) for two-factor authentication.
+ *
+ * Uses the defaults every authenticator app supports: HMAC-SHA1, 6 digits
+ * and a 30 second period.
+ */
+use crate::sha256_hex;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha1::Sha1;
+
+type HmacSha1 = Hmac<Sha1>;
+
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+/// Number of periods before and after now that are accepted, for clock drift
+const SKEW: i64 = 1;
+const SECRET_BYTES: usize = 20;
+const BASE32: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 10;
+
+/// Generate a new random secret, base32 encoded as authenticator apps expect it
+pub fn generate_secret() -> String {
+  let bytes: Vec<u8> = (0..SECRET_BYTES).map(|_| thread_rng().gen()).collect();
+  base32::encode(BASE32, &bytes)
+}
+
+/// URI for the QR code scanned by authenticator apps
+pub fn provisioning_uri(secret: &str, account_name: &str, issuer: &str) -> String {
+  let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC).to_string();
+  let account_name = utf8_percent_encode(account_name, NON_ALPHANUMERIC).to_string();
+  format!(
+    "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+    issuer = issuer,
+    account = account_name,
+    secret = secret,
+    digits = DIGITS,
+    period = PERIOD,
+  )
+}
+
+/// One-time password for the given secret and unix time
+pub fn generate_code(secret: &str, unix_time: i64) -> Option<String> {
+  let key = base32::decode(BASE32, secret)?;
+  Some(hotp(&key, (unix_time / PERIOD) as u64))
+}
+
+/**
+ * Check a one-time password against the current time. Codes of time steps up
+ * to last_step were used already and are refused, so a code can't be replayed
+ * while it is still valid.
+ *
+ * @return the time step of the code, to be stored as the new last step
+ */
+pub fn verify_code(secret: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+  verify_code_at(secret, code, chrono::Utc::now().timestamp(), last_step)
+}
+
+pub fn verify_code_at(
+  secret: &str,
+  code: &str,
+  unix_time: i64,
+  last_step: Option<i64>,
+) -> Option<i64> {
+  let key = base32::decode(BASE32, secret)?;
+  let code = code.trim();
+  let counter = unix_time / PERIOD;
+
+  (-SKEW..=SKEW)
+    .map(|offset| counter + offset)
+    .filter(|step| last_step.map_or(true, |last_step| *step > last_step))
+    .find(|step| hotp(&key, *step as u64) == code)
+}
+
+/// HOTP value (RFC 4226) for the counter
+fn hotp(key: &[u8], counter: u64) -> String {
+  let mut mac = HmacSha1::new_varkey(key).expect("HMAC can take key of any size");
+  mac.input(&counter.to_be_bytes());
+  let hash = mac.result().code();
+
+  // Dynamic truncation
+  let offset = (hash[hash.len() - 1] & 0xf) as usize;
+  let binary = (u32::from(hash[offset]) & 0x7f) << 24
+    | u32::from(hash[offset + 1]) << 16
+    | u32::from(hash[offset + 2]) << 8
+    | u32::from(hash[offset + 3]);
+
+  format!(
+    "{:0width$}",
+    binary % 10u32.pow(DIGITS),
+    width = DIGITS as usize
+  )
+}
+
+/**
+ * Generate one-time recovery codes.
+ *
+ * @return (codes, hashes) : the codes are shown to the user once,
+ *                           only the hashes are stored.
+ */
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+  let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+    .map(|_| {
+      thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(RECOVERY_CODE_LENGTH)
+        .collect::<String>()
+        .to_lowercase()
+    })
+    .collect();
+  let hashes = codes.iter().map(|c| sha256_hex(c)).collect();
+  (codes, hashes)
+}
+
+/// Returns the index of the matching recovery code hash, if any
+pub fn find_recovery_code(code: &str, hashes: &[String]) -> Option<usize> {
+  let hash = sha256_hex(&code.trim().to_lowercase());
+  hashes.iter().position(|h| h == &hash)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Secret "12345678901234567890" from the RFC 6238 test vectors
+  const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+  #[test]
+  fn test_rfc6238_vectors() {
+    // The RFC lists 8 digit codes, these are the last 6 digits
+    assert_eq!(generate_code(RFC_SECRET, 59).unwrap(), "287082");
+    assert_eq!(generate_code(RFC_SECRET, 1_111_111_109).unwrap(), "081804");
+    assert_eq!(generate_code(RFC_SECRET, 1_234_567_890).unwrap(), "005924");
+    assert_eq!(generate_code(RFC_SECRET, 2_000_000_000).unwrap(), "279037");
+  }
+
+  #[test]
+  fn test_verify_with_skew() {
+    assert_eq!(verify_code_at(RFC_SECRET, "287082", 59, None), Some(1));
+    assert_eq!(verify_code_at(RFC_SECRET, "287082", 59 + 30, None), Some(1));
+    assert_eq!(verify_code_at(RFC_SECRET, "287082", 59 + 90, None), None);
+    assert_eq!(verify_code_at(RFC_SECRET, "000000", 59, None), None);
+    assert_eq!(verify_code_at("not base32!", "287082", 59, None), None);
+  }
+
+  #[test]
+  fn test_refuse_used_steps() {
+    assert_eq!(verify_code_at(RFC_SECRET, "287082", 59, Some(0)), Some(1));
+    assert_eq!(verify_code_at(RFC_SECRET, "287082", 59, Some(1)), None);
+    assert_eq!(verify_code_at(RFC_SECRET, "287082", 59 + 30, Some(2)), None);
+  }
+
+  #[test]
+  fn test_generated_secret() {
+    let secret = generate_secret();
+    let code = generate_code(&secret, 1000).unwrap();
+    assert_eq!(verify_code_at(&secret, &code, 1000, None), Some(1000 / PERIOD));
+    assert!(provisioning_uri(&secret, "jim", "my lemmy").starts_with("otpauth://totp/my%20lemmy:jim?secret="));
+  }
+
+  #[test]
+  fn test_recovery_codes() {
+    let (codes, hashes) = generate_recovery_codes();
+    assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+    assert_eq!(find_recovery_code(&codes[3].to_uppercase(), &hashes), Some(3));
+    assert_eq!(find_recovery_code("nope", &hashes), None);
+  }
+}
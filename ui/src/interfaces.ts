@@ -74,6 +74,11 @@ export enum SortType {
   TopMonth,
   TopYear,
   TopAll,
+  Active,
+  Controversial,
+  MostComments,
+  NewComments,
+  Old,
 }
 
 export enum SearchType {